@@ -3,6 +3,7 @@ use std::str;
 use std::sync::{Arc, Mutex};
 
 use futures::Future;
+use futures_util::TryFutureExt;
 use structopt::StructOpt;
 use tokio::runtime::current_thread::Runtime;
 
@@ -73,7 +74,7 @@ fn run(log: Logger, options: Opt) -> Result<()> {
 
     builder.logger(log.clone());
     let (endpoint, driver, _) = builder.bind("[::]:0")?;
-    runtime.spawn(driver.map_err(|e| eprintln!("IO error: {}", e)));
+    runtime.spawn(driver.compat().map_err(|e| eprintln!("IO error: {}", e)));
 
     let mut handshake = false;
     let mut stream_data = false;
@@ -81,49 +82,45 @@ fn run(log: Logger, options: Opt) -> Result<()> {
     let mut resumption = false;
     let mut key_update = false;
     let result = runtime.block_on(
-        endpoint
-            .connect_with(&client_config, &remote, &options.host)?
-            .map_err(|e| format_err!("failed to connect: {}", e))
-            .and_then(|conn| {
-                println!("connected");
-                assert!(state.lock().unwrap().saw_cert);
-                handshake = true;
-                let conn = conn.connection;
-                let stream = conn.open_bi();
-                let stream_data = &mut stream_data;
-                stream
-                    .map_err(|e| format_err!("failed to open stream: {}", e))
-                    .and_then(move |stream| get(stream))
-                    .and_then(move |data| {
-                        println!("read {} bytes, closing", data.len());
-                        *stream_data = true;
-                        conn.close(0, b"done").map_err(|_| unreachable!())
-                    })
-                    .map(|()| {
-                        close = true;
-                    })
-            })
-            .and_then(|_| {
-                println!("attempting resumption");
-                state.lock().unwrap().saw_cert = false;
-                endpoint
-                    .connect_with(&client_config, &remote, &options.host)
-                    .unwrap()
-                    .map_err(|e| format_err!("failed to connect: {}", e))
-                    .and_then(|conn| {
-                        resumption = !state.lock().unwrap().saw_cert;
-                        let conn = conn.connection;
-                        conn.force_key_update();
-                        let stream = conn.open_bi();
-                        stream
-                            .map_err(|e| format_err!("failed to open stream: {}", e))
-                            .and_then(move |stream| get(stream))
-                            .inspect(|_| {
-                                key_update = true;
-                            })
-                            .and_then(move |_| conn.close(0, b"done").map_err(|_| unreachable!()))
-                    })
-            }),
+        Box::pin(async {
+            let conn = endpoint
+                .connect_with(&client_config, &remote, &options.host)?
+                .await
+                .map_err(|e| format_err!("failed to connect: {}", e))?;
+            println!("connected");
+            assert!(state.lock().unwrap().saw_cert);
+            handshake = true;
+            let conn = conn.connection;
+            let stream = conn
+                .open_bi()
+                .await
+                .map_err(|e| format_err!("failed to open stream: {}", e))?;
+            let data = get(stream).await?;
+            println!("read {} bytes, closing", data.len());
+            stream_data = true;
+            conn.close(0, b"done").await;
+            close = true;
+
+            println!("attempting resumption");
+            state.lock().unwrap().saw_cert = false;
+            let conn = endpoint
+                .connect_with(&client_config, &remote, &options.host)
+                .unwrap()
+                .await
+                .map_err(|e| format_err!("failed to connect: {}", e))?;
+            resumption = !state.lock().unwrap().saw_cert;
+            let conn = conn.connection;
+            conn.force_key_update();
+            let stream = conn
+                .open_bi()
+                .await
+                .map_err(|e| format_err!("failed to open stream: {}", e))?;
+            get(stream).await?;
+            key_update = true;
+            conn.close(0, b"done").await;
+            Ok::<_, Error>(())
+        })
+        .compat(),
     );
     if let Err(e) = result {
         println!("failure: {}", e);
@@ -137,14 +134,15 @@ fn run(log: Logger, options: Opt) -> Result<()> {
             .next()
             .ok_or(format_err!("couldn't resolve to an address"))?;
         let result = runtime.block_on(
-            endpoint
-                .connect_with(&client_config, &remote, &options.host)?
-                .and_then(|conn| {
-                    retry = true;
-                    conn.connection
-                        .close(0, b"done")
-                        .map_err(|_| unreachable!())
-                }),
+            Box::pin(async {
+                let conn = endpoint
+                    .connect_with(&client_config, &remote, &options.host)?
+                    .await?;
+                retry = true;
+                conn.connection.close(0, b"done").await;
+                Ok::<_, Error>(())
+            })
+            .compat(),
         );
         if let Err(e) = result {
             println!("failure: {}", e);
@@ -175,17 +173,19 @@ fn run(log: Logger, options: Opt) -> Result<()> {
     Ok(())
 }
 
-fn get(stream: quinn::BiStream) -> impl Future<Item = Box<[u8]>, Error = Error> {
-    tokio::io::write_all(stream, b"GET /index.html\r\n".to_owned())
-        .map_err(|e| format_err!("failed to send request: {}", e))
-        .and_then(|(stream, _)| {
-            tokio::io::shutdown(stream).map_err(|e| format_err!("failed to shutdown stream: {}", e))
-        })
-        .and_then(move |stream| {
-            quinn::read_to_end(stream, usize::max_value())
-                .map_err(|e| format_err!("failed to read response: {}", e))
-        })
-        .map(|(_, data)| data)
+async fn get(mut stream: quinn::BiStream) -> Result<Box<[u8]>> {
+    stream
+        .write_all(b"GET /index.html\r\n")
+        .await
+        .map_err(|e| format_err!("failed to send request: {}", e))?;
+    stream
+        .finish()
+        .await
+        .map_err(|e| format_err!("failed to shutdown stream: {}", e))?;
+    let (_, data) = quinn::read_to_end(stream, usize::max_value())
+        .await
+        .map_err(|e| format_err!("failed to read response: {}", e))?;
+    Ok(data)
 }
 
 struct InteropVerifier(Arc<Mutex<State>>);
@@ -11,11 +11,11 @@ use slog::Logger;
 
 use crate::coding::{BufExt, BufMutExt};
 use crate::crypto::{
-    self, reset_token_for, Crypto, CryptoClientConfig, CryptoSession, HeaderCrypto, TlsSession,
+    self, reset_token_for, CryptoClientConfig, CryptoError, CryptoSession, HeaderKeys, Keys,
     ACK_DELAY_EXPONENT,
 };
 use crate::dedup::Dedup;
-use crate::endpoint::{Config, Event, Timer};
+use crate::endpoint::{Config, Event, Received, Timer};
 use crate::frame::FrameStruct;
 use crate::packet::{
     set_payload_length, ConnectionId, EcnCodepoint, Header, LongType, Packet, PacketNumber,
@@ -25,15 +25,18 @@ use crate::range_set::RangeSet;
 use crate::stream::{self, ReadError, Stream, WriteError};
 use crate::transport_parameters::{self, TransportParameters};
 use crate::{
-    frame, Directionality, Frame, Side, StreamId, Transmit, TransportError, MIN_INITIAL_SIZE,
-    MIN_MTU, RESET_TOKEN_SIZE, TIMER_GRANULARITY, VERSION,
+    frame, is_v1, Directionality, Frame, LocalAddress, Side, StreamId, Transmit, TransportError,
+    MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE, TIMER_GRANULARITY,
 };
 
-pub struct Connection {
+pub struct Connection<S>
+where
+    S: CryptoSession,
+{
     log: Logger,
     config: Arc<Config>,
     rng: OsRng,
-    tls: TlsSession,
+    tls: S,
     app_closed: bool,
     /// DCID of Initial packet
     pub(crate) init_cid: ConnectionId,
@@ -46,11 +49,27 @@ pub struct Connection {
     rem_cid_seq: u64,
     remote: SocketAddr,
     prev_remote: Option<SocketAddr>,
+    /// The address the peer last reached us at, if known, from which servers send packets
+    local: Option<LocalAddress>,
+    /// The socket the peer last reached us on, from which packets are sent
+    socket: Option<usize>,
     state: State,
     side: Side,
+    /// QUIC version in use on this connection
+    version: u32,
     mtu: u16,
-    zero_rtt_crypto: Option<CryptoSpace>,
+    zero_rtt_crypto: Option<CryptoSpace<S::Keys>>,
     key_phase: bool,
+    /// Number of packets sent protected by the current 1-RTT keys
+    key_phase_packets: u64,
+    /// Number of 1-RTT key updates so far
+    key_updates: u64,
+    /// Number of received 1-RTT packets that failed authentication, across all key phases
+    forged_packets: u64,
+    /// Time at which the first handshake packet was sent or received
+    handshake_start: Option<u64>,
+    /// Time taken to establish the connection, once it has been
+    handshake_duration: Option<u64>,
     params: TransportParameters,
     /// Streams on which writing was blocked on *connection-level* flow or congestion control
     blocked_streams: FnvHashSet<StreamId>,
@@ -61,9 +80,11 @@ pub struct Connection {
     data_recvd: u64,
     /// Limit on incoming data
     local_max_data: u64,
-    client_config: Option<ClientConfig>,
+    client_config: Option<ClientConfig<S>>,
     /// ConnectionId sent by this client on the first Initial, if a Retry was received.
     orig_rem_cid: Option<ConnectionId>,
+    /// Source ConnectionId of the Retry received by this client, if any
+    retry_src_cid: Option<ConnectionId>,
     /// Total number of outgoing packets that have been deemed lost
     lost_packets: u64,
     io: IoQueue,
@@ -73,11 +94,11 @@ pub struct Connection {
     /// Outgoing spin bit state
     spin: bool,
     /// Packet number spaces: initial, handshake, 1-RTT
-    spaces: [PacketSpace; 3],
+    spaces: [PacketSpace<S::Keys>; 3],
     /// Highest usable packet number space
     highest_space: SpaceId,
     /// 1-RTT keys used prior to a key update
-    prev_crypto: Option<PrevCrypto>,
+    prev_crypto: Option<PrevCrypto<S::Keys>>,
     /// Latest PATH_CHALLENGE token issued to the peer along the current path
     path_challenge: Option<u64>,
     /// Whether the remote endpoint has opened any streams the application doesn't know about yet
@@ -141,18 +162,25 @@ pub struct Connection {
     rem_cids: Vec<frame::NewConnectionId>,
 }
 
-impl Connection {
+impl<S> Connection<S>
+where
+    S: CryptoSession,
+{
     pub fn new(
         log: Logger,
         config: Arc<Config>,
-        init_cid: ConnectionId,
-        loc_cid: ConnectionId,
-        rem_cid: ConnectionId,
-        remote: SocketAddr,
-        client_config: Option<ClientConfig>,
-        tls: TlsSession,
+        origin: Origin,
+        client_config: Option<ClientConfig<S>>,
+        tls: S,
         remote_validated: bool,
     ) -> Self {
+        let Origin {
+            version,
+            init_cid,
+            loc_cid,
+            rem_cid,
+            remote,
+        } = origin;
         let side = if client_config.is_some() {
             Side::Client
         } else {
@@ -161,7 +189,9 @@ impl Connection {
         let rng = OsRng::new().expect("failed to construct RNG");
 
         let initial_space = PacketSpace {
-            crypto: Some(CryptoSpace::new(Crypto::new_initial(&init_cid, side))),
+            crypto: Some(CryptoSpace::new(S::Keys::new_initial(
+                &init_cid, side, version,
+            ))),
             ..PacketSpace::new()
         };
         let mut streams = FnvHashMap::default();
@@ -196,11 +226,19 @@ impl Connection {
             rem_cid_seq: 0,
             remote,
             prev_remote: None,
+            local: None,
+            socket: None,
             side,
+            version,
             state,
             mtu: MIN_MTU,
             zero_rtt_crypto: None,
             key_phase: false,
+            key_phase_packets: 0,
+            key_updates: 0,
+            forged_packets: 0,
+            handshake_start: None,
+            handshake_duration: None,
             params: TransportParameters::new(&config),
             blocked_streams: FnvHashSet::default(),
             max_data: 0,
@@ -209,6 +247,7 @@ impl Connection {
             local_max_data: config.receive_window as u64,
             client_config,
             orig_rem_cid: None,
+            retry_src_cid: None,
             lost_packets: 0,
             io: IoQueue::new(),
             events: VecDeque::new(),
@@ -796,7 +835,7 @@ impl Connection {
     ///
     /// # Panics
     /// - when applied to a receive stream or an unopened send stream
-    pub fn reset(&mut self, stream_id: StreamId, error_code: u16) {
+    pub fn reset(&mut self, stream_id: StreamId, error_code: u64) {
         assert!(
             stream_id.directionality() == Directionality::Bi || stream_id.initiator() == self.side,
             "only streams supporting outgoing data may be reset"
@@ -827,37 +866,48 @@ impl Connection {
     pub fn handle_initial(
         &mut self,
         now: u64,
-        remote: SocketAddr,
-        ecn: Option<EcnCodepoint>,
+        received: Received,
         packet_number: u64,
         packet: Packet,
         remaining: Option<BytesMut>,
     ) -> Result<(), TransportError> {
+        let Received {
+            remote,
+            local,
+            socket,
+            ecn,
+        } = received;
+        self.handshake_start = Some(now);
+        self.local = local;
+        self.socket = Some(socket);
         let len = packet.header_data.len() + packet.payload.len();
         self.on_packet_authenticated(now, SpaceId::Initial, ecn, Some(packet_number), false, len);
         self.process_early_payload(now, packet)?;
         if self.state.is_closed() {
             return Ok(());
         }
-        let params = self
-            .tls
-            .transport_parameters()?
-            .ok_or(TransportError::PROTOCOL_VIOLATION(
-                "transport parameters missing",
-            ))?;
+        let params = self.tls.transport_parameters(self.version)?.ok_or(
+            TransportError::PROTOCOL_VIOLATION("transport parameters missing"),
+        )?;
         self.set_params(params)?;
         self.write_tls();
         self.init_0rtt();
         if let Some(data) = remaining {
-            self.handle_coalesced(now, remote, ecn, data);
+            self.handle_coalesced(now, remote, local, socket, ecn, data);
         }
         Ok(())
     }
 
     fn init_0rtt(&mut self) {
-        if self.side.is_client() && self.tls.early_secret().is_some() {
-            if let Err(e) = self.tls.transport_parameters().and_then(|params| {
-                self.set_params(
+        let packet = match self.tls.early_crypto() {
+            Some(x) => x,
+            None => return,
+        };
+        if self.side.is_client() {
+            if let Err(e) = self.tls.transport_parameters(self.version).map(|params| {
+                // Remembered parameters describe a previous connection, so there are no
+                // connection IDs to validate
+                self.apply_params(
                     params.expect("rustls didn't supply transport parameters with ticket"),
                 )
             }) {
@@ -868,14 +918,8 @@ impl Connection {
                 return;
             }
         }
-        if let Some(secret) = self.tls.early_secret() {
-            trace!(self.log, "0-RTT enabled");
-            let packet = Crypto::new_0rtt(secret);
-            self.zero_rtt_crypto = Some(CryptoSpace {
-                header: packet.header_crypto(),
-                packet,
-            });
-        }
+        trace!(self.log, "0-RTT enabled");
+        self.zero_rtt_crypto = Some(CryptoSpace::new(packet));
     }
 
     fn read_tls(&mut self, space: SpaceId, crypto: &frame::Crypto) -> Result<(), TransportError> {
@@ -911,11 +955,7 @@ impl Connection {
             trace!(self.log, "read {} TLS bytes", n);
             if let Err(e) = self.tls.read_handshake(&buf[..n]) {
                 debug!(self.log, "TLS error: {}", e);
-                return Err(if let Some(alert) = self.tls.alert() {
-                    TransportError::crypto(alert)
-                } else {
-                    TransportError::PROTOCOL_VIOLATION("TLS error")
-                });
+                return Err(e);
             }
         }
     }
@@ -924,7 +964,10 @@ impl Connection {
         loop {
             let space = self.highest_space;
             let mut outgoing = Vec::new();
-            if let Some(crypto) = self.tls.write_handshake(&mut outgoing) {
+            let crypto = self.tls.write_handshake(&mut outgoing);
+            // New keys may be yielded alone, with more data to be written under them
+            let upgraded = crypto.is_some();
+            if let Some(crypto) = crypto {
                 match space {
                     SpaceId::Initial => {
                         self.upgrade_crypto(SpaceId::Handshake, crypto);
@@ -936,6 +979,9 @@ impl Connection {
                 }
             }
             if outgoing.is_empty() {
+                if upgraded {
+                    continue;
+                }
                 break;
             }
             let offset = self.space_mut(space).crypto_offset;
@@ -957,7 +1003,7 @@ impl Connection {
     }
 
     /// Switch to stronger cryptography during handshake
-    fn upgrade_crypto(&mut self, space: SpaceId, crypto: Crypto) {
+    fn upgrade_crypto(&mut self, space: SpaceId, crypto: S::Keys) {
         debug_assert!(
             self.spaces[space as usize].crypto.is_none(),
             "already reached packet space {:?}",
@@ -981,11 +1027,16 @@ impl Connection {
     pub fn handle_dgram(
         &mut self,
         now: u64,
-        remote: SocketAddr,
-        ecn: Option<EcnCodepoint>,
+        received: Received,
         first_decode: PartialDecode,
         remaining: Option<BytesMut>,
     ) {
+        let Received {
+            remote,
+            local,
+            socket,
+            ecn,
+        } = received;
         if remote != self.remote && self.side.is_client() {
             trace!(
                 self.log,
@@ -995,9 +1046,9 @@ impl Connection {
             return;
         }
 
-        self.handle_decode(now, remote, ecn, first_decode);
+        self.handle_decode(now, remote, local, socket, ecn, first_decode);
         if let Some(data) = remaining {
-            self.handle_coalesced(now, remote, ecn, data);
+            self.handle_coalesced(now, remote, local, socket, ecn, data);
         }
     }
 
@@ -1005,6 +1056,8 @@ impl Connection {
         &mut self,
         now: u64,
         remote: SocketAddr,
+        local: Option<LocalAddress>,
+        socket: usize,
         ecn: Option<EcnCodepoint>,
         data: BytesMut,
     ) {
@@ -1013,7 +1066,7 @@ impl Connection {
             match PartialDecode::new(data, self.config.local_cid_len) {
                 Ok((partial_decode, rest)) => {
                     remaining = rest;
-                    self.handle_decode(now, remote, ecn, partial_decode);
+                    self.handle_decode(now, remote, local, socket, ecn, partial_decode);
                 }
                 Err(e) => {
                     trace!(self.log, "malformed header"; "reason" => %e);
@@ -1027,19 +1080,21 @@ impl Connection {
         &mut self,
         now: u64,
         remote: SocketAddr,
+        local: Option<LocalAddress>,
+        socket: usize,
         ecn: Option<EcnCodepoint>,
         partial_decode: PartialDecode,
     ) {
         let header_crypto = if partial_decode.is_0rtt() {
             if let Some(ref crypto) = self.zero_rtt_crypto {
-                Some(&crypto.header)
+                Some(&crypto.header as &dyn HeaderKeys)
             } else {
                 debug!(self.log, "dropping unexpected 0-RTT packet");
                 return;
             }
         } else if let Some(space) = partial_decode.space() {
             if let Some(ref crypto) = self.spaces[space as usize].crypto {
-                Some(&crypto.header)
+                Some(&crypto.header as &dyn HeaderKeys)
             } else {
                 debug!(
                     self.log,
@@ -1055,7 +1110,7 @@ impl Connection {
         };
 
         match partial_decode.finish(header_crypto) {
-            Ok(packet) => self.handle_packet(now, remote, ecn, packet),
+            Ok(packet) => self.handle_packet(now, remote, local, socket, ecn, packet),
             Err(e) => {
                 trace!(self.log, "unable to complete packet decoding"; "reason" => %e);
             }
@@ -1066,6 +1121,8 @@ impl Connection {
         &mut self,
         now: u64,
         remote: SocketAddr,
+        local: Option<LocalAddress>,
+        socket: usize,
         ecn: Option<EcnCodepoint>,
         mut packet: Packet,
     ) {
@@ -1092,10 +1149,17 @@ impl Connection {
                 debug!(self.log, "failed to authenticate packet");
                 if stateless_reset {
                     Err(ConnectionError::Reset)
+                } else if packet.header.is_short() && self.integrity_limit_reached() {
+                    Err(self.aead_limit_reached("integrity limit exceeded").into())
                 } else {
                     return;
                 }
             }
+            Ok(_) if packet.header.is_short() && self.state.is_handshake() => {
+                // Dropped without being acknowledged, so that the peer retransmits its contents
+                trace!(self.log, "dropping short packet during handshake");
+                return;
+            }
             Ok(number) => {
                 let duplicate = number.and_then(|n| {
                     if self.space_mut(packet.header.space()).dedup.insert(n) {
@@ -1132,7 +1196,7 @@ impl Connection {
                             packet.header_data.len() + packet.payload.len(),
                         );
                     }
-                    self.handle_connected_inner(now, remote, number, packet)
+                    self.handle_connected_inner(now, remote, local, socket, number, packet)
                 }
             }
         };
@@ -1181,6 +1245,8 @@ impl Connection {
         &mut self,
         now: u64,
         remote: SocketAddr,
+        local: Option<LocalAddress>,
+        socket: usize,
         number: Option<u64>,
         packet: Packet,
     ) -> Result<(), ConnectionError> {
@@ -1188,15 +1254,34 @@ impl Connection {
             State::Handshake(ref state) => {
                 match packet.header {
                     Header::Retry {
+                        version,
                         src_cid: rem_cid,
                         orig_dst_cid,
                         ..
                     } => {
-                        if self.orig_rem_cid.is_some() || orig_dst_cid != self.rem_cid {
-                            // A client MUST accept and process at most one Retry packet for each
-                            // connection attempt, and clients MUST discard Retry packets that
-                            // contain an Original Destination Connection ID field that does not
-                            // match the Destination Connection ID from its Initial packet.
+                        // A client MUST accept and process at most one Retry packet for each
+                        // connection attempt, and clients MUST discard Retry packets that fail
+                        // validation against the Destination Connection ID from its Initial
+                        // packet: by integrity tag in version 1, or by echoed CID in drafts.
+                        if self.side.is_server() || self.orig_rem_cid.is_some() {
+                            return Ok(());
+                        }
+                        if version != self.version {
+                            return Ok(());
+                        }
+                        let mut token = packet.payload;
+                        if is_v1(self.version) {
+                            if !crypto::is_valid_retry(&self.rem_cid, &packet.header_data, &token) {
+                                debug!(self.log, "discarding Retry with invalid integrity tag");
+                                return Ok(());
+                            }
+                            let len = token.len() - crypto::RETRY_TAG_SIZE;
+                            token.truncate(len);
+                            if token.is_empty() {
+                                return Ok(());
+                            }
+                            self.retry_src_cid = Some(rem_cid);
+                        } else if orig_dst_cid != Some(self.rem_cid) {
                             return Ok(());
                         }
                         trace!(self.log, "retrying with CID {rem_cid}", rem_cid = rem_cid);
@@ -1207,17 +1292,20 @@ impl Connection {
 
                         // Reset to initial state
                         let client_config = self.client_config.as_ref().unwrap();
+                        let params = TransportParameters {
+                            initial_src_cid: Some(self.handshake_cid),
+                            ..TransportParameters::new(&self.config)
+                        };
                         self.tls = client_config
                             .tls_config
-                            .start_session(
-                                &client_config.server_name,
-                                &TransportParameters::new(&self.config),
-                            )
+                            .start_session(&client_config.server_name, self.version, &params)
                             .unwrap();
                         self.discard_space(SpaceId::Initial); // Make sure we clean up after any retransmitted Initials
                         self.spaces[0] = PacketSpace {
-                            crypto: Some(CryptoSpace::new(Crypto::new_initial(
-                                &rem_cid, self.side,
+                            crypto: Some(CryptoSpace::new(S::Keys::new_initial(
+                                &rem_cid,
+                                self.side,
+                                self.version,
                             ))),
                             ..PacketSpace::new()
                         };
@@ -1225,7 +1313,7 @@ impl Connection {
                         self.write_tls();
 
                         self.state = State::Handshake(state::Handshake {
-                            token: Some(packet.payload.into()),
+                            token: Some(token.freeze()),
                             rem_cid_set: false,
                         });
                         Ok(())
@@ -1257,7 +1345,7 @@ impl Connection {
 
                         if self.side.is_client() {
                             // Client-only beceause server params were set from the client's Initial
-                            let params = match self.tls.transport_parameters() {
+                            let params = match self.tls.transport_parameters(self.version) {
                                 Ok(Some(params)) => Ok(params),
                                 Ok(None) => Err(TransportError::PROTOCOL_VIOLATION(
                                     "transport parameters missing",
@@ -1266,7 +1354,7 @@ impl Connection {
                             }?;
 
                             if self.has_0rtt() {
-                                if !self.tls.as_client().is_early_data_accepted() {
+                                if self.tls.early_data_accepted() != Some(true) {
                                     self.reject_0rtt();
                                 } else {
                                     self.accepted_0rtt = true;
@@ -1290,8 +1378,12 @@ impl Connection {
                                 }
                             }
                             self.set_params(params)?;
+                        } else if is_v1(self.version) {
+                            // The server confirms the handshake as soon as it completes
+                            self.space_mut(SpaceId::Data).pending.handshake_done = true;
                         }
                         self.events.push_back(Event::Connected);
+                        self.handshake_duration = self.handshake_start.map(|x| now - x);
                         self.state = State::Established;
                         trace!(self.log, "established");
                         Ok(())
@@ -1321,7 +1413,14 @@ impl Connection {
                         ty: LongType::ZeroRtt,
                         ..
                     } => {
-                        self.process_payload(now, remote, number.unwrap(), packet.payload.into())?;
+                        self.process_payload(
+                            now,
+                            remote,
+                            local,
+                            socket,
+                            number.unwrap(),
+                            packet.payload.into(),
+                        )?;
                         Ok(())
                     }
                     Header::VersionNegotiate { .. } => {
@@ -1334,7 +1433,7 @@ impl Connection {
                         }
                         while payload.has_remaining() {
                             let version = payload.get::<u32>().unwrap();
-                            if version == VERSION {
+                            if version == self.version {
                                 // Our version is supported, so this packet is spurious
                                 return Ok(());
                             }
@@ -1351,15 +1450,20 @@ impl Connection {
             }
             State::Established => {
                 match packet.header.space() {
-                    SpaceId::Data => {
-                        self.process_payload(now, remote, number.unwrap(), packet.payload.into())?
-                    }
+                    SpaceId::Data => self.process_payload(
+                        now,
+                        remote,
+                        local,
+                        socket,
+                        number.unwrap(),
+                        packet.payload.into(),
+                    )?,
                     _ => self.process_early_payload(now, packet)?,
                 }
                 Ok(())
             }
             State::Closed(_) => {
-                for frame in frame::Iter::new(packet.payload.into()) {
+                for frame in frame::Iter::new(packet.payload.into(), self.version) {
                     let peer_reason = match frame {
                         Frame::ApplicationClose(reason) => {
                             ConnectionError::ApplicationClosed { reason }
@@ -1387,7 +1491,7 @@ impl Connection {
     /// Process an Initial or Handshake packet payload
     fn process_early_payload(&mut self, now: u64, packet: Packet) -> Result<(), TransportError> {
         debug_assert_ne!(packet.header.space(), SpaceId::Data);
-        for frame in frame::Iter::new(packet.payload.into()) {
+        for frame in frame::Iter::new(packet.payload.into(), self.version) {
             match frame {
                 Frame::Padding => {}
                 _ => {
@@ -1437,7 +1541,7 @@ impl Connection {
     }
 
     pub fn issue_cid(&mut self, cid: ConnectionId) {
-        let token = reset_token_for(&self.config.reset_key, &cid);
+        let token = reset_token_for(&self.config.reset_key, &self.config.server_id, &cid);
         self.cids_issued += 1;
         let sequence = self.cids_issued;
         self.space_mut(SpaceId::Data)
@@ -1446,6 +1550,7 @@ impl Connection {
             .push(frame::NewConnectionId {
                 id: cid,
                 sequence,
+                retire_prior_to: 0,
                 reset_token: token,
             });
         self.loc_cids.insert(self.cids_issued, cid);
@@ -1455,12 +1560,14 @@ impl Connection {
         &mut self,
         now: u64,
         remote: SocketAddr,
+        local: Option<LocalAddress>,
+        socket: usize,
         number: u64,
         payload: Bytes,
     ) -> Result<(), TransportError> {
         let is_0rtt = self.space(SpaceId::Data).crypto.is_none();
         let mut is_probing_packet = true;
-        for frame in frame::Iter::new(payload) {
+        for frame in frame::Iter::new(payload, self.version) {
             match frame {
                 Frame::Padding => {}
                 _ => {
@@ -1524,7 +1631,7 @@ impl Connection {
                     if let Some(final_offset) = rs.final_offset() {
                         if end > final_offset || (frame.fin && end != final_offset) {
                             debug!(self.log, "final offset error"; "frame end" => end, "final offset" => final_offset);
-                            return Err(TransportError::FINAL_OFFSET_ERROR(""));
+                            return Err(TransportError::FINAL_SIZE_ERROR(""));
                         }
                     }
                     let prev_end = rs.limit();
@@ -1677,10 +1784,10 @@ impl Connection {
                     // Validate final_offset
                     if let Some(offset) = rs.final_offset() {
                         if offset != final_offset {
-                            return Err(TransportError::FINAL_OFFSET_ERROR("inconsistent value"));
+                            return Err(TransportError::FINAL_SIZE_ERROR("inconsistent value"));
                         }
                     } else if limit > final_offset {
-                        return Err(TransportError::FINAL_OFFSET_ERROR(
+                        return Err(TransportError::FINAL_SIZE_ERROR(
                             "lower than high water mark",
                         ));
                     }
@@ -1782,12 +1889,27 @@ impl Connection {
                             "NEW_CONNECTION_ID when CIDs aren't in use",
                         ));
                     }
+                    if frame.retire_prior_to > 0 {
+                        let retire_cids =
+                            &mut self.spaces[SpaceId::Data as usize].pending.retire_cids;
+                        self.rem_cids.retain(|x| {
+                            if x.sequence < frame.retire_prior_to {
+                                retire_cids.push(x.sequence);
+                                false
+                            } else {
+                                true
+                            }
+                        });
+                    }
                     if self.params.stateless_reset_token.is_none() {
                         // We're a server using the initial remote CID for the client, so let's
                         // switch immediately to enable clientside stateless resets.
                         debug_assert!(self.side.is_server());
                         debug_assert_eq!(self.rem_cid_seq, 0);
                         self.update_rem_cid(frame);
+                    } else if self.rem_cid_seq < frame.retire_prior_to {
+                        // The CID we're using has been retired by the peer
+                        self.update_rem_cid(frame);
                     } else {
                         // Reasonable limit to bound memory use
                         if self.rem_cids.len() < 32 {
@@ -1799,19 +1921,35 @@ impl Connection {
                     trace!(self.log, "got new token");
                     // TODO: Cache, or perhaps forward to user?
                 }
+                Frame::HandshakeDone => {
+                    if self.side.is_server() {
+                        return Err(TransportError::PROTOCOL_VIOLATION(
+                            "client sent HANDSHAKE_DONE",
+                        ));
+                    }
+                    if self.spaces[SpaceId::Handshake as usize].crypto.is_some() {
+                        self.discard_space(SpaceId::Handshake);
+                    }
+                }
             }
         }
 
-        if remote != self.remote && !is_probing_packet {
-            debug_assert!(
-                self.side.is_server(),
-                "packets from unknown remote should be dropped by clients"
-            );
-            self.migrate(now, remote);
-            // Break linkability, if possible
-            if let Some(cid) = self.rem_cids.pop() {
-                self.update_rem_cid(cid);
+        if !is_probing_packet {
+            if remote != self.remote {
+                debug_assert!(
+                    self.side.is_server(),
+                    "packets from unknown remote should be dropped by clients"
+                );
+                self.migrate(now, remote);
+                // Break linkability, if possible
+                if let Some(cid) = self.rem_cids.pop() {
+                    self.update_rem_cid(cid);
+                }
             }
+            // Reply from wherever the peer now reaches us, which only an authenticated packet on
+            // the current path may change
+            self.local = local;
+            self.socket = Some(socket);
         }
 
         Ok(())
@@ -1902,6 +2040,14 @@ impl Connection {
             buf.write(frame::Type::PING);
         }
 
+        // HANDSHAKE_DONE
+        if space.pending.handshake_done && !is_0rtt {
+            trace!(self.log, "HANDSHAKE_DONE");
+            space.pending.handshake_done = false;
+            sent.handshake_done = true;
+            buf.write(frame::Type::HANDSHAKE_DONE);
+        }
+
         // ACK
         // 0-RTT packets must never carry acks (which would have to be of handshake packets)
         let acks = if !space.pending_acks.is_empty() {
@@ -1997,11 +2143,11 @@ impl Connection {
                     error_code,
                     final_offset: stream.send().unwrap().offset,
                 }
-                .encode(buf);
+                .encode(buf, self.version);
             }
 
             // STOP_SENDING
-            while buf.len() + 11 < max_size {
+            while buf.len() + 17 < max_size {
                 let (id, error_code) = if let Some(x) = space.pending.stop_sending.pop() {
                     x
                 } else {
@@ -2019,7 +2165,7 @@ impl Connection {
                 sent.stop_sending.push((id, error_code));
                 buf.write(frame::Type::STOP_SENDING);
                 buf.write(id);
-                buf.write(error_code);
+                frame::write_error_code(buf, self.version, error_code);
             }
         }
 
@@ -2080,7 +2226,7 @@ impl Connection {
         }
 
         // NEW_CONNECTION_ID
-        while buf.len() + 44 < max_size {
+        while buf.len() + 55 < max_size {
             let frame = if let Some(x) = space.pending.new_cids.pop() {
                 x
             } else {
@@ -2091,7 +2237,7 @@ impl Connection {
                 "NEW_CONNECTION_ID {sequence}",
                 sequence = frame.sequence
             );
-            frame.encode(buf);
+            frame.encode(buf, self.version);
             sent.new_cids.push(frame);
         }
 
@@ -2153,7 +2299,44 @@ impl Connection {
     /// - the application performed some I/O on the connection
     /// - an incoming packet is handled
     /// - the LossDetection timer expires
-    pub fn poll_transmit(&mut self, now: u64) -> Option<Transmit> {
+    ///
+    /// Up to `max_datagrams` full-sized packets for the same destination may be returned in a
+    /// single `Transmit`, followed by at most one smaller packet.
+    pub fn poll_transmit(&mut self, now: u64, max_datagrams: usize) -> Option<Transmit> {
+        let mut transmit = self.poll_transmit_one(now, max_datagrams > 1)?;
+        if max_datagrams <= 1
+            || transmit.contents.len() != self.mtu as usize
+            || transmit.destination != self.remote
+        {
+            return Some(transmit);
+        }
+        let segment_size = transmit.contents.len();
+        let mut contents = Vec::from(transmit.contents);
+        let mut datagrams = 1;
+        // Stop after a short packet, and before any that might be bound elsewhere
+        while datagrams < max_datagrams
+            && contents.len() == datagrams * segment_size
+            && self.offpath_responses.is_empty()
+        {
+            match self.poll_transmit_one(now, datagrams + 1 < max_datagrams) {
+                Some(x) => contents.extend_from_slice(&x.contents),
+                None => break,
+            }
+            datagrams += 1;
+        }
+        if datagrams > 1 {
+            transmit.segment_size = Some(segment_size);
+        }
+        transmit.contents = contents.into();
+        Some(transmit)
+    }
+
+    /// Construct a single packet, padded to the full MTU if `pad` is set and another packet can
+    /// follow it in the same batch
+    fn poll_transmit_one(&mut self, now: u64, pad: bool) -> Option<Transmit> {
+        if self.handshake_start.is_none() {
+            self.handshake_start = Some(now);
+        }
         let (space_id, close) = match self.state {
             State::Draining | State::Drained => {
                 return None;
@@ -2217,6 +2400,23 @@ impl Connection {
         if let Some(ref mut prev) = self.prev_crypto {
             prev.update_unacked = false;
         }
+        if space_id == SpaceId::Data && self.space(SpaceId::Data).crypto.is_some() {
+            if close {
+                if self.key_phase_packets >= self.confidentiality_limit() {
+                    // The current keys must not be used again, so the peer must time out
+                    trace!(self.log, "confidentiality limit reached, not sending close");
+                    return None;
+                }
+            } else if let Err(e) = self.update_keys_if_due() {
+                warn!(self.log, "closing connection"; "reason" => %e);
+                self.events
+                    .push_back(ConnectionError::TransportError(e).into());
+                self.state = State::closed(e);
+                self.close_common(now);
+                self.io.close = true;
+                return None;
+            }
+        }
 
         let space = &mut self.spaces[space_id as usize];
         let exact_number = space.get_tx_number();
@@ -2235,18 +2435,21 @@ impl Connection {
                 key_phase: self.key_phase,
             },
             SpaceId::Data => Header::Long {
+                version: self.version,
                 ty: LongType::ZeroRtt,
                 src_cid: self.handshake_cid,
                 dst_cid: self.rem_cid,
                 number,
             },
             SpaceId::Handshake => Header::Long {
+                version: self.version,
                 ty: LongType::Handshake,
                 src_cid: self.handshake_cid,
                 dst_cid: self.rem_cid,
                 number,
             },
             SpaceId::Initial => Header::Initial {
+                version: self.version,
                 src_cid: self.handshake_cid,
                 dst_cid: self.rem_cid,
                 token: match self.state {
@@ -2267,10 +2470,10 @@ impl Connection {
             match self.state {
                 State::Closed(state::Closed {
                     reason: state::CloseReason::Application(ref x),
-                }) => x.encode(&mut buf, max_len),
+                }) => x.encode(&mut buf, self.version, max_len),
                 State::Closed(state::Closed {
                     reason: state::CloseReason::Connection(ref x),
-                }) => x.encode(&mut buf, max_len),
+                }) => x.encode(&mut buf, self.version, max_len),
                 _ => unreachable!("tried to make a close packet when the connection wasn't closed"),
             }
             (self.remote, None)
@@ -2295,6 +2498,12 @@ impl Connection {
             ack_only = false;
         }
 
+        // Padding is wasted unless the next packet joins this one in the batch
+        let pad = pad
+            && !close
+            && remote == self.remote
+            && self.offpath_responses.is_empty()
+            && self.congestion_room(2);
         let space = &mut self.spaces[space_id as usize];
         let crypto = if let Some(ref crypto) = space.crypto {
             crypto
@@ -2309,6 +2518,13 @@ impl Connection {
             buf.resize(MIN_INITIAL_SIZE - crypto.packet.tag_len(), 0);
             padded = true;
         }
+        let full = self.mtu as usize - crypto.packet.tag_len();
+        if pad && header.is_short() && buf.len() < full && !space.pending.is_empty() {
+            // Allow the packets that follow to be batched with this one
+            trace!(self.log, "PADDING * {count}", count = full - buf.len());
+            buf.resize(full, 0);
+            padded = true;
+        }
         let pn_len = number.len();
         // To ensure that sufficient data is available for sampling, packets are padded so that the
         // combined lengths of the encoded packet number and protected payload is at least 4 bytes
@@ -2327,6 +2543,9 @@ impl Connection {
         }
         crypto.packet.encrypt(exact_number, &mut buf, header_len);
         partial_encode.finish(&mut buf, &crypto.header);
+        if header.is_short() {
+            self.key_phase_packets += 1;
+        }
 
         if let Some((sent, acks)) = sent {
             // If we sent any acks, don't immediately resend them. Setting this even if ack_only is
@@ -2363,12 +2582,21 @@ impl Connection {
 
         Some(Transmit {
             destination: remote,
-            packet: buf.into(),
+            contents: buf.into(),
             ecn: if self.sending_ecn {
                 Some(EcnCodepoint::ECT0)
             } else {
                 None
             },
+            segment_size: None,
+            // A client's own address may change under it, e.g. when switching networks, so only
+            // servers, which must reply from the address they were contacted on, pin theirs
+            src: if self.side.is_server() {
+                self.local
+            } else {
+                None
+            },
+            socket: self.socket,
         })
     }
 
@@ -2376,7 +2604,7 @@ impl Connection {
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility
     /// to call this only when all important communications have been completed.
-    pub fn close(&mut self, now: u64, error_code: u16, reason: Bytes) {
+    pub fn close(&mut self, now: u64, error_code: u64, reason: Bytes) {
         let was_closed = self.state.is_closed();
         let reason =
             state::CloseReason::Application(frame::ApplicationClose { error_code, reason });
@@ -2405,19 +2633,47 @@ impl Connection {
 
     fn set_params(&mut self, params: TransportParameters) -> Result<(), TransportError> {
         // Validate
-        if self.side.is_client() && self.orig_rem_cid != params.original_connection_id {
+        let expected_orig_cid = if is_v1(self.version) {
+            // Version 1 servers always echo the CID the client first chose
+            Some(self.orig_rem_cid.unwrap_or(self.init_cid))
+        } else {
+            self.orig_rem_cid
+        };
+        if self.side.is_client() && expected_orig_cid != params.original_connection_id {
             debug!(
                 self.log,
                 "original connection ID mismatch: expected {expected:x?}, actual {actual:x?}",
-                expected = self.orig_rem_cid,
+                expected = expected_orig_cid,
                 actual = params.original_connection_id
             );
             return Err(TransportError::TRANSPORT_PARAMETER_ERROR(
                 "original CID mismatch",
             ));
         }
+        if is_v1(self.version) {
+            if params.initial_src_cid != Some(self.rem_handshake_cid) {
+                debug!(
+                    self.log,
+                    "initial source connection ID mismatch: expected {expected}, actual {actual:x?}",
+                    expected = self.rem_handshake_cid,
+                    actual = params.initial_src_cid
+                );
+                return Err(TransportError::TRANSPORT_PARAMETER_ERROR(
+                    "initial source CID mismatch",
+                ));
+            }
+            if self.side.is_client() && self.retry_src_cid != params.retry_src_cid {
+                return Err(TransportError::TRANSPORT_PARAMETER_ERROR(
+                    "retry source CID mismatch",
+                ));
+            }
+        }
 
-        // Apply
+        self.apply_params(params);
+        Ok(())
+    }
+
+    fn apply_params(&mut self, params: TransportParameters) {
         self.streams.max_bi = params.initial_max_streams_bidi;
         self.streams.max_uni = params.initial_max_streams_uni;
         self.max_data = params.initial_max_data as u64;
@@ -2427,7 +2683,6 @@ impl Connection {
                 params.initial_max_stream_data_bidi_local as u64;
         }
         self.params = params;
-        Ok(())
     }
 
     pub fn open(&mut self, direction: Directionality) -> Option<StreamId> {
@@ -2589,7 +2844,7 @@ impl Connection {
         Ok(len)
     }
 
-    pub fn stop_sending(&mut self, id: StreamId, error_code: u16) {
+    pub fn stop_sending(&mut self, id: StreamId, error_code: u64) {
         assert!(
             id.directionality() == Directionality::Bi || id.initiator() != self.side,
             "only streams supporting incoming data may be stopped"
@@ -2609,10 +2864,15 @@ impl Connection {
     }
 
     fn congestion_blocked(&self) -> bool {
+        !self.congestion_room(1)
+    }
+
+    /// Whether the congestion window admits `packets` more full-sized packets
+    fn congestion_room(&self, packets: u64) -> bool {
         if let State::Established = self.state {
-            self.congestion_window.saturating_sub(self.in_flight.bytes) < self.mtu as u64
+            self.congestion_window.saturating_sub(self.in_flight.bytes) >= packets * self.mtu as u64
         } else {
-            false
+            true
         }
     }
 
@@ -2654,14 +2914,14 @@ impl Connection {
                     .as_ref()
                     .unwrap()
                     .packet
-                    .update(self.side, &self.tls),
+                    .update(self.side, self.version),
             );
             crypto_update.as_ref().unwrap()
         };
 
         crypto
             .decrypt(number, &packet.header_data, &mut packet.payload)
-            .map_err(|()| {
+            .map_err(|CryptoError| {
                 trace!(
                     self.log,
                     "decryption failed with packet number {packet}",
@@ -2716,7 +2976,7 @@ impl Connection {
             .as_ref()
             .unwrap()
             .packet
-            .update(self.side, &self.tls);
+            .update(self.side, self.version);
         self.update_keys(update, space.next_packet_number, false);
     }
 
@@ -2762,7 +3022,81 @@ impl Connection {
         Ok(n)
     }
 
-    fn update_keys(&mut self, crypto: Crypto, number: u64, remote: bool) {
+    /// Maximum number of packets the current 1-RTT keys may protect
+    ///
+    /// `Config::max_packets_per_key` only applies once the handshake is confirmed, as the keys
+    /// can't be updated before then.
+    fn confidentiality_limit(&self) -> u64 {
+        let limit = self.spaces[SpaceId::Data as usize]
+            .crypto
+            .as_ref()
+            .unwrap()
+            .packet
+            .confidentiality_limit();
+        if self.is_handshake_confirmed() {
+            cmp::min(limit, self.config.max_packets_per_key)
+        } else {
+            limit
+        }
+    }
+
+    /// Whether the handshake is complete and the handshake keys have been discarded
+    fn is_handshake_confirmed(&self) -> bool {
+        match self.state {
+            State::Established => self.spaces[SpaceId::Handshake as usize].crypto.is_none(),
+            _ => false,
+        }
+    }
+
+    /// Initiate a key update if the current 1-RTT keys have protected enough packets
+    ///
+    /// Fails if the confidentiality limit of the keys has nearly been reached without a key update
+    /// being permitted, leaving one packet to carry a CONNECTION_CLOSE.
+    fn update_keys_if_due(&mut self) -> Result<(), TransportError> {
+        let limit = self.confidentiality_limit();
+        // Leave plenty of headroom for the peer to respond before we run out
+        let threshold = limit - limit / 4;
+        if self.key_phase_packets < threshold {
+            return Ok(());
+        }
+        // Only one update may be in progress at a time, and only once the handshake is confirmed
+        let permitted = self.is_handshake_confirmed()
+            && !matches!(
+                self.prev_crypto,
+                Some(PrevCrypto {
+                    update_ack_time: None,
+                    ..
+                })
+            );
+        if permitted {
+            trace!(self.log, "initiating key update"; "packets" => self.key_phase_packets);
+            self.force_key_update();
+        } else if self.key_phase_packets + 1 >= limit {
+            return Err(self.aead_limit_reached("confidentiality limit reached"));
+        }
+        Ok(())
+    }
+
+    /// Error for exhausting an AEAD usage limit, which draft 17 has no specific code for
+    fn aead_limit_reached(&self, reason: &'static str) -> TransportError {
+        if is_v1(self.version) {
+            TransportError::AEAD_LIMIT_REACHED(reason)
+        } else {
+            TransportError::PROTOCOL_VIOLATION(reason)
+        }
+    }
+
+    /// Count a 1-RTT packet that failed authentication, returning whether too many have
+    fn integrity_limit_reached(&mut self) -> bool {
+        self.forged_packets += 1;
+        self.spaces[SpaceId::Data as usize]
+            .crypto
+            .as_ref()
+            .map(|x| cmp::min(x.packet.integrity_limit(), self.config.max_forged_packets))
+            .map_or(false, |limit| self.forged_packets > limit)
+    }
+
+    fn update_keys(&mut self, crypto: S::Keys, number: u64, remote: bool) {
         let old = mem::replace(
             &mut self.spaces[SpaceId::Data as usize]
                 .crypto
@@ -2778,6 +3112,8 @@ impl Connection {
             update_unacked: remote,
         });
         self.key_phase = !self.key_phase;
+        self.key_phase_packets = 0;
+        self.key_updates += 1;
     }
 
     pub fn is_handshaking(&self) -> bool {
@@ -2819,10 +3155,30 @@ impl Connection {
         self.rem_cid
     }
 
+    /// Maximum number of `ConnectionId`s the peer is willing to store for us
+    pub(crate) fn peer_cid_limit(&self) -> u64 {
+        if is_v1(self.version) {
+            self.params.active_connection_id_limit
+        } else {
+            // Draft 17 places no limit on the connection IDs we may issue
+            u64::max_value()
+        }
+    }
+
+    /// The QUIC version in use on this Connection
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
     pub fn remote(&self) -> SocketAddr {
         self.remote
     }
 
+    /// The socket the peer last reached us on, which packets are sent from
+    pub(crate) fn socket(&self) -> Option<usize> {
+        self.socket
+    }
+
     pub fn protocol(&self) -> Option<&[u8]> {
         self.tls.alpn_protocol()
     }
@@ -2845,11 +3201,44 @@ impl Connection {
         self.tls.sni_hostname()
     }
 
+    /// Derive `len` bytes of keying material bound to this connection
+    ///
+    /// Both endpoints obtain the same material for the same `label` and `context`, which makes
+    /// it suitable for channel binding. Fails until the handshake is complete.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, crypto::ExportKeyingMaterialError> {
+        let mut output = vec![0; len];
+        self.tls
+            .export_keying_material(&mut output, label, context)?;
+        Ok(output)
+    }
+
+    /// The cryptographic session securing this connection, for protocol-specific details
+    pub fn crypto_session(&self) -> &S {
+        &self.tls
+    }
+
     /// Total number of outgoing packets that have been deemed lost
     pub fn lost_packets(&self) -> u64 {
         self.lost_packets
     }
 
+    /// Number of 1-RTT key updates, whether initiated locally or by the peer
+    pub fn key_updates(&self) -> u64 {
+        self.key_updates
+    }
+
+    /// Time taken to establish the connection (μs), once it has been
+    ///
+    /// Measured from when the client sent or the server received the first Initial packet.
+    pub fn handshake_duration(&self) -> Option<u64> {
+        self.handshake_duration
+    }
+
     /// Whether explicit congestion notification is in use on outgoing packets.
     pub fn using_ecn(&self) -> bool {
         self.sending_ecn
@@ -2860,11 +3249,11 @@ impl Connection {
         u64::from(self.params.max_ack_delay) * 1000
     }
 
-    fn space(&self, id: SpaceId) -> &PacketSpace {
+    fn space(&self, id: SpaceId) -> &PacketSpace<S::Keys> {
         &self.spaces[id as usize]
     }
 
-    fn space_mut(&mut self, id: SpaceId) -> &mut PacketSpace {
+    fn space_mut(&mut self, id: SpaceId) -> &mut PacketSpace<S::Keys> {
         &mut self.spaces[id as usize]
     }
 
@@ -2905,19 +3294,22 @@ impl Connection {
     }
 }
 
-pub fn initial_close<R>(
-    crypto: &Crypto,
-    header_crypto: &HeaderCrypto,
+pub fn initial_close<K, R>(
+    version: u32,
+    crypto: &K,
+    header_crypto: &dyn HeaderKeys,
     remote_id: &ConnectionId,
     local_id: &ConnectionId,
     packet_number: u8,
     reason: R,
 ) -> Box<[u8]>
 where
+    K: Keys,
     R: Into<state::CloseReason>,
 {
     let number = PacketNumber::U8(packet_number);
     let header = Header::Initial {
+        version,
         dst_cid: *remote_id,
         src_cid: *local_id,
         number,
@@ -2929,8 +3321,8 @@ where
     let header_len = buf.len();
     let max_len = MIN_MTU as usize - header_len - crypto.tag_len();
     match reason.into() {
-        state::CloseReason::Application(ref x) => x.encode(&mut buf, max_len),
-        state::CloseReason::Connection(ref x) => x.encode(&mut buf, max_len),
+        state::CloseReason::Application(ref x) => x.encode(&mut buf, version, max_len),
+        state::CloseReason::Connection(ref x) => x.encode(&mut buf, version, max_len),
     }
     set_payload_length(&mut buf, header_len, number.len(), crypto.tag_len());
     crypto.encrypt(packet_number as u64, &mut buf, header_len);
@@ -3003,12 +3395,13 @@ impl Streams {
 /// Retransmittable data queue
 #[derive(Debug, Clone)]
 struct Retransmits {
+    handshake_done: bool,
     max_data: bool,
     max_uni_stream_id: bool,
     max_bi_stream_id: bool,
     stream: VecDeque<frame::Stream>,
-    rst_stream: Vec<(StreamId, u16)>,
-    stop_sending: Vec<(StreamId, u16)>,
+    rst_stream: Vec<(StreamId, u64)>,
+    stop_sending: Vec<(StreamId, u64)>,
     max_stream_data: FnvHashSet<StreamId>,
     crypto: VecDeque<frame::Crypto>,
     new_cids: Vec<frame::NewConnectionId>,
@@ -3017,7 +3410,8 @@ struct Retransmits {
 
 impl Retransmits {
     fn is_empty(&self) -> bool {
-        !self.max_data
+        !self.handshake_done
+            && !self.max_data
             && !self.max_uni_stream_id
             && !self.max_bi_stream_id
             && self.stream.is_empty()
@@ -3033,6 +3427,7 @@ impl Retransmits {
 impl Default for Retransmits {
     fn default() -> Self {
         Self {
+            handshake_done: false,
             max_data: false,
             max_uni_stream_id: false,
             max_bi_stream_id: false,
@@ -3049,6 +3444,7 @@ impl Default for Retransmits {
 
 impl ::std::ops::AddAssign for Retransmits {
     fn add_assign(&mut self, rhs: Self) {
+        self.handshake_done |= rhs.handshake_done;
         self.max_data |= rhs.max_data;
         self.max_uni_stream_id |= rhs.max_uni_stream_id;
         self.max_bi_stream_id |= rhs.max_bi_stream_id;
@@ -3210,10 +3606,22 @@ mod state {
     }
 }
 
-#[derive(Clone)]
-pub struct ClientConfig {
+pub struct ClientConfig<S>
+where
+    S: CryptoSession,
+{
     pub server_name: String,
-    pub tls_config: Arc<crypto::ClientConfig>,
+    pub tls_config: S::ClientConfig,
+}
+
+/// The version, connection IDs and peer a new connection starts out with
+pub struct Origin {
+    pub version: u32,
+    /// Destination CID of the client's first Initial packet, from which initial keys are derived
+    pub init_cid: ConnectionId,
+    pub loc_cid: ConnectionId,
+    pub rem_cid: ConnectionId,
+    pub remote: SocketAddr,
 }
 
 /// Represents one or more packets subject to retransmission
@@ -3299,8 +3707,11 @@ pub struct TimerUpdate {
     pub update: TimerSetting,
 }
 
-struct PacketSpace {
-    crypto: Option<CryptoSpace>,
+struct PacketSpace<K>
+where
+    K: Keys,
+{
+    crypto: Option<CryptoSpace<K>>,
     dedup: Dedup,
     /// Highest received packet number
     rx_packet: u64,
@@ -3335,7 +3746,10 @@ struct PacketSpace {
     crypto_offset: u64,
 }
 
-impl PacketSpace {
+impl<K> PacketSpace<K>
+where
+    K: Keys,
+{
     fn new() -> Self {
         Self {
             crypto: None,
@@ -3403,22 +3817,28 @@ impl PacketSpace {
     }
 }
 
-struct CryptoSpace {
-    packet: Crypto,
-    header: HeaderCrypto,
+struct CryptoSpace<K>
+where
+    K: Keys,
+{
+    packet: K,
+    header: K::HeaderKeys,
 }
 
-impl CryptoSpace {
-    pub fn new(packet: Crypto) -> Self {
+impl<K> CryptoSpace<K>
+where
+    K: Keys,
+{
+    pub fn new(packet: K) -> Self {
         Self {
-            header: packet.header_crypto(),
+            header: packet.header_keys(),
             packet,
         }
     }
 }
 
-struct PrevCrypto {
-    crypto: Crypto,
+struct PrevCrypto<K> {
+    crypto: K,
     end_packet: u64,
     /// Time at which a packet using the following key phase was received
     update_ack_time: Option<u64>,
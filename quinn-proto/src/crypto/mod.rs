@@ -0,0 +1,416 @@
+//! Traits and implementations for the QUIC cryptography protocol
+//!
+//! The protocol logic in Quinn is contained in types that abstract over the actual
+//! cryptographic protocol used. This module contains the traits used for this
+//! abstraction layer as well as a single implementation of these traits that uses
+//! *ring* and rustls to implement the TLS protocol support.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ::ring::aead::{self, Aad, Nonce};
+use ::ring::constant_time;
+use ::ring::digest;
+use ::ring::hmac::{self, SigningKey};
+use bytes::{Buf, BufMut, BytesMut};
+use err_derive::Error;
+
+use crate::coding::{BufExt, BufMutExt};
+use crate::packet::ConnectionId;
+use crate::transport_parameters::TransportParameters;
+use crate::{Side, TransportError, MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE};
+
+#[cfg(feature = "noise")]
+pub mod noise;
+#[cfg(feature = "null-crypto")]
+pub mod null;
+pub mod ring;
+#[cfg(feature = "tls-rustls")]
+pub mod rustls;
+
+/// A cryptographic session (commonly TLS)
+///
+/// Drives the handshake carried in CRYPTO frames and produces the keys used to protect packets
+/// once each encryption level is reached.
+pub trait CryptoSession: Sized {
+    /// Data required to initiate client connections
+    type ClientConfig: CryptoClientConfig<Session = Self>;
+    /// Data required to accept incoming connections
+    type ServerConfig: CryptoServerConfig<Session = Self>;
+    /// Packet protection keys negotiated by the session
+    type Keys: Keys;
+
+    /// Get the negotiated ALPN protocol, if any
+    fn alpn_protocol(&self) -> Option<&[u8]>;
+    /// Get the 0-RTT keys if available (clients only)
+    fn early_crypto(&self) -> Option<Self::Keys>;
+    /// Whether the server accepted our 0-RTT data, once that's known (clients only)
+    fn early_data_accepted(&self) -> Option<bool>;
+    /// Fill `output` with keying material derived from the session's secrets
+    ///
+    /// Both peers obtain the same material for the same `label` and `context`, as in RFC 5705.
+    /// Fails until the handshake is complete.
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError>;
+    /// Returns `true` until the handshake is complete
+    fn is_handshaking(&self) -> bool;
+    /// Whether packets may be exchanged with `remote` at all
+    ///
+    /// Sessions offering no real protection use this to refuse peers outside the local host.
+    fn permits_remote(_remote: &SocketAddr) -> bool {
+        true
+    }
+    /// Inspect the start of a client's handshake data without committing to a session
+    ///
+    /// Returns `None` if `buf` doesn't contain a complete first handshake message, or the
+    /// protocol carries no such information.
+    fn peek_client_hello(_buf: &[u8]) -> Option<ClientHello> {
+        None
+    }
+    /// Read bytes of handshake data received from the peer
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<(), TransportError>;
+    /// The server name requested by the client, if any (servers only)
+    fn sni_hostname(&self) -> Option<&str>;
+    /// The peer's QUIC transport parameters
+    ///
+    /// `Ok(None)` until they've been received.
+    fn transport_parameters(
+        &self,
+        version: u32,
+    ) -> Result<Option<TransportParameters>, TransportError>;
+    /// Write handshake data to be sent to the peer, returning keys for the next encryption level
+    /// once they become available
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Self::Keys>;
+}
+
+/// Parameters requested by a client in its first handshake message
+#[derive(Debug, Clone, Default)]
+pub struct ClientHello {
+    /// The server name indicated by the client, if any
+    pub server_name: Option<String>,
+    /// Application-layer protocols offered by the client, in order of preference
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// Client-side configuration for the crypto protocol
+pub trait CryptoClientConfig: Clone {
+    /// The session type produced by this configuration
+    type Session: CryptoSession;
+    /// Start a client session with this configuration
+    fn start_session(
+        &self,
+        server_name: &str,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Result<Self::Session, ConnectError>;
+}
+
+/// Server-side configuration for the crypto protocol
+pub trait CryptoServerConfig {
+    /// The session type produced by this configuration
+    type Session: CryptoSession;
+    /// Construct the default configuration
+    fn new() -> Self;
+    /// Start a server session with this configuration
+    fn start_session(&self, version: u32, params: &TransportParameters) -> Self::Session;
+}
+
+/// Keys used to protect packet payloads
+pub trait Keys: Sized {
+    /// Keys used to protect packet headers
+    type HeaderKeys: HeaderKeys;
+
+    /// Derive the keys protecting Initial packets sent on connection `id`
+    fn new_initial(id: &ConnectionId, side: Side, version: u32) -> Self;
+    /// Encrypt the packet payload following `header_len` bytes of header in place, appending the tag
+    fn encrypt(&self, packet: u64, buf: &mut Vec<u8>, header_len: usize);
+    /// Decrypt a packet payload in place, removing the tag
+    fn decrypt(
+        &self,
+        packet: u64,
+        header: &[u8],
+        payload: &mut BytesMut,
+    ) -> Result<(), CryptoError>;
+    /// Derive the keys protecting the headers of packets protected by these keys
+    fn header_keys(&self) -> Self::HeaderKeys;
+    /// Derive the next generation of keys for a key update
+    fn update(&self, side: Side, version: u32) -> Self;
+    /// Length of the AEAD tag appended to packets
+    fn tag_len(&self) -> usize;
+    /// Number of packets that may be encrypted with these keys before they must be updated
+    fn confidentiality_limit(&self) -> u64;
+    /// Number of forged packets that may be received across all keys before the connection must
+    /// be abandoned
+    fn integrity_limit(&self) -> u64;
+}
+
+/// Keys used to protect packet headers
+pub trait HeaderKeys {
+    /// Remove header protection from a received packet
+    fn decrypt(&self, pn_offset: usize, packet: &mut [u8]);
+    /// Apply header protection to an outgoing packet
+    fn encrypt(&self, pn_offset: usize, packet: &mut [u8]);
+    /// Number of bytes of ciphertext sampled to compute the header protection mask
+    fn sample_size(&self) -> usize;
+}
+
+/// A packet payload failed authentication
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CryptoError;
+
+/// Value used in ACKs we transmit
+pub const ACK_DELAY_EXPONENT: u8 = 3;
+// Magic value used to indicate 0-RTT support in NewSessionTicket
+//pub const TLS_MAX_EARLY_DATA: u32 = 0xffff_ffff;
+
+/// Compute the stateless reset token for connection ID `id` issued by the server `server_id`
+pub fn reset_token_for(
+    key: &SigningKey,
+    server_id: &[u8],
+    id: &ConnectionId,
+) -> [u8; RESET_TOKEN_SIZE] {
+    let mut ctx = hmac::SigningContext::with_key(key);
+    // The CID is length-prefixed so that it can't be confused with part of the server ID
+    ctx.update(&[id.len() as u8]);
+    ctx.update(id);
+    ctx.update(server_id);
+    let signature = ctx.sign();
+    let mut result = [0; RESET_TOKEN_SIZE];
+    result.copy_from_slice(&signature.as_ref()[..RESET_TOKEN_SIZE]);
+    result
+}
+
+/// Errors in the parameters being used to create a new connection
+///
+/// These arise before any I/O has been performed.
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    /// The domain name supplied was malformed
+    #[error(display = "invalid DNS name: {}", _0)]
+    InvalidDnsName(String),
+    /// The cryptographic session in use does not permit connecting to the remote address
+    #[error(display = "refusing to connect to {} without packet protection", _0)]
+    InsecureRemote(SocketAddr),
+    /// The endpoint has no socket able to send to the remote address
+    #[error(display = "no socket can reach {}", _0)]
+    UnreachableRemote(SocketAddr),
+    /// The configured remote static key was malformed
+    #[cfg(feature = "noise")]
+    #[error(display = "invalid remote public key")]
+    InvalidRemoteKey,
+    /// The TLS configuration was invalid
+    #[cfg(feature = "tls-rustls")]
+    #[error(display = "TLS error: {}", _0)]
+    Tls(::rustls::TLSError),
+}
+
+#[cfg(feature = "tls-rustls")]
+impl From<::rustls::TLSError> for ConnectError {
+    fn from(x: ::rustls::TLSError) -> Self {
+        ConnectError::Tls(x)
+    }
+}
+
+/// Reasons keying material could not be exported from a session
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ExportKeyingMaterialError {
+    /// The handshake has not yet completed
+    #[error(display = "handshake incomplete")]
+    HandshakeIncomplete,
+    /// The label exceeds the maximum length of 249 bytes
+    #[error(display = "label too long")]
+    LabelTooLong,
+    /// More output was requested than can be derived from the session's secrets
+    #[error(display = "requested output too long")]
+    OutputTooLong,
+    /// The TLS session failed to derive the material
+    #[cfg(feature = "tls-rustls")]
+    #[error(display = "TLS error: {}", _0)]
+    Tls(::rustls::TLSError),
+}
+
+/// Longest label accepted by HKDF-Expand-Label after its "tls13 " prefix is applied
+pub(crate) const MAX_EXPORTER_LABEL_LEN: usize = 255 - 6;
+
+/// Compute the integrity tag of a version 1 Retry packet
+///
+/// `packet` is the Retry packet up to and including the token.
+pub fn retry_tag(orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; RETRY_TAG_SIZE] {
+    let mut pseudo_packet = Vec::with_capacity(1 + orig_dst_cid.len() + packet.len());
+    pseudo_packet.push(orig_dst_cid.len() as u8);
+    pseudo_packet.extend_from_slice(orig_dst_cid);
+    pseudo_packet.extend_from_slice(packet);
+
+    let key = aead::SealingKey::new(&aead::AES_128_GCM, &RETRY_INTEGRITY_KEY).unwrap();
+    let nonce = Nonce::assume_unique_for_key(RETRY_INTEGRITY_NONCE);
+    let mut tag = [0; RETRY_TAG_SIZE];
+    aead::seal_in_place(
+        &key,
+        nonce,
+        Aad::from(&pseudo_packet[..]),
+        &mut tag,
+        RETRY_TAG_SIZE,
+    )
+    .unwrap();
+    tag
+}
+
+/// Check the integrity tag at the end of a version 1 Retry packet's payload
+pub fn is_valid_retry(orig_dst_cid: &ConnectionId, header: &[u8], payload: &[u8]) -> bool {
+    let tag_start = match payload.len().checked_sub(RETRY_TAG_SIZE) {
+        Some(x) => x,
+        None => return false,
+    };
+    let mut packet = Vec::with_capacity(header.len() + tag_start);
+    packet.extend_from_slice(header);
+    packet.extend_from_slice(&payload[..tag_start]);
+    let expected = retry_tag(orig_dst_cid, &packet);
+    constant_time::verify_slices_are_equal(&expected, &payload[tag_start..]).is_ok()
+}
+
+pub const RETRY_TAG_SIZE: usize = 16;
+
+const RETRY_INTEGRITY_KEY: [u8; 16] = [
+    0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a, 0x1d, 0x76, 0x6b, 0x54, 0xe3, 0x68, 0xc8, 0x4e,
+];
+
+const RETRY_INTEGRITY_NONCE: [u8; 12] = [
+    0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+];
+
+pub struct TokenKey {
+    // TODO: Use AEAD to hide token details from clients for better stability guarantees:
+    // - ticket consists of (random, aead-encrypted-data)
+    // - AEAD encryption key is HKDF(master-key, random)
+    // - AEAD nonce is always set to 0
+    // in other words, for each ticket, use different key derived from random using HKDF
+    inner: SigningKey,
+}
+
+impl TokenKey {
+    pub const SIZE: usize = 64;
+
+    pub fn new(key: &[u8; Self::SIZE]) -> Self {
+        let inner = SigningKey::new(&digest::SHA512_256, key);
+        Self { inner }
+    }
+
+    pub(crate) fn generate(
+        &self,
+        address: &SocketAddr,
+        dst_cid: &ConnectionId,
+        issued: SystemTime,
+    ) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write(dst_cid.len() as u8);
+        buf.put_slice(dst_cid);
+        buf.write::<u64>(
+            issued
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or(0),
+        );
+        let signature_pos = buf.len();
+        match address.ip() {
+            IpAddr::V4(x) => buf.put_slice(&x.octets()),
+            IpAddr::V6(x) => buf.put_slice(&x.octets()),
+        }
+        buf.write(address.port());
+        let signature = hmac::sign(&self.inner, &buf);
+        // No reason to actually encode the IP in the token, since we always have the remote addr for an incoming packet.
+        buf.truncate(signature_pos);
+        buf.extend_from_slice(signature.as_ref());
+        buf
+    }
+
+    pub(crate) fn check(
+        &self,
+        address: &SocketAddr,
+        data: &[u8],
+    ) -> Option<(ConnectionId, SystemTime)> {
+        let mut reader = io::Cursor::new(data);
+        let dst_cid_len = reader.get::<u8>().ok()? as usize;
+        if dst_cid_len > reader.remaining()
+            || dst_cid_len != 0 && !(MIN_CID_SIZE..=MAX_CID_SIZE).contains(&dst_cid_len)
+        {
+            return None;
+        }
+        let dst_cid = ConnectionId::new(&data[1..=dst_cid_len]);
+        reader.advance(dst_cid_len);
+        let issued = UNIX_EPOCH + Duration::new(reader.get::<u64>().ok()?, 0);
+        let signature_start = reader.position() as usize;
+
+        let mut buf = Vec::new();
+        buf.put_slice(&data[0..signature_start]);
+        match address.ip() {
+            IpAddr::V4(x) => buf.put_slice(&x.octets()),
+            IpAddr::V6(x) => buf.put_slice(&x.octets()),
+        }
+        buf.write(address.port());
+
+        hmac::verify_with_own_key(&self.inner, &buf, &data[signature_start..]).ok()?;
+        Some((dst_cid, issued))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{self, RngCore};
+
+    #[test]
+    fn retry_integrity() {
+        // Test vector from RFC 9001 Appendix A.4
+        let orig_dst_cid = ConnectionId::new(&hex!("8394c8f03e515708"));
+        let header = hex!("ff000000010008f067a5502a4262b5");
+        let payload = hex!("746f6b656e 04a265ba2eff4d829058fb3f0f2496ba");
+        let mut packet = header.to_vec();
+        packet.extend_from_slice(&payload[..5]);
+        assert_eq!(retry_tag(&orig_dst_cid, &packet), payload[5..]);
+        assert!(is_valid_retry(&orig_dst_cid, &header, &payload));
+
+        let mut corrupt = payload;
+        corrupt[0] ^= 1;
+        assert!(!is_valid_retry(&orig_dst_cid, &header, &corrupt));
+        let other_cid = ConnectionId::new(&hex!("8394c8f03e515709"));
+        assert!(!is_valid_retry(&other_cid, &header, &payload));
+    }
+
+    #[test]
+    fn token_sanity() {
+        use std::net::Ipv6Addr;
+
+        let mut key = [0; TokenKey::SIZE];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = TokenKey::new(&key);
+        let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4433);
+        let dst_cid = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE);
+        let issued = UNIX_EPOCH + Duration::new(42, 0); // Fractional seconds would be lost
+        let token = key.generate(&addr, &dst_cid, issued);
+        let (dst_cid2, issued2) = key.check(&addr, &token).expect("token didn't validate");
+        assert_eq!(dst_cid, dst_cid2);
+        assert_eq!(issued, issued2);
+    }
+
+    #[test]
+    fn reset_token_server_id() {
+        let mut key = [0; 64];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = SigningKey::new(&digest::SHA512_256, &key);
+        let cid = ConnectionId::new(&hex!("8394c8f03e515708"));
+        let token = reset_token_for(&key, b"a", &cid);
+        assert_eq!(token, reset_token_for(&key, b"a", &cid));
+        assert_ne!(token, reset_token_for(&key, b"b", &cid));
+        // Moving bytes between the CID and the server ID must change the token
+        let short_cid = ConnectionId::new(&hex!("8394c8f03e5157"));
+        assert_ne!(
+            reset_token_for(&key, &hex!("08"), &short_cid),
+            reset_token_for(&key, b"", &cid)
+        );
+    }
+}
@@ -0,0 +1,858 @@
+//! Handshakes authenticated by static public keys using the Noise protocol framework
+//!
+//! Clients that already know the server's public key use the `IK` pattern; others use `XX` and
+//! learn the server's key during the handshake, leaving the application to decide through a
+//! `ServerVerifier` whether to trust it. Either way no certificates are involved.
+//!
+//! Handshake messages are carried in CRYPTO frames, each preceded by its 16-bit length:
+//!
+//! 1. The client's Initial packets carry a pattern identifier followed by the first Noise message,
+//!    whose payload is the client's transport parameters.
+//! 2. The server's Initial packets carry the second Noise message, whose encrypted payload is the
+//!    server's transport parameters. Both sides then derive Handshake secrets from the Noise
+//!    chaining key, and for `IK`, which is now complete, 1-RTT secrets as well.
+//! 3. The client's Handshake packets carry the third `XX` message, after which 1-RTT secrets are
+//!    derived, or an empty message for `IK`.
+//!
+//! Each side sends exactly one message at each encryption level, so any further handshake data
+//! at a level, or data arriving when no message is expected, is rejected.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use ::ring::aead;
+use ::ring::digest;
+use ::ring::hmac::SigningKey;
+use snow::params::NoiseParams;
+use snow::{Builder, HandshakeState};
+
+use super::ring::{hkdf_expand, hkdf_expand_label, Crypto, Secrets};
+use super::{
+    ConnectError, CryptoClientConfig, CryptoServerConfig, CryptoSession, ExportKeyingMaterialError,
+    MAX_EXPORTER_LABEL_LEN,
+};
+use crate::transport_parameters::TransportParameters;
+use crate::{Side, TransportError};
+
+/// A static X25519 key pair identifying an endpoint
+#[derive(Clone)]
+pub struct Keypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl Keypair {
+    /// Generate a new random key pair
+    pub fn generate() -> Self {
+        let keypair = Builder::new(pattern(Pattern::XX))
+            .generate_keypair()
+            .expect("key generation failed");
+        Self {
+            private: keypair.private,
+            public: keypair.public,
+        }
+    }
+
+    /// Reconstruct a previously generated key pair
+    pub fn new(private: Vec<u8>, public: Vec<u8>) -> Self {
+        Self { private, public }
+    }
+
+    /// The private key, to be kept secret
+    pub fn private_key(&self) -> &[u8] {
+        &self.private
+    }
+
+    /// The public key, by which peers identify this endpoint
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+/// Noise configuration used for outgoing connections
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// Our static key pair
+    pub keypair: Arc<Keypair>,
+    /// The server's public key, if known in advance
+    ///
+    /// When set, the `IK` pattern is used and the handshake fails unless the server holds the
+    /// matching private key. Otherwise the `XX` pattern is used and the server's key is learned
+    /// during the handshake, to be judged by `server_verifier`.
+    pub remote_public_key: Option<Vec<u8>>,
+    /// Decides which servers to trust, by their static public key
+    ///
+    /// Consulted as soon as the server's key is received, before any 1-RTT data is sent. All
+    /// servers are trusted if unset, which is only safe when `remote_public_key` is set.
+    pub server_verifier: Option<Arc<dyn ServerVerifier>>,
+}
+
+impl ClientConfig {
+    /// Create a configuration using `keypair` that learns the server's key during the handshake
+    pub fn new(keypair: Keypair) -> Self {
+        Self {
+            keypair: Arc::new(keypair),
+            remote_public_key: None,
+            server_verifier: None,
+        }
+    }
+}
+
+impl CryptoClientConfig for ClientConfig {
+    type Session = NoiseSession;
+    fn start_session(
+        &self,
+        _server_name: &str,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Result<Self::Session, ConnectError> {
+        let pattern_id = if self.remote_public_key.is_some() {
+            Pattern::IK
+        } else {
+            Pattern::XX
+        };
+        let prologue = prologue(version);
+        let mut builder = Builder::new(pattern(pattern_id))
+            .local_private_key(&self.keypair.private)
+            .prologue(&prologue);
+        if let Some(ref key) = self.remote_public_key {
+            builder = builder.remote_public_key(key);
+        }
+        let handshake = builder
+            .build_initiator()
+            .map_err(|_| ConnectError::InvalidRemoteKey)?;
+        Ok(NoiseSession {
+            side: Side::Client,
+            version,
+            state: State::ClientStart(pattern_id, handshake),
+            keypair: Arc::clone(&self.keypair),
+            client_verifier: None,
+            server_verifier: self.server_verifier.clone(),
+            local_params: to_vec(Side::Client, version, params),
+            remote_params: None,
+            remote_static: None,
+            incoming: Vec::new(),
+            keys: KeySchedule::default(),
+        })
+    }
+}
+
+/// Noise configuration used for incoming connections
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Our static key pair
+    pub keypair: Arc<Keypair>,
+    /// Decides which clients may connect, by their static public key
+    ///
+    /// All clients are accepted if unset.
+    pub client_verifier: Option<Arc<dyn ClientVerifier>>,
+}
+
+impl ServerConfig {
+    /// Create a configuration identified by `keypair`
+    pub fn new(keypair: Keypair) -> Self {
+        Self {
+            keypair: Arc::new(keypair),
+            client_verifier: None,
+        }
+    }
+}
+
+/// Application-defined verification of the static keys presented by clients
+///
+/// Implemented for any suitable closure. The client's possession of the matching private key is
+/// verified by the handshake regardless of this decision.
+pub trait ClientVerifier: Send + Sync {
+    /// Whether to accept a client identified by `public_key`
+    fn verify(&self, public_key: &[u8]) -> bool;
+}
+
+impl<F> ClientVerifier for F
+where
+    F: Fn(&[u8]) -> bool + Send + Sync,
+{
+    fn verify(&self, public_key: &[u8]) -> bool {
+        self(public_key)
+    }
+}
+
+/// Application-defined verification of the static keys presented by servers
+///
+/// Implemented for any suitable closure. The server's possession of the matching private key is
+/// verified by the handshake regardless of this decision.
+pub trait ServerVerifier: Send + Sync {
+    /// Whether to trust a server identified by `public_key`
+    fn verify(&self, public_key: &[u8]) -> bool;
+}
+
+impl<F> ServerVerifier for F
+where
+    F: Fn(&[u8]) -> bool + Send + Sync,
+{
+    fn verify(&self, public_key: &[u8]) -> bool {
+        self(public_key)
+    }
+}
+
+impl CryptoServerConfig for ServerConfig {
+    type Session = NoiseSession;
+    fn new() -> Self {
+        Self::new(Keypair::generate())
+    }
+
+    fn start_session(&self, version: u32, params: &TransportParameters) -> Self::Session {
+        NoiseSession {
+            side: Side::Server,
+            version,
+            state: State::ServerStart,
+            keypair: Arc::clone(&self.keypair),
+            client_verifier: self.client_verifier.clone(),
+            server_verifier: None,
+            local_params: to_vec(Side::Server, version, params),
+            remote_params: None,
+            remote_static: None,
+            incoming: Vec::new(),
+            keys: KeySchedule::default(),
+        }
+    }
+}
+
+/// A handshake using the Noise protocol framework
+pub struct NoiseSession {
+    side: Side,
+    version: u32,
+    state: State,
+    keypair: Arc<Keypair>,
+    client_verifier: Option<Arc<dyn ClientVerifier>>,
+    server_verifier: Option<Arc<dyn ServerVerifier>>,
+    /// Our encoded transport parameters
+    local_params: Vec<u8>,
+    /// The peer's encoded transport parameters
+    remote_params: Option<Vec<u8>>,
+    remote_static: Option<Vec<u8>>,
+    /// Received handshake data not yet forming a complete message
+    incoming: Vec<u8>,
+    keys: KeySchedule,
+}
+
+impl NoiseSession {
+    /// The peer's static public key, once it has been authenticated
+    pub fn remote_public_key(&self) -> Option<&[u8]> {
+        self.remote_static.as_ref().map(|x| &x[..])
+    }
+
+    fn read_message(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        match (self.side, mem_take(&mut self.state)) {
+            (Side::Server, State::ServerStart) => {
+                let (&id, message) = message.split_first().ok_or_else(decode_error)?;
+                let pattern_id = match id {
+                    0 => Pattern::XX,
+                    1 => Pattern::IK,
+                    _ => return Err(TransportError::crypto(HANDSHAKE_FAILURE)),
+                };
+                let prologue = prologue(self.version);
+                let mut handshake = Builder::new(pattern(pattern_id))
+                    .local_private_key(&self.keypair.private)
+                    .prologue(&prologue)
+                    .build_responder()
+                    .map_err(|_| TransportError::crypto(HANDSHAKE_FAILURE))?;
+                self.remote_params = Some(read_message(&mut handshake, message)?);
+                if let Pattern::IK = pattern_id {
+                    self.verify_client(&handshake)?;
+                }
+                self.state = State::ServerHello(pattern_id, handshake);
+            }
+            (Side::Client, State::ClientHello(pattern_id, mut handshake)) => {
+                self.remote_params = Some(read_message(&mut handshake, message)?);
+                self.verify_server(&handshake)?;
+                self.keys.derive_handshake(&mut handshake);
+                if let Pattern::IK = pattern_id {
+                    self.keys.derive_one_rtt(&mut handshake);
+                }
+                self.state = State::ClientFinish(pattern_id, handshake);
+            }
+            (Side::Server, State::ServerFinish(Pattern::IK, handshake)) => {
+                if !message.is_empty() {
+                    return Err(TransportError::crypto(DECODE_ERROR));
+                }
+                debug_assert!(handshake.is_handshake_finished());
+                self.state = State::Done;
+            }
+            (Side::Server, State::ServerFinish(Pattern::XX, mut handshake)) => {
+                read_message(&mut handshake, message)?;
+                self.verify_client(&handshake)?;
+                self.keys.derive_one_rtt(&mut handshake);
+                self.state = State::Done;
+            }
+            _ => {
+                return Err(TransportError::PROTOCOL_VIOLATION(
+                    "unexpected handshake message",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the client's static key, refusing it if the application doesn't accept it
+    fn verify_client(&mut self, handshake: &HandshakeState) -> Result<(), TransportError> {
+        let key = handshake
+            .get_remote_static()
+            .ok_or_else(|| TransportError::crypto(HANDSHAKE_FAILURE))?;
+        if let Some(ref verifier) = self.client_verifier {
+            if !verifier.verify(key) {
+                return Err(TransportError::crypto(ACCESS_DENIED));
+            }
+        }
+        self.remote_static = Some(key.to_vec());
+        Ok(())
+    }
+
+    /// Record the server's static key, refusing it if the application doesn't trust it
+    fn verify_server(&mut self, handshake: &HandshakeState) -> Result<(), TransportError> {
+        let key = handshake
+            .get_remote_static()
+            .ok_or_else(|| TransportError::crypto(HANDSHAKE_FAILURE))?;
+        if let Some(ref verifier) = self.server_verifier {
+            if !verifier.verify(key) {
+                return Err(TransportError::crypto(ACCESS_DENIED));
+            }
+        }
+        self.remote_static = Some(key.to_vec());
+        Ok(())
+    }
+}
+
+impl CryptoSession for NoiseSession {
+    type ClientConfig = ClientConfig;
+    type ServerConfig = ServerConfig;
+    type Keys = Crypto;
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn early_crypto(&self) -> Option<Crypto> {
+        None
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        match self.side {
+            Side::Client => Some(false),
+            Side::Server => None,
+        }
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        let secret = match self.keys.exporter {
+            Some(ref secret) if !self.is_handshaking() => secret,
+            _ => return Err(ExportKeyingMaterialError::HandshakeIncomplete),
+        };
+        if label.len() > MAX_EXPORTER_LABEL_LEN {
+            return Err(ExportKeyingMaterialError::LabelTooLong);
+        }
+        if output.len() > 255 * digest::SHA256.output_len {
+            return Err(ExportKeyingMaterialError::OutputTooLong);
+        }
+        // Derived as by the TLS 1.3 exporter (RFC 8446 §7.5)
+        let mut derived = vec![0; digest::SHA256.output_len];
+        hkdf_expand_label(
+            &SigningKey::new(&digest::SHA256, secret),
+            label,
+            digest::digest(&digest::SHA256, &[]).as_ref(),
+            &mut derived,
+        );
+        hkdf_expand_label(
+            &SigningKey::new(&digest::SHA256, &derived),
+            b"exporter",
+            digest::digest(&digest::SHA256, context).as_ref(),
+            output,
+        );
+        Ok(())
+    }
+
+    fn is_handshaking(&self) -> bool {
+        !matches!(self.state, State::Done)
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<(), TransportError> {
+        if !buf.is_empty() && !self.state.is_reading() {
+            return Err(TransportError::PROTOCOL_VIOLATION(
+                "unexpected handshake data",
+            ));
+        }
+        self.incoming.extend_from_slice(buf);
+        if self.incoming.len() < 2 {
+            return Ok(());
+        }
+        let len = usize::from(u16::from_be_bytes([self.incoming[0], self.incoming[1]]));
+        if self.incoming.len() < 2 + len {
+            return Ok(());
+        }
+        // The message received at each encryption level must be the last data at that level
+        if self.incoming.len() > 2 + len {
+            return Err(TransportError::PROTOCOL_VIOLATION(
+                "trailing handshake data",
+            ));
+        }
+        let message = self.incoming[2..].to_vec();
+        self.incoming.clear();
+        self.read_message(&message)
+    }
+
+    fn sni_hostname(&self) -> Option<&str> {
+        None
+    }
+
+    fn transport_parameters(
+        &self,
+        version: u32,
+    ) -> Result<Option<TransportParameters>, TransportError> {
+        match self.remote_params {
+            None => Ok(None),
+            Some(ref buf) => {
+                match TransportParameters::read(self.side, version, &mut io::Cursor::new(buf)) {
+                    Ok(params) => Ok(Some(params)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Crypto> {
+        // Newly derived keys must be in use before anything is sent at their encryption level
+        if let Some(keys) = self.keys.handshake(self.side) {
+            return Some(keys);
+        }
+        match mem_take(&mut self.state) {
+            State::ClientStart(pattern_id, mut handshake) => {
+                let mut message = vec![pattern_id as u8];
+                write_message(&mut handshake, &self.local_params, &mut message);
+                write_frame(buf, &message);
+                self.state = State::ClientHello(pattern_id, handshake);
+                None
+            }
+            State::ServerHello(pattern_id, mut handshake) => {
+                let mut message = Vec::new();
+                write_message(&mut handshake, &self.local_params, &mut message);
+                write_frame(buf, &message);
+                self.keys.derive_handshake(&mut handshake);
+                if let Pattern::IK = pattern_id {
+                    self.keys.derive_one_rtt(&mut handshake);
+                }
+                self.state = State::ServerFinish(pattern_id, handshake);
+                self.keys.handshake(self.side)
+            }
+            State::ClientFinish(pattern_id, mut handshake) => {
+                let mut message = Vec::new();
+                if let Pattern::XX = pattern_id {
+                    write_message(&mut handshake, &[], &mut message);
+                    self.keys.derive_one_rtt(&mut handshake);
+                }
+                write_frame(buf, &message);
+                self.state = State::Done;
+                self.keys.one_rtt(self.side)
+            }
+            state => {
+                self.state = state;
+                self.keys.one_rtt(self.side)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Pattern {
+    XX = 0,
+    IK = 1,
+}
+
+enum State {
+    /// Client about to send its first message
+    ClientStart(Pattern, HandshakeState),
+    /// Client awaiting the server's Noise message
+    ClientHello(Pattern, HandshakeState),
+    /// Client about to send its final message
+    ClientFinish(Pattern, HandshakeState),
+    /// Server awaiting the client's first message
+    ServerStart,
+    /// Server about to send its Noise message
+    ServerHello(Pattern, HandshakeState),
+    /// Server awaiting the client's final message
+    ServerFinish(Pattern, HandshakeState),
+    Done,
+    /// Placeholder while a transition is in progress
+    Poisoned,
+}
+
+impl State {
+    /// Whether a message from the peer is expected
+    fn is_reading(&self) -> bool {
+        matches!(
+            *self,
+            State::ClientHello(..) | State::ServerStart | State::ServerFinish(..)
+        )
+    }
+}
+
+/// Secrets derived as the handshake progresses, each yielded only once
+#[derive(Default)]
+struct KeySchedule {
+    handshake: Option<Secrets>,
+    one_rtt: Option<Secrets>,
+    /// Secret from which exported keying material is derived, retained for the session's life
+    exporter: Option<Vec<u8>>,
+}
+
+impl KeySchedule {
+    /// Derive Handshake secrets once the server's Noise message has been processed
+    fn derive_handshake(&mut self, handshake: &mut HandshakeState) {
+        let (initiator, responder) = split(handshake);
+        self.handshake = Some(secrets(&initiator, &responder, b"quic noise hs"));
+    }
+
+    /// Derive 1-RTT and exporter secrets once the final Noise message has been processed
+    ///
+    /// Only then are both peers authenticated, so these depend on the complete transcript.
+    fn derive_one_rtt(&mut self, handshake: &mut HandshakeState) {
+        let (initiator, responder) = split(handshake);
+        self.one_rtt = Some(secrets(&initiator, &responder, b"quic noise ap"));
+        self.exporter = Some(expand(&initiator, b"quic noise exp"));
+    }
+
+    fn handshake(&mut self, side: Side) -> Option<Crypto> {
+        self.handshake.take().map(|x| keys(side, x))
+    }
+
+    fn one_rtt(&mut self, side: Side) -> Option<Crypto> {
+        self.one_rtt.take().map(|x| keys(side, x))
+    }
+}
+
+fn split(handshake: &mut HandshakeState) -> (SigningKey, SigningKey) {
+    let (initiator, responder) = handshake.dangerously_get_raw_split();
+    (
+        SigningKey::new(&digest::SHA256, &initiator),
+        SigningKey::new(&digest::SHA256, &responder),
+    )
+}
+
+fn secrets(initiator: &SigningKey, responder: &SigningKey, label: &[u8]) -> Secrets {
+    Secrets {
+        client: expand(initiator, label),
+        server: expand(responder, label),
+    }
+}
+
+fn keys(side: Side, secrets: Secrets) -> Crypto {
+    Crypto::new(side, &digest::SHA256, &aead::CHACHA20_POLY1305, secrets)
+}
+
+fn expand(key: &SigningKey, label: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; digest::SHA256.output_len];
+    hkdf_expand(key, label, &mut out);
+    out
+}
+
+fn pattern(pattern: Pattern) -> NoiseParams {
+    match pattern {
+        Pattern::XX => "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+        Pattern::IK => "Noise_IK_25519_ChaChaPoly_BLAKE2s",
+    }
+    .parse()
+    .unwrap()
+}
+
+/// Binds the handshake to the QUIC version in use
+fn prologue(version: u32) -> Vec<u8> {
+    let mut prologue = b"quic noise ".to_vec();
+    prologue.extend_from_slice(&version.to_be_bytes());
+    prologue
+}
+
+/// Process a Noise message, returning its payload
+fn read_message(handshake: &mut HandshakeState, message: &[u8]) -> Result<Vec<u8>, TransportError> {
+    let mut payload = vec![0; message.len()];
+    let n = handshake
+        .read_message(message, &mut payload)
+        .map_err(|_| TransportError::crypto(DECRYPT_ERROR))?;
+    payload.truncate(n);
+    Ok(payload)
+}
+
+fn write_message(handshake: &mut HandshakeState, payload: &[u8], out: &mut Vec<u8>) {
+    let mut message = vec![0; MAX_MESSAGE_LEN];
+    let n = handshake
+        .write_message(payload, &mut message)
+        .expect("handshake message construction failed");
+    out.extend_from_slice(&message[..n]);
+}
+
+fn write_frame(buf: &mut Vec<u8>, message: &[u8]) {
+    debug_assert!(message.len() <= MAX_MESSAGE_LEN);
+    buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buf.extend_from_slice(message);
+}
+
+fn to_vec(side: Side, version: u32, params: &TransportParameters) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    params.write(side, version, &mut bytes);
+    bytes
+}
+
+fn mem_take(state: &mut State) -> State {
+    std::mem::replace(state, State::Poisoned)
+}
+
+fn decode_error() -> TransportError {
+    TransportError::crypto(DECODE_ERROR)
+}
+
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// TLS alert codes reported on handshake failure
+const HANDSHAKE_FAILURE: u8 = 40;
+const ACCESS_DENIED: u8 = 49;
+const DECODE_ERROR: u8 = 50;
+const DECRYPT_ERROR: u8 = 51;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::Keys;
+    use crate::endpoint::Config;
+    use crate::VERSION;
+    use bytes::BytesMut;
+
+    fn params() -> TransportParameters {
+        TransportParameters::new(&Config::default())
+    }
+
+    /// Run a handshake to completion, returning the sessions and their 1-RTT keys
+    fn handshake(
+        client: &ClientConfig,
+        server: &ServerConfig,
+    ) -> Result<(NoiseSession, NoiseSession, Crypto, Crypto), TransportError> {
+        let mut client = client.start_session("", VERSION, &params()).unwrap();
+        let mut server = server.start_session(VERSION, &params());
+        let (mut client_keys, mut server_keys) = (None, None);
+        for _ in 0..4 {
+            let mut buf = Vec::new();
+            while let Some(keys) = client.write_handshake(&mut buf) {
+                client_keys = Some(keys);
+            }
+            server.read_handshake(&buf)?;
+            let mut buf = Vec::new();
+            while let Some(keys) = server.write_handshake(&mut buf) {
+                server_keys = Some(keys);
+            }
+            client.read_handshake(&buf)?;
+        }
+        Ok((client, server, client_keys.unwrap(), server_keys.unwrap()))
+    }
+
+    fn check_keys(client: &Crypto, server: &Crypto) {
+        let mut buf = b"headerpayload".to_vec();
+        client.encrypt(0, &mut buf, 6);
+        let mut header = BytesMut::from(buf);
+        let mut payload = header.split_off(6);
+        server.decrypt(0, &header, &mut payload).unwrap();
+        assert_eq!(&*payload, b"payload");
+    }
+
+    #[test]
+    fn xx() {
+        let client_key = Keypair::generate();
+        let server_key = Keypair::generate();
+        let client = ClientConfig::new(client_key.clone());
+        let server = ServerConfig::new(server_key.clone());
+        let (client, server, client_keys, server_keys) = handshake(&client, &server).unwrap();
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+        assert_eq!(client.remote_public_key(), Some(server_key.public_key()));
+        assert_eq!(server.remote_public_key(), Some(client_key.public_key()));
+        assert!(client.transport_parameters(VERSION).unwrap().is_some());
+        assert!(server.transport_parameters(VERSION).unwrap().is_some());
+        check_keys(&client_keys, &server_keys);
+
+        let (mut client_out, mut server_out) = ([0; 64], [0; 64]);
+        client
+            .export_keying_material(&mut client_out, b"label", b"context")
+            .unwrap();
+        server
+            .export_keying_material(&mut server_out, b"label", b"context")
+            .unwrap();
+        assert_eq!(&client_out[..], &server_out[..]);
+        server
+            .export_keying_material(&mut server_out, b"label", b"other")
+            .unwrap();
+        assert_ne!(&client_out[..], &server_out[..]);
+    }
+
+    #[test]
+    fn ik() {
+        let client_key = Keypair::generate();
+        let server_key = Keypair::generate();
+        let client = ClientConfig {
+            remote_public_key: Some(server_key.public_key().to_vec()),
+            ..ClientConfig::new(client_key.clone())
+        };
+        let server = ServerConfig::new(server_key.clone());
+        let (client, server, client_keys, server_keys) = handshake(&client, &server).unwrap();
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+        assert_eq!(client.remote_public_key(), Some(server_key.public_key()));
+        assert_eq!(server.remote_public_key(), Some(client_key.public_key()));
+        check_keys(&client_keys, &server_keys);
+    }
+
+    #[test]
+    fn ik_wrong_server_key() {
+        let client = ClientConfig {
+            remote_public_key: Some(Keypair::generate().public_key().to_vec()),
+            ..ClientConfig::new(Keypair::generate())
+        };
+        let server = ServerConfig::new(Keypair::generate());
+        assert_eq!(
+            handshake(&client, &server).err().map(|e| e.code),
+            Some(crate::TransportErrorCode::crypto(DECRYPT_ERROR))
+        );
+    }
+
+    #[test]
+    fn xx_one_rtt_after_final_message() {
+        let client = ClientConfig::new(Keypair::generate());
+        let server = ServerConfig::new(Keypair::generate());
+        let mut client = client.start_session("", VERSION, &params()).unwrap();
+        let mut server = server.start_session(VERSION, &params());
+
+        let mut buf = Vec::new();
+        assert!(client.write_handshake(&mut buf).is_none());
+        server.read_handshake(&buf).unwrap();
+        let mut buf = Vec::new();
+        assert!(server.write_handshake(&mut buf).is_some()); // Handshake keys
+        assert!(server.write_handshake(&mut buf).is_none());
+        client.read_handshake(&buf).unwrap();
+
+        let mut buf = Vec::new();
+        assert!(client.write_handshake(&mut buf).is_some()); // Handshake keys
+        assert!(buf.is_empty());
+        let mut finish = Vec::new();
+        assert!(client.write_handshake(&mut finish).is_some()); // 1-RTT keys
+        assert!(!finish.is_empty());
+        assert!(server.write_handshake(&mut buf).is_none());
+        assert!(server.is_handshaking());
+
+        server.read_handshake(&finish).unwrap();
+        assert!(server.write_handshake(&mut buf).is_some()); // 1-RTT keys
+        assert!(buf.is_empty());
+        assert!(!server.is_handshaking());
+    }
+
+    #[test]
+    fn trailing_data() {
+        let client = ClientConfig::new(Keypair::generate());
+        let server = ServerConfig::new(Keypair::generate());
+        let mut client = client.start_session("", VERSION, &params()).unwrap();
+        let mut server = server.start_session(VERSION, &params());
+        let mut buf = Vec::new();
+        client.write_handshake(&mut buf);
+        server.read_handshake(&buf).unwrap();
+        let mut buf = Vec::new();
+        server.write_handshake(&mut buf);
+
+        // Forged transport parameters following the server's message at the same level
+        let mut forged = buf.clone();
+        write_frame(&mut forged, &to_vec(Side::Server, VERSION, &params()));
+        assert_eq!(
+            client.read_handshake(&forged).err().map(|e| e.code),
+            Some(crate::TransportErrorCode::PROTOCOL_VIOLATION)
+        );
+    }
+
+    #[test]
+    fn server_params_authenticated() {
+        let client = ClientConfig::new(Keypair::generate());
+        let server = ServerConfig::new(Keypair::generate());
+        let mut client = client.start_session("", VERSION, &params()).unwrap();
+        let mut server = server.start_session(VERSION, &params());
+        let mut buf = Vec::new();
+        client.write_handshake(&mut buf);
+        server.read_handshake(&buf).unwrap();
+        let mut buf = Vec::new();
+        server.write_handshake(&mut buf);
+        *buf.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            client.read_handshake(&buf).err().map(|e| e.code),
+            Some(crate::TransportErrorCode::crypto(DECRYPT_ERROR))
+        );
+        assert!(client.transport_parameters(VERSION).unwrap().is_none());
+    }
+
+    #[test]
+    fn client_verifier() {
+        let client_key = Keypair::generate();
+        let server_key = Keypair::generate();
+        let accepted = client_key.public_key().to_vec();
+        let server = ServerConfig {
+            client_verifier: Some(Arc::new(move |key: &[u8]| key == &accepted[..])),
+            ..ServerConfig::new(server_key.clone())
+        };
+        let xx = ClientConfig::new(client_key.clone());
+        let ik = ClientConfig {
+            remote_public_key: Some(server_key.public_key().to_vec()),
+            ..ClientConfig::new(client_key.clone())
+        };
+        handshake(&xx, &server).unwrap();
+        handshake(&ik, &server).unwrap();
+
+        let refused = crate::TransportErrorCode::crypto(ACCESS_DENIED);
+        let xx = ClientConfig::new(Keypair::generate());
+        let ik = ClientConfig {
+            remote_public_key: Some(server_key.public_key().to_vec()),
+            ..ClientConfig::new(Keypair::generate())
+        };
+        assert_eq!(handshake(&xx, &server).err().map(|e| e.code), Some(refused));
+        assert_eq!(handshake(&ik, &server).err().map(|e| e.code), Some(refused));
+    }
+
+    #[test]
+    fn server_verifier() {
+        let server_key = Keypair::generate();
+        let trusted = server_key.public_key().to_vec();
+        let client = ClientConfig {
+            server_verifier: Some(Arc::new(move |key: &[u8]| key == &trusted[..])),
+            ..ClientConfig::new(Keypair::generate())
+        };
+        handshake(&client, &ServerConfig::new(server_key)).unwrap();
+
+        // Refused upon receipt of the server's key, before the client derives any keys
+        let server = ServerConfig::new(Keypair::generate());
+        let mut client = client.start_session("", VERSION, &params()).unwrap();
+        let mut server = server.start_session(VERSION, &params());
+        let mut buf = Vec::new();
+        client.write_handshake(&mut buf);
+        server.read_handshake(&buf).unwrap();
+        let mut buf = Vec::new();
+        while server.write_handshake(&mut buf).is_some() {}
+        assert_eq!(
+            client.read_handshake(&buf).err().map(|e| e.code),
+            Some(crate::TransportErrorCode::crypto(ACCESS_DENIED))
+        );
+        assert!(client.write_handshake(&mut Vec::new()).is_none());
+        assert!(client.remote_public_key().is_none());
+    }
+}
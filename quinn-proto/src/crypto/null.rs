@@ -0,0 +1,229 @@
+//! Unprotected packets, for profiling and simulation
+//!
+//! Wrapping a configuration in [`Plaintext`] runs the wrapped cryptographic handshake as usual but
+//! discards the keys it produces, sending every packet in the clear. Packets keep the same size
+//! and layout as they would under TLS, but nothing is authenticated, so sessions only permit
+//! loopback peers.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use bytes::BytesMut;
+
+use super::{
+    ConnectError, CryptoClientConfig, CryptoError, CryptoServerConfig, CryptoSession,
+    ExportKeyingMaterialError, HeaderKeys, Keys,
+};
+use crate::packet::ConnectionId;
+use crate::transport_parameters::TransportParameters;
+use crate::{Side, TransportError};
+
+/// Length of the all-zero tag standing in for an AEAD tag, matching AES-GCM and ChaCha20-Poly1305
+const TAG_LEN: usize = 16;
+/// Bytes "sampled" for header protection, matching the AEADs used by TLS
+const SAMPLE_LEN: usize = 16;
+
+/// Configuration producing sessions whose packets are sent without protection
+#[derive(Clone)]
+pub struct Plaintext<C>(pub C);
+
+impl<C> CryptoClientConfig for Plaintext<C>
+where
+    C: CryptoClientConfig,
+{
+    type Session = NullSession<C::Session>;
+    fn start_session(
+        &self,
+        server_name: &str,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Result<Self::Session, ConnectError> {
+        Ok(NullSession(self.0.start_session(
+            server_name,
+            version,
+            params,
+        )?))
+    }
+}
+
+impl<C> CryptoServerConfig for Plaintext<C>
+where
+    C: CryptoServerConfig,
+{
+    type Session = NullSession<C::Session>;
+    fn new() -> Self {
+        Plaintext(C::new())
+    }
+
+    fn start_session(&self, version: u32, params: &TransportParameters) -> Self::Session {
+        NullSession(self.0.start_session(version, params))
+    }
+}
+
+/// A cryptographic session whose negotiated keys are replaced by [`NullKeys`]
+pub struct NullSession<S>(pub S);
+
+impl<S> CryptoSession for NullSession<S>
+where
+    S: CryptoSession,
+{
+    type ClientConfig = Plaintext<S::ClientConfig>;
+    type ServerConfig = Plaintext<S::ServerConfig>;
+    type Keys = NullKeys;
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.0.alpn_protocol()
+    }
+
+    fn early_crypto(&self) -> Option<NullKeys> {
+        self.0.early_crypto().map(|_| NullKeys)
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        self.0.early_data_accepted()
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        self.0.export_keying_material(output, label, context)
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.0.is_handshaking()
+    }
+
+    fn permits_remote(remote: &SocketAddr) -> bool {
+        match remote.ip() {
+            IpAddr::V4(x) => x.is_loopback(),
+            IpAddr::V6(x) => x.is_loopback() || x.to_ipv4().filter(Ipv4Addr::is_loopback).is_some(),
+        }
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<(), TransportError> {
+        self.0.read_handshake(buf)
+    }
+
+    fn sni_hostname(&self) -> Option<&str> {
+        self.0.sni_hostname()
+    }
+
+    fn transport_parameters(
+        &self,
+        version: u32,
+    ) -> Result<Option<TransportParameters>, TransportError> {
+        self.0.transport_parameters(version)
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<NullKeys> {
+        self.0.write_handshake(buf).map(|_| NullKeys)
+    }
+}
+
+/// Packet "protection" that leaves payloads in the clear, followed by an all-zero tag
+pub struct NullKeys;
+
+impl Keys for NullKeys {
+    type HeaderKeys = NullHeaderKeys;
+
+    fn new_initial(_: &ConnectionId, _: Side, _: u32) -> Self {
+        NullKeys
+    }
+
+    fn encrypt(&self, _: u64, buf: &mut Vec<u8>, _: usize) {
+        buf.extend_from_slice(&[0; TAG_LEN]);
+    }
+
+    fn decrypt(&self, _: u64, _: &[u8], payload: &mut BytesMut) -> Result<(), CryptoError> {
+        let tag_start = payload.len().checked_sub(TAG_LEN).ok_or(CryptoError)?;
+        // A nonzero tag means the peer is really encrypting, so its packets are unintelligible
+        if payload[tag_start..].iter().any(|&x| x != 0) {
+            return Err(CryptoError);
+        }
+        payload.truncate(tag_start);
+        Ok(())
+    }
+
+    fn header_keys(&self) -> NullHeaderKeys {
+        NullHeaderKeys
+    }
+
+    fn update(&self, _: Side, _: u32) -> Self {
+        NullKeys
+    }
+
+    fn tag_len(&self) -> usize {
+        TAG_LEN
+    }
+
+    fn confidentiality_limit(&self) -> u64 {
+        u64::max_value()
+    }
+
+    fn integrity_limit(&self) -> u64 {
+        u64::max_value()
+    }
+}
+
+/// Header "protection" that leaves headers unmasked
+pub struct NullHeaderKeys;
+
+impl HeaderKeys for NullHeaderKeys {
+    fn decrypt(&self, _: usize, _: &mut [u8]) {}
+
+    fn encrypt(&self, _: usize, _: &mut [u8]) {}
+
+    fn sample_size(&self) -> usize {
+        SAMPLE_LEN
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::VERSION;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn roundtrip() {
+        let id = ConnectionId::new(&hex!("8394c8f03e515708"));
+        let keys = NullKeys::new_initial(&id, Side::Client, VERSION);
+        let mut buf = b"headerpayload".to_vec();
+        keys.encrypt(0, &mut buf, 6);
+        assert_eq!(buf.len(), 13 + keys.tag_len());
+        assert_eq!(&buf[..13], b"headerpayload");
+
+        let mut header = BytesMut::from(buf);
+        let mut payload = header.split_off(6);
+        keys.decrypt(0, &header, &mut payload).unwrap();
+        assert_eq!(&*payload, b"payload");
+    }
+
+    #[test]
+    fn reject_protected() {
+        let mut payload = BytesMut::from(&[1; TAG_LEN + 4][..]);
+        assert!(NullKeys.decrypt(0, &[], &mut payload).is_err());
+        let mut short = BytesMut::from(&[0; TAG_LEN - 1][..]);
+        assert!(NullKeys.decrypt(0, &[], &mut short).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "tls-rustls")]
+    fn loopback_only() {
+        type Session = NullSession<super::super::rustls::TlsSession>;
+        let port = 4433;
+        assert!(Session::permits_remote(&(Ipv4Addr::LOCALHOST, port).into()));
+        assert!(Session::permits_remote(&(Ipv6Addr::LOCALHOST, port).into()));
+        assert!(Session::permits_remote(
+            &(Ipv4Addr::LOCALHOST.to_ipv6_mapped(), port).into()
+        ));
+        assert!(!Session::permits_remote(
+            &(Ipv4Addr::new(192, 0, 2, 1), port).into()
+        ));
+        assert!(!Session::permits_remote(
+            &(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), port).into()
+        ));
+    }
+}
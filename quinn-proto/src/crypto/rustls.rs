@@ -0,0 +1,541 @@
+//! TLS 1.3 handshakes for QUIC driven by rustls
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use rustls::internal::msgs::codec::Reader;
+use rustls::internal::msgs::handshake::{
+    ConvertProtocolNameList, ConvertServerNameList, HandshakeMessagePayload, HandshakePayload,
+};
+use rustls::quic::{ClientQuicExt, ServerQuicExt};
+use rustls::ProtocolVersion;
+pub use rustls::{Certificate, NoClientAuth, PrivateKey, TLSError};
+pub use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, Session};
+use webpki::DNSNameRef;
+
+use super::ring::{Crypto, Secrets};
+use super::{
+    ClientHello, ConnectError, CryptoClientConfig, CryptoServerConfig, CryptoSession,
+    ExportKeyingMaterialError, MAX_EXPORTER_LABEL_LEN,
+};
+use crate::transport_parameters::TransportParameters;
+use crate::{Side, TransportError};
+
+/// A rustls TLS session
+pub struct TlsSession {
+    inner: Inner,
+    /// Whether our ServerHello selected a pre-shared key offered by the client, i.e. accepted its
+    /// session ticket (servers only)
+    accepted_ticket: bool,
+}
+
+enum Inner {
+    Client(ClientSession),
+    Server(ServerSession),
+}
+
+impl TlsSession {
+    fn side(&self) -> Side {
+        match self.inner {
+            Inner::Client(_) => Side::Client,
+            Inner::Server(_) => Side::Server,
+        }
+    }
+
+    /// Whether the handshake resumed a previous session rather than authenticating the server
+    /// afresh
+    ///
+    /// `None` until the handshake completes.
+    pub fn is_resumed(&self) -> Option<bool> {
+        if self.is_handshaking() {
+            return None;
+        }
+        match self.inner {
+            Inner::Client(ref session) => Some(session.get_peer_certificates().is_none()),
+            Inner::Server(_) => Some(self.accepted_ticket),
+        }
+    }
+
+    /// The cipher suite negotiated by the handshake, once known
+    pub fn cipher_suite(&self) -> Option<rustls::CipherSuite> {
+        self.get_negotiated_ciphersuite().map(|x| x.suite)
+    }
+
+    fn new(inner: Inner) -> Self {
+        Self {
+            inner,
+            accepted_ticket: false,
+        }
+    }
+}
+
+impl CryptoSession for TlsSession {
+    type ClientConfig = Arc<ClientConfig>;
+    type ServerConfig = Arc<ServerConfig>;
+    type Keys = Crypto;
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.get_alpn_protocol()
+    }
+
+    fn early_crypto(&self) -> Option<Crypto> {
+        self.get_early_secret().map(Crypto::new_0rtt)
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        match self.inner {
+            Inner::Client(ref session) => Some(session.is_early_data_accepted()),
+            Inner::Server(_) => None,
+        }
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        let suite = match self.get_negotiated_ciphersuite() {
+            Some(suite) if !self.is_handshaking() => suite,
+            _ => return Err(ExportKeyingMaterialError::HandshakeIncomplete),
+        };
+        if label.len() > MAX_EXPORTER_LABEL_LEN {
+            return Err(ExportKeyingMaterialError::LabelTooLong);
+        }
+        if output.len() > 255 * suite.get_hash().output_len {
+            return Err(ExportKeyingMaterialError::OutputTooLong);
+        }
+        Session::export_keying_material(&**self, output, label, Some(context)).map_err(
+            |e| match e {
+                TLSError::HandshakeNotComplete => ExportKeyingMaterialError::HandshakeIncomplete,
+                e => ExportKeyingMaterialError::Tls(e),
+            },
+        )
+    }
+
+    fn is_handshaking(&self) -> bool {
+        match self.inner {
+            Inner::Client(ref session) => session.is_handshaking(),
+            Inner::Server(ref session) => session.is_handshaking(),
+        }
+    }
+
+    fn peek_client_hello(buf: &[u8]) -> Option<ClientHello> {
+        parse_client_hello(buf)
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<(), TransportError> {
+        self.read_hs(buf).map_err(|_| {
+            if let Some(alert) = self.get_alert() {
+                TransportError::crypto(alert.get_u8())
+            } else {
+                TransportError::PROTOCOL_VIOLATION("TLS error")
+            }
+        })
+    }
+
+    fn sni_hostname(&self) -> Option<&str> {
+        match self.inner {
+            Inner::Client(_) => None,
+            Inner::Server(ref session) => session.get_sni_hostname(),
+        }
+    }
+
+    fn transport_parameters(
+        &self,
+        version: u32,
+    ) -> Result<Option<TransportParameters>, TransportError> {
+        match self.get_quic_transport_parameters() {
+            None => Ok(None),
+            Some(buf) => {
+                match TransportParameters::read(self.side(), version, &mut io::Cursor::new(buf)) {
+                    Ok(params) => Ok(Some(params)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Crypto> {
+        let start = buf.len();
+        let secrets = self.write_hs(buf);
+        if self.side().is_server() {
+            if let Some(psk) = server_hello_selects_psk(&buf[start..]) {
+                self.accepted_ticket = psk;
+            }
+        }
+        let secrets = secrets?;
+        let suite = self
+            .get_negotiated_ciphersuite()
+            .expect("should not get secrets without cipher suite");
+        Some(Crypto::new(
+            self.side(),
+            suite.get_hash(),
+            suite.get_aead_alg(),
+            Secrets {
+                client: secrets.client,
+                server: secrets.server,
+            },
+        ))
+    }
+}
+
+impl Deref for TlsSession {
+    type Target = dyn Session;
+    fn deref(&self) -> &Self::Target {
+        match self.inner {
+            Inner::Client(ref session) => session,
+            Inner::Server(ref session) => session,
+        }
+    }
+}
+
+impl DerefMut for TlsSession {
+    fn deref_mut(&mut self) -> &mut (dyn Session + 'static) {
+        match self.inner {
+            Inner::Client(ref mut session) => session,
+            Inner::Server(ref mut session) => session,
+        }
+    }
+}
+
+impl CryptoClientConfig for Arc<ClientConfig> {
+    type Session = TlsSession;
+    fn start_session(
+        &self,
+        server_name: &str,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Result<Self::Session, ConnectError> {
+        let pki_server_name = DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| ConnectError::InvalidDnsName(server_name.into()))?;
+        Ok(TlsSession::new(Inner::Client(ClientSession::new_quic(
+            self,
+            pki_server_name,
+            to_vec(Side::Client, version, params),
+        ))))
+    }
+}
+
+impl CryptoServerConfig for Arc<ServerConfig> {
+    type Session = TlsSession;
+    fn new() -> Self {
+        Arc::new(build_server_config())
+    }
+
+    fn start_session(&self, version: u32, params: &TransportParameters) -> Self::Session {
+        TlsSession::new(Inner::Server(ServerSession::new_quic(
+            self,
+            to_vec(Side::Server, version, params),
+        )))
+    }
+}
+
+pub fn build_server_config() -> ServerConfig {
+    let mut cfg = ServerConfig::new(NoClientAuth::new());
+    cfg.versions = vec![ProtocolVersion::TLSv1_3];
+    cfg
+}
+
+/// Extract the SNI and ALPN extensions from an unencrypted TLS 1.3 ClientHello
+///
+/// Uses rustls's own message codec so that exactly the hellos rustls would accept are inspected.
+fn parse_client_hello(buf: &[u8]) -> Option<ClientHello> {
+    let msg =
+        HandshakeMessagePayload::read_version(&mut Reader::init(buf), ProtocolVersion::TLSv1_3)?;
+    let hello = match msg.payload {
+        HandshakePayload::ClientHello(ref hello) => hello,
+        _ => return None,
+    };
+    Some(ClientHello {
+        server_name: hello
+            .get_sni_extension()
+            .and_then(|x| x.get_hostname())
+            .map(|x| {
+                let name: &str = x.into();
+                name.into()
+            }),
+        alpn_protocols: hello
+            .get_alpn_extension()
+            .map_or_else(Vec::new, |x| x.to_vecs()),
+    })
+}
+
+/// Whether the ServerHello at the start of `buf`, if any, selects a pre-shared key
+fn server_hello_selects_psk(buf: &[u8]) -> Option<bool> {
+    let msg =
+        HandshakeMessagePayload::read_version(&mut Reader::init(buf), ProtocolVersion::TLSv1_3)?;
+    match msg.payload {
+        HandshakePayload::ServerHello(ref hello) => Some(hello.get_psk_index().is_some()),
+        _ => None,
+    }
+}
+
+fn to_vec(side: Side, version: u32, params: &TransportParameters) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    params.write(side, version, &mut bytes);
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, RngCore};
+
+    #[test]
+    fn peek_client_hello() {
+        let mut config = ClientConfig::new();
+        config.versions = vec![ProtocolVersion::TLSv1_3];
+        config.set_protocols(&["h3".into(), "hq-29".into()]);
+        let mut session = Arc::new(config)
+            .start_session(
+                "example.com",
+                crate::VERSION,
+                &TransportParameters::default(),
+            )
+            .unwrap();
+        let mut buf = Vec::new();
+        session.write_handshake(&mut buf);
+
+        let hello = TlsSession::peek_client_hello(&buf).unwrap();
+        assert_eq!(
+            hello.server_name.as_ref().map(|x| &x[..]),
+            Some("example.com")
+        );
+        assert_eq!(
+            hello.alpn_protocols,
+            vec![b"h3".to_vec(), b"hq-29".to_vec()]
+        );
+        assert!(TlsSession::peek_client_hello(&buf[..buf.len() - 1]).is_none());
+    }
+
+    fn client_hello_bytes() -> Vec<u8> {
+        let mut config = ClientConfig::new();
+        config.versions = vec![ProtocolVersion::TLSv1_3];
+        config.set_protocols(&["h3".into()]);
+        let mut session = Arc::new(config)
+            .start_session(
+                "example.com",
+                crate::VERSION,
+                &TransportParameters::default(),
+            )
+            .unwrap();
+        let mut buf = Vec::new();
+        session.write_handshake(&mut buf);
+        buf
+    }
+
+    /// Encode a TLS 1.3 ClientHello carrying `extensions`
+    fn client_hello_with(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]); // random
+        body.push(0); // legacy_session_id
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]); // cipher_suites
+        body.extend_from_slice(&[1, 0]); // legacy_compression_methods
+        body.extend_from_slice(&encode_extensions(extensions));
+        handshake_message(1, &body)
+    }
+
+    /// Encode a TLS 1.3 ServerHello carrying `extensions`
+    fn server_hello_with(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]); // random
+        body.push(0); // legacy_session_id_echo
+        body.extend_from_slice(&[0x13, 0x01]); // cipher_suite
+        body.push(0); // legacy_compression_method
+        body.extend_from_slice(&encode_extensions(extensions));
+        handshake_message(2, &body)
+    }
+
+    fn encode_extensions(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut exts = Vec::new();
+        for &(ty, ref data) in extensions {
+            exts.extend_from_slice(&ty.to_be_bytes());
+            exts.extend_from_slice(&vec_with_len(2, data));
+        }
+        vec_with_len(2, &exts)
+    }
+
+    fn handshake_message(ty: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![ty];
+        msg.extend_from_slice(&vec_with_len(3, body));
+        msg
+    }
+
+    /// Prefix `data` with its length in `len_bytes` big-endian bytes
+    fn vec_with_len(len_bytes: usize, data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_be_bytes()[4 - len_bytes..].to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    #[test]
+    fn peek_client_hello_truncated() {
+        let buf = client_hello_bytes();
+        for len in 0..buf.len() {
+            assert!(
+                TlsSession::peek_client_hello(&buf[..len]).is_none(),
+                "prefix of {} bytes parsed",
+                len
+            );
+        }
+        // Data following the hello in the same CRYPTO frame doesn't belong to it
+        let mut extended = buf.clone();
+        extended.extend_from_slice(&[0xff; 16]);
+        let hello = TlsSession::peek_client_hello(&extended).unwrap();
+        assert_eq!(hello.alpn_protocols, vec![b"h3".to_vec()]);
+    }
+
+    #[test]
+    fn peek_client_hello_garbage() {
+        let buf = client_hello_bytes();
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let mut mutated = buf.clone();
+            for _ in 0..rng.gen_range(1, 8) {
+                let i = rng.gen_range(0, mutated.len());
+                mutated[i] = rng.gen();
+            }
+            let len = rng.gen_range(0, mutated.len() + 1);
+            TlsSession::peek_client_hello(&mutated[..len]);
+
+            let mut garbage = vec![0; rng.gen_range(0, 512)];
+            rng.fill_bytes(&mut garbage);
+            if !garbage.is_empty() {
+                garbage[0] = 1; // reach past the message type check
+            }
+            TlsSession::peek_client_hello(&garbage);
+        }
+    }
+
+    #[test]
+    fn peek_client_hello_extension_heavy() {
+        let mut extensions = (1000..1100)
+            .map(|ty| (ty, vec![0xab; (ty % 7) as usize]))
+            .collect::<Vec<_>>();
+        let names = [
+            &[0][..], // host_name
+            &vec_with_len(2, b"example.com")[..],
+            &[3][..], // unknown name type, opaque to the end of the list
+            &vec_with_len(2, b"opaque")[..],
+        ]
+        .concat();
+        extensions.push((0, vec_with_len(2, &names)));
+        let protocols = (0..100)
+            .map(|i| vec_with_len(1, format!("proto-{}", i).as_bytes()))
+            .collect::<Vec<_>>()
+            .concat();
+        extensions.push((16, vec_with_len(2, &protocols)));
+        extensions.push((2000, Vec::new()));
+
+        let hello = TlsSession::peek_client_hello(&client_hello_with(&extensions)).unwrap();
+        assert_eq!(
+            hello.server_name.as_ref().map(|x| &x[..]),
+            Some("example.com")
+        );
+        assert_eq!(hello.alpn_protocols.len(), 100);
+        assert_eq!(hello.alpn_protocols[42], b"proto-42".to_vec());
+
+        // Neither extension present
+        let hello = TlsSession::peek_client_hello(&client_hello_with(&[(1000, vec![1])])).unwrap();
+        assert!(hello.server_name.is_none());
+        assert!(hello.alpn_protocols.is_empty());
+
+        // An inner length overrunning its extension is rejected
+        let mut overrun = vec_with_len(2, &vec_with_len(1, b"h3"));
+        overrun[1] += 1;
+        assert!(TlsSession::peek_client_hello(&client_hello_with(&[(16, overrun)])).is_none());
+
+        // So is an extension overrunning the message
+        let mut buf = client_hello_with(&[(1000, vec![0; 4])]);
+        let n = buf.len();
+        buf[n - 5] = 0xff;
+        assert!(TlsSession::peek_client_hello(&buf).is_none());
+
+        // A hostname that isn't a DNS name is rejected along with the rest of the hello, as
+        // rustls would reject it during the handshake
+        let names = [&[0][..], &vec_with_len(2, b"not a name")[..]].concat();
+        let buf = client_hello_with(&[(0, vec_with_len(2, &names))]);
+        assert!(TlsSession::peek_client_hello(&buf).is_none());
+    }
+
+    /// The ServerHello a rustls server sends in response to a fresh ClientHello
+    fn server_hello_bytes() -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec!["example.com".into()]);
+        let mut config = build_server_config();
+        config
+            .set_single_cert(
+                vec![Certificate(cert.serialize_der())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut server =
+            Arc::new(config).start_session(crate::VERSION, &TransportParameters::default());
+        server.read_handshake(&client_hello_bytes()).unwrap();
+        let mut buf = Vec::new();
+        server.write_handshake(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn server_hello_psk_truncated() {
+        let buf = server_hello_bytes();
+        assert_eq!(server_hello_selects_psk(&buf), Some(false));
+        for len in 0..buf.len() {
+            assert!(
+                server_hello_selects_psk(&buf[..len]).is_none(),
+                "prefix of {} bytes parsed",
+                len
+            );
+        }
+        assert!(server_hello_selects_psk(&client_hello_bytes()).is_none());
+    }
+
+    #[test]
+    fn server_hello_psk_garbage() {
+        let buf = server_hello_with(&[(41, vec![0, 0])]);
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let mut mutated = buf.clone();
+            for _ in 0..rng.gen_range(1, 8) {
+                let i = rng.gen_range(0, mutated.len());
+                mutated[i] = rng.gen();
+            }
+            let len = rng.gen_range(0, mutated.len() + 1);
+            server_hello_selects_psk(&mutated[..len]);
+
+            let mut garbage = vec![0; rng.gen_range(0, 512)];
+            rng.fill_bytes(&mut garbage);
+            if !garbage.is_empty() {
+                garbage[0] = 2; // reach past the message type check
+            }
+            server_hello_selects_psk(&garbage);
+        }
+    }
+
+    #[test]
+    fn server_hello_psk_extension_heavy() {
+        let mut extensions = (1000..1100)
+            .map(|ty| (ty, vec![0xab; (ty % 7) as usize]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            server_hello_selects_psk(&server_hello_with(&extensions)),
+            Some(false)
+        );
+        extensions.insert(50, (41, vec![0, 3]));
+        assert_eq!(
+            server_hello_selects_psk(&server_hello_with(&extensions)),
+            Some(true)
+        );
+
+        // A pre_shared_key extension too short to select an identity is rejected
+        assert!(server_hello_selects_psk(&server_hello_with(&[(41, vec![0])])).is_none());
+
+        // So is an extension overrunning the message
+        let mut buf = server_hello_with(&[(41, vec![0, 0])]);
+        let n = buf.len();
+        buf[n - 3] = 0xff;
+        assert!(server_hello_selects_psk(&buf).is_none());
+    }
+}
@@ -1,5 +1,6 @@
 use std::cmp;
 use std::collections::VecDeque;
+use std::iter;
 use std::net::SocketAddr;
 use std::ops::{Index, IndexMut};
 use std::sync::Arc;
@@ -16,18 +17,24 @@ use slog::{self, Logger};
 
 use crate::coding::BufMutExt;
 use crate::connection::{
-    self, initial_close, ClientConfig, Connection, ConnectionError, TimerUpdate,
+    self, initial_close, ClientConfig, Connection, ConnectionError, Origin, TimerUpdate,
 };
 use crate::crypto::{
-    self, reset_token_for, ConnectError, Crypto, CryptoClientConfig, CryptoServerConfig,
-    HeaderCrypto, TokenKey,
+    self, reset_token_for, ConnectError, CryptoClientConfig, CryptoServerConfig, CryptoSession,
+    HeaderKeys, Keys, TokenKey,
 };
-use crate::packet::{ConnectionId, EcnCodepoint, Header, Packet, PacketDecodeError, PartialDecode};
+use crate::frame::{self, Frame};
+use crate::packet::{
+    ConnectionId, EcnCodepoint, Header, Packet, PacketDecodeError, PartialDecode,
+    DRAFT_MAX_CID_SIZE, LONG_HEADER_FORM,
+};
+use crate::rate_limit::{LimitAction, ResetLimiter, SourceLimiter, SourceLimits};
 use crate::stream::{ReadError, WriteError};
 use crate::transport_parameters::TransportParameters;
 use crate::{
-    varint, Directionality, Side, StreamId, Transmit, TransportError, MAX_CID_SIZE, MIN_CID_SIZE,
-    MIN_INITIAL_SIZE, RESET_TOKEN_SIZE, VERSION,
+    is_v1, varint, Directionality, LocalAddress, Side, StreamId, Transmit, TransportError,
+    ADVERTISED_VERSIONS, DRAFT_VERSION, MAX_CID_SIZE, MIN_CID_SIZE, MIN_INITIAL_SIZE,
+    RESET_TOKEN_SIZE, SUPPORTED_VERSIONS,
 };
 
 /// The main entry point to the library
@@ -35,7 +42,10 @@ use crate::{
 /// This object performs no I/O whatsoever. Instead, it generates a stream of I/O operations for a
 /// backend to perform via `poll_io`, and consumes incoming packets and timer expirations via
 /// `handle` and `timeout`.
-pub struct Endpoint {
+pub struct Endpoint<S>
+where
+    S: CryptoSession,
+{
     log: Logger,
     rng: OsRng,
     transmits: VecDeque<Transmit>,
@@ -43,9 +53,11 @@ pub struct Endpoint {
     connection_ids_initial: FnvHashMap<ConnectionId, ConnectionHandle>,
     connection_ids: FnvHashMap<ConnectionId, ConnectionHandle>,
     connection_remotes: FnvHashMap<SocketAddr, ConnectionHandle>,
-    pub(crate) connections: Slab<Connection>,
+    pub(crate) connections: Slab<Connection<S>>,
     config: Arc<Config>,
-    server_config: Option<ServerConfig>,
+    server_config: Option<ServerConfig<S>>,
+    /// Whether new connections are accepted, rather than refused, by a server
+    accepting: bool,
     /// Connections that might have timer updates to apply perform
     dirty_timers: FnvHashSet<ConnectionHandle>,
     /// Connections that might have packets to send
@@ -53,15 +65,23 @@ pub struct Endpoint {
     /// Connections that might have application-facing events to report
     eventful_conns: FnvHashSet<ConnectionHandle>,
     incoming_handshakes: usize,
+    reset_limiter: ResetLimiter,
+    source_limiter: Option<SourceLimiter>,
+    /// Handshaking connections whose remote address has not been validated by a retry
+    unvalidated_handshakes: FnvHashSet<ConnectionHandle>,
 }
 
-impl Endpoint {
+impl<S> Endpoint<S>
+where
+    S: CryptoSession,
+{
     pub fn new(
         log: Logger,
         config: Config,
-        server_config: Option<ServerConfig>,
+        server_config: Option<ServerConfig<S>>,
     ) -> Result<Self, ConfigError> {
         config.validate()?;
+        let source_limiter = source_limiter(server_config.as_ref())?;
         let rng = OsRng::new().unwrap();
         let config = Arc::new(config);
         Ok(Self {
@@ -77,11 +97,51 @@ impl Endpoint {
             needs_transmit: FnvHashSet::default(),
             eventful_conns: FnvHashSet::default(),
             incoming_handshakes: 0,
+            reset_limiter: ResetLimiter::new(config.min_reset_interval),
+            source_limiter,
+            unvalidated_handshakes: FnvHashSet::default(),
             config,
+            accepting: server_config.is_some(),
             server_config,
         })
     }
 
+    /// Replace the configuration governing new incoming connections
+    ///
+    /// Established connections, including those still handshaking, are unaffected. An endpoint
+    /// that had no server configuration starts accepting connections, while one paused by
+    /// `pause_accepting` remains paused. Per-source limits are enforced afresh only if they differ
+    /// from the previous configuration's.
+    pub fn set_server_config(&mut self, server_config: ServerConfig<S>) -> Result<(), ConfigError> {
+        let limits = self
+            .server_config
+            .as_ref()
+            .and_then(|x| x.source_limits.as_ref());
+        if server_config.source_limits.as_ref() != limits {
+            self.source_limiter = source_limiter(Some(&server_config))?;
+        }
+        if self.server_config.is_none() {
+            self.accepting = true;
+        }
+        self.server_config = Some(server_config);
+        Ok(())
+    }
+
+    /// Refuse new incoming connections with CONNECTION_REFUSED until `resume_accepting` is called
+    ///
+    /// Established connections, including those still handshaking, are unaffected, and the server
+    /// configuration and per-source limits are retained.
+    pub fn pause_accepting(&mut self) {
+        self.accepting = false;
+    }
+
+    /// Accept new incoming connections again after `pause_accepting`
+    ///
+    /// Has no effect on an endpoint without a server configuration.
+    pub fn resume_accepting(&mut self) {
+        self.accepting = self.server_config.is_some();
+    }
+
     fn is_server(&self) -> bool {
         self.server_config.is_some()
     }
@@ -127,15 +187,38 @@ impl Endpoint {
         }
     }
 
-    /// Get the next packet to transmit
-    pub fn poll_transmit(&mut self, now: u64) -> Option<Transmit> {
-        if let Some(x) = self.transmits.pop_front() {
-            return Some(x);
+    /// Get the next packet(s) to transmit
+    ///
+    /// At most `max_datagrams` datagrams will be combined into a single `Transmit`.
+    pub fn poll_transmit(&mut self, now: u64, max_datagrams: usize) -> Option<Transmit> {
+        self.poll_transmit_where(now, max_datagrams, |_, _| true)
+    }
+
+    /// Get the next packet(s) to transmit whose `Transmit::socket` and destination satisfy `ready`
+    ///
+    /// Allows an application with several sockets to hold back only the packets bound for those
+    /// that can't currently be written to.
+    pub fn poll_transmit_where(
+        &mut self,
+        now: u64,
+        max_datagrams: usize,
+        mut ready: impl FnMut(Option<usize>, &SocketAddr) -> bool,
+    ) -> Option<Transmit> {
+        if let Some(i) = self
+            .transmits
+            .iter()
+            .position(|x| ready(x.socket, &x.destination))
+        {
+            return self.transmits.remove(i);
         }
         loop {
-            let &ch = self.needs_transmit.iter().next()?;
+            let connections = &self.connections;
+            let &ch = self.needs_transmit.iter().find(|&&ch| {
+                let conn = &connections[ch];
+                ready(conn.socket(), &conn.remote())
+            })?;
             loop {
-                if let Some(transmit) = self.connections[ch].poll_transmit(now) {
+                if let Some(transmit) = self.connections[ch].poll_transmit(now, max_datagrams) {
                     self.dirty_timers.insert(ch);
                     return Some(transmit);
                 } else {
@@ -147,13 +230,27 @@ impl Endpoint {
     }
 
     /// Process an incoming UDP datagram
+    ///
+    /// `local` is the address the datagram was sent to, if known. `socket` identifies the
+    /// socket it was received on, for endpoints using more than one, and is reported in
+    /// `Transmit::socket` for replies.
     pub fn handle(
         &mut self,
         now: u64,
         remote: SocketAddr,
+        local: Option<LocalAddress>,
+        socket: usize,
         ecn: Option<EcnCodepoint>,
         data: BytesMut,
     ) {
+        if !S::permits_remote(&remote) {
+            debug!(
+                self.log,
+                "dropping packet from forbidden remote {remote}",
+                remote = remote
+            );
+            return;
+        }
         let datagram_len = data.len();
         let (partial_decode, rest) = match PartialDecode::new(data, self.config.local_cid_len) {
             Ok(x) => x,
@@ -175,11 +272,16 @@ impl Endpoint {
                 }
                 .encode(&mut buf);
                 buf.write::<u32>(0x0a1a_2a3a); // reserved version
-                buf.write(VERSION); // supported version
+                for &version in ADVERTISED_VERSIONS {
+                    buf.write(version);
+                }
                 self.transmits.push_back(Transmit {
                     destination: remote,
                     ecn: None,
-                    packet: buf.into(),
+                    contents: buf.into(),
+                    segment_size: None,
+                    src: local,
+                    socket: Some(socket),
                 });
                 return;
             }
@@ -214,7 +316,20 @@ impl Endpoint {
         };
         if let Some(ch) = known_ch {
             let had_1rtt = self.connections[ch].has_1rtt();
-            self.connections[ch].handle_dgram(now, remote, ecn, partial_decode, rest);
+            self.connections[ch].handle_dgram(
+                now,
+                Received {
+                    remote,
+                    local,
+                    socket,
+                    ecn,
+                },
+                partial_decode,
+                rest,
+            );
+            if !self.connections[ch].is_handshaking() {
+                self.handshake_finished(ch);
+            }
             if !had_1rtt
                 && (self.connections[ch].has_1rtt() || !self.connections[ch].is_handshaking())
             {
@@ -236,7 +351,7 @@ impl Endpoint {
                 "got unexpected packet on unrecognized connection {connection}",
                 connection = dst_cid
             );
-            self.stateless_reset(datagram_len, remote, &dst_cid);
+            self.stateless_reset(now, datagram_len, remote, local, socket, &dst_cid);
             return;
         }
 
@@ -251,12 +366,31 @@ impl Endpoint {
                     return;
                 }
 
-                let crypto = Crypto::new_initial(&partial_decode.dst_cid(), Side::Server);
-                let header_crypto = crypto.header_crypto();
+                let version = partial_decode.version().unwrap();
+                if !is_v1(version) && self.config.local_cid_len > DRAFT_MAX_CID_SIZE {
+                    debug!(
+                        self.log,
+                        "ignoring draft initial: local CIDs too long for version {version:x}",
+                        version = version
+                    );
+                    return;
+                }
+                let crypto = S::Keys::new_initial(&partial_decode.dst_cid(), Side::Server, version);
+                let header_crypto = crypto.header_keys();
                 match partial_decode.finish(Some(&header_crypto)) {
-                    Ok(packet) => {
-                        self.handle_initial(now, remote, ecn, packet, rest, &crypto, &header_crypto)
-                    }
+                    Ok(packet) => self.handle_initial(
+                        now,
+                        Received {
+                            remote,
+                            local,
+                            socket,
+                            ecn,
+                        },
+                        packet,
+                        rest,
+                        &crypto,
+                        &header_crypto,
+                    ),
                     Err(e) => {
                         trace!(self.log, "unable to decode packet"; "reason" => %e);
                     }
@@ -278,7 +412,7 @@ impl Endpoint {
         //
 
         if !dst_cid.is_empty() {
-            self.stateless_reset(datagram_len, remote, &dst_cid);
+            self.stateless_reset(now, datagram_len, remote, local, socket, &dst_cid);
         } else {
             trace!(self.log, "dropping unrecognized short packet without ID");
         }
@@ -286,8 +420,11 @@ impl Endpoint {
 
     fn stateless_reset(
         &mut self,
+        now: u64,
         inciting_dgram_len: usize,
         remote: SocketAddr,
+        local: Option<LocalAddress>,
+        socket: usize,
         dst_cid: &ConnectionId,
     ) {
         /// Minimum amount of padding for the stateless reset to look like a short-header packet
@@ -303,38 +440,64 @@ impl Endpoint {
             }
         };
 
+        // Limit the rate of resets so they can't be used to reflect traffic at third parties
+        if !self.reset_limiter.check(now, remote.ip()) {
+            debug!(
+                self.log,
+                "not resetting {remote}: stateless reset rate limited",
+                remote = remote
+            );
+            return;
+        }
+
         debug!(
             self.log,
             "sending stateless reset to {remote}",
             remote = remote
         );
-        let mut buf = Vec::<u8>::new();
-        let padding_len = self.rng.gen_range(MIN_PADDING_LEN, max_padding_len);
-        buf.reserve_exact(padding_len + RESET_TOKEN_SIZE);
-        buf.resize(padding_len, 0);
-        self.rng.fill_bytes(&mut buf[0..padding_len]);
-        buf[0] = 0b0100_0000 | buf[0] >> 2;
-        buf.extend(&reset_token_for(&self.config.reset_key, dst_cid));
-
-        debug_assert!(buf.len() < inciting_dgram_len);
-
-        self.transmits.push_back(Transmit {
-            destination: remote,
-            ecn: None,
-            packet: buf.into(),
-        });
+        let server_ids =
+            iter::once(&self.config.server_id).chain(self.config.adopted_server_ids.iter());
+        for server_id in server_ids {
+            let mut buf = Vec::<u8>::new();
+            let padding_len = self.rng.gen_range(MIN_PADDING_LEN, max_padding_len);
+            buf.reserve_exact(padding_len + RESET_TOKEN_SIZE);
+            buf.resize(padding_len, 0);
+            self.rng.fill_bytes(&mut buf[0..padding_len]);
+            buf[0] = 0b0100_0000 | buf[0] >> 2;
+            buf.extend(&reset_token_for(&self.config.reset_key, server_id, dst_cid));
+
+            debug_assert!(buf.len() < inciting_dgram_len);
+
+            self.transmits.push_back(Transmit {
+                destination: remote,
+                ecn: None,
+                contents: buf.into(),
+                segment_size: None,
+                src: local,
+                socket: Some(socket),
+            });
+        }
     }
 
     /// Initiate a connection
     pub fn connect(
         &mut self,
         remote: SocketAddr,
-        config: &Arc<crypto::ClientConfig>,
+        config: &S::ClientConfig,
         server_name: &str,
     ) -> Result<ConnectionHandle, ConnectError> {
-        let remote_id = ConnectionId::random(&mut self.rng, MAX_CID_SIZE);
+        if !S::permits_remote(&remote) {
+            return Err(ConnectError::InsecureRemote(remote));
+        }
+        let remote_id_len = if is_v1(self.config.version) {
+            MAX_CID_SIZE
+        } else {
+            DRAFT_MAX_CID_SIZE
+        };
+        let remote_id = ConnectionId::random(&mut self.rng, remote_id_len);
         trace!(self.log, "initial dcid"; "value" => %remote_id);
         let ch = self.add_connection(
+            self.config.version,
             remote_id,
             remote_id,
             remote,
@@ -349,7 +512,10 @@ impl Endpoint {
 
     fn new_cid(&mut self) -> ConnectionId {
         loop {
-            let cid = ConnectionId::random(&mut self.rng, self.config.local_cid_len);
+            let mut cid = ConnectionId::random(&mut self.rng, self.config.local_cid_len);
+            if self.config.shards > 1 {
+                cid[0] = self.config.shard;
+            }
             if !self.connection_ids.contains_key(&cid) {
                 break cid;
             }
@@ -359,46 +525,65 @@ impl Endpoint {
 
     fn add_connection(
         &mut self,
+        version: u32,
         initial_id: ConnectionId,
         remote_id: ConnectionId,
         remote: SocketAddr,
-        opts: ConnectionOpts,
+        opts: ConnectionOpts<S>,
     ) -> Result<ConnectionHandle, ConnectError> {
         let local_id = self.new_cid();
+        let params = TransportParameters {
+            initial_src_cid: Some(local_id),
+            ..TransportParameters::new(&self.config)
+        };
+        let remote_validated = match opts {
+            ConnectionOpts::Server { orig_dst_cid } => orig_dst_cid.is_some(),
+            ConnectionOpts::Client(_) => false,
+        };
         let (tls, client_config) = match opts {
             ConnectionOpts::Client(config) => (
                 config
                     .tls_config
-                    .start_session(&config.server_name, &TransportParameters::new(&self.config))?,
+                    .start_session(&config.server_name, version, &params)?,
                 Some(config),
             ),
             ConnectionOpts::Server { orig_dst_cid } => {
-                let server_params = TransportParameters {
-                    stateless_reset_token: Some(reset_token_for(&self.config.reset_key, &local_id)),
+                let mut server_params = TransportParameters {
+                    stateless_reset_token: Some(reset_token_for(
+                        &self.config.reset_key,
+                        &self.config.server_id,
+                        &local_id,
+                    )),
                     original_connection_id: orig_dst_cid,
-                    ..TransportParameters::new(&self.config)
+                    ..params
                 };
+                if is_v1(version) {
+                    // Drafts only echo the original CID after a Retry, whose source CID the
+                    // client is now addressing us by.
+                    server_params.original_connection_id = Some(orig_dst_cid.unwrap_or(initial_id));
+                    server_params.retry_src_cid = orig_dst_cid.map(|_| initial_id);
+                }
                 (
                     self.server_config
                         .as_ref()
                         .unwrap()
                         .tls_config
-                        .start_session(&server_params),
+                        .start_session(version, &server_params),
                     None,
                 )
             }
         };
 
-        let remote_validated = self.server_config.as_ref().map_or(false, |cfg| {
-            cfg.use_stateless_retry && client_config.is_none()
-        });
         let id = self.connections.insert(Connection::new(
             self.log.new(o!("connection" => local_id)),
             Arc::clone(&self.config),
-            initial_id,
-            local_id,
-            remote_id,
-            remote,
+            Origin {
+                version,
+                init_cid: initial_id,
+                loc_cid: local_id,
+                rem_cid: remote_id,
+                remote,
+            },
             client_config,
             tls,
             remote_validated,
@@ -415,20 +600,26 @@ impl Endpoint {
     fn handle_initial(
         &mut self,
         now: u64,
-        remote: SocketAddr,
-        ecn: Option<EcnCodepoint>,
+        received: Received,
         mut packet: Packet,
         rest: Option<BytesMut>,
-        crypto: &Crypto,
-        header_crypto: &HeaderCrypto,
+        crypto: &S::Keys,
+        header_crypto: &dyn HeaderKeys,
     ) {
-        let (src_cid, dst_cid, token, packet_number) = match packet.header {
+        let Received {
+            remote,
+            local,
+            socket,
+            ..
+        } = received;
+        let (version, src_cid, dst_cid, token, packet_number) = match packet.header {
             Header::Initial {
+                version,
                 src_cid,
                 dst_cid,
                 ref token,
                 number,
-            } => (src_cid, dst_cid, token.clone(), number),
+            } => (version, src_cid, dst_cid, token.clone(), number),
             _ => panic!("non-initial packet in handle_initial()"),
         };
         let packet_number = packet_number.expand(0);
@@ -448,49 +639,36 @@ impl Endpoint {
         // Local CID used for stateless packets
         let temp_loc_cid = ConnectionId::random(&mut self.rng, self.config.local_cid_len);
 
-        if self.incoming_handshakes == self.server_config.as_ref().unwrap().accept_buffer as usize {
-            debug!(self.log, "rejecting connection due to full accept buffer");
-            self.transmits.push_back(Transmit {
-                destination: remote,
-                ecn: None,
-                packet: initial_close(
-                    crypto,
-                    header_crypto,
-                    &src_cid,
-                    &temp_loc_cid,
-                    0,
-                    TransportError::SERVER_BUSY(""),
-                ),
-            });
-            return;
-        }
-
-        if dst_cid.len() < 8
-            && (!self.server_config.as_ref().unwrap().use_stateless_retry
-                || dst_cid.len() != self.config.local_cid_len)
+        if !self.accepting
+            || self.incoming_handshakes
+                == self.server_config.as_ref().unwrap().accept_buffer as usize
         {
-            debug!(
-                self.log,
-                "rejecting connection due to invalid DCID length {len}",
-                len = dst_cid.len()
-            );
+            debug!(self.log, "rejecting connection"; "accepting" => self.accepting);
             self.transmits.push_back(Transmit {
                 destination: remote,
                 ecn: None,
-                packet: initial_close(
+                contents: initial_close(
+                    version,
                     crypto,
                     header_crypto,
                     &src_cid,
                     &temp_loc_cid,
                     0,
-                    TransportError::PROTOCOL_VIOLATION("invalid destination CID length"),
+                    TransportError::CONNECTION_REFUSED(""),
                 ),
+                segment_size: None,
+                src: local,
+                socket: Some(socket),
             });
             return;
         }
 
+        // Original destination CID recovered from a valid retry token
+        //
+        // Tokens are honored even when a retry isn't currently required, since the policy that
+        // demanded one may have changed its mind by the time the client responds.
         let mut retry_cid = None;
-        if self.server_config.as_ref().unwrap().use_stateless_retry {
+        if !token.is_empty() {
             if let Some((token_dst_cid, token_issued)) = self
                 .server_config
                 .as_ref()
@@ -505,38 +683,170 @@ impl Endpoint {
                 if expires > SystemTime::now() {
                     retry_cid = Some(token_dst_cid);
                 } else {
-                    trace!(self.log, "sending stateless retry due to expired token");
+                    trace!(self.log, "ignoring expired token");
                 }
             } else {
-                trace!(self.log, "sending stateless retry due to invalid token");
+                trace!(self.log, "ignoring invalid token");
             }
-            if retry_cid.is_none() {
-                let token = self.server_config.as_ref().unwrap().token_key.generate(
-                    &remote,
-                    &dst_cid,
-                    SystemTime::now(),
-                );
-                let mut buf = Vec::new();
-                let header = Header::Retry {
-                    src_cid: temp_loc_cid,
-                    dst_cid: src_cid,
-                    orig_dst_cid: dst_cid,
+        }
+
+        let mut use_retry = self.server_config.as_ref().unwrap().use_stateless_retry;
+        if let Some(ref mut limiter) = self.source_limiter {
+            // A source that has validated its address as demanded is exempt from the attempt rate,
+            // but not from the limit on concurrent handshakes
+            let validated = retry_cid.is_some() && limiter.action() == LimitAction::Retry;
+            let within_limits = if validated {
+                limiter.check_handshaking(remote.ip())
+            } else {
+                limiter.check(now, remote.ip())
+            };
+            if !within_limits {
+                if limiter.action() == LimitAction::Retry && !validated {
+                    trace!(
+                        self.log,
+                        "requiring address validation from rate-limited source"
+                    );
+                    use_retry = true;
+                } else {
+                    debug!(self.log, "rejecting connection from rate-limited source");
+                    self.transmits.push_back(Transmit {
+                        destination: remote,
+                        ecn: None,
+                        contents: initial_close(
+                            version,
+                            crypto,
+                            header_crypto,
+                            &src_cid,
+                            &temp_loc_cid,
+                            0,
+                            TransportError::CONNECTION_REFUSED(""),
+                        ),
+                        segment_size: None,
+                        src: local,
+                        socket: Some(socket),
+                    });
+                    return;
+                }
+            }
+        }
+
+        if !use_retry {
+            if let Some(ref policy) = self.server_config.as_ref().unwrap().retry_policy {
+                let load = ServerLoad {
+                    unvalidated_handshakes: self.unvalidated_handshakes.len(),
+                    connections: self.connections.len(),
                 };
-                let encode = header.encode(&mut buf);
-                encode.finish(&mut buf, header_crypto);
-                buf.put_slice(&token);
+                use_retry = policy.require_retry(&remote, &load);
+            }
+        }
 
-                self.transmits.push_back(Transmit {
-                    destination: remote,
-                    ecn: None,
-                    packet: buf.into(),
+        if let Some(ref filter) = self.server_config.as_ref().unwrap().accept_filter {
+            let hello =
+                frame::Iter::new(packet.payload.clone().freeze(), version).find_map(|frame| {
+                    match frame {
+                        Frame::Crypto(ref x) if x.offset == 0 => S::peek_client_hello(&x.data),
+                        _ => None,
+                    }
                 });
-                return;
+            let complete = hello.is_some();
+            let hello = hello.unwrap_or_default();
+            let attempt = ConnectionAttempt {
+                remote,
+                server_name: hello.server_name.as_ref().map(|x| &x[..]),
+                alpn_protocols: &hello.alpn_protocols,
+                complete,
+            };
+            match filter.filter(&attempt) {
+                AcceptDecision::Accept => {}
+                AcceptDecision::Retry => {
+                    use_retry = true;
+                }
+                AcceptDecision::Refuse(e) => {
+                    debug!(self.log, "connection refused by accept filter"; "reason" => %e);
+                    self.transmits.push_back(Transmit {
+                        destination: remote,
+                        ecn: None,
+                        contents: initial_close(
+                            version,
+                            crypto,
+                            header_crypto,
+                            &src_cid,
+                            &temp_loc_cid,
+                            0,
+                            e,
+                        ),
+                        segment_size: None,
+                        src: local,
+                        socket: Some(socket),
+                    });
+                    return;
+                }
+            }
+        }
+
+        if dst_cid.len() < 8
+            && ((!use_retry && retry_cid.is_none()) || dst_cid.len() != self.config.local_cid_len)
+        {
+            debug!(
+                self.log,
+                "rejecting connection due to invalid DCID length {len}",
+                len = dst_cid.len()
+            );
+            self.transmits.push_back(Transmit {
+                destination: remote,
+                ecn: None,
+                contents: initial_close(
+                    version,
+                    crypto,
+                    header_crypto,
+                    &src_cid,
+                    &temp_loc_cid,
+                    0,
+                    TransportError::PROTOCOL_VIOLATION("invalid destination CID length"),
+                ),
+                segment_size: None,
+                src: local,
+                socket: Some(socket),
+            });
+            return;
+        }
+
+        if use_retry && retry_cid.is_none() {
+            trace!(self.log, "sending stateless retry");
+            let token = self.server_config.as_ref().unwrap().token_key.generate(
+                &remote,
+                &dst_cid,
+                SystemTime::now(),
+            );
+            let mut buf = Vec::new();
+            let header = Header::Retry {
+                version,
+                src_cid: temp_loc_cid,
+                dst_cid: src_cid,
+                orig_dst_cid: if is_v1(version) { None } else { Some(dst_cid) },
+            };
+            let encode = header.encode(&mut buf);
+            encode.finish(&mut buf, header_crypto);
+            buf.put_slice(&token);
+            if is_v1(version) {
+                let tag = crypto::retry_tag(&dst_cid, &buf);
+                buf.put_slice(&tag);
             }
+
+            self.transmits.push_back(Transmit {
+                destination: remote,
+                ecn: None,
+                contents: buf.into(),
+                segment_size: None,
+                src: local,
+                socket: Some(socket),
+            });
+            return;
         }
 
         let ch = self
             .add_connection(
+                version,
                 dst_cid,
                 src_cid,
                 remote,
@@ -548,16 +858,15 @@ impl Endpoint {
         if dst_cid.len() != 0 {
             self.connection_ids_initial.insert(dst_cid, ch);
         }
-        match self.connections[ch].handle_initial(
-            now,
-            remote,
-            ecn,
-            packet_number as u64,
-            packet,
-            rest,
-        ) {
+        match self.connections[ch].handle_initial(now, received, packet_number, packet, rest) {
             Ok(()) => {
                 self.incoming_handshakes += 1;
+                if let Some(ref mut limiter) = self.source_limiter {
+                    limiter.started(now, ch, remote.ip());
+                }
+                if retry_cid.is_none() {
+                    self.unvalidated_handshakes.insert(ch);
+                }
                 self.needs_transmit.insert(ch);
                 if self.connections[ch].has_1rtt() {
                     self.conn_ready(ch);
@@ -569,7 +878,18 @@ impl Endpoint {
                 self.transmits.push_back(Transmit {
                     destination: remote,
                     ecn: None,
-                    packet: initial_close(crypto, header_crypto, &src_cid, &temp_loc_cid, 0, e),
+                    contents: initial_close(
+                        version,
+                        crypto,
+                        header_crypto,
+                        &src_cid,
+                        &temp_loc_cid,
+                        0,
+                        e,
+                    ),
+                    segment_size: None,
+                    src: local,
+                    socket: Some(socket),
                 });
             }
         }
@@ -583,9 +903,11 @@ impl Endpoint {
         if self.config.local_cid_len != 0 && !self.connections[ch].is_closed() {
             /// Draft 17 §5.1.1: endpoints SHOULD provide and maintain at least eight
             /// connection IDs
-            const LOCAL_CID_COUNT: usize = 8;
+            const LOCAL_CID_COUNT: u64 = 8;
+            // Never exceed the number of CIDs the peer has agreed to store
+            let count = cmp::min(LOCAL_CID_COUNT, self.connections[ch].peer_cid_limit());
             // We've already issued one CID as part of the normal handshake process.
-            for _ in 1..LOCAL_CID_COUNT {
+            for _ in 1..count {
                 let cid = self.new_cid();
                 self.connection_ids.insert(cid, ch);
                 self.connections[ch].issue_cid(cid);
@@ -593,7 +915,16 @@ impl Endpoint {
         }
     }
 
+    /// Stop counting `ch` against limits on incomplete handshakes
+    fn handshake_finished(&mut self, ch: ConnectionHandle) {
+        if let Some(ref mut limiter) = self.source_limiter {
+            limiter.finished(ch);
+        }
+        self.unvalidated_handshakes.remove(&ch);
+    }
+
     fn forget(&mut self, ch: ConnectionHandle) {
+        self.handshake_finished(ch);
         if self.connections[ch].side().is_server() {
             self.connection_ids_initial
                 .remove(&self.connections[ch].init_cid);
@@ -711,7 +1042,7 @@ impl Endpoint {
     ///
     /// # Panics
     /// - when applied to a receive stream or an unopened send stream
-    pub fn reset(&mut self, ch: ConnectionHandle, stream: StreamId, error_code: u16) {
+    pub fn reset(&mut self, ch: ConnectionHandle, stream: StreamId, error_code: u64) {
         self.connections[ch].reset(stream, error_code);
         self.needs_transmit.insert(ch);
     }
@@ -720,7 +1051,7 @@ impl Endpoint {
     ///
     /// # Panics
     /// - when applied to a stream that has not begun receiving data
-    pub fn stop_sending(&mut self, ch: ConnectionHandle, stream: StreamId, error_code: u16) {
+    pub fn stop_sending(&mut self, ch: ConnectionHandle, stream: StreamId, error_code: u64) {
         self.connections[ch].stop_sending(stream, error_code);
         self.needs_transmit.insert(ch);
     }
@@ -745,7 +1076,7 @@ impl Endpoint {
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility
     /// to call this only when all important communications have been completed.
-    pub fn close(&mut self, now: u64, ch: ConnectionHandle, error_code: u16, reason: Bytes) {
+    pub fn close(&mut self, now: u64, ch: ConnectionHandle, error_code: u64, reason: Bytes) {
         if self.connections[ch].is_drained() {
             self.forget(ch);
             return;
@@ -775,11 +1106,26 @@ impl Endpoint {
         self.ping(ch);
     }
 
-    pub fn connection(&self, ch: ConnectionHandle) -> &Connection {
+    pub fn connection(&self, ch: ConnectionHandle) -> &Connection<S> {
         &self.connections[ch]
     }
 }
 
+fn source_limiter<S>(
+    server_config: Option<&ServerConfig<S>>,
+) -> Result<Option<SourceLimiter>, ConfigError>
+where
+    S: CryptoSession,
+{
+    match server_config.and_then(|x| x.source_limits.as_ref()) {
+        Some(limits) => {
+            limits.validate()?;
+            Ok(Some(SourceLimiter::new(limits.clone())))
+        }
+        None => Ok(None),
+    }
+}
+
 /// Parameters governing the core QUIC state machine
 ///
 /// This should be tuned to suit the application. In particular, window sizes for streams, stream
@@ -859,7 +1205,8 @@ pub struct Config {
 
     /// Length of connection IDs for the endpoint.
     ///
-    /// This must be either 0 or between 4 and 18 inclusive. The length of the local connection IDs
+    /// This must be either 0 or between 4 and 20 inclusive. Peers speaking draft versions can
+    /// only be served when this is at most 18. The length of the local connection IDs
     /// constrains the amount of simultaneous connections the endpoint can maintain. The API user is
     /// responsible for making sure that the pool is large enough to cover the intended usage.
     pub local_cid_len: usize,
@@ -869,6 +1216,61 @@ pub struct Config {
     ///
     /// Must be persisted across restarts to be useful.
     pub reset_key: SigningKey,
+    /// Identity of this endpoint, bound into the stateless reset tokens it issues
+    ///
+    /// Servers that share `reset_key` can only reset each other's connections if they also share
+    /// this identity. Give each server in a cluster its own identity, and let a server that takes
+    /// over another's connections after failover list the failed server's identity in
+    /// `adopted_server_ids`.
+    pub server_id: Vec<u8>,
+    /// Identities of other servers sharing `reset_key` whose connections this endpoint may reset
+    ///
+    /// The issuer of an unrecognized connection ID can't be told from the ID itself, so a stateless
+    /// reset is sent for `server_id` and for each of these, of which only the one matching the
+    /// issuer is accepted by the peer. Each inciting packet therefore draws as many resets as there
+    /// are identities, so keep this list short.
+    pub adopted_server_ids: Vec<Vec<u8>>,
+    /// Minimum time between stateless resets sent to any one address (μs)
+    ///
+    /// Stateless resets are sent in response to unrecognized packets, so without a limit an
+    /// attacker spoofing source addresses could use them to flood third parties. Peers at other
+    /// addresses are unaffected, so a restarted server can still reset all of its former
+    /// connections promptly. Must be nonzero; `u64::max_value()` sends at most one reset to each address.
+    pub min_reset_interval: u64,
+
+    /// Number of endpoints sharing this endpoint's address, each owning a share of its connections
+    ///
+    /// When greater than 1, the first byte of every connection ID issued by this endpoint is its
+    /// `shard`, so a datagram that reaches the wrong shard, e.g. after the peer's NAT rebinds, can
+    /// be passed on to the owner identified by `shard_of`. Requires a nonzero `local_cid_len`.
+    ///
+    /// The shard byte is neither random nor hidden: connection IDs carry 8 fewer bits of entropy,
+    /// and on-path observers can tell which shard owns each connection, so consider a longer
+    /// `local_cid_len` to compensate. With a single shard, connection IDs are entirely random.
+    pub shards: u8,
+    /// Index of this endpoint among `shards`
+    pub shard: u8,
+
+    /// QUIC version to use for outgoing connections
+    ///
+    /// Must be one of `SUPPORTED_VERSIONS`. Defaults to `DRAFT_VERSION` until version 1 can
+    /// interoperate with other implementations. Incoming connections use whichever supported
+    /// version the client chose.
+    pub version: u32,
+
+    /// Maximum number of packets to protect with one set of 1-RTT keys
+    ///
+    /// Only takes effect once the handshake is confirmed, as keys can't be updated before then; the
+    /// confidentiality limit of the negotiated AEAD applies regardless. A key update is initiated
+    /// once three quarters of the effective limit has been used, and a connection that nearly
+    /// reaches it without being permitted to update its keys is closed with `AEAD_LIMIT_REACHED`,
+    /// or `PROTOCOL_VIOLATION` under draft 17.
+    pub max_packets_per_key: u64,
+    /// Maximum number of received 1-RTT packets failing authentication to tolerate before closing
+    /// a connection with `AEAD_LIMIT_REACHED`, or `PROTOCOL_VIOLATION` under draft 17
+    ///
+    /// The integrity limit of the negotiated AEAD applies regardless of this setting.
+    pub max_forged_packets: u64,
 }
 
 impl Default for Config {
@@ -907,6 +1309,16 @@ impl Default for Config {
 
             local_cid_len: 8,
             reset_key: SigningKey::new(&digest::SHA512_256, &reset_value),
+            server_id: Vec::new(),
+            adopted_server_ids: Vec::new(),
+            min_reset_interval: 20 * 1000,
+
+            shards: 1,
+            shard: 0,
+
+            version: DRAFT_VERSION,
+            max_packets_per_key: u64::max_value(),
+            max_forged_packets: u64::max_value(),
         }
     }
 }
@@ -917,7 +1329,28 @@ impl Config {
             || self.local_cid_len > MAX_CID_SIZE
         {
             return Err(ConfigError::IllegalValue(
-                "local_cid_len must be 0 or in [4, 18]",
+                "local_cid_len must be 0 or in [4, 20]",
+            ));
+        }
+        if !SUPPORTED_VERSIONS.contains(&self.version) {
+            return Err(ConfigError::IllegalValue("unsupported version"));
+        }
+        if self.max_packets_per_key == 0 {
+            return Err(ConfigError::IllegalValue(
+                "max_packets_per_key must be nonzero",
+            ));
+        }
+        if self.min_reset_interval == 0 {
+            return Err(ConfigError::IllegalValue(
+                "min_reset_interval must be nonzero",
+            ));
+        }
+        if self.shards == 0 || self.shard >= self.shards {
+            return Err(ConfigError::IllegalValue("shard must be less than shards"));
+        }
+        if self.shards > 1 && self.local_cid_len == 0 {
+            return Err(ConfigError::IllegalValue(
+                "sharding requires a nonzero local_cid_len",
             ));
         }
         if let Some((name, _)) = [
@@ -934,14 +1367,55 @@ impl Config {
         }
         Ok(())
     }
+
+    /// The shard owning the connection `datagram` is addressed to, if it can be determined
+    ///
+    /// Short header and Handshake packets are attributed to the shard named by the first byte of
+    /// their destination connection ID, which is always chosen by this endpoint. Initial and 0-RTT
+    /// packets may be addressed to a connection ID chosen by the peer, so `None` is returned for
+    /// them, as well as for unsupported versions and when sharding is disabled.
+    pub fn shard_of(&self, datagram: &[u8]) -> Option<u8> {
+        if self.shards <= 1 || datagram.len() < 1 + self.local_cid_len {
+            return None;
+        }
+        let first = datagram[0];
+        let shard = if first & LONG_HEADER_FORM == 0 {
+            datagram[1]
+        } else {
+            if first & 0x30 != 0x20 || datagram.len() < 6 {
+                // Not a Handshake packet
+                return None;
+            }
+            let version = u32::from_be_bytes([datagram[1], datagram[2], datagram[3], datagram[4]]);
+            if !SUPPORTED_VERSIONS.contains(&version) {
+                return None;
+            }
+            let dcil = if is_v1(version) {
+                datagram[5] as usize
+            } else {
+                match datagram[5] >> 4 {
+                    0 => 0,
+                    x => x as usize + 3,
+                }
+            };
+            if dcil != self.local_cid_len || datagram.len() < 6 + dcil {
+                return None;
+            }
+            datagram[6]
+        };
+        Some(shard).filter(|&x| x < self.shards)
+    }
 }
 
 /// Parameters governing incoming connections.
-pub struct ServerConfig {
+pub struct ServerConfig<S>
+where
+    S: CryptoSession,
+{
     /// TLS configuration used for incoming connections.
     ///
     /// Must be set to use TLS 1.3 only.
-    pub tls_config: Arc<crypto::ServerConfig>,
+    pub tls_config: S::ServerConfig,
 
     /// Private key used to authenticate data included in handshake tokens.
     pub token_key: TokenKey,
@@ -957,9 +1431,27 @@ pub struct ServerConfig {
     /// Calling `Endpoint::accept` removes a connection from the buffer, so this does not need to
     /// be large.
     pub accept_buffer: u32,
+
+    /// Limits on connection attempts from any one source address prefix, if any
+    ///
+    /// When `action` is `LimitAction::Retry`, a source that has proven ownership of its address
+    /// via stateless retry is exempt from the attempt rate, but is still refused if it has too many
+    /// connections handshaking.
+    pub source_limits: Option<SourceLimits>,
+
+    /// Decides whether to proceed with each connection attempt before any state is allocated
+    pub accept_filter: Option<Arc<dyn AcceptFilter>>,
+    /// Decides whether to require address validation from each connection attempt
+    ///
+    /// Consulted only when `use_stateless_retry` is disabled. [`LoadBasedRetry`] enables retries
+    /// only while the server is under load.
+    pub retry_policy: Option<Arc<dyn RetryPolicy>>,
 }
 
-impl Default for ServerConfig {
+impl<S> Default for ServerConfig<S>
+where
+    S: CryptoSession,
+{
     fn default() -> Self {
         let rng = &mut rand::thread_rng();
 
@@ -967,17 +1459,118 @@ impl Default for ServerConfig {
         rng.fill_bytes(&mut token_value);
 
         Self {
-            tls_config: Arc::new(crypto::build_server_config()),
+            tls_config: S::ServerConfig::new(),
 
             token_key: TokenKey::new(&token_value),
             use_stateless_retry: false,
             retry_token_lifetime: 15_000_000,
 
             accept_buffer: 1024,
+            source_limits: None,
+            accept_filter: None,
+            retry_policy: None,
         }
     }
 }
 
+/// Application policy for admitting incoming connections
+///
+/// Implemented for any suitable closure.
+pub trait AcceptFilter: Send + Sync {
+    /// Decide how to respond to a connection attempt
+    fn filter(&self, attempt: &ConnectionAttempt<'_>) -> AcceptDecision;
+}
+
+impl<F> AcceptFilter for F
+where
+    F: Fn(&ConnectionAttempt<'_>) -> AcceptDecision + Send + Sync,
+{
+    fn filter(&self, attempt: &ConnectionAttempt<'_>) -> AcceptDecision {
+        self(attempt)
+    }
+}
+
+/// Policy deciding when clients must prove ownership of their address before a handshake begins
+///
+/// Implemented for any suitable closure.
+pub trait RetryPolicy: Send + Sync {
+    /// Whether to send a stateless retry in response to a connection attempt from `remote`
+    fn require_retry(&self, remote: &SocketAddr, load: &ServerLoad) -> bool;
+}
+
+impl<F> RetryPolicy for F
+where
+    F: Fn(&SocketAddr, &ServerLoad) -> bool + Send + Sync,
+{
+    fn require_retry(&self, remote: &SocketAddr, load: &ServerLoad) -> bool {
+        self(remote, load)
+    }
+}
+
+/// Summary of an endpoint's state used to inform a [`RetryPolicy`]
+#[derive(Debug, Clone)]
+pub struct ServerLoad {
+    /// Number of incomplete handshakes with clients that have not proven ownership of their
+    /// address
+    pub unvalidated_handshakes: usize,
+    /// Total number of connections, including those still handshaking or closing
+    pub connections: usize,
+}
+
+/// Require retries only while many handshakes with unvalidated clients are in progress
+///
+/// Avoids the extra round trip under normal load, while bounding the state an attacker spoofing
+/// source addresses can cause the server to allocate.
+#[derive(Debug, Copy, Clone)]
+pub struct LoadBasedRetry {
+    /// Number of unvalidated handshakes at which retries become required
+    pub max_unvalidated: usize,
+}
+
+impl RetryPolicy for LoadBasedRetry {
+    fn require_retry(&self, _: &SocketAddr, load: &ServerLoad) -> bool {
+        load.unvalidated_handshakes >= self.max_unvalidated
+    }
+}
+
+/// What's known about an incoming connection attempt from its first packet
+///
+/// The client's first handshake message is only inspected if it fits in that packet. A
+/// ClientHello spanning several packets, e.g. because it offers many protocols or carries large
+/// extensions, yields neither a server name nor any protocols. Filters that decide by either must
+/// check `complete`, since a client can pad its ClientHello to hide them.
+#[derive(Debug)]
+pub struct ConnectionAttempt<'a> {
+    /// The address the attempt was received from
+    pub remote: SocketAddr,
+    /// The server name requested by the client
+    ///
+    /// `None` if the client requested none or `complete` is false.
+    pub server_name: Option<&'a str>,
+    /// Application-layer protocols offered by the client, in order of preference
+    ///
+    /// Empty if the client offered none or `complete` is false.
+    pub alpn_protocols: &'a [Vec<u8>],
+    /// Whether the client's first handshake message was inspected
+    ///
+    /// False if it spans multiple packets, or if the crypto protocol doesn't support inspecting
+    /// it.
+    pub complete: bool,
+}
+
+/// Response to a connection attempt chosen by an [`AcceptFilter`]
+#[derive(Debug)]
+pub enum AcceptDecision {
+    /// Proceed with the handshake
+    Accept,
+    /// Proceed only once the client has proven ownership of its address via stateless retry
+    ///
+    /// A client presenting a valid retry token is admitted regardless.
+    Retry,
+    /// Abandon the attempt, closing the connection with the given error
+    Refuse(TransportError),
+}
+
 /// Errors in the configuration of an endpoint
 #[derive(Debug, Error)]
 pub enum ConfigError {
@@ -1019,7 +1612,7 @@ impl From<ConnectionError> for Event {
     }
 }
 
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub enum Timer {
     LossDetection = 0,
     Idle = 1,
@@ -1059,20 +1652,40 @@ impl From<ConnectionHandle> for usize {
     }
 }
 
-impl Index<ConnectionHandle> for Slab<Connection> {
-    type Output = Connection;
-    fn index(&self, ch: ConnectionHandle) -> &Connection {
+impl<S> Index<ConnectionHandle> for Slab<Connection<S>>
+where
+    S: CryptoSession,
+{
+    type Output = Connection<S>;
+    fn index(&self, ch: ConnectionHandle) -> &Connection<S> {
         &self[ch.0]
     }
 }
 
-impl IndexMut<ConnectionHandle> for Slab<Connection> {
-    fn index_mut(&mut self, ch: ConnectionHandle) -> &mut Connection {
+impl<S> IndexMut<ConnectionHandle> for Slab<Connection<S>>
+where
+    S: CryptoSession,
+{
+    fn index_mut(&mut self, ch: ConnectionHandle) -> &mut Connection<S> {
         &mut self[ch.0]
     }
 }
 
-enum ConnectionOpts {
-    Client(ClientConfig),
+/// Where a datagram came from and how it arrived
+#[derive(Debug, Copy, Clone)]
+pub struct Received {
+    pub remote: SocketAddr,
+    /// The local address the datagram was sent to, if known
+    pub local: Option<LocalAddress>,
+    /// Index of the socket the datagram was received on
+    pub socket: usize,
+    pub ecn: Option<EcnCodepoint>,
+}
+
+enum ConnectionOpts<S>
+where
+    S: CryptoSession,
+{
+    Client(ClientConfig<S>),
     Server { orig_dst_cid: Option<ConnectionId> },
 }
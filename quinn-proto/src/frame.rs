@@ -7,8 +7,8 @@ use crate::coding::{self, BufExt, BufMutExt, UnexpectedEnd};
 use crate::packet::EcnCodepoint;
 use crate::range_set::RangeSet;
 use crate::{
-    varint, ConnectionId, Directionality, StreamId, TransportError, TransportErrorCode,
-    MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE,
+    is_v1, max_cid_size, varint, ConnectionId, Directionality, StreamId, TransportError,
+    TransportErrorCode, MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE, VERSION,
 };
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -115,6 +115,7 @@ frame_types! {
     PATH_RESPONSE = 0x1b,
     CONNECTION_CLOSE = 0x1c,
     APPLICATION_CLOSE = 0x1d,
+    HANDSHAKE_DONE = 0x1e,
 }
 
 const STREAM_TY_MIN: u64 = 0x08;
@@ -128,7 +129,7 @@ pub enum Frame {
     ResetStream(ResetStream),
     StopSending {
         id: StreamId,
-        error_code: u16,
+        error_code: u64,
     },
     Crypto(Crypto),
     NewToken {
@@ -163,6 +164,7 @@ pub enum Frame {
     PathResponse(u64),
     ConnectionClose(ConnectionClose),
     ApplicationClose(ApplicationClose),
+    HandshakeDone,
     Invalid {
         ty: Type,
         reason: &'static str,
@@ -216,21 +218,41 @@ impl Frame {
             NewConnectionId { .. } => Type::NEW_CONNECTION_ID,
             Crypto(_) => Type::CRYPTO,
             NewToken { .. } => Type::NEW_TOKEN,
+            HandshakeDone => Type::HANDSHAKE_DONE,
             Invalid { ty, .. } => ty,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ConnectionClose {
     pub error_code: TransportErrorCode,
     pub frame_type: Option<Type>,
     pub reason: Bytes,
+    /// QUIC version under which `error_code` is to be interpreted
+    ///
+    /// Draft 17 assigned some codes differently from version 1. Locally raised errors use
+    /// version 1's codes.
+    pub version: u32,
+}
+
+impl fmt::Debug for ConnectionClose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("ConnectionClose");
+        match self.error_code.name(self.version) {
+            Some(name) => s.field("error_code", &format_args!("{}", name)),
+            None => s.field("error_code", &self.error_code),
+        };
+        s.field("frame_type", &self.frame_type)
+            .field("reason", &self.reason)
+            .field("version", &format_args!("{:x}", self.version))
+            .finish()
+    }
 }
 
 impl fmt::Display for ConnectionClose {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.error_code.fmt(f)?;
+        f.write_str(self.error_code.description(self.version))?;
         if !self.reason.as_ref().is_empty() {
             f.write_str(": ")?;
             f.write_str(&String::from_utf8_lossy(&self.reason))?;
@@ -245,22 +267,24 @@ impl From<TransportError> for ConnectionClose {
             error_code: x.code,
             frame_type: x.frame,
             reason: x.reason.into(),
+            version: VERSION,
         }
     }
 }
 
 impl FrameStruct for ConnectionClose {
-    const SIZE_BOUND: usize = 1 + 2 + 8 + 8;
+    const SIZE_BOUND: usize = 1 + 8 + 8 + 8;
 }
 
 impl ConnectionClose {
-    pub fn encode<W: BufMut>(&self, out: &mut W, max_len: usize) {
+    pub fn encode<W: BufMut>(&self, out: &mut W, version: u32, max_len: usize) {
         out.write(Type::CONNECTION_CLOSE); // 1 byte
-        out.write(self.error_code); // 2 bytes
+        let code_len = write_error_code(out, version, self.error_code.into()); // <= 8 bytes
         let ty = self.frame_type.map_or(0, |x| x.0);
         out.write_var(ty); // <= 8 bytes
         let max_len = max_len
-            - 3
+            - 1
+            - code_len
             - varint::size(ty).unwrap()
             - varint::size(self.reason.len() as u64).unwrap();
         let actual_len = self.reason.len().min(max_len);
@@ -271,7 +295,7 @@ impl ConnectionClose {
 
 #[derive(Debug, Clone)]
 pub struct ApplicationClose {
-    pub error_code: u16,
+    pub error_code: u64,
     pub reason: Bytes,
 }
 
@@ -290,14 +314,14 @@ impl fmt::Display for ApplicationClose {
 }
 
 impl FrameStruct for ApplicationClose {
-    const SIZE_BOUND: usize = 1 + 2 + 8;
+    const SIZE_BOUND: usize = 1 + 8 + 8;
 }
 
 impl ApplicationClose {
-    pub fn encode<W: BufMut>(&self, out: &mut W, max_len: usize) {
+    pub fn encode<W: BufMut>(&self, out: &mut W, version: u32, max_len: usize) {
         out.write(Type::APPLICATION_CLOSE); // 1 byte
-        out.write(self.error_code); // 2 bytes
-        let max_len = max_len as usize - 3 - varint::size(self.reason.len() as u64).unwrap();
+        let code_len = write_error_code(out, version, self.error_code); // <= 8 bytes
+        let max_len = max_len - 1 - code_len - varint::size(self.reason.len() as u64).unwrap();
         let actual_len = self.reason.len().min(max_len);
         out.write_var(actual_len as u64); // <= 8 bytes
         out.put_slice(&self.reason[0..actual_len]); // whatever's left
@@ -447,6 +471,7 @@ pub struct Iter {
     // TODO: ditch io::Cursor after bytes 0.5
     bytes: io::Cursor<Bytes>,
     last_ty: Option<Type>,
+    version: u32,
 }
 
 enum IterErr {
@@ -473,10 +498,19 @@ impl From<UnexpectedEnd> for IterErr {
 }
 
 impl Iter {
-    pub fn new(payload: Bytes) -> Self {
+    pub fn new(payload: Bytes, version: u32) -> Self {
         Iter {
             bytes: io::Cursor::new(payload),
             last_ty: None,
+            version,
+        }
+    }
+
+    fn get_error_code(&mut self) -> Result<u64, UnexpectedEnd> {
+        if is_v1(self.version) {
+            self.bytes.get_var()
+        } else {
+            self.bytes.get::<u16>().map(u64::from)
         }
     }
 
@@ -497,11 +531,11 @@ impl Iter {
             Type::PADDING => Frame::Padding,
             Type::RESET_STREAM => Frame::ResetStream(ResetStream {
                 id: self.bytes.get()?,
-                error_code: self.bytes.get()?,
+                error_code: self.get_error_code()?,
                 final_offset: self.bytes.get_var()?,
             }),
             Type::CONNECTION_CLOSE => Frame::ConnectionClose(ConnectionClose {
-                error_code: self.bytes.get()?,
+                error_code: self.get_error_code()?.into(),
                 frame_type: {
                     let x = self.bytes.get_var()?;
                     if x == 0 {
@@ -511,9 +545,10 @@ impl Iter {
                     }
                 },
                 reason: self.take_len()?,
+                version: self.version,
             }),
             Type::APPLICATION_CLOSE => Frame::ApplicationClose(ApplicationClose {
-                error_code: self.bytes.get()?,
+                error_code: self.get_error_code()?,
                 reason: self.take_len()?,
             }),
             Type::MAX_DATA => Frame::MaxData(self.bytes.get_var()?),
@@ -547,7 +582,7 @@ impl Iter {
             },
             Type::STOP_SENDING => Frame::StopSending {
                 id: self.bytes.get()?,
-                error_code: self.bytes.get()?,
+                error_code: self.get_error_code()?,
             },
             Type::RETIRE_CONNECTION_ID => Frame::RetireConnectionId {
                 sequence: self.bytes.get_var()?,
@@ -579,8 +614,16 @@ impl Iter {
             Type::PATH_RESPONSE => Frame::PathResponse(self.bytes.get()?),
             Type::NEW_CONNECTION_ID => {
                 let sequence = self.bytes.get_var()?;
+                let retire_prior_to = if is_v1(self.version) {
+                    self.bytes.get_var()?
+                } else {
+                    0
+                };
+                if retire_prior_to > sequence {
+                    return Err(IterErr::Malformed);
+                }
                 let length = self.bytes.get::<u8>()? as usize;
-                if length < MIN_CID_SIZE || length > MAX_CID_SIZE {
+                if length < MIN_CID_SIZE || length > max_cid_size(self.version) {
                     return Err(IterErr::Malformed);
                 }
                 if length > self.bytes.remaining() {
@@ -596,6 +639,7 @@ impl Iter {
                 self.bytes.copy_to_slice(&mut reset_token);
                 Frame::NewConnectionId(NewConnectionId {
                     sequence,
+                    retire_prior_to,
                     id,
                     reset_token,
                 })
@@ -607,6 +651,7 @@ impl Iter {
             Type::NEW_TOKEN => Frame::NewToken {
                 token: self.take_len()?,
             },
+            Type::HANDSHAKE_DONE if is_v1(self.version) => Frame::HandshakeDone,
             _ => match ty.stream() {
                 Some(s) => Frame::Stream(Stream {
                     id: self.bytes.get()?,
@@ -693,19 +738,19 @@ impl<'a> Iterator for AckIter<'a> {
 #[derive(Debug, Copy, Clone)]
 pub struct ResetStream {
     pub id: StreamId,
-    pub error_code: u16,
+    pub error_code: u64,
     pub final_offset: u64,
 }
 
 impl FrameStruct for ResetStream {
-    const SIZE_BOUND: usize = 1 + 8 + 2 + 8;
+    const SIZE_BOUND: usize = 1 + 8 + 8 + 8;
 }
 
 impl ResetStream {
-    pub fn encode<W: BufMut>(&self, out: &mut W) {
+    pub fn encode<W: BufMut>(&self, out: &mut W, version: u32) {
         out.write(Type::RESET_STREAM); // 1 byte
         out.write_var(self.id.0); // <= 8 bytes
-        out.write(self.error_code); // 2 bytes
+        write_error_code(out, version, self.error_code); // <= 8 bytes
         out.write_var(self.final_offset); // <= 8 bytes
     }
 }
@@ -713,14 +758,19 @@ impl ResetStream {
 #[derive(Debug, Copy, Clone)]
 pub struct NewConnectionId {
     pub sequence: u64,
+    /// Sequence number below which the peer must retire its connection IDs. Always 0 in drafts.
+    pub retire_prior_to: u64,
     pub id: ConnectionId,
     pub reset_token: [u8; 16],
 }
 
 impl NewConnectionId {
-    pub fn encode<W: BufMut>(&self, out: &mut W) {
+    pub fn encode<W: BufMut>(&self, out: &mut W, version: u32) {
         out.write(Type::NEW_CONNECTION_ID);
         out.write_var(self.sequence);
+        if is_v1(version) {
+            out.write_var(self.retire_prior_to);
+        }
         out.write(self.id.len() as u8);
         out.put_slice(&self.id);
         out.put_slice(&self.reset_token);
@@ -730,9 +780,23 @@ impl NewConnectionId {
 /// Smallest number of bytes this type of frame is guaranteed to fit within.
 pub const RETIRE_CONNECTION_ID_SIZE_BOUND: usize = 9;
 
+/// Encode an error code as a varint for version 1, or as a 16-bit integer for drafts
+///
+/// Returns the number of bytes written.
+pub fn write_error_code<W: BufMut>(out: &mut W, version: u32, code: u64) -> usize {
+    if is_v1(version) {
+        out.write_var(code);
+        varint::size(code).unwrap()
+    } else {
+        out.write::<u16>(code as u16);
+        2
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{DRAFT_VERSION, VERSION};
 
     #[test]
     fn ack_coding() {
@@ -748,7 +812,7 @@ mod test {
             ce: 12,
         };
         Ack::encode(42, &ranges, Some(&ECN), &mut buf);
-        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        let frames = Iter::new(Bytes::from(buf), VERSION).collect::<Vec<_>>();
         assert_eq!(frames.len(), 1);
         match frames[0] {
             Frame::Ack(ref ack) => {
@@ -760,4 +824,77 @@ mod test {
             ref x => panic!("incorrect frame {:?}", x),
         }
     }
+
+    #[test]
+    fn application_close_coding() {
+        for &(version, code) in &[(VERSION, 0x1234_5678), (DRAFT_VERSION, 0x1234)] {
+            let mut buf = Vec::new();
+            ApplicationClose {
+                error_code: code,
+                reason: Bytes::from_static(b"bye"),
+            }
+            .encode(&mut buf, version, 64);
+            let frames = Iter::new(Bytes::from(buf), version).collect::<Vec<_>>();
+            assert_eq!(frames.len(), 1);
+            match frames[0] {
+                Frame::ApplicationClose(ref close) => {
+                    assert_eq!(close.error_code, code);
+                    assert_eq!(&close.reason[..], b"bye");
+                }
+                ref x => panic!("incorrect frame {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn connection_close_names() {
+        // Draft 17 assigned 0x9 a different meaning than version 1
+        let expected = &[
+            (VERSION, "CONNECTION_ID_LIMIT_ERROR"),
+            (DRAFT_VERSION, "VERSION_NEGOTIATION_ERROR"),
+        ];
+        for &(version, name) in expected {
+            let mut buf = Vec::new();
+            ConnectionClose::from(TransportError::CONNECTION_ID_LIMIT_ERROR(""))
+                .encode(&mut buf, version, 64);
+            let frames = Iter::new(Bytes::from(buf), version).collect::<Vec<_>>();
+            assert_eq!(frames.len(), 1);
+            match frames[0] {
+                Frame::ConnectionClose(ref close) => {
+                    assert_eq!(
+                        close.error_code,
+                        TransportErrorCode::CONNECTION_ID_LIMIT_ERROR
+                    );
+                    assert_eq!(close.error_code.name(version), Some(name));
+                    assert_eq!(close.to_string(), close.error_code.description(version));
+                }
+                ref x => panic!("incorrect frame {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn new_connection_id_size() {
+        // Version 1 allows longer connection IDs than draft 17
+        for &(version, valid) in &[(VERSION, true), (DRAFT_VERSION, false)] {
+            let mut buf = Vec::new();
+            NewConnectionId {
+                sequence: 1,
+                retire_prior_to: 0,
+                id: ConnectionId::new(&[0xab; MAX_CID_SIZE]),
+                reset_token: [0; 16],
+            }
+            .encode(&mut buf, version);
+            let frames = Iter::new(Bytes::from(buf), version).collect::<Vec<_>>();
+            assert_eq!(frames.len(), 1);
+            match frames[0] {
+                Frame::NewConnectionId(ref frame) => {
+                    assert!(valid);
+                    assert_eq!(&frame.id[..], &[0xab; MAX_CID_SIZE][..]);
+                }
+                Frame::Invalid { .. } => assert!(!valid),
+                ref x => panic!("incorrect frame {:?}", x),
+            }
+        }
+    }
 }
@@ -11,22 +11,27 @@ extern crate lazy_static;
 extern crate slog;
 
 use std::fmt;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops;
 
 mod coding;
 mod dedup;
 mod range_set;
-#[cfg(test)]
+mod rate_limit;
+pub use crate::rate_limit::{LimitAction, SourceLimits};
+#[cfg(all(test, feature = "tls-rustls"))]
 mod tests;
 mod transport_parameters;
+pub use crate::transport_parameters::TransportParameters;
 mod varint;
 
 mod connection;
 pub use crate::connection::{ConnectionError, TimerSetting, TimerUpdate};
 
-mod crypto;
-pub use crate::crypto::{ClientConfig, ConnectError, TokenKey};
+pub mod crypto;
+#[cfg(feature = "tls-rustls")]
+pub use crate::crypto::rustls::{ClientConfig, TlsSession};
+pub use crate::crypto::{ConnectError, ExportKeyingMaterialError, TokenKey};
 
 mod frame;
 use crate::frame::Frame;
@@ -34,10 +39,12 @@ pub use crate::frame::{ApplicationClose, ConnectionClose};
 
 mod endpoint;
 pub use crate::endpoint::{
-    Config, ConfigError, ConnectionHandle, Endpoint, Event, ServerConfig, Timer,
+    AcceptDecision, AcceptFilter, Config, ConfigError, ConnectionAttempt, ConnectionHandle,
+    Endpoint, Event, LoadBasedRetry, RetryPolicy, ServerConfig, ServerLoad, Timer,
 };
 
 mod packet;
+use crate::packet::DRAFT_MAX_CID_SIZE;
 pub use crate::packet::{ConnectionId, EcnCodepoint};
 
 mod stream;
@@ -46,10 +53,40 @@ pub use crate::stream::{ReadError, WriteError};
 mod transport_error;
 pub use crate::transport_error::{Code as TransportErrorCode, Error as TransportError};
 
-/// The QUIC protocol version implemented
-pub const VERSION: u32 = 0xff00_0011;
+/// QUIC protocol version 1 (RFC 9000), partially supported
+///
+/// Only Quinn endpoints can be reached with this version for now: the TLS backend carries transport
+/// parameters in the draft extension codepoint (0xffa5) rather than RFC 9001's 0x39, and
+/// `ALPN_QUIC_HTTP` still names draft 17. Outgoing connections therefore use `DRAFT_VERSION` unless
+/// `Config::version` says otherwise, and servers accept but don't advertise this version.
+pub const VERSION: u32 = 0x0000_0001;
+
+/// The final draft version supported, and the version used for new outgoing connections by default
+pub const DRAFT_VERSION: u32 = 0xff00_0011;
+
+/// QUIC versions accepted from peers, in descending order of preference
+pub const SUPPORTED_VERSIONS: &[u32] = &[VERSION, DRAFT_VERSION];
+
+/// QUIC versions offered to peers in Version Negotiation packets
+///
+/// Leaves out `VERSION`, which other implementations can't complete a handshake with yet.
+const ADVERTISED_VERSIONS: &[u32] = &[DRAFT_VERSION];
+
+/// Whether `version` uses the wire image of the final RFC rather than draft 17
+fn is_v1(version: u32) -> bool {
+    version != DRAFT_VERSION
+}
 
-/// TLS ALPN value for HTTP over QUIC
+/// Maximum length of a connection ID under `version`
+fn max_cid_size(version: u32) -> usize {
+    if is_v1(version) {
+        MAX_CID_SIZE
+    } else {
+        DRAFT_MAX_CID_SIZE
+    }
+}
+
+/// TLS ALPN value for HTTP over QUIC, as of draft 17
 pub const ALPN_QUIC_HTTP: &[u8] = b"hq-17";
 
 /// Whether an endpoint was the initiator of a connection
@@ -194,13 +231,40 @@ impl coding::Codec for StreamId {
     }
 }
 
-/// An outgoing packet
+/// The local address a datagram was sent to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LocalAddress {
+    pub ip: IpAddr,
+    /// Index of the network interface the datagram arrived on, if known
+    ///
+    /// Needed to reply from addresses, such as IPv6 link-local ones, that may be assigned to more
+    /// than one interface.
+    pub interface: Option<u32>,
+}
+
+/// One or more outgoing datagrams
 #[derive(Debug)]
 pub struct Transmit {
     pub destination: SocketAddr,
-    /// Explicit congestion notification bits to set on the packet
+    /// Explicit congestion notification bits to set on the packets
     pub ecn: Option<EcnCodepoint>,
-    pub packet: Box<[u8]>,
+    /// Contents of the datagrams
+    pub contents: Box<[u8]>,
+    /// The size of each datagram in `contents` but the last, which may be shorter, if there's
+    /// more than one
+    ///
+    /// Suitable for use with UDP generic segmentation offload.
+    pub segment_size: Option<usize>,
+    /// Address to send from, if it should be chosen explicitly
+    ///
+    /// Set by servers to the local address the peer last sent to, so that hosts with several
+    /// addresses reply from the one they were contacted on.
+    pub src: Option<LocalAddress>,
+    /// Index of the socket to send from, as passed to `Endpoint::handle`
+    ///
+    /// `None` if the connection hasn't yet received anything, in which case any socket able to
+    /// reach `destination` may be used.
+    pub socket: Option<usize>,
 }
 
 //
@@ -208,7 +272,7 @@ pub struct Transmit {
 //
 
 const RESET_TOKEN_SIZE: usize = 16;
-const MAX_CID_SIZE: usize = 18;
+const MAX_CID_SIZE: usize = 20;
 const MIN_CID_SIZE: usize = 4;
 const MIN_INITIAL_SIZE: usize = 1200;
 const MIN_MTU: u16 = 1232;
@@ -6,13 +6,13 @@ use rand::Rng;
 use slog;
 
 use crate::coding::{self, BufExt, BufMutExt};
-use crate::crypto::HeaderCrypto;
+use crate::crypto::HeaderKeys;
 use crate::varint;
-use crate::{MAX_CID_SIZE, MIN_CID_SIZE, VERSION};
+use crate::{is_v1, MAX_CID_SIZE, SUPPORTED_VERSIONS, VERSION};
 
 // Due to packet number encryption, it is impossible to fully decode a header
 // (which includes a variable-length packet number) without crypto context.
-// The crypto context (represented by the `Crypto` type in Quinn) is usually
+// The crypto context (represented by the `crypto::Keys` trait in Quinn) is usually
 // part of the `Connection`, or can be derived from the destination CID for
 // Initial packets.
 //
@@ -89,6 +89,11 @@ impl PartialDecode {
         self.plain_header.dst_cid()
     }
 
+    /// QUIC version of a long header packet
+    pub fn version(&self) -> Option<u32> {
+        self.plain_header.version()
+    }
+
     /// Length of data being decoded
     ///
     /// May account for multiple packets.
@@ -96,7 +101,10 @@ impl PartialDecode {
         self.buf.get_ref().len()
     }
 
-    pub fn finish(self, header_crypto: Option<&HeaderCrypto>) -> Result<Packet, PacketDecodeError> {
+    pub fn finish(
+        self,
+        header_crypto: Option<&dyn HeaderKeys>,
+    ) -> Result<Packet, PacketDecodeError> {
         use self::PlainHeader::*;
         let Self {
             plain_header,
@@ -104,6 +112,7 @@ impl PartialDecode {
         } = self;
 
         if let Initial {
+            version,
             dst_cid,
             src_cid,
             token_pos,
@@ -118,6 +127,7 @@ impl PartialDecode {
             let token = header_data.slice(token_pos.start, token_pos.end);
             return Ok(Packet {
                 header: Header::Initial {
+                    version,
                     dst_cid,
                     src_cid,
                     token,
@@ -130,21 +140,25 @@ impl PartialDecode {
 
         let header = match plain_header {
             Long {
+                version,
                 ty,
                 dst_cid,
                 src_cid,
                 ..
             } => Header::Long {
+                version,
                 ty,
                 dst_cid,
                 src_cid,
                 number: Self::decrypt_header(&mut buf, header_crypto.unwrap())?,
             },
             Retry {
+                version,
                 dst_cid,
                 src_cid,
                 orig_dst_cid,
             } => Header::Retry {
+                version,
                 dst_cid,
                 src_cid,
                 orig_dst_cid,
@@ -182,7 +196,7 @@ impl PartialDecode {
 
     fn decrypt_header(
         buf: &mut io::Cursor<BytesMut>,
-        header_crypto: &HeaderCrypto,
+        header_crypto: &dyn HeaderKeys,
     ) -> Result<PacketNumber, PacketDecodeError> {
         let packet_length = buf.get_ref().len();
         let pn_offset = buf.position() as usize;
@@ -208,21 +222,26 @@ pub struct Packet {
 #[derive(Debug, Clone)]
 pub enum Header {
     Initial {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         token: Bytes,
         number: PacketNumber,
     },
     Long {
+        version: u32,
         ty: LongType,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         number: PacketNumber,
     },
     Retry {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
-        orig_dst_cid: ConnectionId,
+        /// Only present on the wire in draft versions; later versions authenticate it through the
+        /// Retry integrity tag instead
+        orig_dst_cid: Option<ConnectionId>,
     },
     Short {
         spin: bool,
@@ -242,53 +261,60 @@ impl Header {
         use self::Header::*;
         match *self {
             Initial {
+                version,
                 ref dst_cid,
                 ref src_cid,
                 ref token,
                 number,
             } => {
                 w.write(u8::from(LongHeaderType::Initial) | number.tag());
-                w.write(VERSION);
-                Self::encode_cids(w, dst_cid, src_cid);
+                w.write(version);
+                let cids_len = Self::encode_cids(w, version, dst_cid, src_cid);
                 w.write_var(token.len() as u64);
                 w.put_slice(token);
                 w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
                 number.encode(w);
-                let pn_pos = 8
-                    + dst_cid.len()
-                    + src_cid.len()
-                    + varint::size(token.len() as u64).unwrap()
-                    + token.len();
+                let pn_pos = 7 + cids_len + varint::size(token.len() as u64).unwrap() + token.len();
                 PartialEncode { pn: Some(pn_pos) }
             }
             Long {
+                version,
                 ty,
                 ref dst_cid,
                 ref src_cid,
                 number,
             } => {
                 w.write(u8::from(LongHeaderType::Standard(ty)) | number.tag());
-                w.write(VERSION);
-                Self::encode_cids(w, dst_cid, src_cid);
+                w.write(version);
+                let cids_len = Self::encode_cids(w, version, dst_cid, src_cid);
                 w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
                 number.encode(w);
-                let pn_pos = 8 + dst_cid.len() + src_cid.len();
+                let pn_pos = 7 + cids_len;
                 PartialEncode { pn: Some(pn_pos) }
             }
             Retry {
+                version,
                 ref dst_cid,
                 ref src_cid,
                 ref orig_dst_cid,
             } => {
-                let odcil = if orig_dst_cid.len() == 0 {
-                    0
+                if is_v1(version) {
+                    w.write(u8::from(LongHeaderType::Retry));
+                    w.write(version);
+                    Self::encode_cids(w, version, dst_cid, src_cid);
+                    // The integrity tag follows the token; see `crypto::retry_tag`
                 } else {
-                    orig_dst_cid.len() as u8 - 3
-                };
-                w.write(u8::from(LongHeaderType::Retry) | odcil);
-                w.write(VERSION);
-                Self::encode_cids(w, dst_cid, src_cid);
-                w.put_slice(orig_dst_cid);
+                    let orig_dst_cid = orig_dst_cid.expect("draft Retry requires original CID");
+                    let odcil = if orig_dst_cid.is_empty() {
+                        0
+                    } else {
+                        orig_dst_cid.len() as u8 - 3
+                    };
+                    w.write(u8::from(LongHeaderType::Retry) | odcil);
+                    w.write(version);
+                    Self::encode_cids(w, version, dst_cid, src_cid);
+                    w.put_slice(&orig_dst_cid);
+                }
                 PartialEncode { pn: None }
             }
             Short {
@@ -316,24 +342,43 @@ impl Header {
             } => {
                 w.write(0x80u8 | random);
                 w.write::<u32>(0);
-                Self::encode_cids(w, dst_cid, src_cid);
+                // Version negotiation always uses the version-independent format
+                Self::encode_cids(w, VERSION, dst_cid, src_cid);
                 PartialEncode { pn: None }
             }
         }
     }
 
-    fn encode_cids<W: BufMut>(w: &mut W, dst_cid: &ConnectionId, src_cid: &ConnectionId) {
-        let mut dcil = dst_cid.len() as u8;
-        if dcil > 0 {
-            dcil -= 3;
-        }
-        let mut scil = src_cid.len() as u8;
-        if scil > 0 {
-            scil -= 3;
+    /// Write the connection ID fields of a long header, returning the number of bytes written
+    fn encode_cids<W: BufMut>(
+        w: &mut W,
+        version: u32,
+        dst_cid: &ConnectionId,
+        src_cid: &ConnectionId,
+    ) -> usize {
+        if is_v1(version) {
+            w.write(dst_cid.len() as u8);
+            w.put_slice(dst_cid);
+            w.write(src_cid.len() as u8);
+            w.put_slice(src_cid);
+            2 + dst_cid.len() + src_cid.len()
+        } else {
+            debug_assert!(
+                dst_cid.len() <= DRAFT_MAX_CID_SIZE && src_cid.len() <= DRAFT_MAX_CID_SIZE
+            );
+            let mut dcil = dst_cid.len() as u8;
+            if dcil > 0 {
+                dcil -= 3;
+            }
+            let mut scil = src_cid.len() as u8;
+            if scil > 0 {
+                scil -= 3;
+            }
+            w.write(dcil << 4 | scil);
+            w.put_slice(dst_cid);
+            w.put_slice(src_cid);
+            1 + dst_cid.len() + src_cid.len()
         }
-        w.write(dcil << 4 | scil);
-        w.put_slice(dst_cid);
-        w.put_slice(src_cid);
     }
 
     pub fn is_retry(&self) -> bool {
@@ -401,7 +446,7 @@ pub struct PartialEncode {
 }
 
 impl PartialEncode {
-    pub fn finish(self, buf: &mut [u8], header_crypto: &HeaderCrypto) {
+    pub fn finish(self, buf: &mut [u8], header_crypto: &dyn HeaderKeys) {
         let PartialEncode { pn, .. } = self;
         let pn_pos = if let Some(pn) = pn {
             pn
@@ -421,21 +466,24 @@ impl PartialEncode {
 
 pub enum PlainHeader {
     Initial {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         token_pos: Range<usize>,
         len: u64,
     },
     Long {
+        version: u32,
         ty: LongType,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         len: u64,
     },
     Retry {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
-        orig_dst_cid: ConnectionId,
+        orig_dst_cid: Option<ConnectionId>,
     },
     Short {
         first: u8,
@@ -461,6 +509,14 @@ impl PlainHeader {
         }
     }
 
+    fn version(&self) -> Option<u32> {
+        use self::PlainHeader::*;
+        match *self {
+            Initial { version, .. } | Long { version, .. } | Retry { version, .. } => Some(version),
+            _ => None,
+        }
+    }
+
     fn payload_len(&self) -> Option<u64> {
         use self::PlainHeader::*;
         match self {
@@ -491,23 +547,39 @@ impl PlainHeader {
             })
         } else {
             let version = buf.get::<u32>()?;
-            let ci_lengths = buf.get::<u8>()?;
-            let mut dcil = (ci_lengths >> 4) as usize;
-            if dcil > 0 {
-                dcil += 3
-            };
-            let mut scil = (ci_lengths & 0xF) as usize;
-            if scil > 0 {
-                scil += 3
+            let (dst_cid, src_cid) = if is_v1(version) {
+                // Also the version-independent format, used for version negotiation
+                let dcil = buf.get::<u8>()? as usize;
+                if dcil > MAX_CID_SIZE || buf.remaining() < dcil {
+                    return Err(PacketDecodeError::InvalidHeader(
+                        "destination connection ID too long",
+                    ));
+                }
+                let dst_cid = Self::get_cid(buf, dcil);
+                let scil = buf.get::<u8>()? as usize;
+                if scil > MAX_CID_SIZE || buf.remaining() < scil {
+                    return Err(PacketDecodeError::InvalidHeader(
+                        "source connection ID too long",
+                    ));
+                }
+                (dst_cid, Self::get_cid(buf, scil))
+            } else {
+                let ci_lengths = buf.get::<u8>()?;
+                let mut dcil = (ci_lengths >> 4) as usize;
+                if dcil > 0 {
+                    dcil += 3
+                };
+                let mut scil = (ci_lengths & 0xF) as usize;
+                if scil > 0 {
+                    scil += 3
+                };
+                if buf.remaining() < dcil + scil {
+                    return Err(PacketDecodeError::InvalidHeader(
+                        "connection IDs longer than packet",
+                    ));
+                }
+                (Self::get_cid(buf, dcil), Self::get_cid(buf, scil))
             };
-            if buf.remaining() < (dcil + scil) as usize {
-                return Err(PacketDecodeError::InvalidHeader(
-                    "connection IDs longer than packet",
-                ));
-            }
-
-            let dst_cid = Self::get_cid(buf, dcil);
-            let src_cid = Self::get_cid(buf, scil);
 
             if version == 0 {
                 let random = first & !LONG_HEADER_FORM;
@@ -518,7 +590,7 @@ impl PlainHeader {
                 });
             }
 
-            if version != VERSION {
+            if !SUPPORTED_VERSIONS.contains(&version) {
                 return Err(PacketDecodeError::UnsupportedVersion {
                     source: src_cid,
                     destination: dst_cid,
@@ -533,6 +605,7 @@ impl PlainHeader {
 
                     let len = buf.get_var()?;
                     Ok(PlainHeader::Initial {
+                        version,
                         dst_cid,
                         src_cid,
                         token_pos: token_start..token_start + token_len,
@@ -540,17 +613,28 @@ impl PlainHeader {
                     })
                 }
                 LongHeaderType::Retry => {
-                    let odcil = first & 0xf;
-                    let odcil = if odcil == 0 { 0 } else { (odcil + 3) as usize };
-                    let orig_dst_cid = Self::get_cid(buf, odcil);
+                    let orig_dst_cid = if is_v1(version) {
+                        None
+                    } else {
+                        let odcil = first & 0xf;
+                        let odcil = if odcil == 0 { 0 } else { (odcil + 3) as usize };
+                        if buf.remaining() < odcil {
+                            return Err(PacketDecodeError::InvalidHeader(
+                                "original destination connection ID longer than packet",
+                            ));
+                        }
+                        Some(Self::get_cid(buf, odcil))
+                    };
 
                     Ok(PlainHeader::Retry {
+                        version,
                         dst_cid,
                         src_cid,
                         orig_dst_cid,
                     })
                 }
                 LongHeaderType::Standard(ty) => Ok(PlainHeader::Long {
+                    version,
                     ty,
                     dst_cid,
                     src_cid,
@@ -764,9 +848,7 @@ pub struct ConnectionId {
 
 impl ConnectionId {
     pub fn new(bytes: &[u8]) -> Self {
-        debug_assert!(
-            bytes.is_empty() || (bytes.len() >= MIN_CID_SIZE && bytes.len() <= MAX_CID_SIZE)
-        );
+        debug_assert!(bytes.len() <= MAX_CID_SIZE);
         let mut res = Self {
             len: bytes.len() as u8,
             bytes: [0; MAX_CID_SIZE],
@@ -836,6 +918,8 @@ pub fn set_payload_length(packet: &mut [u8], header_len: usize, pn_len: usize, t
     );
 }
 
+/// Largest connection ID that can be encoded in a draft long header
+pub const DRAFT_MAX_CID_SIZE: usize = 18;
 pub const LONG_HEADER_FORM: u8 = 0x80;
 const FIXED_BIT: u8 = 0x40;
 pub const SPIN_BIT: u8 = 0x20;
@@ -898,7 +982,8 @@ impl slog::Value for SpaceId {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{crypto::Crypto, Side};
+    use crate::crypto::{ring::Crypto, Keys};
+    use crate::{Side, DRAFT_VERSION};
     use std::io;
 
     fn check_pn(typed: PacketNumber, encoded: &[u8]) {
@@ -937,10 +1022,11 @@ mod tests {
     #[test]
     fn header_encoding() {
         let dcid = ConnectionId::new(&hex!("06b858ec6f80452b"));
-        let client_crypto = Crypto::new_initial(&dcid, Side::Client);
-        let client_header_crypto = client_crypto.header_crypto();
+        let client_crypto = Crypto::new_initial(&dcid, Side::Client, DRAFT_VERSION);
+        let client_header_crypto = client_crypto.header_keys();
         let mut buf = Vec::new();
         let header = Header::Initial {
+            version: DRAFT_VERSION,
             number: PacketNumber::U8(0),
             src_cid: ConnectionId::new(&[]),
             dst_cid: dcid,
@@ -965,8 +1051,8 @@ mod tests {
             )[..]
         );
 
-        let server_crypto = Crypto::new_initial(&dcid, Side::Server);
-        let server_header_crypto = server_crypto.header_crypto();
+        let server_crypto = Crypto::new_initial(&dcid, Side::Server, DRAFT_VERSION);
+        let server_header_crypto = server_crypto.header_keys();
         let decode = PartialDecode::new(buf.clone().into(), 0).unwrap().0;
         let mut packet = decode.finish(Some(&server_header_crypto)).unwrap();
         assert_eq!(
@@ -987,4 +1073,44 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn header_encoding_v1() {
+        let dcid = ConnectionId::new(&hex!("06b858ec6f80452b"));
+        let client_crypto = Crypto::new_initial(&dcid, Side::Client, VERSION);
+        let client_header_crypto = client_crypto.header_keys();
+        let mut buf = Vec::new();
+        let header = Header::Initial {
+            version: VERSION,
+            number: PacketNumber::U8(0),
+            src_cid: ConnectionId::new(&[]),
+            dst_cid: dcid,
+            token: Bytes::new(),
+        };
+        let encode = header.encode(&mut buf);
+        let header_len = buf.len();
+        buf.resize(header_len + 16, 0);
+        set_payload_length(&mut buf, header_len, 1, client_crypto.tag_len());
+        assert_eq!(
+            buf[..],
+            hex!("c000000001 08 06b858ec6f80452b 00 00 4021 00 00000000000000000000000000000000")[..]
+        );
+
+        client_crypto.encrypt(0, &mut buf, header_len);
+        encode.finish(&mut buf, &client_header_crypto);
+
+        let server_crypto = Crypto::new_initial(&dcid, Side::Server, VERSION);
+        let server_header_crypto = server_crypto.header_keys();
+        let decode = PartialDecode::new(buf.clone().into(), 0).unwrap().0;
+        assert_eq!(decode.version(), Some(VERSION));
+        let mut packet = decode.finish(Some(&server_header_crypto)).unwrap();
+        assert_eq!(
+            packet.header_data[..],
+            hex!("c000000001 08 06b858ec6f80452b 00 00 4021 00")[..]
+        );
+        server_crypto
+            .decrypt(0, &packet.header_data, &mut packet.payload)
+            .unwrap();
+        assert_eq!(packet.payload[..], [0; 16]);
+    }
 }
@@ -0,0 +1,359 @@
+//! Per-source limits on incoming connection attempts and outgoing stateless resets
+
+use std::cmp;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use fnv::FnvHashMap;
+
+use crate::endpoint::{ConfigError, ConnectionHandle};
+
+/// Limits on incoming connection attempts from any one network prefix
+///
+/// Sources are grouped by address prefix so that an attacker controlling a whole subnet is
+/// treated as a single source.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SourceLimits {
+    /// Length of the prefix by which IPv4 sources are grouped, at most 32
+    pub ipv4_prefix_len: u8,
+    /// Length of the prefix by which IPv6 sources are grouped, at most 128
+    pub ipv6_prefix_len: u8,
+    /// Time for a source's attempt budget to recover by one attempt (μs)
+    pub attempt_interval: u64,
+    /// Number of attempts a source may make in rapid succession
+    pub attempt_burst: u32,
+    /// Maximum number of connections from one source that may be handshaking at once
+    pub max_handshaking: u32,
+    /// How to respond to a source exceeding these limits
+    pub action: LimitAction,
+}
+
+impl SourceLimits {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.ipv4_prefix_len > 32 || self.ipv6_prefix_len > 128 {
+            return Err(ConfigError::IllegalValue("source prefix length too long"));
+        }
+        if self.attempt_interval == 0 {
+            return Err(ConfigError::IllegalValue(
+                "attempt_interval must be nonzero",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SourceLimits {
+    fn default() -> Self {
+        Self {
+            ipv4_prefix_len: 32,
+            ipv6_prefix_len: 64,
+            attempt_interval: 100 * 1000,
+            attempt_burst: 20,
+            max_handshaking: 8,
+            action: LimitAction::Retry,
+        }
+    }
+}
+
+/// Response to a connection attempt that exceeds its source's limits
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LimitAction {
+    /// Send a stateless retry, admitting the attempt only once the source has proven it can
+    /// receive packets at its address
+    ///
+    /// Defeats floods from spoofed addresses while still admitting legitimate clients.
+    Retry,
+    /// Close the connection immediately with CONNECTION_REFUSED
+    Refuse,
+}
+
+/// Tracks connection attempts by source prefix
+pub(crate) struct SourceLimiter {
+    limits: SourceLimits,
+    sources: FnvHashMap<IpAddr, Source>,
+    /// Prefix of each connection still counted as handshaking
+    handshaking: FnvHashMap<ConnectionHandle, IpAddr>,
+    /// Number of sources at which stale entries will next be pruned
+    prune_threshold: usize,
+}
+
+impl SourceLimiter {
+    pub(crate) fn new(limits: SourceLimits) -> Self {
+        Self {
+            limits,
+            sources: FnvHashMap::default(),
+            handshaking: FnvHashMap::default(),
+            prune_threshold: MIN_PRUNE_THRESHOLD,
+        }
+    }
+
+    pub(crate) fn action(&self) -> LimitAction {
+        self.limits.action
+    }
+
+    /// Charge a connection attempt from `addr` at time `now`, returning whether it's within limits
+    pub(crate) fn check(&mut self, now: u64, addr: IpAddr) -> bool {
+        let prefix = self.prefix(addr);
+        if !self.sources.contains_key(&prefix) && self.sources.len() >= self.prune_threshold {
+            self.prune(now);
+        }
+        let limits = &self.limits;
+        let source = self.sources.entry(prefix).or_insert_with(|| Source {
+            tokens: limits.attempt_burst,
+            refilled: now,
+            handshaking: 0,
+        });
+        source.refill(now, limits);
+        if source.tokens == 0 || source.handshaking >= limits.max_handshaking {
+            return false;
+        }
+        source.tokens -= 1;
+        true
+    }
+
+    /// Whether `addr` may start another handshake, without charging an attempt
+    pub(crate) fn check_handshaking(&self, addr: IpAddr) -> bool {
+        match self.sources.get(&self.prefix(addr)) {
+            Some(source) => source.handshaking < self.limits.max_handshaking,
+            None => true,
+        }
+    }
+
+    /// Count `ch` against the handshake limit of `addr` until `finished` is called
+    pub(crate) fn started(&mut self, now: u64, ch: ConnectionHandle, addr: IpAddr) {
+        let prefix = self.prefix(addr);
+        let limits = &self.limits;
+        self.sources
+            .entry(prefix)
+            .or_insert_with(|| Source {
+                tokens: limits.attempt_burst,
+                refilled: now,
+                handshaking: 0,
+            })
+            .handshaking += 1;
+        self.handshaking.insert(ch, prefix);
+    }
+
+    /// Stop counting `ch` as handshaking, if it was
+    pub(crate) fn finished(&mut self, ch: ConnectionHandle) {
+        if let Some(prefix) = self.handshaking.remove(&ch) {
+            if let Some(source) = self.sources.get_mut(&prefix) {
+                source.handshaking -= 1;
+            }
+        }
+    }
+
+    /// Forget sources that have fully recovered, bounding memory use to active sources
+    fn prune(&mut self, now: u64) {
+        let limits = &self.limits;
+        self.sources.retain(|_, source| {
+            source.refill(now, limits);
+            source.handshaking != 0 || source.tokens < limits.attempt_burst
+        });
+        self.prune_threshold = cmp::max(MIN_PRUNE_THRESHOLD, 2 * self.sources.len());
+    }
+
+    fn prefix(&self, addr: IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V4(x) => mask_v4(x, self.limits.ipv4_prefix_len).into(),
+            IpAddr::V6(x) => {
+                let segments = x.segments();
+                if segments[..5] == [0; 5] && segments[5] == 0xffff {
+                    // IPv4-mapped, as seen on dual-stack sockets
+                    let v4 =
+                        Ipv4Addr::from((u32::from(segments[6]) << 16) | u32::from(segments[7]));
+                    mask_v4(v4, self.limits.ipv4_prefix_len).into()
+                } else {
+                    let bits = u128::from(x) & mask(128, self.limits.ipv6_prefix_len);
+                    Ipv6Addr::from(bits).into()
+                }
+            }
+        }
+    }
+}
+
+struct Source {
+    /// Number of attempts that may currently be made
+    tokens: u32,
+    /// Time up to which tokens have been credited
+    refilled: u64,
+    /// Number of connections from this source that are still handshaking
+    handshaking: u32,
+}
+
+impl Source {
+    fn refill(&mut self, now: u64, limits: &SourceLimits) {
+        let earned = now.saturating_sub(self.refilled) / limits.attempt_interval;
+        if earned >= u64::from(limits.attempt_burst - self.tokens) {
+            self.tokens = limits.attempt_burst;
+            self.refilled = now;
+        } else {
+            self.tokens += earned as u32;
+            self.refilled += earned * limits.attempt_interval;
+        }
+    }
+}
+
+/// Tracks the stateless resets recently sent to each address
+pub(crate) struct ResetLimiter {
+    /// Minimum time between resets sent to one address (μs)
+    interval: u64,
+    /// Time of the most recent reset sent to each address
+    sent: FnvHashMap<IpAddr, u64>,
+    /// Number of addresses at which stale entries will next be pruned
+    prune_threshold: usize,
+}
+
+impl ResetLimiter {
+    pub(crate) fn new(interval: u64) -> Self {
+        Self {
+            interval,
+            sent: FnvHashMap::default(),
+            prune_threshold: MIN_PRUNE_THRESHOLD,
+        }
+    }
+
+    /// Whether a reset may be sent to `addr` at time `now`, recording it if so
+    pub(crate) fn check(&mut self, now: u64, addr: IpAddr) -> bool {
+        match self.sent.get(&addr) {
+            Some(&last) if now < last.saturating_add(self.interval) => return false,
+            Some(_) => {}
+            None if self.sent.len() >= self.prune_threshold => self.prune(now),
+            None => {}
+        }
+        self.sent.insert(addr, now);
+        true
+    }
+
+    /// Forget addresses that may be reset again, bounding memory use to recent resets
+    fn prune(&mut self, now: u64) {
+        let interval = self.interval;
+        self.sent
+            .retain(|_, &mut last| now < last.saturating_add(interval));
+        self.prune_threshold = cmp::max(MIN_PRUNE_THRESHOLD, 2 * self.sent.len());
+    }
+}
+
+fn mask_v4(addr: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(addr) & mask(32, prefix_len) as u32)
+}
+
+/// A mask selecting the leading `prefix_len` bits of a `width`-bit address
+fn mask(width: u32, prefix_len: u8) -> u128 {
+    match u32::from(prefix_len) {
+        0 => 0,
+        n => (!0u128 << (128 - n)) >> (128 - width),
+    }
+}
+
+const MIN_PRUNE_THRESHOLD: usize = 64;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limiter(action: LimitAction) -> SourceLimiter {
+        SourceLimiter::new(SourceLimits {
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 48,
+            attempt_interval: 1000,
+            attempt_burst: 2,
+            max_handshaking: 1,
+            action,
+        })
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut limiter = SourceLimiter::new(SourceLimits {
+            attempt_interval: 1000,
+            attempt_burst: 2,
+            ..SourceLimits::default()
+        });
+        let addr = IpAddr::from([192, 0, 2, 1]);
+        assert!(limiter.check(0, addr));
+        assert!(limiter.check(0, addr));
+        assert!(!limiter.check(999, addr));
+        assert!(limiter.check(1000, addr));
+        assert!(!limiter.check(1999, addr));
+        // Credit doesn't accumulate beyond the burst size
+        assert!(limiter.check(100_000, addr));
+        assert!(limiter.check(100_000, addr));
+        assert!(!limiter.check(100_000, addr));
+    }
+
+    #[test]
+    fn prefixes() {
+        let mut limiter = limiter(LimitAction::Refuse);
+        limiter.limits.max_handshaking = 10;
+        assert!(limiter.check(0, IpAddr::from([192, 0, 2, 1])));
+        assert!(limiter.check(0, IpAddr::from([192, 0, 2, 2])));
+        assert!(!limiter.check(0, IpAddr::from([192, 0, 2, 3])));
+        assert!(!limiter.check(0, Ipv4Addr::new(192, 0, 2, 4).to_ipv6_mapped().into()));
+        assert!(limiter.check(0, IpAddr::from([192, 0, 3, 1])));
+
+        let v6 = |last| IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, last]);
+        assert!(limiter.check(0, v6(1)));
+        assert!(limiter.check(0, v6(2)));
+        assert!(!limiter.check(0, v6(3)));
+        assert!(limiter.check(0, IpAddr::from([0x2001, 0xdb8, 1, 0, 0, 0, 0, 1])));
+    }
+
+    #[test]
+    fn handshaking() {
+        let mut limiter = limiter(LimitAction::Refuse);
+        let addr = IpAddr::from([192, 0, 2, 1]);
+        let ch = ConnectionHandle(0);
+        assert!(limiter.check(0, addr));
+        limiter.started(0, ch, addr);
+        assert!(!limiter.check(10_000, addr));
+        assert!(!limiter.check_handshaking(addr));
+        limiter.finished(ch);
+        limiter.finished(ch);
+        assert!(limiter.check_handshaking(addr));
+        assert!(limiter.check(10_000, addr));
+    }
+
+    #[test]
+    fn prune() {
+        let mut limiter = limiter(LimitAction::Refuse);
+        for i in 0..MIN_PRUNE_THRESHOLD as u32 {
+            assert!(limiter.check(0, Ipv4Addr::from(i << 8).into()));
+        }
+        limiter.started(0, ConnectionHandle(0), IpAddr::from([0, 0, 0, 1]));
+        assert_eq!(limiter.sources.len(), MIN_PRUNE_THRESHOLD);
+        // Every source has recovered, but the one with a handshake in progress must be kept
+        assert!(limiter.check(1000, IpAddr::from([192, 0, 2, 1])));
+        assert_eq!(limiter.sources.len(), 2);
+    }
+
+    #[test]
+    fn resets() {
+        let mut limiter = ResetLimiter::new(1000);
+        let a = IpAddr::from([192, 0, 2, 1]);
+        let b = IpAddr::from([192, 0, 2, 2]);
+        assert!(limiter.check(0, a));
+        assert!(!limiter.check(999, a));
+        assert!(limiter.check(999, b));
+        assert!(limiter.check(1000, a));
+
+        let mut limiter = ResetLimiter::new(1000);
+        for i in 0..MIN_PRUNE_THRESHOLD as u32 {
+            assert!(limiter.check(0, Ipv4Addr::from(i).into()));
+        }
+        assert!(limiter.check(1000, a));
+        assert_eq!(limiter.sent.len(), 1);
+
+        let mut limiter = ResetLimiter::new(u64::max_value());
+        assert!(limiter.check(1000, a));
+        assert!(!limiter.check(u64::max_value() - 1, a));
+    }
+
+    #[test]
+    fn masks() {
+        assert_eq!(mask(32, 0), 0);
+        assert_eq!(mask(32, 24), 0xffff_ff00);
+        assert_eq!(mask(32, 32), 0xffff_ffff);
+        assert_eq!(mask(128, 128), !0);
+        assert_eq!(mask(128, 1), 1 << 127);
+    }
+}
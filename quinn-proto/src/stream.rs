@@ -125,7 +125,7 @@ pub enum WriteError {
     Blocked,
     /// The peer is no longer accepting data on this stream.
     #[error(display = "stopped by peer: error {}", error_code)]
-    Stopped { error_code: u16 },
+    Stopped { error_code: u64 },
 }
 
 #[derive(Debug)]
@@ -246,7 +246,7 @@ impl Recv {
         }
     }
 
-    pub fn reset(&mut self, error_code: u16, final_offset: u64) {
+    pub fn reset(&mut self, error_code: u64, final_offset: u64) {
         if self.is_closed() {
             return;
         }
@@ -270,7 +270,7 @@ pub enum ReadError {
     Blocked,
     /// The peer abandoned transmitting data on this stream.
     #[error(display = "reset by peer: error {}", error_code)]
-    Reset { error_code: u16 },
+    Reset { error_code: u64 },
     /// The data on this stream has been fully delivered and no more will be transmitted.
     #[error(display = "finished")]
     Finished,
@@ -280,9 +280,9 @@ pub enum ReadError {
 pub enum SendState {
     Ready,
     DataSent,
-    ResetSent { stop_reason: Option<u16> },
+    ResetSent { stop_reason: Option<u64> },
     DataRecvd,
-    ResetRecvd { stop_reason: Option<u16> },
+    ResetRecvd { stop_reason: Option<u64> },
 }
 
 impl SendState {
@@ -299,7 +299,7 @@ impl SendState {
 pub enum RecvState {
     Recv { size: Option<u64> },
     DataRecvd { size: u64 },
-    ResetRecvd { size: u64, error_code: u16 },
+    ResetRecvd { size: u64, error_code: u64 },
     Closed,
 }
 
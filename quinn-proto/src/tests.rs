@@ -7,7 +7,7 @@ use std::time::Duration;
 use std::{cmp, env, fmt, mem, str};
 
 use byteorder::{BigEndian, ByteOrder};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use rand::RngCore;
 use ring::digest;
 use ring::hmac::SigningKey;
@@ -17,6 +17,11 @@ use slog::{Drain, Logger, KV};
 use untrusted::Input;
 
 use super::*;
+use crate::crypto::CryptoSession;
+use crate::packet::{PartialDecode, SpaceId};
+
+type Endpoint = crate::Endpoint<TlsSession>;
+type ServerConfig = crate::ServerConfig<TlsSession>;
 
 struct TestDrain;
 
@@ -61,10 +66,10 @@ lazy_static! {
         rcgen::generate_simple_self_signed(vec!["localhost".into()]);
 }
 
-struct Pair {
+struct Pair<S: CryptoSession = TlsSession> {
     log: Logger,
-    server: TestEndpoint,
-    client: TestEndpoint,
+    server: TestEndpoint<S>,
+    client: TestEndpoint<S>,
     time: u64,
     // One-way
     latency: u64,
@@ -83,7 +88,7 @@ fn server_config() -> ServerConfig {
     let key = CERTIFICATE.serialize_private_key_der();
     let cert = CERTIFICATE.serialize_der();
 
-    let mut tls_config = crypto::build_server_config();
+    let mut tls_config = crypto::rustls::build_server_config();
     tls_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
     tls_config
         .set_single_cert(vec![rustls::Certificate(cert)], rustls::PrivateKey(key))
@@ -111,16 +116,21 @@ fn client_config() -> Arc<ClientConfig> {
     Arc::new(tls_client_config)
 }
 
-impl Pair {
-    fn new(server_config: Config, client_config: Config, listen_keys: ServerConfig) -> Self {
+impl<S: CryptoSession> Pair<S> {
+    fn new(
+        server_config: Config,
+        client_config: Config,
+        listen_keys: crate::ServerConfig<S>,
+    ) -> Self {
         let log = logger();
-        let server = Endpoint::new(
+        let server = crate::Endpoint::new(
             log.new(o!("side" => "Server")),
             server_config,
             Some(listen_keys),
         )
         .unwrap();
-        let client = Endpoint::new(log.new(o!("side" => "Client")), client_config, None).unwrap();
+        let client =
+            crate::Endpoint::new(log.new(o!("side" => "Client")), client_config, None).unwrap();
 
         let server_addr = SocketAddr::new(
             Ipv6Addr::LOCALHOST.into(),
@@ -183,18 +193,18 @@ impl Pair {
         trace!(self.log, "client running");
         self.client.drive(&self.log, self.time, self.server.addr);
         for x in self.client.outbound.drain(..) {
-            if x.packet[0] & packet::LONG_HEADER_FORM == 0 {
-                let spin = x.packet[0] & packet::SPIN_BIT != 0;
+            if x.contents[0] & packet::LONG_HEADER_FORM == 0 {
+                let spin = x.contents[0] & packet::SPIN_BIT != 0;
                 self.spins += (spin == self.last_spin) as u64;
                 self.last_spin = spin;
             }
             if let Some(ref socket) = self.client.socket {
-                socket.send_to(&x.packet, x.destination).unwrap();
+                socket.send_to(&x.contents, x.destination).unwrap();
             }
             if self.server.addr == x.destination {
                 self.server
                     .inbound
-                    .push_back((self.time + self.latency, x.ecn, x.packet));
+                    .push_back((self.time + self.latency, x.ecn, x.contents));
             }
         }
     }
@@ -204,21 +214,21 @@ impl Pair {
         self.server.drive(&self.log, self.time, self.client.addr);
         for x in self.server.outbound.drain(..) {
             if let Some(ref socket) = self.server.socket {
-                socket.send_to(&x.packet, x.destination).unwrap();
+                socket.send_to(&x.contents, x.destination).unwrap();
             }
             if self.client.addr == x.destination {
                 self.client
                     .inbound
-                    .push_back((self.time + self.latency, x.ecn, x.packet));
+                    .push_back((self.time + self.latency, x.ecn, x.contents));
             }
         }
     }
 
-    fn connect(&mut self) -> (ConnectionHandle, ConnectionHandle) {
+    fn connect_with(&mut self, config: &S::ClientConfig) -> (ConnectionHandle, ConnectionHandle) {
         info!(self.log, "connecting");
         let client_ch = self
             .client
-            .connect(self.server.addr, &client_config(), "localhost")
+            .connect(self.server.addr, config, "localhost")
             .unwrap();
         self.drive();
         let server_ch = self.server.assert_accept();
@@ -228,9 +238,15 @@ impl Pair {
     }
 }
 
-struct TestEndpoint {
+impl Pair {
+    fn connect(&mut self) -> (ConnectionHandle, ConnectionHandle) {
+        self.connect_with(&client_config())
+    }
+}
+
+struct TestEndpoint<S: CryptoSession = TlsSession> {
     side: Side,
-    endpoint: Endpoint,
+    endpoint: crate::Endpoint<S>,
     addr: SocketAddr,
     socket: Option<UdpSocket>,
     timers: [u64; 5],
@@ -238,10 +254,12 @@ struct TestEndpoint {
     outbound: VecDeque<Transmit>,
     delayed: VecDeque<Transmit>,
     inbound: VecDeque<(u64, Option<EcnCodepoint>, Box<[u8]>)>,
+    /// Datagrams that may be sent in a single `Transmit`
+    max_datagrams: usize,
 }
 
-impl TestEndpoint {
-    fn new(side: Side, endpoint: Endpoint, addr: SocketAddr) -> Self {
+impl<S: CryptoSession> TestEndpoint<S> {
+    fn new(side: Side, endpoint: crate::Endpoint<S>, addr: SocketAddr) -> Self {
         let socket = if env::var_os("SSLKEYLOGFILE").is_some() {
             let socket = UdpSocket::bind(addr).expect("failed to bind UDP socket");
             socket
@@ -261,6 +279,7 @@ impl TestEndpoint {
             outbound: VecDeque::new(),
             delayed: VecDeque::new(),
             inbound: VecDeque::new(),
+            max_datagrams: 1,
         }
     }
 
@@ -289,11 +308,31 @@ impl TestEndpoint {
         }
         while self.inbound.front().map_or(false, |x| x.0 <= now) {
             let (_, ecn, packet) = self.inbound.pop_front().unwrap();
-            self.endpoint
-                .handle(now, remote, ecn, Vec::from(packet).into());
+            self.endpoint.handle(
+                now,
+                remote,
+                Some(LocalAddress {
+                    ip: self.addr.ip(),
+                    interface: None,
+                }),
+                0,
+                ecn,
+                Vec::from(packet).into(),
+            );
         }
-        while let Some(x) = self.endpoint.poll_transmit(now) {
-            self.outbound.push_back(x);
+        while let Some(x) = self.endpoint.poll_transmit(now, self.max_datagrams) {
+            // Split batches into separate datagrams, as the OS would
+            let segment_size = x.segment_size.unwrap_or_else(|| x.contents.len());
+            for contents in x.contents.chunks(segment_size) {
+                self.outbound.push_back(Transmit {
+                    destination: x.destination,
+                    ecn: x.ecn,
+                    contents: contents.into(),
+                    segment_size: None,
+                    src: x.src,
+                    socket: x.socket,
+                });
+            }
         }
         while let Some((ch, x)) = self.endpoint.poll_timers() {
             self.conn = Some(ch);
@@ -350,15 +389,15 @@ impl TestEndpoint {
     }
 }
 
-impl ::std::ops::Deref for TestEndpoint {
-    type Target = Endpoint;
-    fn deref(&self) -> &Endpoint {
+impl<S: CryptoSession> ::std::ops::Deref for TestEndpoint<S> {
+    type Target = crate::Endpoint<S>;
+    fn deref(&self) -> &crate::Endpoint<S> {
         &self.endpoint
     }
 }
 
-impl ::std::ops::DerefMut for TestEndpoint {
-    fn deref_mut(&mut self) -> &mut Endpoint {
+impl<S: CryptoSession> ::std::ops::DerefMut for TestEndpoint<S> {
+    fn deref_mut(&mut self) -> &mut crate::Endpoint<S> {
         &mut self.endpoint
     }
 }
@@ -367,6 +406,10 @@ impl ::std::ops::DerefMut for TestEndpoint {
 fn version_negotiate() {
     let log = logger();
     let client_addr = "[::2]:7890".parse().unwrap();
+    let server_ip = LocalAddress {
+        ip: "::1".parse().unwrap(),
+        interface: Some(2),
+    };
     let mut server = Endpoint::new(
         log.new(o!("peer" => "server")),
         Config::default(),
@@ -376,25 +419,39 @@ fn version_negotiate() {
     server.handle(
         0,
         client_addr,
+        Some(server_ip),
+        0,
         None,
         // Long-header packet with reserved version number
         hex!(
             "80 0a1a2a3a
-                        11 00000000 00000000
+                        04 00000000 04 00000000
                         00"
         )[..]
             .into(),
     );
-    let io = server.poll_transmit(0);
+    let io = server.poll_transmit(0, 1);
     assert!(io.is_some());
-    if let Some(Transmit { packet, .. }) = io {
-        assert_ne!(packet[0] & 0x80, 0);
-        assert_eq!(&packet[1..14], hex!("00000000 11 00000000 00000000"));
-        assert!(packet[14..]
+    if let Some(Transmit {
+        contents,
+        src,
+        socket,
+        ..
+    }) = io
+    {
+        assert_eq!(src, Some(server_ip));
+        assert_eq!(socket, Some(0));
+        assert_ne!(contents[0] & 0x80, 0);
+        assert_eq!(&contents[1..15], hex!("00000000 04 00000000 04 00000000"));
+        let versions = contents[15..]
             .chunks(4)
-            .any(|x| BigEndian::read_u32(x) == VERSION));
+            .map(BigEndian::read_u32)
+            .collect::<Vec<_>>();
+        // Version 1 is accepted but not advertised until it interoperates
+        assert!(!versions.contains(&VERSION));
+        assert!(versions.contains(&DRAFT_VERSION));
     }
-    assert_matches!(server.poll_transmit(0), None);
+    assert_matches!(server.poll_transmit(0, 1), None);
     assert_matches!(server.poll(), None);
 }
 
@@ -431,6 +488,85 @@ fn stateless_retry() {
     pair.connect();
 }
 
+#[test]
+fn v1_stateless_retry() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            version: VERSION,
+            ..Config::default()
+        },
+        ServerConfig {
+            use_stateless_retry: true,
+            ..server_config()
+        },
+    );
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.client.connection(client_ch).version(), VERSION);
+    assert_eq!(pair.server.connection(server_ch).version(), VERSION);
+}
+
+#[test]
+fn draft_stateless_retry() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            version: DRAFT_VERSION,
+            ..Config::default()
+        },
+        ServerConfig {
+            use_stateless_retry: true,
+            ..server_config()
+        },
+    );
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.client.connection(client_ch).version(), DRAFT_VERSION);
+    assert_eq!(pair.server.connection(server_ch).version(), DRAFT_VERSION);
+}
+
+#[test]
+fn draft_stream_reset() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            version: DRAFT_VERSION,
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let (client_ch, server_ch) = pair.connect();
+
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.drive();
+
+    pair.client.reset(client_ch, s, 42);
+    pair.drive();
+
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamOpened)) if conn == server_ch);
+    assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
+    assert_matches!(
+        pair.server.read_unordered(server_ch, s),
+        Err(ReadError::Reset { error_code: 42 })
+    );
+}
+
+#[test]
+fn draft_cid_count() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            version: DRAFT_VERSION,
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let (client_ch, server_ch) = pair.connect();
+    // Draft 17 has no active_connection_id_limit, so the usual eight CIDs are issued
+    assert_eq!(pair.server.connection(server_ch).loc_cids().count(), 8);
+    assert_eq!(pair.client.connection(client_ch).loc_cids().count(), 8);
+}
+
 #[test]
 fn server_stateless_reset() {
     let mut reset_value = [0; 64];
@@ -497,6 +633,303 @@ fn client_stateless_reset() {
     assert_matches!(pair.server.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::Reset })) if conn == server_ch);
 }
 
+#[test]
+fn stateless_reset_server_id() {
+    let mut reset_value = [0; 64];
+    rand::thread_rng().fill_bytes(&mut reset_value);
+
+    let server = Config {
+        reset_key: SigningKey::new(&digest::SHA512_256, &reset_value),
+        server_id: b"server a".to_vec(),
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    let (client_ch, _) = pair.connect();
+    // A different server sharing the reset key must not be able to reset the connection
+    pair.server.endpoint = Endpoint::new(
+        pair.log.new(o!("side" => "Server")),
+        Config {
+            reset_key: SigningKey::new(&digest::SHA512_256, &reset_value),
+            server_id: b"server b".to_vec(),
+            ..Config::default()
+        },
+        Some(server_config()),
+    )
+    .unwrap();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, &[0xab; 128]).unwrap();
+    pair.drive();
+    assert!(!pair.client.connection(client_ch).is_closed());
+}
+
+#[test]
+fn stateless_reset_rate_limit() {
+    let config = Config {
+        min_reset_interval: 1000,
+        ..Config::default()
+    };
+    let mut server = Endpoint::new(logger(), config, Some(server_config())).unwrap();
+    let remote = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4433);
+    let packet = || {
+        let mut packet = vec![0; 100];
+        rand::thread_rng().fill_bytes(&mut packet);
+        packet[0] = 0b0100_0000;
+        packet
+    };
+
+    server.handle(0, remote, None, 0, None, packet()[..].into());
+    assert!(server.poll_transmit(0, 1).is_some());
+    server.handle(999, remote, None, 0, None, packet()[..].into());
+    assert!(server.poll_transmit(999, 1).is_none());
+    // Other peers are limited independently
+    let other = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 4433);
+    server.handle(999, other, None, 0, None, packet()[..].into());
+    assert!(server.poll_transmit(999, 1).is_some());
+    server.handle(1000, remote, None, 0, None, packet()[..].into());
+    assert!(server.poll_transmit(1000, 1).is_some());
+}
+
+#[test]
+fn stateless_reset_failover() {
+    let mut reset_value = [0; 64];
+    rand::thread_rng().fill_bytes(&mut reset_value);
+    let config = || Config {
+        reset_key: SigningKey::new(&digest::SHA512_256, &reset_value),
+        server_id: b"server a".to_vec(),
+        ..Config::default()
+    };
+
+    let mut pair = Pair::new(config(), Config::default(), server_config());
+    let (client_ch, _) = pair.connect();
+    // The server restarts, losing its connection state but keeping its identity
+    pair.server.endpoint = Endpoint::new(
+        pair.log.new(o!("side" => "Server")),
+        config(),
+        Some(server_config()),
+    )
+    .unwrap();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, &[0xab; 128]).unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::Reset })) if conn == client_ch);
+
+    // Having been reset promptly rather than left to time out, the client fails over to a new
+    // connection
+    info!(pair.log, "reconnecting");
+    pair.connect();
+}
+
+#[test]
+fn stateless_reset_adopted_server_ids() {
+    let mut reset_value = [0; 64];
+    rand::thread_rng().fill_bytes(&mut reset_value);
+    let config = |server_id: &[u8], adopted_server_ids: Vec<Vec<u8>>| Config {
+        reset_key: SigningKey::new(&digest::SHA512_256, &reset_value),
+        server_id: server_id.to_vec(),
+        adopted_server_ids,
+        min_reset_interval: 1,
+        ..Config::default()
+    };
+
+    // Connections to two servers in a cluster, which then both fail
+    let mut pair = Pair::new(
+        config(b"server a", vec![]),
+        Config::default(),
+        server_config(),
+    );
+    let a_addr = pair.server.addr;
+    let (a_ch, _) = pair.connect();
+    let b_addr = SocketAddr::new(a_addr.ip(), SERVER_PORTS.lock().unwrap().next().unwrap());
+    pair.server.addr = b_addr;
+    pair.server.endpoint = Endpoint::new(
+        pair.log.new(o!("side" => "Server")),
+        config(b"server b", vec![]),
+        Some(server_config()),
+    )
+    .unwrap();
+    let (b_ch, _) = pair.connect();
+
+    // A third server takes over both their connections, and addresses
+    pair.server.endpoint = Endpoint::new(
+        pair.log.new(o!("side" => "Server")),
+        config(
+            b"server c",
+            vec![b"server a".to_vec(), b"server b".to_vec()],
+        ),
+        Some(server_config()),
+    )
+    .unwrap();
+    for &(ch, addr) in &[(a_ch, a_addr), (b_ch, b_addr)] {
+        pair.server.addr = addr;
+        let s = pair.client.open(ch, Directionality::Uni).unwrap();
+        pair.client.write(ch, s, &[0xab; 128]).unwrap();
+        pair.drive();
+        assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::Reset })) if conn == ch);
+    }
+}
+
+#[test]
+fn reply_from_local_ip() {
+    let mut pair = Pair::default();
+    let (_, server_ch) = pair.connect();
+
+    let s = pair.server.open(server_ch, Directionality::Uni).unwrap();
+    pair.server.write(server_ch, s, b"hello").unwrap();
+    pair.server.drive(&pair.log, pair.time, pair.client.addr);
+    assert!(!pair.server.outbound.is_empty());
+    for x in &pair.server.outbound {
+        assert_eq!(x.src.map(|x| x.ip), Some(pair.server.addr.ip()));
+        assert_eq!(x.socket, Some(0));
+    }
+}
+
+#[test]
+fn client_src_unpinned() {
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    assert!(!pair.client.outbound.is_empty());
+    for x in &pair.client.outbound {
+        assert_eq!(x.src, None);
+    }
+}
+
+#[test]
+fn path_follows_authenticated_datagrams() {
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let elsewhere = LocalAddress {
+        ip: "::2".parse().unwrap(),
+        interface: Some(3),
+    };
+
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    let packet = Vec::from(pair.client.outbound.pop_front().unwrap().contents);
+    pair.client.outbound.clear();
+
+    // A datagram anyone could have sent, arriving elsewhere, mustn't redirect replies
+    let mut forged = packet.clone();
+    *forged.last_mut().unwrap() ^= 0xFF;
+    pair.server.handle(
+        pair.time,
+        pair.client.addr,
+        Some(elsewhere),
+        1,
+        None,
+        forged.into(),
+    );
+    let s = pair.server.open(server_ch, Directionality::Uni).unwrap();
+    pair.server.write(server_ch, s, b"hello").unwrap();
+    pair.server.drive(&pair.log, pair.time, pair.client.addr);
+    assert!(!pair.server.outbound.is_empty());
+    for x in pair.server.outbound.drain(..) {
+        assert_eq!(x.src.map(|x| x.ip), Some(pair.server.addr.ip()));
+        assert_eq!(x.socket, Some(0));
+    }
+
+    // The genuine article does
+    pair.server.handle(
+        pair.time,
+        pair.client.addr,
+        Some(elsewhere),
+        1,
+        None,
+        packet.into(),
+    );
+    pair.server.write(server_ch, s, b"again").unwrap();
+    pair.server.drive(&pair.log, pair.time, pair.client.addr);
+    assert!(!pair.server.outbound.is_empty());
+    for x in &pair.server.outbound {
+        assert_eq!(x.src, Some(elsewhere));
+        assert_eq!(x.socket, Some(1));
+    }
+}
+
+#[test]
+fn sharded_cids() {
+    let server = Config {
+        shards: 4,
+        shard: 2,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    let (client_ch, server_ch) = pair.connect();
+
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    assert!(!pair.client.outbound.is_empty());
+    // Any shard can tell which one owns the connection
+    let other = Config {
+        shards: 4,
+        shard: 0,
+        ..Config::default()
+    };
+    for x in &pair.client.outbound {
+        assert_eq!(other.shard_of(&x.contents), Some(2));
+    }
+    pair.drive();
+    assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
+
+    let unsharded = Config {
+        shards: 4,
+        local_cid_len: 0,
+        ..Config::default()
+    };
+    assert!(Endpoint::new(logger(), unsharded, Some(server_config())).is_err());
+}
+
+/// Handshake packets are addressed to a connection ID of the server's choosing, so they can be
+/// attributed to a shard just like short header packets, whereas Initial packets can't
+#[test]
+fn sharded_handshake() {
+    let server = Config {
+        shards: 4,
+        shard: 2,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    assert!(!pair.client.outbound.is_empty());
+
+    let other = Config {
+        shards: 4,
+        shard: 0,
+        ..Config::default()
+    };
+    let mut handshakes = 0;
+    for x in &pair.client.outbound {
+        let mut rest = Some(BytesMut::from(&x.contents[..]));
+        while let Some(buf) = rest.take() {
+            let bytes = buf.clone();
+            let (decode, next) = PartialDecode::new(buf, other.local_cid_len).unwrap();
+            match decode.space() {
+                Some(SpaceId::Initial) => assert_eq!(other.shard_of(&bytes), None),
+                Some(SpaceId::Handshake) => {
+                    assert_eq!(other.shard_of(&bytes), Some(2));
+                    handshakes += 1;
+                }
+                Some(SpaceId::Data) => assert_eq!(other.shard_of(&bytes), Some(2)),
+                x => panic!("unexpected packet space {:?}", x),
+            }
+            rest = next;
+        }
+    }
+    assert!(handshakes > 0);
+    pair.drive();
+    pair.server.assert_accept();
+}
+
 #[test]
 fn finish_stream() {
     let mut pair = Pair::default();
@@ -533,7 +966,7 @@ fn reset_stream() {
     pair.drive();
 
     info!(pair.log, "resetting stream");
-    const ERROR: u16 = 42;
+    const ERROR: u64 = 42;
     pair.client.reset(client_ch, s, ERROR);
     pair.drive();
 
@@ -558,7 +991,7 @@ fn stop_stream() {
     pair.drive();
 
     info!(pair.log, "stopping stream");
-    const ERROR: u16 = 42;
+    const ERROR: u64 = 42;
     pair.server.stop_sending(server_ch, s, ERROR);
     pair.drive();
 
@@ -591,7 +1024,7 @@ fn reject_self_signed_cert() {
     pair.drive();
     assert_matches!(pair.client.poll(),
                     Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError(error)}))
-                    if conn == client_ch && error.code == TransportErrorCode::crypto(AlertDescription::BadCertificate));
+                    if conn == client_ch && error.code == TransportErrorCode::crypto(AlertDescription::BadCertificate.get_u8()));
 }
 
 #[test]
@@ -666,6 +1099,94 @@ fn zero_rtt() {
     assert_eq!(pair.client.connection(client_ch).lost_packets(), 0);
 }
 
+#[test]
+fn session_details() {
+    let mut pair = Pair::default();
+    pair.latency = 10_000;
+    let config = client_config();
+
+    let (client_ch, server_ch) = pair.connect_with(&config);
+    for conn in &[
+        pair.client.connection(client_ch),
+        pair.server.connection(server_ch),
+    ] {
+        assert_eq!(conn.crypto_session().is_resumed(), Some(false));
+        assert!(conn.crypto_session().cipher_suite().is_some());
+    }
+    // Each side waits one round trip: the client for the server's flight, the server for the
+    // client's Finished
+    assert_eq!(
+        pair.client.connection(client_ch).handshake_duration(),
+        Some(20_000)
+    );
+    assert_eq!(
+        pair.server.connection(server_ch).handshake_duration(),
+        Some(20_000)
+    );
+    pair.client.close(pair.time, client_ch, 0, [][..].into());
+    pair.drive();
+
+    pair.client.addr = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    let (client_ch, server_ch) = pair.connect_with(&config);
+    assert_eq!(
+        pair.client
+            .connection(client_ch)
+            .crypto_session()
+            .is_resumed(),
+        Some(true)
+    );
+    assert_eq!(
+        pair.server
+            .connection(server_ch)
+            .crypto_session()
+            .is_resumed(),
+        Some(true)
+    );
+}
+
+#[test]
+fn export_keying_material() {
+    let mut pair = Pair::default();
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    assert_eq!(
+        pair.client
+            .connection(client_ch)
+            .export_keying_material(b"label", b"", 32),
+        Err(ExportKeyingMaterialError::HandshakeIncomplete)
+    );
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    let client = pair.client.connection(client_ch);
+    let server = pair.server.connection(server_ch);
+
+    let client_out = client.export_keying_material(b"label", b"", 32).unwrap();
+    assert_eq!(client_out.len(), 32);
+    assert_eq!(
+        server.export_keying_material(b"label", b"", 32).unwrap(),
+        client_out
+    );
+    assert_ne!(
+        server.export_keying_material(b"other", b"", 32).unwrap(),
+        client_out
+    );
+    assert_ne!(
+        server
+            .export_keying_material(b"label", b"context", 32)
+            .unwrap(),
+        client_out
+    );
+    assert_eq!(
+        client.export_keying_material(b"label", b"", 255 * 48 + 1),
+        Err(ExportKeyingMaterialError::OutputTooLong)
+    );
+}
+
 #[test]
 fn zero_rtt_rejection() {
     let mut pair = Pair::default();
@@ -716,7 +1237,39 @@ fn close_during_handshake() {
 }
 
 #[test]
-fn stream_id_backpressure() {
+fn short_packet_during_handshake() {
+    let mut pair = Pair::default();
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_ch);
+    info!(pair.log, "holding back the client's handshake flight");
+    pair.client.delay_outbound();
+
+    // Reaches the server before its handshake has completed
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_ch, s, MSG).unwrap();
+    pair.client.finish(client_ch, s);
+    pair.drive_client();
+    pair.drive_server();
+
+    pair.client.finish_delay();
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
+    assert_matches!(
+        pair.server.read_unordered(server_ch, s),
+        Ok((ref data, 0)) if data == MSG
+    );
+}
+
+#[test]
+fn stream_id_backpressure() {
     let server = Config {
         stream_window_uni: 1,
         ..Config::default()
@@ -841,6 +1394,140 @@ fn key_update_reordered() {
     assert_eq!(pair.client.connection(client_ch).lost_packets(), 0);
 }
 
+#[test]
+fn automatic_key_update() {
+    let client_config = Config {
+        max_packets_per_key: 4,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(Default::default(), client_config, server_config());
+    let (client_ch, server_ch) = pair.connect();
+    let s = pair
+        .client
+        .open(client_ch, Directionality::Uni)
+        .expect("couldn't open stream");
+
+    let mut sent = Vec::new();
+    for i in 0..32u8 {
+        let msg = [i; 8];
+        pair.client.write(client_ch, s, &msg).unwrap();
+        sent.extend_from_slice(&msg);
+        pair.drive();
+    }
+    pair.client.finish(client_ch, s);
+    pair.drive();
+
+    let updates = pair.client.connection(client_ch).key_updates();
+    assert!(updates > 1, "only {} key updates", updates);
+    assert_eq!(pair.server.connection(server_ch).key_updates(), updates);
+    assert_eq!(pair.client.connection(client_ch).lost_packets(), 0);
+
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamOpened)) if conn == server_ch);
+    assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
+    let mut buf = vec![0; sent.len()];
+    assert_eq!(pair.server.read(server_ch, s, &mut buf), Ok(sent.len()));
+    assert_eq!(buf, sent);
+}
+
+#[test]
+fn tiny_key_limit() {
+    let config = || Config {
+        max_packets_per_key: 1,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(config(), config(), server_config());
+    let (client_ch, server_ch) = pair.connect();
+    let s = pair
+        .client
+        .open(client_ch, Directionality::Uni)
+        .expect("couldn't open stream");
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.client.finish(client_ch, s);
+    pair.drive();
+
+    assert!(pair.client.connection(client_ch).key_updates() > 0);
+    assert!(pair.server.connection(server_ch).key_updates() > 0);
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamOpened)) if conn == server_ch);
+    assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
+    let mut buf = [0; 5];
+    assert_eq!(pair.server.read(server_ch, s, &mut buf), Ok(5));
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn confidentiality_limit() {
+    for &(version, code) in &[
+        (VERSION, TransportErrorCode::AEAD_LIMIT_REACHED),
+        (DRAFT_VERSION, TransportErrorCode::PROTOCOL_VIOLATION),
+    ] {
+        let client_config = Config {
+            max_packets_per_key: 8,
+            version,
+            ..Config::default()
+        };
+        let mut pair = Pair::new(Default::default(), client_config, server_config());
+        let (client_ch, server_ch) = pair.connect();
+        let s = pair
+            .client
+            .open(client_ch, Directionality::Uni)
+            .expect("couldn't open stream");
+
+        // Nothing reaches the server, so the first key update is never acknowledged and no
+        // further update is permitted
+        for i in 0..32u8 {
+            if pair.client.write(client_ch, s, &[i; 8]).is_err() {
+                break;
+            }
+            pair.drive_client();
+            pair.server.inbound.clear();
+        }
+        assert_eq!(pair.client.connection(client_ch).key_updates(), 1);
+        assert_matches!(pair.client.poll(),
+                        Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError(error)}))
+                        if conn == client_ch && error.code == code);
+
+        // The last packet the keys may protect tells the server why
+        pair.drive();
+        assert_matches!(pair.server.poll(),
+                        Some((conn, Event::ConnectionLost { reason: ConnectionError::ConnectionClosed { reason }}))
+                        if conn == server_ch && reason.error_code == code);
+    }
+}
+
+#[test]
+fn integrity_limit() {
+    let config = Config {
+        max_forged_packets: 2,
+        ..Config::default()
+    };
+    let client_config = Config {
+        version: VERSION,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(config, client_config, server_config());
+    let (client_ch, server_ch) = pair.connect();
+    pair.client.ping(client_ch);
+    pair.drive_client();
+    let mut forged = Vec::from(pair.server.inbound.pop_back().unwrap().2);
+    *forged.last_mut().unwrap() ^= 1;
+
+    for _ in 0..2 {
+        pair.server
+            .inbound
+            .push_back((pair.time, None, forged.clone().into()));
+    }
+    pair.drive();
+    assert_matches!(pair.server.poll(), None);
+
+    pair.server
+        .inbound
+        .push_back((pair.time, None, forged.into()));
+    pair.drive();
+    assert_matches!(pair.server.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError(error)}))
+                    if conn == server_ch && error.code == TransportErrorCode::AEAD_LIMIT_REACHED);
+}
+
 #[test]
 fn initial_retransmit() {
     let mut pair = Pair::default();
@@ -944,16 +1631,415 @@ fn server_busy() {
                     ConnectionError::ConnectionClosed {
                         reason:
                             frame::ConnectionClose {
-                                error_code: TransportErrorCode::SERVER_BUSY,
+                                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                                ..
+                            },
+                    },
+            },
+        ))
+    );
+    assert_matches!(pair.server.poll(), None);
+}
+
+#[test]
+fn reload_server_config() {
+    let limits = SourceLimits {
+        attempt_burst: 2,
+        attempt_interval: 60 * 1_000_000,
+        action: LimitAction::Refuse,
+        ..SourceLimits::default()
+    };
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            source_limits: Some(limits.clone()),
+            ..server_config()
+        },
+    );
+    let (client_ch, server_ch) = pair.connect();
+
+    // Paused servers refuse new connection attempts...
+    pair.server.pause_accepting();
+    assert_refused(&mut pair);
+    assert_eq!(pair.server.connections.len(), 1);
+
+    // ...but established connections carry on
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"still here";
+    pair.client.write(client_ch, s, MSG).unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamOpened)) if conn == server_ch);
+    let (data, _) = pair.server.read_unordered(server_ch, s).unwrap();
+    assert_eq!(&data[..], MSG);
+
+    // A new configuration leaves the server paused, and unchanged limits keep counting the
+    // attempts already made
+    pair.server
+        .set_server_config(ServerConfig {
+            source_limits: Some(limits.clone()),
+            ..server_config()
+        })
+        .unwrap();
+    assert_refused(&mut pair);
+    pair.server.resume_accepting();
+    pair.connect();
+    assert_refused(&mut pair);
+
+    pair.server.set_server_config(server_config()).unwrap();
+    pair.connect();
+}
+
+/// Check that the server refuses a new connection from the client
+fn assert_refused(pair: &mut Pair) {
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.client.poll(),
+        Some((
+            ch,
+            Event::ConnectionLost {
+                reason:
+                    ConnectionError::ConnectionClosed {
+                        reason:
+                            frame::ConnectionClose {
+                                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                                ..
+                            },
+                    },
+            },
+        )) if ch == client_ch
+    );
+    assert_matches!(pair.server.poll(), None);
+}
+
+#[test]
+fn source_limit_refuse() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            source_limits: Some(SourceLimits {
+                attempt_burst: 1,
+                action: LimitAction::Refuse,
+                ..SourceLimits::default()
+            }),
+            ..server_config()
+        },
+    );
+    pair.connect();
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.client.poll(),
+        Some((
+            ch,
+            Event::ConnectionLost {
+                reason:
+                    ConnectionError::ConnectionClosed {
+                        reason:
+                            frame::ConnectionClose {
+                                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                                ..
+                            },
+                    },
+            },
+        )) if ch == client_ch
+    );
+}
+
+#[test]
+fn source_limit_retry() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            source_limits: Some(SourceLimits {
+                attempt_burst: 1,
+                ..SourceLimits::default()
+            }),
+            ..server_config()
+        },
+    );
+    pair.connect();
+    // The second attempt exceeds the limit, but is admitted after validating its address
+    pair.connect();
+}
+
+#[test]
+fn source_limit_retry_handshaking() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            source_limits: Some(SourceLimits {
+                max_handshaking: 1,
+                ..SourceLimits::default()
+            }),
+            ..server_config()
+        },
+    );
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    // Keep the first handshake outstanding
+    pair.client.inbound.clear();
+
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.client.inbound.len(), 1, "expected a retry");
+    // Validating its address doesn't let the source exceed its handshake limit
+    pair.drive_client();
+    pair.drive_server();
+    pair.drive_client();
+    assert_matches!(
+        pair.client.poll(),
+        Some((
+            ch,
+            Event::ConnectionLost {
+                reason:
+                    ConnectionError::ConnectionClosed {
+                        reason:
+                            frame::ConnectionClose {
+                                error_code: TransportErrorCode::CONNECTION_REFUSED,
                                 ..
                             },
                     },
             },
+        )) if ch == client_ch
+    );
+}
+
+#[test]
+fn accept_filter_refuse() {
+    let filter = |attempt: &ConnectionAttempt<'_>| {
+        if attempt.server_name == Some("localhost")
+            && attempt.alpn_protocols.iter().any(|x| x == ALPN_QUIC_HTTP)
+        {
+            AcceptDecision::Refuse(TransportError::CONNECTION_REFUSED("go away"))
+        } else {
+            AcceptDecision::Accept
+        }
+    };
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            accept_filter: Some(Arc::new(filter)),
+            ..server_config()
+        },
+    );
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.client.poll(),
+        Some((
+            _,
+            Event::ConnectionLost {
+                reason: ConnectionError::ConnectionClosed {
+                    reason: frame::ConnectionClose {
+                        error_code: TransportErrorCode::CONNECTION_REFUSED,
+                        ..
+                    },
+                },
+            },
         ))
     );
     assert_matches!(pair.server.poll(), None);
 }
 
+#[test]
+fn accept_filter_retry() {
+    let attempts = Arc::new(Mutex::new(0));
+    let filter = {
+        let attempts = attempts.clone();
+        move |_: &ConnectionAttempt<'_>| {
+            *attempts.lock().unwrap() += 1;
+            AcceptDecision::Retry
+        }
+    };
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            accept_filter: Some(Arc::new(filter)),
+            ..server_config()
+        },
+    );
+    pair.connect();
+    // Once for the original attempt, and again when the retry token is presented
+    assert_eq!(*attempts.lock().unwrap(), 2);
+}
+
+#[test]
+fn accept_filter_split_hello() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let filter = {
+        let seen = seen.clone();
+        move |attempt: &ConnectionAttempt<'_>| {
+            seen.lock().unwrap().push((
+                attempt.server_name.map(String::from),
+                attempt.alpn_protocols.len(),
+                attempt.complete,
+            ));
+            if attempt.complete {
+                AcceptDecision::Accept
+            } else {
+                AcceptDecision::Refuse(TransportError::CONNECTION_REFUSED("incomplete hello"))
+            }
+        }
+    };
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            accept_filter: Some(Arc::new(filter)),
+            ..server_config()
+        },
+    );
+    // Offer enough protocols that the ClientHello can't fit in the first packet
+    let mut client_config = (*client_config()).clone();
+    let mut protocols = (0..40)
+        .map(|i| format!("{:040}", i).into_bytes())
+        .collect::<Vec<_>>();
+    protocols.push(ALPN_QUIC_HTTP.to_vec());
+    client_config.set_protocols(&protocols);
+    pair.client
+        .connect(pair.server.addr, &Arc::new(client_config), "localhost")
+        .unwrap();
+    pair.drive();
+    // Neither the server name nor the protocols are visible to the filter, which knows it
+    {
+        let seen = seen.lock().unwrap();
+        assert!(!seen.is_empty());
+        assert!(seen.iter().all(|x| *x == (None, 0, false)));
+    }
+    assert_matches!(
+        pair.client.poll(),
+        Some((
+            _,
+            Event::ConnectionLost {
+                reason: ConnectionError::ConnectionClosed {
+                    reason: frame::ConnectionClose {
+                        error_code: TransportErrorCode::CONNECTION_REFUSED,
+                        ..
+                    },
+                },
+            },
+        ))
+    );
+
+    // A ClientHello fitting in one packet is seen in full
+    seen.lock().unwrap().clear();
+    pair.connect();
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0], (Some("localhost".into()), 1, true));
+}
+
+#[test]
+fn load_based_retry() {
+    let decisions = Arc::new(Mutex::new(Vec::new()));
+    let policy = {
+        let decisions = decisions.clone();
+        let inner = LoadBasedRetry { max_unvalidated: 1 };
+        move |remote: &SocketAddr, load: &ServerLoad| {
+            let retry = inner.require_retry(remote, load);
+            decisions.lock().unwrap().push(retry);
+            retry
+        }
+    };
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            retry_policy: Some(Arc::new(policy)),
+            ..server_config()
+        },
+    );
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(*decisions.lock().unwrap(), [false]);
+
+    // A second attempt while the first is incomplete must validate its address
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    assert!(decisions.lock().unwrap()[1]);
+    pair.server.assert_accept();
+    pair.server.assert_accept();
+    while pair.client.poll().is_some() {}
+    while pair.server.poll().is_some() {}
+
+    // Once the handshakes are complete, retries are no longer required
+    decisions.lock().unwrap().clear();
+    pair.connect();
+    assert_eq!(*decisions.lock().unwrap(), [false]);
+}
+
+#[test]
+fn client_auth() {
+    let cert = rustls::Certificate(CERTIFICATE.serialize_der());
+    let key = rustls::PrivateKey(CERTIFICATE.serialize_private_key_der());
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&cert).unwrap();
+    let mut tls_config = rustls::ServerConfig::new(rustls::AllowAnyAuthenticatedClient::new(roots));
+    tls_config.versions = vec![ProtocolVersion::TLSv1_3];
+    tls_config
+        .set_single_cert(vec![cert.clone()], key.clone())
+        .unwrap();
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            tls_config: Arc::new(tls_config),
+            ..server_config()
+        },
+    );
+
+    // Clients without a certificate are rejected
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    // The client may consider itself connected before the server has checked its credentials
+    let mut lost = false;
+    while let Some((_, event)) = pair.client.poll() {
+        lost |= matches!(event, Event::ConnectionLost { .. });
+    }
+    assert!(lost);
+    while pair.server.poll().is_some() {}
+
+    let mut client_config = (*client_config()).clone();
+    client_config.set_single_client_cert(vec![cert.clone()], key);
+    let (_, server_ch) = pair.connect_with(&Arc::new(client_config));
+    assert_eq!(
+        pair.server
+            .connection(server_ch)
+            .crypto_session()
+            .get_peer_certificates(),
+        Some(vec![cert])
+    );
+}
+
 #[test]
 fn server_hs_retransmit() {
     let mut pair = Pair::default();
@@ -1046,17 +2132,10 @@ fn test_flow_control(config: Config, window_size: usize) {
     pair.drive();
     let mut cursor = 0;
     loop {
-        match pair.server.read(server_conn, s, &mut buf[cursor..]) {
-            Ok(n) => {
-                cursor += n;
-            }
-            Err(ReadError::Blocked) => {
-                break;
-            }
-            Err(e) => {
-                panic!(e);
-            }
-        }
+        cursor += match pair.server.read(server_conn, s, &mut buf[cursor..]) {
+            Err(ReadError::Blocked) => break,
+            x => x.expect("read failed"),
+        };
     }
     assert_eq!(cursor, window_size);
     pair.drive();
@@ -1064,21 +2143,112 @@ fn test_flow_control(config: Config, window_size: usize) {
     pair.drive();
     let mut cursor = 0;
     loop {
-        match pair.server.read(server_conn, s, &mut buf[cursor..]) {
-            Ok(n) => {
-                cursor += n;
-            }
-            Err(ReadError::Blocked) => {
-                break;
-            }
-            Err(e) => {
-                panic!(e);
-            }
-        }
+        cursor += match pair.server.read(server_conn, s, &mut buf[cursor..]) {
+            Err(ReadError::Blocked) => break,
+            x => x.expect("read failed"),
+        };
     }
     assert_eq!(cursor, window_size);
 }
 
+#[test]
+fn batched_transmit() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let msg = vec![0xAB; 16 * 1024];
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(msg.len()));
+    pair.client.finish(client_conn, s);
+    let transmit = pair.client.poll_transmit(pair.time, 4).unwrap();
+    let segment_size = transmit.segment_size.expect("packets weren't batched");
+    assert!(transmit.contents.len() > 3 * segment_size);
+    for datagram in transmit.contents.chunks(segment_size) {
+        pair.server
+            .inbound
+            .push_back((pair.time, transmit.ecn, datagram.into()));
+    }
+    pair.drive();
+
+    let mut buf = vec![0; msg.len()];
+    let mut cursor = 0;
+    loop {
+        cursor += match pair.server.read(server_conn, s, &mut buf[cursor..]) {
+            Err(ReadError::Finished) => break,
+            x => x.expect("read failed"),
+        };
+    }
+    assert_eq!(buf, msg);
+}
+
+#[test]
+fn batched_transfer() {
+    let mut pair = Pair::default();
+    pair.client.max_datagrams = 10;
+    let (client_conn, server_conn) = pair.connect();
+    let msg = vec![0xAB; 64 * 1024];
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(msg.len()));
+    pair.client.finish(client_conn, s);
+    pair.drive();
+
+    let mut buf = vec![0; msg.len()];
+    let mut cursor = 0;
+    loop {
+        cursor += match pair.server.read(server_conn, s, &mut buf[cursor..]) {
+            Err(ReadError::Finished) => break,
+            x => x.expect("read failed"),
+        };
+    }
+    assert_eq!(buf, msg);
+}
+
+#[test]
+fn batch_tail_unpadded() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let msg = vec![0xAB; 16 * 1024];
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(msg.len()));
+    let transmit = pair.client.poll_transmit(pair.time, 2).unwrap();
+    let segment_size = transmit.segment_size.expect("packets weren't batched");
+    // The second packet ends the batch, so padding it would be wasted
+    assert!(transmit.contents.len() < 2 * segment_size);
+}
+
+#[test]
+fn congestion_blocked_unpadded() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let msg = vec![0xAB; 256 * 1024];
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(msg.len()));
+    let mut transmits = Vec::new();
+    while let Some(x) = pair.client.poll_transmit(pair.time, 100) {
+        transmits.push(x);
+    }
+    let segment_size = transmits[0].segment_size.expect("packets weren't batched");
+    // Nothing can follow the last packet until the congestion window opens
+    let last = transmits.last().unwrap();
+    assert!(last.contents.len() % segment_size != 0);
+}
+
+#[test]
+fn transmit_held_back() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, b"hello"), Ok(5));
+    assert_matches!(
+        pair.client.poll_transmit_where(pair.time, 1, |_, _| false),
+        None
+    );
+    assert_matches!(pair.client.poll_transmit(pair.time, 1), Some(_));
+}
+
 #[test]
 fn stream_flow_control() {
     test_flow_control(
@@ -1106,7 +2276,7 @@ fn stop_opens_bidi() {
     let mut pair = Pair::default();
     let (client_conn, server_conn) = pair.connect();
     let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
-    const ERROR: u16 = 42;
+    const ERROR: u64 = 42;
     pair.client.stop_sending(server_conn, s, ERROR);
     pair.drive();
 
@@ -1135,3 +2305,78 @@ fn implicit_open() {
     assert_eq!(pair.server.accept_stream(server_conn), Some(s2));
     assert_eq!(pair.server.accept_stream(server_conn), None);
 }
+
+#[test]
+#[cfg(feature = "null-crypto")]
+fn null_crypto() {
+    use crate::crypto::null::{NullSession, Plaintext};
+    let server_config = crate::ServerConfig::<NullSession<TlsSession>> {
+        tls_config: Plaintext(server_config().tls_config),
+        ..Default::default()
+    };
+    let mut pair = Pair::new(Default::default(), Default::default(), server_config);
+    let (client_ch, server_ch) = pair.connect_with(&Plaintext(client_config()));
+
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello in the clear";
+    pair.client.write(client_ch, s, MSG).unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    // Stream data is visible on the wire
+    assert!(pair
+        .client
+        .outbound
+        .iter()
+        .any(|x| x.contents.windows(MSG.len()).any(|w| w == MSG)));
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamOpened)) if conn == server_ch);
+    assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
+    assert_matches!(
+        pair.server.read_unordered(server_ch, s),
+        Ok((ref data, 0)) if data == MSG
+    );
+}
+
+#[test]
+#[cfg(feature = "noise")]
+fn noise_handshake() {
+    use crate::crypto::noise;
+    let client_key = noise::Keypair::generate();
+    let server_key = noise::Keypair::generate();
+    for &known in &[false, true] {
+        let server_config = crate::ServerConfig::<noise::NoiseSession> {
+            tls_config: noise::ServerConfig::new(server_key.clone()),
+            ..Default::default()
+        };
+        let mut pair = Pair::new(Default::default(), Default::default(), server_config);
+        let client_config = noise::ClientConfig {
+            remote_public_key: if known {
+                Some(server_key.public_key().to_vec())
+            } else {
+                None
+            },
+            ..noise::ClientConfig::new(client_key.clone())
+        };
+        let (client_ch, server_ch) = pair.connect_with(&client_config);
+        let client_session = pair.client.connection(client_ch).crypto_session();
+        assert_eq!(
+            client_session.remote_public_key(),
+            Some(server_key.public_key())
+        );
+        let server_session = pair.server.connection(server_ch).crypto_session();
+        assert_eq!(
+            server_session.remote_public_key(),
+            Some(client_key.public_key())
+        );
+
+        let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+        const MSG: &[u8] = b"hello";
+        pair.client.write(client_ch, s, MSG).unwrap();
+        pair.drive();
+        assert_matches!(pair.server.poll(), Some((conn, Event::StreamOpened)) if conn == server_ch);
+        assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
+        assert_matches!(
+            pair.server.read_unordered(server_ch, s),
+            Ok((ref data, 0)) if data == MSG
+        );
+    }
+}
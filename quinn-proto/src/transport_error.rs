@@ -1,11 +1,8 @@
-use std::fmt;
+use std::{cmp, fmt};
 
-use bytes::{Buf, BufMut};
 use slog;
 
-use crate::coding::{self, BufExt, BufMutExt};
-use crate::frame;
-use rustls::internal::msgs::{codec::Codec, enums::AlertDescription};
+use crate::{frame, is_v1, VERSION};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct Error {
@@ -23,8 +20,9 @@ impl Error {
         }
     }
 
-    pub fn crypto(alert: AlertDescription) -> Self {
-        Self::new(Code::crypto(alert), None, "")
+    /// Error resulting from a failed cryptographic handshake, identified by a TLS alert code
+    pub fn crypto(code: u8) -> Self {
+        Self::new(Code::crypto(code), None, "")
     }
 }
 
@@ -62,27 +60,95 @@ impl slog::Value for Error {
     }
 }
 
+/// Transport-level error code
+///
+/// Encoded as a variable-length integer in QUIC version 1 and as a 16-bit integer in draft
+/// versions, which can represent every code currently defined. Formatting uses version 1's names
+/// and descriptions; `name` and `description` interpret a code under a specific version.
 #[derive(Copy, Clone, Eq, PartialEq)]
-pub struct Code(u16);
+pub struct Code(u64);
 
 impl Code {
-    pub fn crypto(alert: AlertDescription) -> Self {
-        Code(0x100 | alert.get_u8() as u16)
+    /// Code for a failed cryptographic handshake, identified by a TLS alert code
+    pub fn crypto(code: u8) -> Self {
+        Code(0x100 | u64::from(code))
+    }
+
+    /// Renamed to `CONNECTION_REFUSED` in version 1
+    #[deprecated(note = "renamed to CONNECTION_REFUSED")]
+    pub const SERVER_BUSY: Self = Code::CONNECTION_REFUSED;
+    /// Renamed to `FINAL_SIZE_ERROR` in version 1
+    #[deprecated(note = "renamed to FINAL_SIZE_ERROR")]
+    pub const FINAL_OFFSET_ERROR: Self = Code::FINAL_SIZE_ERROR;
+    /// Draft 17's code 0x9, which version 1 reassigned to `CONNECTION_ID_LIMIT_ERROR`
+    #[deprecated(note = "draft 17 only; version 1 uses 0x9 for CONNECTION_ID_LIMIT_ERROR")]
+    pub const VERSION_NEGOTIATION_ERROR: Self = Code(0x9);
+    /// Draft 17's code 0xC, which version 1 reassigned to `APPLICATION_ERROR`
+    #[deprecated(note = "draft 17 only; version 1 uses 0xC for APPLICATION_ERROR")]
+    pub const INVALID_MIGRATION: Self = Code(0xC);
+
+    /// The name of this code under QUIC `version`, if it defines one
+    pub fn name(self, version: u32) -> Option<&'static str> {
+        self.meaning(version).map(|(name, _)| name)
+    }
+
+    /// A description of this code under QUIC `version`
+    pub fn description(self, version: u32) -> &'static str {
+        match self.meaning(version) {
+            Some((_, desc)) => desc,
+            None if self.is_crypto() => "the cryptographic handshake failed", // FIXME: Describe specific alert
+            None => "unknown error",
+        }
+    }
+
+    fn meaning(self, version: u32) -> Option<(&'static str, &'static str)> {
+        if is_v1(version) {
+            v1_meaning(self.0)
+        } else {
+            DRAFT_CODES
+                .iter()
+                .find(|&&(value, _, _)| value == self.0)
+                .map(|&(_, name, desc)| (name, desc))
+        }
+    }
+
+    fn is_crypto(self) -> bool {
+        (0x100..0x200).contains(&self.0)
     }
 }
 
-impl coding::Codec for Code {
-    fn decode<B: Buf>(buf: &mut B) -> coding::Result<Self> {
-        Ok(Code(buf.get::<u16>()?))
+impl From<u64> for Code {
+    fn from(x: u64) -> Self {
+        Code(x)
     }
-    fn encode<B: BufMut>(&self, buf: &mut B) {
-        buf.write::<u16>(self.0)
+}
+
+impl From<Code> for u64 {
+    fn from(x: Code) -> u64 {
+        x.0
     }
 }
 
+/// Saturates at `u16::max_value()`, which only codes sent by version 1 peers can exceed
 impl From<Code> for u16 {
     fn from(x: Code) -> u16 {
-        x.0
+        cmp::min(x.0, u64::from(u16::max_value())) as u16
+    }
+}
+
+impl fmt::Debug for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name(VERSION) {
+            Some(name) => f.write_str(name),
+            None if self.is_crypto() => write!(f, "Code::crypto({:02x})", self.0 as u8),
+            None => write!(f, "Code({:x})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description(VERSION))
     }
 }
 
@@ -105,27 +171,10 @@ macro_rules! errors {
             $(#[doc = $desc] pub const $name: Self = Code($val);)*
         }
 
-        impl fmt::Debug for Code {
-            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                match self.0 {
-                    $($val => f.write_str(stringify!($name)),)*
-                    x if x >= 0x100 && x < 0x200 => match AlertDescription::read_bytes(&[self.0 as u8]) {
-                        Some(desc) => write!(f, "Code::crypto({:?})", desc),
-                        None => write!(f, "Code::crypto({:02x})", self.0 as u8),
-                    },
-                    _ => write!(f, "Code({:04x})", self.0),
-                }
-            }
-        }
-
-        impl fmt::Display for Code {
-            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                let x = match self.0 {
-                    $($val => $desc,)*
-                    _ if self.0 >= 0x100 && self.0 < 0x200 => "the cryptographic handshake failed", // FIXME: Describe specific alert
-                    _ => "unknown error",
-                };
-                f.write_str(x)
+        fn v1_meaning(value: u64) -> Option<(&'static str, &'static str)> {
+            match value {
+                $($val => Some((stringify!($name), $desc)),)*
+                _ => None,
             }
         }
     }
@@ -145,14 +194,36 @@ impl slog::Value for Code {
 errors! {
     NO_ERROR(0x0) "the connection is being closed abruptly in the absence of any error";
     INTERNAL_ERROR(0x1) "the endpoint encountered an internal error and cannot continue with the connection";
-    SERVER_BUSY(0x2) "the server is currently busy and does not accept any new connections";
+    CONNECTION_REFUSED(0x2) "the server refused to accept a new connection";
     FLOW_CONTROL_ERROR(0x3) "received more data than permitted in advertised data limits";
     STREAM_LIMIT_ERROR(0x4) "received a frame for a stream identifier that exceeded advertised the stream limit for the corresponding stream type";
     STREAM_STATE_ERROR(0x5) "received a frame for a stream that was not in a state that permitted that frame";
-    FINAL_OFFSET_ERROR(0x6) "received a STREAM frame containing data that exceeded the previously established final offset, or a RST_STREAM frame containing a final offset that was lower than the maximum offset of data that was already received, or a RST_STREAM frame containing a different final offset to the one already established";
+    FINAL_SIZE_ERROR(0x6) "received a STREAM frame containing data that exceeded the previously established final size, or a RESET_STREAM frame containing a final size that was lower than the size of data that was already received, or a RESET_STREAM frame containing a different final size to the one already established";
     FRAME_ENCODING_ERROR(0x7) "received a frame that was badly formatted";
     TRANSPORT_PARAMETER_ERROR(0x8) "received transport parameters that were badly formatted, included an invalid value, was absent even though it is mandatory, was present though it is forbidden, or is otherwise in error";
-    VERSION_NEGOTIATION_ERROR(0x9) "received transport parameters that contained version negotiation parameters that disagreed with the version negotiation that was performed, constituting a potential version downgrade attack";
+    CONNECTION_ID_LIMIT_ERROR(0x9) "the number of connection IDs provided by the peer exceeds the advertised active_connection_id_limit";
     PROTOCOL_VIOLATION(0xA) "detected an error with protocol compliance that was not covered by more specific error codes";
-    INVALID_MIGRATION(0xC) "received a PATH_RESPONSE frame that did not correspond to any PATH_CHALLENGE frame that it previously sent";
+    INVALID_TOKEN(0xB) "received an invalid Retry Token in a client Initial";
+    APPLICATION_ERROR(0xC) "the application or application protocol caused the connection to be closed during the handshake";
+    CRYPTO_BUFFER_EXCEEDED(0xD) "received more data in CRYPTO frames than can be buffered";
+    KEY_UPDATE_ERROR(0xE) "key update error";
+    AEAD_LIMIT_REACHED(0xF) "the endpoint has reached the confidentiality or integrity limit for the AEAD algorithm";
+    NO_VIABLE_PATH(0x10) "no viable network path exists";
 }
+
+/// Codes as defined by draft 17, which assigned 0x2, 0x6, 0x9 and 0xC differently and lacked 0xB and
+/// above
+const DRAFT_CODES: &[(u64, &str, &str)] = &[
+    (0x0, "NO_ERROR", "the connection is being closed abruptly in the absence of any error"),
+    (0x1, "INTERNAL_ERROR", "the endpoint encountered an internal error and cannot continue with the connection"),
+    (0x2, "SERVER_BUSY", "the server is currently busy and does not accept any new connections"),
+    (0x3, "FLOW_CONTROL_ERROR", "received more data than permitted in advertised data limits"),
+    (0x4, "STREAM_LIMIT_ERROR", "received a frame for a stream identifier that exceeded advertised the stream limit for the corresponding stream type"),
+    (0x5, "STREAM_STATE_ERROR", "received a frame for a stream that was not in a state that permitted that frame"),
+    (0x6, "FINAL_OFFSET_ERROR", "received a STREAM frame containing data that exceeded the previously established final offset, or a RST_STREAM frame containing a final offset that was lower than the maximum offset of data that was already received, or a RST_STREAM frame containing a different final offset to the one already established"),
+    (0x7, "FRAME_ENCODING_ERROR", "received a frame that was badly formatted"),
+    (0x8, "TRANSPORT_PARAMETER_ERROR", "received transport parameters that were badly formatted, included an invalid value, was absent even though it is mandatory, was present though it is forbidden, or is otherwise in error"),
+    (0x9, "VERSION_NEGOTIATION_ERROR", "received transport parameters that contained version negotiation parameters that disagreed with the version negotiation that was performed, constituting a potential version downgrade attack"),
+    (0xA, "PROTOCOL_VIOLATION", "detected an error with protocol compliance that was not covered by more specific error codes"),
+    (0xC, "INVALID_MIGRATION", "received a PATH_RESPONSE frame that did not correspond to any PATH_CHALLENGE frame that it previously sent"),
+];
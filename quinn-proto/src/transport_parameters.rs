@@ -6,7 +6,10 @@ use err_derive::Error;
 use crate::coding::{BufExt, BufMutExt, UnexpectedEnd};
 use crate::endpoint::Config;
 use crate::packet::ConnectionId;
-use crate::{varint, Side, TransportError, MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE, VERSION};
+use crate::{
+    is_v1, max_cid_size, varint, Side, TransportError, MAX_CID_SIZE, MIN_CID_SIZE,
+    RESET_TOKEN_SIZE, SUPPORTED_VERSIONS,
+};
 
 // Apply a given macro to a list of all the transport parameters having integer types, along with
 // their codes and default values. Using this helps us avoid error-prone duplication of the
@@ -17,6 +20,7 @@ macro_rules! apply_params {
     ($macro:ident) => {
         $macro! {
             // name (id) = default,
+            // Seconds here, but milliseconds on the wire in version 1
             idle_timeout(0x0001) = 0,
             max_packet_size(0x0003) = 65527,
 
@@ -30,6 +34,8 @@ macro_rules! apply_params {
 
             ack_delay_exponent(0x000a) = 3,
             max_ack_delay(0x000b) = 25,
+
+            active_connection_id_limit(0x000e) = 2,
         }
     };
 }
@@ -42,8 +48,15 @@ macro_rules! make_struct {
 
             pub disable_migration: bool,
 
+            /// Connection ID the sender placed in the source field of its first Initial packet
+            ///
+            /// Only exchanged in version 1.
+            pub initial_src_cid: Option<ConnectionId>,
+
             // Server-only
             pub original_connection_id: Option<ConnectionId>,
+            /// Source connection ID of the server's Retry packet, if any. Only exchanged in version 1.
+            pub retry_src_cid: Option<ConnectionId>,
             pub stateless_reset_token: Option<[u8; RESET_TOKEN_SIZE]>,
             pub preferred_address: Option<PreferredAddress>,
         }
@@ -56,7 +69,10 @@ macro_rules! make_struct {
 
                     disable_migration: false,
 
+                    initial_src_cid: None,
+
                     original_connection_id: None,
+                    retry_src_cid: None,
                     stateless_reset_token: None,
                     preferred_address: None,
                 }
@@ -78,11 +94,15 @@ impl TransportParameters {
             initial_max_stream_data_uni: config.stream_receive_window,
             idle_timeout: config.idle_timeout,
             max_ack_delay: 0, // Unimplemented
+            active_connection_id_limit: LOCAL_CID_LIMIT,
             ..Self::default()
         }
     }
 }
 
+/// Number of connection IDs we're willing to store for the peer
+const LOCAL_CID_LIMIT: u64 = 8;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PreferredAddress {
     address_v4: Option<SocketAddrV4>,
@@ -106,14 +126,15 @@ impl PreferredAddress {
         w.put_slice(&self.stateless_reset_token);
     }
 
-    fn read<R: Buf>(r: &mut R) -> Result<Self, Error> {
+    fn read<R: Buf>(version: u32, r: &mut R) -> Result<Self, Error> {
         let ip_v4 = r.get::<Ipv4Addr>()?;
         let port_v4 = r.get::<u16>()?;
         let ip_v6 = r.get::<Ipv6Addr>()?;
         let port_v6 = r.get::<u16>()?;
         let cid_len = r.get::<u8>()?;
         if r.remaining() < cid_len as usize
-            || (cid_len != 0 && (cid_len < MIN_CID_SIZE as u8 || cid_len > MAX_CID_SIZE as u8))
+            || (cid_len != 0
+                && (cid_len < MIN_CID_SIZE as u8 || cid_len as usize > max_cid_size(version)))
         {
             return Err(Error::Malformed);
         }
@@ -160,7 +181,9 @@ pub enum Error {
 impl From<Error> for TransportError {
     fn from(e: Error) -> Self {
         match e {
-            Error::VersionNegotiation => TransportError::VERSION_NEGOTIATION_ERROR(""),
+            Error::VersionNegotiation => {
+                TransportError::TRANSPORT_PARAMETER_ERROR("version negotiation was tampered with")
+            }
             Error::IllegalValue => TransportError::TRANSPORT_PARAMETER_ERROR("illegal value"),
             Error::Malformed => TransportError::TRANSPORT_PARAMETER_ERROR("malformed"),
         }
@@ -174,25 +197,40 @@ impl From<UnexpectedEnd> for Error {
 }
 
 impl TransportParameters {
-    pub fn write<W: BufMut>(&self, side: Side, w: &mut W) {
-        if side.is_server() {
-            w.write::<u32>(VERSION); // Negotiated version
-            w.write::<u8>(8); // Bytes of supported versions
-            w.write::<u32>(0x0a1a_2a3a); // Reserved version
-            w.write::<u32>(VERSION); // Real supported version
-        } else {
-            w.write::<u32>(VERSION); // Initially requested version
+    pub fn write<W: BufMut>(&self, side: Side, version: u32, w: &mut W) {
+        if !is_v1(version) {
+            if side.is_server() {
+                w.write::<u32>(version); // Negotiated version
+                w.write::<u8>(8); // Bytes of supported versions
+                w.write::<u32>(0x0a1a_2a3a); // Reserved version
+                w.write::<u32>(version); // Real supported version
+            } else {
+                w.write::<u32>(version); // Initially requested version
+            }
         }
 
         let mut buf = Vec::new();
+        let put_header = |buf: &mut Vec<u8>, id: u64, len: usize| {
+            if is_v1(version) {
+                buf.write_var(id);
+                buf.write_var(len as u64);
+            } else {
+                buf.write::<u16>(id as u16);
+                buf.write::<u16>(len as u16);
+            }
+        };
+
+        let mut wire = *self;
+        if is_v1(version) {
+            wire.idle_timeout = self.idle_timeout.saturating_mul(1000);
+        }
 
         macro_rules! write_params {
             {$($name:ident ($code:expr) = $default:expr,)*} => {
                 $(
-                    if self.$name != $default {
-                        buf.write::<u16>($code);
-                        buf.write::<u16>(varint::size(self.$name).expect("value too large") as u16);
-                        buf.write_var(self.$name);
+                    if wire.$name != $default {
+                        put_header(&mut buf, $code, varint::size(wire.$name).expect("value too large"));
+                        buf.write_var(wire.$name);
                     }
                 )*
             }
@@ -200,68 +238,54 @@ impl TransportParameters {
         apply_params!(write_params);
 
         if let Some(ref x) = self.original_connection_id {
-            buf.write::<u16>(0x0000);
-            buf.write::<u16>(x.len() as u16);
+            put_header(&mut buf, 0x0000, x.len());
             buf.put_slice(x);
         }
 
         if let Some(ref x) = self.stateless_reset_token {
-            buf.write::<u16>(0x0002);
-            buf.write::<u16>(16);
+            put_header(&mut buf, 0x0002, 16);
             buf.put_slice(x);
         }
 
         if self.disable_migration {
-            buf.write::<u16>(0x000c);
-            buf.write::<u16>(0);
+            put_header(&mut buf, 0x000c, 0);
         }
 
         if let Some(ref x) = self.preferred_address {
-            buf.write::<u16>(0x000d);
-            buf.write::<u16>(x.wire_size());
+            put_header(&mut buf, 0x000d, x.wire_size() as usize);
             x.write(&mut buf);
         }
 
-        w.write::<u16>(buf.len() as u16);
-        w.put_slice(&buf);
-    }
+        if is_v1(version) {
+            if let Some(ref x) = self.initial_src_cid {
+                put_header(&mut buf, 0x000f, x.len());
+                buf.put_slice(x);
+            }
 
-    pub fn read<R: Buf>(side: Side, r: &mut R) -> Result<Self, Error> {
-        if side.is_server() {
-            if r.remaining() < 26 {
-                return Err(Error::Malformed);
+            if let Some(ref x) = self.retry_src_cid {
+                put_header(&mut buf, 0x0010, x.len());
+                buf.put_slice(x);
             }
-            // We only support one version, so there is no validation to do here.
-            r.get::<u32>().unwrap();
+
+            w.put_slice(&buf);
         } else {
-            if r.remaining() < 31 {
-                return Err(Error::Malformed);
-            }
-            let negotiated = r.get::<u32>().unwrap();
-            if negotiated != VERSION {
-                return Err(Error::VersionNegotiation);
-            }
-            let supported_bytes = r.get::<u8>().unwrap();
-            if supported_bytes < 4 || supported_bytes > 252 || supported_bytes % 4 != 0 {
+            w.write::<u16>(buf.len() as u16);
+            w.put_slice(&buf);
+        }
+    }
+
+    pub fn read<R: Buf>(side: Side, version: u32, r: &mut R) -> Result<Self, Error> {
+        if !is_v1(version) {
+            Self::read_versions(side, version, r)?;
+            let params_len = r.get::<u16>()?;
+            if params_len as usize != r.remaining() {
                 return Err(Error::Malformed);
             }
-            let mut found = false;
-            for _ in 0..(supported_bytes / 4) {
-                found |= r.get::<u32>().unwrap() == negotiated;
-            }
-            if !found {
-                return Err(Error::VersionNegotiation);
-            }
         }
 
         // Initialize to protocol-specified defaults
         let mut params = TransportParameters::default();
 
-        let params_len = r.get::<u16>().unwrap();
-        if params_len as usize != r.remaining() {
-            return Err(Error::Malformed);
-        }
-
         // State to check for duplicate transport parameters.
         macro_rules! param_state {
             {$($name:ident ($code:expr) = $default:expr,)*} => {{
@@ -277,27 +301,28 @@ impl TransportParameters {
         let mut got = apply_params!(param_state);
 
         while r.has_remaining() {
-            if r.remaining() < 4 {
-                return Err(Error::Malformed);
-            }
-            let id = r.get::<u16>().unwrap();
-            let len = r.get::<u16>().unwrap();
+            let (id, len) = if is_v1(version) {
+                (r.get_var()?, r.get_var()?)
+            } else {
+                if r.remaining() < 4 {
+                    return Err(Error::Malformed);
+                }
+                (
+                    u64::from(r.get::<u16>().unwrap()),
+                    u64::from(r.get::<u16>().unwrap()),
+                )
+            };
             if r.remaining() < len as usize {
                 return Err(Error::Malformed);
             }
+            let len = len as usize;
 
             match id {
                 0x0000 => {
-                    if len < MIN_CID_SIZE as u16
-                        || len > MAX_CID_SIZE as u16
-                        || params.original_connection_id.is_some()
-                    {
+                    if params.original_connection_id.is_some() {
                         return Err(Error::Malformed);
                     }
-                    let mut staging = [0; MAX_CID_SIZE];
-                    r.copy_to_slice(&mut staging[0..len as usize]);
-                    params.original_connection_id =
-                        Some(ConnectionId::new(&staging[0..len as usize]));
+                    params.original_connection_id = Some(read_cid(version, r, len)?);
                 }
                 0x0002 => {
                     if len != 16 || params.stateless_reset_token.is_some() {
@@ -318,7 +343,19 @@ impl TransportParameters {
                         return Err(Error::Malformed);
                     }
                     params.preferred_address =
-                        Some(PreferredAddress::read(&mut r.take(len as usize))?);
+                        Some(PreferredAddress::read(version, &mut r.take(len))?);
+                }
+                0x000f if is_v1(version) => {
+                    if params.initial_src_cid.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.initial_src_cid = Some(read_cid(version, r, len)?);
+                }
+                0x0010 if is_v1(version) => {
+                    if params.retry_src_cid.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.retry_src_cid = Some(read_cid(version, r, len)?);
                 }
                 _ => {
                     macro_rules! parse {
@@ -326,10 +363,10 @@ impl TransportParameters {
                             match id {
                                 $($code => {
                                     params.$name = r.get_var()?;
-                                    if len != varint::size(params.$name).unwrap() as u16 || got.$name { return Err(Error::Malformed); }
+                                    if len != varint::size(params.$name).unwrap() || got.$name { return Err(Error::Malformed); }
                                     got.$name = true;
                                 })*
-                                _ => r.advance(len as usize),
+                                _ => r.advance(len),
                             }
                         }
                     }
@@ -338,21 +375,78 @@ impl TransportParameters {
             }
         }
 
+        if is_v1(version) {
+            // Round up so that a short but nonzero timeout is never mistaken for "disabled"
+            params.idle_timeout = params.idle_timeout.saturating_add(999) / 1000;
+        }
+
         if params.ack_delay_exponent > 20
             || params.max_ack_delay >= 1 << 14
+            || params.max_packet_size < 1200
+            || params.active_connection_id_limit < 2
             || (side.is_server()
-                && (params.stateless_reset_token.is_some() || params.preferred_address.is_some()))
+                && (params.stateless_reset_token.is_some()
+                    || params.preferred_address.is_some()
+                    || (is_v1(version)
+                        && (params.original_connection_id.is_some()
+                            || params.retry_src_cid.is_some()))))
         {
             return Err(Error::IllegalValue);
         }
 
         Ok(params)
     }
+
+    /// Validate the version negotiation block that precedes draft transport parameters
+    fn read_versions<R: Buf>(side: Side, version: u32, r: &mut R) -> Result<(), Error> {
+        if side.is_server() {
+            if r.remaining() < 6 {
+                return Err(Error::Malformed);
+            }
+            // The initially requested version was the one in use, so there's no downgrade to
+            // detect.
+            r.get::<u32>().unwrap();
+        } else {
+            if r.remaining() < 11 {
+                return Err(Error::Malformed);
+            }
+            let negotiated = r.get::<u32>().unwrap();
+            if negotiated != version {
+                return Err(Error::VersionNegotiation);
+            }
+            let supported_bytes = r.get::<u8>().unwrap();
+            if !(4..=252).contains(&supported_bytes) || supported_bytes % 4 != 0 {
+                return Err(Error::Malformed);
+            }
+            if r.remaining() < supported_bytes as usize + 2 {
+                return Err(Error::Malformed);
+            }
+            let mut found = false;
+            for _ in 0..(supported_bytes / 4) {
+                let x = r.get::<u32>().unwrap();
+                found |= x == negotiated && SUPPORTED_VERSIONS.contains(&x);
+            }
+            if !found {
+                return Err(Error::VersionNegotiation);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_cid<R: Buf>(version: u32, r: &mut R, len: usize) -> Result<ConnectionId, Error> {
+    if (len != 0 && len < MIN_CID_SIZE) || len > max_cid_size(version) {
+        return Err(Error::Malformed);
+    }
+    let mut staging = [0; MAX_CID_SIZE];
+    r.copy_to_slice(&mut staging[0..len]);
+    Ok(ConnectionId::new(&staging[0..len]))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{DRAFT_VERSION, VERSION};
     use bytes::IntoBuf;
 
     #[test]
@@ -371,10 +465,45 @@ mod test {
             }),
             ..TransportParameters::default()
         };
-        params.write(Side::Server, &mut buf);
+        params.write(Side::Server, DRAFT_VERSION, &mut buf);
         assert_eq!(
-            TransportParameters::read(Side::Client, &mut buf.into_buf()).unwrap(),
+            TransportParameters::read(Side::Client, DRAFT_VERSION, &mut buf.into_buf()).unwrap(),
             params
         );
     }
+
+    #[test]
+    fn coding_v1() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            idle_timeout: 30,
+            initial_max_streams_bidi: 16,
+            active_connection_id_limit: 4,
+            initial_src_cid: Some(ConnectionId::new(&[0x42; 20])),
+            original_connection_id: Some(ConnectionId::new(&[0x17; 8])),
+            retry_src_cid: Some(ConnectionId::new(&[])),
+            ..TransportParameters::default()
+        };
+        params.write(Side::Server, VERSION, &mut buf);
+        // Parameter IDs and lengths are varints with no leading version information
+        assert_eq!(buf[..6], [0x01, 0x04, 0x80, 0x00, 0x75, 0x30]);
+        assert_eq!(
+            TransportParameters::read(Side::Client, VERSION, &mut buf.into_buf()).unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    fn server_only_params_rejected_v1() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            retry_src_cid: Some(ConnectionId::new(&[0x17; 8])),
+            ..TransportParameters::default()
+        };
+        params.write(Side::Server, VERSION, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Server, VERSION, &mut buf.into_buf()),
+            Err(Error::IllegalValue)
+        );
+    }
 }
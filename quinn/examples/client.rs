@@ -11,6 +11,7 @@ use std::time::{Duration, Instant};
 
 use failure::Error;
 use futures::Future;
+use futures_util::TryFutureExt;
 use slog::{Drain, Logger};
 use structopt::StructOpt;
 use tokio::runtime::current_thread::Runtime;
@@ -88,51 +89,51 @@ fn run(log: Logger, options: Opt) -> Result<()> {
 
     let (endpoint, driver, _) = endpoint.bind("[::]:0")?;
     let mut runtime = Runtime::new()?;
-    runtime.spawn(driver.map_err(|e| eprintln!("IO error: {}", e)));
+    runtime.spawn(driver.compat().map_err(|e| eprintln!("IO error: {}", e)));
 
     let request = format!("GET {}\r\n", url.path());
     let start = Instant::now();
+    let connecting = endpoint.connect(
+        &remote,
+        url.host_str().ok_or(format_err!("URL missing host"))?,
+    )?;
     runtime.block_on(
-        endpoint
-            .connect(
-                &remote,
-                url.host_str().ok_or(format_err!("URL missing host"))?,
-            )?
-            .map_err(|e| format_err!("failed to connect: {}", e))
-            .and_then(move |conn| {
-                eprintln!("connected at {:?}", start.elapsed());
-                let conn = conn.connection;
-                let stream = conn.open_bi();
-                stream
-                    .map_err(|e| format_err!("failed to open stream: {}", e))
-                    .and_then(move |stream| {
-                        tokio::io::write_all(stream, request.as_bytes().to_owned())
-                            .map_err(|e| format_err!("failed to send request: {}", e))
-                    })
-                    .and_then(|(stream, _)| {
-                        tokio::io::shutdown(stream)
-                            .map_err(|e| format_err!("failed to shutdown stream: {}", e))
-                    })
-                    .and_then(move |stream| {
-                        let response_start = Instant::now();
-                        eprintln!("request sent at {:?}", response_start - start);
-                        quinn::read_to_end(stream, usize::max_value())
-                            .map_err(|e| format_err!("failed to read response: {}", e))
-                            .map(move |x| (x, response_start))
-                    })
-                    .and_then(move |((_, data), response_start)| {
-                        let duration = response_start.elapsed();
-                        eprintln!(
-                            "response received in {:?} - {} KiB/s",
-                            duration,
-                            data.len() as f32 / (duration_secs(&duration) * 1024.0)
-                        );
-                        io::stdout().write_all(&data).unwrap();
-                        io::stdout().flush().unwrap();
-                        conn.close(0, b"done").map_err(|_| unreachable!())
-                    })
-                    .map(|()| eprintln!("drained"))
-            }),
+        Box::pin(async move {
+            let conn = connecting
+                .await
+                .map_err(|e| format_err!("failed to connect: {}", e))?;
+            eprintln!("connected at {:?}", start.elapsed());
+            let conn = conn.connection;
+            let mut stream = conn
+                .open_bi()
+                .await
+                .map_err(|e| format_err!("failed to open stream: {}", e))?;
+            stream
+                .write_all(request.as_bytes())
+                .await
+                .map_err(|e| format_err!("failed to send request: {}", e))?;
+            stream
+                .finish()
+                .await
+                .map_err(|e| format_err!("failed to shutdown stream: {}", e))?;
+            let response_start = Instant::now();
+            eprintln!("request sent at {:?}", response_start - start);
+            let (_, data) = quinn::read_to_end(stream, usize::max_value())
+                .await
+                .map_err(|e| format_err!("failed to read response: {}", e))?;
+            let duration = response_start.elapsed();
+            eprintln!(
+                "response received in {:?} - {} KiB/s",
+                duration,
+                data.len() as f32 / (duration_secs(&duration) * 1024.0)
+            );
+            io::stdout().write_all(&data).unwrap();
+            io::stdout().flush().unwrap();
+            conn.close(0, b"done").await;
+            eprintln!("drained");
+            Ok::<_, Error>(())
+        })
+        .compat(),
     )?;
 
     Ok(())
@@ -9,7 +9,7 @@ use std::rc::Rc;
 use std::{ascii, fmt, fs, io, str};
 
 use failure::{Error, Fail, ResultExt};
-use futures::{Future, Stream};
+use futures_util::{FutureExt, StreamExt, TryFutureExt};
 use slog::{Drain, Logger};
 use structopt::{self, StructOpt};
 use tokio::runtime::current_thread::Runtime;
@@ -147,11 +147,17 @@ fn run(log: Logger, options: Opt) -> Result<()> {
 
     let (_, driver, incoming) = endpoint.bind(options.listen)?;
     let mut runtime = Runtime::new()?;
-    runtime.spawn(incoming.for_each(move |conn| {
-        handle_connection(&root, &log, conn);
-        Ok(())
-    }));
-    runtime.block_on(driver)?;
+    runtime.spawn(
+        Box::pin(async move {
+            let mut incoming = incoming;
+            while let Some(conn) = incoming.next().await {
+                handle_connection(&root, &log, conn);
+            }
+        })
+        .unit_error()
+        .compat(),
+    );
+    runtime.block_on(driver.compat())?;
 
     Ok(())
 }
@@ -166,17 +172,21 @@ fn handle_connection(root: &PathBuf, log: &Logger, conn: quinn::NewConnection) {
           "remote_id" => %connection.remote_id(),
           "address" => %connection.remote_address(),
           "protocol" => connection.protocol().map_or_else(|| "<none>".into(), |x| String::from_utf8_lossy(&x).into_owned()));
-    let log2 = log.clone();
     let root = root.clone();
 
     // Each stream initiated by the client constitutes a new request.
     tokio_current_thread::spawn(
-        incoming
-            .map_err(move |e| info!(log2, "connection terminated"; "reason" => %e))
-            .for_each(move |stream| {
-                handle_request(&root, &log, stream);
-                Ok(())
-            }),
+        Box::pin(async move {
+            let mut incoming = incoming;
+            while let Some(stream) = incoming.next().await {
+                match stream {
+                    Ok(stream) => handle_request(&root, &log, stream),
+                    Err(e) => info!(log, "connection terminated"; "reason" => %e),
+                }
+            }
+        })
+        .unit_error()
+        .compat(),
     );
 }
 
@@ -187,40 +197,50 @@ fn handle_request(root: &PathBuf, log: &Logger, stream: quinn::NewStream) {
     };
     let root = root.clone();
     let log = log.clone();
-    let log2 = log.clone();
-    let log3 = log.clone();
 
     tokio_current_thread::spawn(
-        quinn::read_to_end(stream, 64 * 1024) // Read the request, which must be at most 64KiB
-            .map_err(|e| format_err!("failed reading request: {}", e))
-            .and_then(move |(stream, req)| {
-                let mut escaped = String::new();
-                for &x in &req[..] {
-                    let part = ascii::escape_default(x).collect::<Vec<_>>();
-                    escaped.push_str(str::from_utf8(&part).unwrap());
-                }
-                info!(log, "got request"; "content" => escaped);
-                // Execute the request
-                let resp = process_get(&root, &req).unwrap_or_else(move |e| {
-                    error!(log, "failed to process request"; "reason" => %e.pretty());
-                    format!("failed to process request: {}\n", e.pretty())
-                        .into_bytes()
-                        .into()
-                });
-                // Write the response
-                tokio::io::write_all(stream, resp)
-                    .map_err(|e| format_err!("failed to send response: {}", e))
-            })
-            // Gracefully terminate the stream
-            .and_then(|(stream, _)| {
-                tokio::io::shutdown(stream)
-                    .map_err(|e| format_err!("failed to shutdown stream: {}", e))
-            })
-            .map(move |_| info!(log3, "request complete"))
-            .map_err(move |e| error!(log2, "request failed"; "reason" => %e.pretty())),
+        Box::pin(async move {
+            match process_request(&root, &log, stream).await {
+                Ok(()) => info!(log, "request complete"),
+                Err(e) => error!(log, "request failed"; "reason" => %e.pretty()),
+            }
+        })
+        .unit_error()
+        .compat(),
     )
 }
 
+async fn process_request(root: &Path, log: &Logger, stream: quinn::BiStream) -> Result<()> {
+    // Read the request, which must be at most 64KiB
+    let (mut stream, req) = quinn::read_to_end(stream, 64 * 1024)
+        .await
+        .map_err(|e| format_err!("failed reading request: {}", e))?;
+    let mut escaped = String::new();
+    for &x in &req[..] {
+        let part = ascii::escape_default(x).collect::<Vec<_>>();
+        escaped.push_str(str::from_utf8(&part).unwrap());
+    }
+    info!(log, "got request"; "content" => escaped);
+    // Execute the request
+    let resp = process_get(root, &req).unwrap_or_else(|e| {
+        error!(log, "failed to process request"; "reason" => %e.pretty());
+        format!("failed to process request: {}\n", e.pretty())
+            .into_bytes()
+            .into()
+    });
+    // Write the response
+    stream
+        .write_all(&resp)
+        .await
+        .map_err(|e| format_err!("failed to send response: {}", e))?;
+    // Gracefully terminate the stream
+    stream
+        .finish()
+        .await
+        .map_err(|e| format_err!("failed to shutdown stream: {}", e))?;
+    Ok(())
+}
+
 fn process_get(root: &Path, x: &[u8]) -> Result<Box<[u8]>> {
     if x.len() < 4 || &x[0..4] != b"GET " {
         bail!("missing GET");
@@ -1,37 +1,42 @@
-use std::borrow::Cow;
-use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::io;
 use std::net::ToSocketAddrs;
-use std::rc::Rc;
 use std::str;
 use std::sync::Arc;
-use std::time::Instant;
 
 use err_derive::Error;
 use fnv::FnvHashMap;
-use futures::stream::futures_unordered::FuturesUnordered;
 use quinn_proto as quinn;
+use quinn_proto::crypto::CryptoSession;
 use rustls::{KeyLogFile, ProtocolVersion, TLSError};
 use slog::Logger;
 
-use quinn_proto::{Config, ServerConfig};
+use quinn_proto::Config;
 
-use crate::tls::{Certificate, CertificateChain, PrivateKey};
-use crate::udp::UdpSocket;
-use crate::{Driver, Endpoint, EndpointInner, Incoming};
+#[cfg(feature = "runtime-tokio")]
+use crate::runtime::TokioRuntime;
+use crate::runtime::{AsyncUdpSocket, Runtime};
+use crate::tls::{
+    Certificate, CertificateChain, CertificateResolver, CertificateVerifier, CustomVerifier,
+    PinningVerifier, PrivateKey,
+};
+#[cfg(feature = "runtime-tokio")]
+use crate::{platform, shard};
+use crate::{
+    Driver, Endpoint, EndpointInner, EndpointRef, EndpointSocket, Incoming, ServerConfig,
+    TlsSession, BATCH_SIZE, RECV_SLOT_SIZE,
+};
 
 /// A helper for constructing an `Endpoint`.
-pub struct EndpointBuilder<'a> {
-    reactor: Option<&'a tokio_reactor::Handle>,
+pub struct EndpointBuilder<S: CryptoSession = TlsSession> {
+    runtime: Option<Arc<dyn Runtime>>,
     logger: Logger,
-    server_config: Option<ServerConfig>,
+    server_config: Option<ServerConfig<S>>,
     config: Config,
-    client_config: ClientConfig,
+    client_config: ClientConfig<S>,
 }
 
-#[allow(missing_docs)]
-impl<'a> EndpointBuilder<'a> {
+impl EndpointBuilder {
     /// Start a builder with a specific initial low-level configuration.
     pub fn new(config: Config) -> Self {
         Self {
@@ -39,12 +44,29 @@ impl<'a> EndpointBuilder<'a> {
             ..Self::default()
         }
     }
+}
+
+#[allow(missing_docs)]
+impl<S: CryptoSession> EndpointBuilder<S> {
+    /// Start a builder for endpoints using the cryptographic protocol of `client_config`
+    ///
+    /// Used to select a handshake other than TLS, e.g. `crypto::noise`, for which there is no
+    /// default client configuration.
+    pub fn with_client_config(config: Config, client_config: ClientConfig<S>) -> Self {
+        Self {
+            runtime: default_runtime(),
+            logger: Logger::root(slog::Discard, o!()),
+            server_config: None,
+            config,
+            client_config,
+        }
+    }
 
     /// Build an endpoint bound to `addr`.
     pub fn bind<T: ToSocketAddrs>(
         self,
         addr: T,
-    ) -> Result<(Endpoint, Driver, Incoming), EndpointError> {
+    ) -> Result<(Endpoint<S>, Driver<S>, Incoming<S>), EndpointError> {
         let socket = std::net::UdpSocket::bind(addr).map_err(EndpointError::Socket)?;
         self.from_socket(socket)
     }
@@ -53,48 +75,168 @@ impl<'a> EndpointBuilder<'a> {
     pub fn from_socket(
         self,
         socket: std::net::UdpSocket,
-    ) -> Result<(Endpoint, Driver, Incoming), EndpointError> {
-        let reactor = if let Some(x) = self.reactor {
-            Cow::Borrowed(x)
-        } else {
-            Cow::Owned(tokio_reactor::Handle::default())
-        };
-        let addr = socket.local_addr().map_err(EndpointError::Socket)?;
-        let socket = UdpSocket::from_std(socket, &reactor).map_err(EndpointError::Socket)?;
-        let (send, recv) = futures::sync::mpsc::channel(4);
-        let rc = Rc::new(RefCell::new(EndpointInner {
+    ) -> Result<(Endpoint<S>, Driver<S>, Incoming<S>), EndpointError> {
+        self.from_sockets(vec![socket])
+    }
+
+    /// Build an endpoint which sends and receives on several pre-configured sockets
+    ///
+    /// Allows a single endpoint to serve separate IPv4 and IPv6 sockets, or several interfaces or
+    /// ports. Each connection is sent on the socket its peer last reached it on. Outgoing
+    /// connections start on the first socket matching the remote address family, or the first
+    /// socket if there is none.
+    pub fn from_sockets(
+        self,
+        sockets: Vec<std::net::UdpSocket>,
+    ) -> Result<(Endpoint<S>, Driver<S>, Incoming<S>), EndpointError> {
+        let runtime = self.runtime.as_ref().ok_or(EndpointError::NoRuntime)?;
+        let sockets = sockets
+            .into_iter()
+            .map(|x| runtime.wrap_udp_socket(x))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(EndpointError::Socket)?;
+        self.from_transports(sockets)
+    }
+
+    /// Build an endpoint around a custom UDP transport
+    ///
+    /// Allows endpoints to communicate over something other than an OS socket, such as an
+    /// in-memory channel, a userspace network stack, or a tunnel through a relay. Timers are still
+    /// supplied by the builder's `Runtime`.
+    pub fn from_transport(
+        self,
+        socket: Box<dyn AsyncUdpSocket>,
+    ) -> Result<(Endpoint<S>, Driver<S>, Incoming<S>), EndpointError> {
+        self.from_transports(vec![socket])
+    }
+
+    /// Build an endpoint around several custom UDP transports
+    ///
+    /// See `from_sockets` and `from_transport`.
+    pub fn from_transports(
+        self,
+        sockets: Vec<Box<dyn AsyncUdpSocket>>,
+    ) -> Result<(Endpoint<S>, Driver<S>, Incoming<S>), EndpointError> {
+        let runtime = self.runtime.ok_or(EndpointError::NoRuntime)?;
+        if sockets.is_empty() {
+            return Err(EndpointError::NoSockets);
+        }
+        let sockets = sockets
+            .into_iter()
+            .map(EndpointSocket::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(EndpointError::Socket)?;
+        let epoch = runtime.now();
+        let rc = EndpointRef::new(EndpointInner {
             log: self.logger.clone(),
-            socket,
+            runtime,
+            sockets,
             inner: quinn::Endpoint::new(self.logger, self.config, self.server_config)?,
-            outgoing: None,
-            epoch: Instant::now(),
+            recv_buf: vec![0; BATCH_SIZE * RECV_SLOT_SIZE].into_boxed_slice(),
+            epoch,
             pending: FnvHashMap::default(),
-            timers: FuturesUnordered::new(),
-            buffered_incoming: VecDeque::new(),
-            incoming: send,
+            timers: FnvHashMap::default(),
+            incoming: VecDeque::new(),
+            incoming_reader: None,
             driver: None,
-            ipv6: addr.is_ipv6(),
-        }));
+            driver_lost: false,
+            poisoned: false,
+        });
         Ok((
             Endpoint {
                 inner: rc.clone(),
                 default_client_config: self.client_config,
             },
-            Driver(rc),
-            recv,
+            Driver(rc.clone()),
+            Incoming(rc),
         ))
     }
 
+    /// Build `shards` endpoints sharing `addr`, so that a server's connections can be spread
+    /// across threads
+    ///
+    /// Each shard is built by the builder `configure` returns for its index, with `Config::shards`
+    /// and `Config::shard` filled in, and gets its own `SO_REUSEPORT` socket. The kernel assigns
+    /// incoming datagrams to a socket by hashing their addresses, so a peer whose address changes,
+    /// e.g. due to NAT rebinding, may reach a different shard; such datagrams are passed on to the
+    /// shard that issued their connection ID. Each `Driver` may then be run on its own thread.
+    ///
+    /// If `addr` has port 0, every shard is bound to the port chosen for the first. Only supported
+    /// on Linux.
+    #[cfg(feature = "runtime-tokio")]
+    pub fn bind_sharded<F>(
+        addr: std::net::SocketAddr,
+        shards: u8,
+        mut configure: F,
+    ) -> Result<Vec<(Endpoint<S>, Driver<S>, Incoming<S>)>, EndpointError>
+    where
+        F: FnMut(u8) -> Self,
+    {
+        let builders = (0..shards)
+            .map(|shard| {
+                let mut builder = configure(shard);
+                builder.config.shards = shards;
+                builder.config.shard = shard;
+                builder
+            })
+            .collect::<Vec<_>>();
+        let local_cid_len = builders
+            .first()
+            .ok_or(EndpointError::NoSockets)?
+            .config
+            .local_cid_len;
+        if builders
+            .iter()
+            .any(|x| x.config.local_cid_len != local_cid_len)
+        {
+            return Err(quinn::ConfigError::IllegalValue("shards must share local_cid_len").into());
+        }
+        let mut addr = addr;
+        let mut sockets = Vec::with_capacity(builders.len());
+        for builder in &builders {
+            let runtime = builder.runtime.as_ref().ok_or(EndpointError::NoRuntime)?;
+            let socket = platform::bind_reuseport(&addr).map_err(EndpointError::Socket)?;
+            addr = socket.local_addr().map_err(EndpointError::Socket)?;
+            sockets.push(
+                runtime
+                    .wrap_udp_socket(socket)
+                    .map_err(EndpointError::Socket)?,
+            );
+        }
+        let routing = Config {
+            shards,
+            local_cid_len,
+            ..Config::default()
+        };
+        shard::connect(sockets, routing)
+            .into_iter()
+            .zip(builders)
+            .map(|(socket, builder)| builder.from_transport(socket))
+            .collect()
+    }
+
     /// Accept incoming connections.
-    pub fn listen(&mut self, config: ServerConfig) -> &mut Self {
+    pub fn listen(&mut self, config: ServerConfig<S>) -> &mut Self {
         self.server_config = Some(config);
         self
     }
 
-    pub fn reactor(&mut self, handle: &'a tokio_reactor::Handle) -> &mut Self {
-        self.reactor = Some(handle);
+    /// Set the runtime supplying the endpoint's timers and socket
+    ///
+    /// Defaults to `TokioRuntime` when the `runtime-tokio` feature is enabled, and must otherwise
+    /// be set before the endpoint is built.
+    pub fn runtime(&mut self, runtime: Arc<dyn Runtime>) -> &mut Self {
+        self.runtime = Some(runtime);
         self
     }
+
+    /// Register the endpoint's socket with a specific tokio reactor
+    #[cfg(feature = "runtime-tokio")]
+    pub fn reactor(&mut self, handle: &tokio_reactor::Handle) -> &mut Self {
+        self.runtime = Some(Arc::new(TokioRuntime::with_reactor(handle.clone())));
+        self
+    }
+
     pub fn logger(&mut self, logger: Logger) -> &mut Self {
         self.logger = logger;
         self
@@ -103,24 +245,28 @@ impl<'a> EndpointBuilder<'a> {
     /// Set the default configuration used for outgoing connections.
     ///
     /// The default can be overriden by using `Endpoint::connect_with`.
-    pub fn default_client_config(&mut self, config: ClientConfig) -> &mut Self {
+    pub fn default_client_config(&mut self, config: ClientConfig<S>) -> &mut Self {
         self.client_config = config;
         self
     }
 }
 
-impl<'a> Default for EndpointBuilder<'a> {
+impl Default for EndpointBuilder {
     fn default() -> Self {
-        Self {
-            reactor: None,
-            logger: Logger::root(slog::Discard, o!()),
-            server_config: None,
-            config: Config::default(),
-            client_config: ClientConfig::default(),
-        }
+        Self::with_client_config(Config::default(), ClientConfig::default())
     }
 }
 
+#[cfg(feature = "runtime-tokio")]
+fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    Some(Arc::new(TokioRuntime::default()))
+}
+
+#[cfg(not(feature = "runtime-tokio"))]
+fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    None
+}
+
 /// Errors that can occur during the construction of an `Endpoint`.
 #[derive(Debug, Error)]
 pub enum EndpointError {
@@ -136,6 +282,12 @@ pub enum EndpointError {
     /// An error in the Quinn transport configuration
     #[error(display = "configuration error: {:?}", _0)]
     Config(quinn::ConfigError),
+    /// No `Runtime` was supplied to drive the endpoint
+    #[error(display = "no runtime configured")]
+    NoRuntime,
+    /// An endpoint was to be built around an empty set of sockets
+    #[error(display = "no sockets supplied")]
+    NoSockets,
 }
 
 impl From<quinn::ConfigError> for EndpointError {
@@ -191,6 +343,55 @@ impl ServerConfigBuilder {
         Ok(self)
     }
 
+    /// Select the certificate presented to each client by the server name it requests
+    ///
+    /// Replaces any certificate set by `set_certificate`. Retain a clone of `resolver` to update
+    /// certificates while the endpoint is running.
+    pub fn set_certificate_resolver(&mut self, resolver: CertificateResolver) -> &mut Self {
+        {
+            let tls_server_config = Arc::get_mut(&mut self.config.tls_config).unwrap();
+            tls_server_config.cert_resolver = Arc::new(resolver);
+        }
+        self
+    }
+
+    /// Construct a builder for a server that requires clients to present a certificate chain
+    /// issued by one of `authorities`
+    ///
+    /// The verified chain is exposed by `Connection::peer_certificates`.
+    pub fn require_client_certificates(authorities: &[Certificate]) -> Result<Self, EndpointError> {
+        Self::with_client_authorities(authorities, true)
+    }
+
+    /// Construct a builder for a server that asks clients for a certificate chain issued by one of
+    /// `authorities`, but accepts clients that don't present one
+    ///
+    /// A chain that is presented must still be valid for the handshake to succeed.
+    pub fn request_client_certificates(authorities: &[Certificate]) -> Result<Self, EndpointError> {
+        Self::with_client_authorities(authorities, false)
+    }
+
+    fn with_client_authorities(
+        authorities: &[Certificate],
+        required: bool,
+    ) -> Result<Self, EndpointError> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in authorities {
+            roots.add(&cert.inner)?;
+        }
+        let verifier = if required {
+            rustls::AllowAnyAuthenticatedClient::new(roots)
+        } else {
+            rustls::AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        };
+        let mut tls_config = rustls::ServerConfig::new(verifier);
+        tls_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+        Ok(Self::new(ServerConfig {
+            tls_config: Arc::new(tls_config),
+            ..ServerConfig::default()
+        }))
+    }
+
     /// Set the application-layer protocols to accept.
     ///
     /// When set, clients which don't declare support for at least one of the supplied protocols will be rejected.
@@ -212,6 +413,26 @@ impl ServerConfigBuilder {
         self.config.use_stateless_retry = enabled;
         self
     }
+
+    /// Decide per connection attempt whether to require a stateless retry
+    ///
+    /// Only consulted while `use_stateless_retry` is disabled.
+    pub fn retry_policy<P: quinn::RetryPolicy + 'static>(&mut self, policy: P) -> &mut Self {
+        self.config.retry_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Limit the rate of connection attempts from any one source address prefix
+    pub fn source_limits(&mut self, limits: quinn::SourceLimits) -> &mut Self {
+        self.config.source_limits = Some(limits);
+        self
+    }
+
+    /// Decide whether to proceed with each connection attempt before committing resources to it
+    pub fn accept_filter<F: quinn::AcceptFilter + 'static>(&mut self, filter: F) -> &mut Self {
+        self.config.accept_filter = Some(Arc::new(filter));
+        self
+    }
 }
 
 impl Default for ServerConfigBuilder {
@@ -225,6 +446,7 @@ impl Default for ServerConfigBuilder {
 /// Helper for creating new outgoing connections.
 pub struct ClientConfigBuilder {
     config: quinn::ClientConfig,
+    pinning: PinningVerifier,
 }
 
 impl ClientConfigBuilder {
@@ -237,14 +459,16 @@ impl ClientConfigBuilder {
         config.ct_logs = Some(&ct_logs::LOGS);
         config.versions = vec![ProtocolVersion::TLSv1_3];
         config.enable_early_data = true;
-        Self { config }
+        Self {
+            config,
+            pinning: PinningVerifier::default(),
+        }
     }
 
     /// Add a trusted certificate authority.
     ///
-    /// For more advanced/less secure certificate verification, construct a [`ClientConfig`]
-    /// manually and use rustls's `dangerous_configuration` feature to override the certificate
-    /// verifier.
+    /// For certificates not issued by a certificate authority, see `pin_public_key`,
+    /// `trust_certificate`, and `set_certificate_verifier`.
     pub fn add_certificate_authority(
         &mut self,
         cert: Certificate,
@@ -260,6 +484,64 @@ impl ClientConfigBuilder {
         Ok(self)
     }
 
+    /// Accept only servers whose end-entity certificate has a public key with the given
+    /// SHA-256 digest, as computed by `Certificate::public_key_sha256`
+    ///
+    /// May be called repeatedly to accept any of several keys, and combined with
+    /// `trust_certificate`. Replaces verification against certificate authorities, so the
+    /// certificate need not be issued by one or name the server.
+    pub fn pin_public_key(&mut self, sha256: [u8; 32]) -> &mut Self {
+        self.pinning.public_keys.push(sha256);
+        self.install_pinning();
+        self
+    }
+
+    /// Accept only servers presenting exactly `cert` as their end-entity certificate, e.g. a
+    /// self-signed certificate exchanged out of band
+    ///
+    /// May be called repeatedly to accept any of several certificates, and combined with
+    /// `pin_public_key`. Replaces verification against certificate authorities.
+    pub fn trust_certificate(&mut self, cert: Certificate) -> &mut Self {
+        self.pinning.certificates.push(cert.inner);
+        self.install_pinning();
+        self
+    }
+
+    fn install_pinning(&mut self) {
+        self.config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(self.pinning.clone()));
+    }
+
+    /// Decide whether to accept each server's certificates with `verifier`
+    ///
+    /// Replaces all other verification of server certificates, including any configured by
+    /// `pin_public_key` or `trust_certificate`.
+    pub fn set_certificate_verifier<V: CertificateVerifier + 'static>(
+        &mut self,
+        verifier: V,
+    ) -> &mut Self {
+        self.pinning = PinningVerifier::default();
+        self.config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(CustomVerifier(Box::new(verifier))));
+        self
+    }
+
+    /// Present `cert_chain` to servers that request client authentication
+    pub fn set_certificate(
+        &mut self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<&mut Self, TLSError> {
+        // rustls panics on unusable keys here, so check first
+        rustls::sign::any_supported_type(&key.inner)
+            .map_err(|()| TLSError::General("invalid private key".into()))?;
+        self.config
+            .set_single_client_cert(cert_chain.certs, key.inner);
+        Ok(self)
+    }
+
     /// Enable NSS-compatible cryptographic key logging to the `SSLKEYLOGFILE` environment variable.
     ///
     /// Useful for debugging encrypted communications with protocol analyzers such as Wireshark.
@@ -296,12 +578,19 @@ impl Default for ClientConfigBuilder {
 }
 
 /// Configuration for outgoing connections
-#[derive(Clone)]
-pub struct ClientConfig {
-    /// TLS configuration to use.
+pub struct ClientConfig<S: CryptoSession = TlsSession> {
+    /// Cryptographic configuration to use.
     ///
-    /// `versions` *must* be `vec![ProtocolVersion::TLSv1_3]`.
-    pub tls_config: Arc<quinn::ClientConfig>,
+    /// When using TLS, `versions` *must* be `vec![ProtocolVersion::TLSv1_3]`.
+    pub tls_config: S::ClientConfig,
+}
+
+impl<S: CryptoSession> Clone for ClientConfig<S> {
+    fn clone(&self) -> Self {
+        Self {
+            tls_config: self.tls_config.clone(),
+        }
+    }
 }
 
 impl Default for ClientConfig {
@@ -0,0 +1,61 @@
+//! Bridges between `std::task` wakers and the futures 0.1 tasks used by the tokio reactor
+
+use std::sync::Arc;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Wake, Waker};
+
+use futures::executor::{self, Notify};
+use futures::task::{self, Task};
+
+/// Run `f` as part of a futures 0.1 task that's notified by waking `cx`
+///
+/// Allows I/O objects from the tokio reactor and timer to be polled by a `std::future::Future`.
+pub fn with_task<R>(cx: &mut Context<'_>, f: impl FnOnce() -> R) -> R {
+    let notify = Arc::new(WakerNotify(cx.waker().clone()));
+    executor::with_notify(&notify, 0, f)
+}
+
+/// Run `f` with a `Context` that notifies the current futures 0.1 task when woken
+///
+/// `f` is first polled with a waker that does nothing, and only polled again with one for the
+/// current task if it's pending, so that operations which complete immediately may be used
+/// outside of a task.
+///
+/// # Panics
+/// - If `f` is pending outside of a futures 0.1 task
+pub fn with_context<R>(mut f: impl FnMut(&mut Context<'_>) -> Poll<R>) -> Poll<R> {
+    let noop = unsafe { Waker::from_raw(noop_raw_waker()) };
+    if let Poll::Ready(x) = f(&mut Context::from_waker(&noop)) {
+        return Poll::Ready(x);
+    }
+    let waker = Waker::from(Arc::new(TaskWaker(task::current())));
+    f(&mut Context::from_waker(&waker))
+}
+
+fn noop_raw_waker() -> RawWaker {
+    fn clone(_: *const ()) -> RawWaker {
+        noop_raw_waker()
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    RawWaker::new(std::ptr::null(), &VTABLE)
+}
+
+struct WakerNotify(Waker);
+
+impl Notify for WakerNotify {
+    fn notify(&self, _: usize) {
+        self.0.wake_by_ref();
+    }
+}
+
+struct TaskWaker(Task);
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.notify();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.notify();
+    }
+}
@@ -6,20 +6,27 @@
 //!
 //! The entry point of this crate is the [`Endpoint`](struct.Endpoint.html).
 //!
-//! The futures and streams defined in this crate are not `Send` because they necessarily share
-//! state with each other. As a result, they must be spawned on a single-threaded tokio runtime.
+//! Endpoints, connections, and streams are `Send` and `Sync`, sharing state behind a lock, so
+//! they may be used from any thread and spawned on a multi-threaded tokio runtime.
+//!
+//! The API is built on `std::future::Future` and `futures_core::Stream`, so connections and
+//! streams may be used from `async` code on any executor. The endpoint's `Driver` polls timers
+//! and a UDP socket supplied by a [`Runtime`](runtime/trait.Runtime.html). The default,
+//! `TokioRuntime`, uses the tokio 0.1 reactor and timer, so that driver must run within a tokio
+//! runtime, e.g. by way of `futures_util::compat`.
 //!
 //! ```
 //! # extern crate tokio;
 //! # extern crate quinn;
 //! # extern crate futures;
 //! # use futures::Future;
+//! # use futures_util::TryFutureExt;
 //! # fn main() {
-//! let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+//! let mut runtime = tokio::runtime::Runtime::new().unwrap();
 //! let mut builder = quinn::Endpoint::new();
 //! // <configure builder>
 //! let (endpoint, driver, _) = builder.bind("[::]:0").unwrap();
-//! runtime.spawn(driver.map_err(|e| panic!("IO error: {}", e)));
+//! runtime.spawn(driver.compat().map_err(|e| panic!("IO error: {}", e)));
 //! // ...
 //! # }
 //! ```
@@ -48,70 +55,101 @@
 //! server, it is useful for a QUIC server to be identified by a certificate signed by a trusted
 //! authority. If this is infeasible--for example, if servers are short-lived or not associated
 //! with a domain name--then as with TLS, self-signed certificates can be used to provide
-//! encryption alone.
+//! encryption alone. Alternatively, the `noise` feature provides `crypto::noise`, which
+//! authenticates peers by static public keys and involves no certificates at all; select it with
+//! `EndpointBuilder::with_client_config`.
 #![warn(missing_docs)]
 
 #[macro_use]
 extern crate slog;
 
 mod builders;
+#[cfg(feature = "runtime-tokio")]
+mod compat;
+#[cfg(any(test, feature = "loopback"))]
+pub mod loopback;
+#[cfg(feature = "runtime-tokio")]
 mod platform;
+pub mod runtime;
+#[cfg(feature = "runtime-tokio")]
+mod shard;
 pub mod tls;
+#[cfg(feature = "runtime-tokio")]
 mod udp;
 
-use std::cell::RefCell;
 use std::collections::{hash_map, VecDeque};
+use std::future::Future;
+use std::io::IoSliceMut;
 use std::net::{SocketAddr, SocketAddrV6};
-use std::rc::Rc;
+use std::pin::Pin;
 use std::str;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
 use std::time::{Duration, Instant};
 use std::{io, mem};
 
 use bytes::Bytes;
 use err_derive::Error;
-use fnv::FnvHashMap;
-use futures::stream::FuturesUnordered;
-use futures::task::{self, Task};
-use futures::unsync::oneshot;
-use futures::Stream as FuturesStream;
-use futures::{Async, Future, Poll, Sink};
+use fnv::{FnvHashMap, FnvHashSet};
+#[cfg(feature = "runtime-tokio")]
+use futures::Async;
+use futures_core::Stream;
+use quinn_proto::crypto::CryptoSession;
 use quinn_proto::{self as quinn, ConnectionHandle, Directionality, Side, StreamId, TimerUpdate};
 use slog::Logger;
+#[cfg(feature = "runtime-tokio")]
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_timer::Delay;
 
 pub use crate::quinn::{
-    Config, ConnectError, ConnectionError, ConnectionId, ServerConfig, ALPN_QUIC_HTTP,
+    crypto, AcceptDecision, AcceptFilter, Config, ConnectError, ConnectionAttempt, ConnectionError,
+    ConnectionId, EcnCodepoint, ExportKeyingMaterialError, LimitAction, LoadBasedRetry,
+    RetryPolicy, ServerLoad, SourceLimits, TlsSession, Transmit, ALPN_QUIC_HTTP,
+};
+pub use crate::tls::{
+    Certificate, CertificateChain, CertificateResolver, CertificateVerifier, PrivateKey,
 };
-pub use crate::tls::{Certificate, CertificateChain, PrivateKey};
 
 pub use crate::builders::{
     ClientConfig, ClientConfigBuilder, EndpointBuilder, EndpointError, ServerConfigBuilder,
 };
-use crate::udp::UdpSocket;
+#[cfg(feature = "runtime-tokio")]
+pub use crate::runtime::TokioRuntime;
+pub use crate::runtime::{AsyncTimer, AsyncUdpSocket, RecvMeta, Runtime};
 
-#[cfg(test)]
+#[cfg(all(test, feature = "runtime-tokio"))]
 mod tests;
 
+/// Parameters governing incoming connections, using rustls for the cryptographic handshake by default
+pub type ServerConfig<S = TlsSession> = quinn::ServerConfig<S>;
+
 /// A QUIC endpoint.
 ///
-/// An endpoint corresponds to a single UDP socket, may host many connections, and may act as both
-/// client and server for different connections.
+/// An endpoint corresponds to one or more UDP sockets, may host many connections, and may act as
+/// both client and server for different connections.
 ///
 /// May be cloned to obtain another handle to the same endpoint.
-#[derive(Clone)]
-pub struct Endpoint {
-    inner: Rc<RefCell<EndpointInner>>,
-    default_client_config: ClientConfig,
+pub struct Endpoint<S: CryptoSession = TlsSession> {
+    inner: EndpointRef<S>,
+    default_client_config: ClientConfig<S>,
 }
 
 impl Endpoint {
-    /// Begin constructing an `Endpoint`
-    pub fn new<'a>() -> EndpointBuilder<'a> {
+    /// Begin constructing an `Endpoint` using TLS
+    pub fn new() -> EndpointBuilder {
         EndpointBuilder::default()
     }
+}
+
+impl<S: CryptoSession> Clone for Endpoint<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            default_client_config: self.default_client_config.clone(),
+        }
+    }
+}
 
+impl<S: CryptoSession> Endpoint<S> {
     /// Connect to a remote endpoint.
     ///
     /// May fail immediately due to configuration errors, or in the future if the connection could
@@ -120,8 +158,7 @@ impl Endpoint {
         &self,
         addr: &SocketAddr,
         server_name: &str,
-    ) -> Result<impl Future<Item = NewClientConnection, Error = ConnectionError>, ConnectError>
-    {
+    ) -> Result<Connecting<S>, ConnectError> {
         self.connect_with(&self.default_client_config, addr, server_name)
     }
 
@@ -131,19 +168,34 @@ impl Endpoint {
     /// not be established.
     pub fn connect_with(
         &self,
-        config: &ClientConfig,
+        config: &ClientConfig<S>,
         addr: &SocketAddr,
         server_name: &str,
-    ) -> Result<impl Future<Item = NewClientConnection, Error = ConnectionError>, ConnectError>
-    {
-        let (fut, conn) = self.connect_inner(addr, &config.tls_config, server_name)?;
-        Ok(fut.map_err(|_| unreachable!()).and_then(move |err| {
-            if let Some(err) = err {
-                Err(err)
-            } else {
-                Ok(NewClientConnection::new(Rc::new(conn)))
-            }
-        }))
+    ) -> Result<Connecting<S>, ConnectError> {
+        let conn = self.connect_inner(addr, &config.tls_config, server_name)?;
+        Ok(Connecting(Some(Arc::new(conn))))
+    }
+
+    /// Replace the configuration used for new incoming connections
+    ///
+    /// Established connections are unaffected, making this suitable for rotating certificates. An
+    /// endpoint that had no server configuration begins delivering connections to its `Incoming`
+    /// stream; one paused by `pause_accepting` remains paused.
+    pub fn set_server_config(&self, server_config: ServerConfig<S>) -> Result<(), EndpointError> {
+        self.inner.lock().inner.set_server_config(server_config)?;
+        Ok(())
+    }
+
+    /// Refuse new incoming connections until `resume_accepting` is called
+    ///
+    /// Established connections are unaffected.
+    pub fn pause_accepting(&self) {
+        self.inner.lock().inner.pause_accepting();
+    }
+
+    /// Accept new incoming connections again after `pause_accepting`
+    pub fn resume_accepting(&self) {
+        self.inner.lock().inner.resume_accepting();
     }
 
     /*
@@ -178,7 +230,7 @@ impl Endpoint {
             "a session ticket must be supplied for zero-rtt transmits to be possible"
         );
         let (fut, conn) = self.connect_inner(addr, config)?;
-        let conn = NewClientConnection::new(Rc::new(conn));
+        let conn = NewClientConnection::new(Arc::new(conn));
         Ok((
             conn,
             fut.map_err(|_| unreachable!())
@@ -190,270 +242,409 @@ impl Endpoint {
     fn connect_inner(
         &self,
         addr: &SocketAddr,
-        config: &Arc<quinn::ClientConfig>,
+        config: &S::ClientConfig,
         server_name: &str,
-    ) -> Result<
-        (
-            impl Future<Item = Option<ConnectionError>, Error = futures::Canceled>,
-            ConnectionInner,
-        ),
-        ConnectError,
-    > {
-        let (send, recv) = oneshot::channel();
+    ) -> Result<ConnectionInner<S>, ConnectError> {
         let handle = {
-            let mut endpoint = self.inner.borrow_mut();
-            let addr = if endpoint.ipv6 {
+            let mut endpoint = self.inner.lock();
+            // IPv4 peers can only be reached from IPv6 sockets by way of mapped addresses
+            let addr = if addr.is_ipv4() && endpoint.sockets.iter().all(|x| x.ipv6) {
                 SocketAddr::V6(ensure_ipv6(*addr))
             } else {
                 *addr
             };
+            if !endpoint.sockets.iter().any(|x| x.ipv6 == addr.is_ipv6()) {
+                return Err(ConnectError::UnreachableRemote(addr));
+            }
             let handle = endpoint.inner.connect(addr, config, server_name)?;
-            endpoint.pending.insert(handle, Pending::new(Some(send)));
+            let mut pending = Pending::default();
+            if endpoint.driver_lost {
+                let reason = if endpoint.poisoned {
+                    POISONED
+                } else {
+                    "driver future was dropped"
+                };
+                pending.fail(driver_lost_error(reason));
+            }
+            endpoint.pending.insert(handle, pending);
             endpoint.notify();
             handle
         };
-        let conn = ConnectionInner {
+        Ok(ConnectionInner {
             endpoint: self.inner.clone(),
             handle,
             side: Side::Client,
-        };
-        Ok((recv, conn))
+        })
+    }
+}
+
+/// Future produced by `Endpoint::connect`, completing when the handshake does
+pub struct Connecting<S: CryptoSession = TlsSession>(Option<Arc<ConnectionInner<S>>>);
+
+impl<S: CryptoSession> Future for Connecting<S> {
+    type Output = Result<NewClientConnection<S>, ConnectionError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        {
+            let conn = self.0.as_ref().expect("polled after completion");
+            let mut endpoint = conn.endpoint.lock();
+            let pending = endpoint.pending.get_mut(&conn.handle).unwrap();
+            if !pending.connected {
+                if let Some(ref x) = pending.error {
+                    return Poll::Ready(Err(x.clone()));
+                }
+                pending.connecting = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(Ok(NewClientConnection::new(self.0.take().unwrap())))
     }
 }
 
 /// A future that drives IO on an endpoint.
-pub struct Driver(Rc<RefCell<EndpointInner>>);
-
-impl Future for Driver {
-    type Item = ();
-    type Error = io::Error;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut buf = [0; 64 * 1024];
-        let endpoint = &mut *self.0.borrow_mut();
-        if endpoint.driver.is_none() {
-            endpoint.driver = Some(task::current());
+///
+/// Polls the timers and socket of the endpoint's `Runtime`, and so must be run wherever those
+/// require; for `TokioRuntime`, that's a tokio runtime.
+pub struct Driver<S: CryptoSession = TlsSession>(EndpointRef<S>);
+
+impl<S: CryptoSession> Future for Driver<S> {
+    type Output = Result<(), io::Error>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let endpoint = &mut *self.0.lock();
+        if endpoint.poisoned {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, POISONED)));
         }
-        let now = micros_from(endpoint.epoch.elapsed());
+        if !endpoint
+            .driver
+            .as_ref()
+            .map_or(false, |x| x.will_wake(cx.waker()))
+        {
+            endpoint.driver = Some(cx.waker().clone());
+        }
+        match endpoint.drive(cx) {
+            Ok(()) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl<S: CryptoSession> Drop for Driver<S> {
+    fn drop(&mut self) {
+        self.0.lock().lose_driver("driver future was dropped");
+    }
+}
+
+/// Reason given for failures following a panic while the endpoint's state was locked
+const POISONED: &str = "endpoint state poisoned by a panic";
+
+/// Maximum number of `Transmit`s sent, or receive buffers filled, by a single socket operation
+const BATCH_SIZE: usize = 32;
+
+/// Space for a single received datagram or, with receive offload, a batch of them
+const RECV_SLOT_SIZE: usize = 64 * 1024;
+
+/// A handle to the state shared by an endpoint and its connections
+///
+/// A panic while the lock is held may leave the state half-updated, so it's fatal: the `Driver`
+/// fails, and every connection with it.
+struct EndpointRef<S: CryptoSession>(Arc<Mutex<EndpointInner<S>>>);
+
+impl<S: CryptoSession> EndpointRef<S> {
+    fn new(inner: EndpointInner<S>) -> Self {
+        EndpointRef(Arc::new(Mutex::new(inner)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, EndpointInner<S>> {
+        match self.0.lock() {
+            Ok(x) => x,
+            Err(e) => {
+                let mut endpoint = e.into_inner();
+                if !endpoint.poisoned {
+                    endpoint.poisoned = true;
+                    endpoint.lose_driver(POISONED);
+                    endpoint.notify();
+                }
+                endpoint
+            }
+        }
+    }
+}
+
+impl<S: CryptoSession> Clone for EndpointRef<S> {
+    fn clone(&self) -> Self {
+        EndpointRef(self.0.clone())
+    }
+}
+
+struct EndpointInner<S: CryptoSession> {
+    log: Logger,
+    runtime: Arc<dyn Runtime>,
+    /// Indexed by the `socket` passed to `quinn::Endpoint::handle`
+    sockets: Vec<EndpointSocket>,
+    inner: quinn::Endpoint<S>,
+    /// Storage for `BATCH_SIZE` slots of `RECV_SLOT_SIZE` bytes each
+    recv_buf: Box<[u8]>,
+    epoch: Instant,
+    pending: FnvHashMap<ConnectionHandle, Pending>,
+    /// Running timers, which are removed once expired or stopped
+    timers: FnvHashMap<(ConnectionHandle, quinn::Timer), Pin<Box<dyn AsyncTimer>>>,
+    /// Connections not yet taken from `Incoming`
+    incoming: VecDeque<ConnectionHandle>,
+    incoming_reader: Option<Waker>,
+    driver: Option<Waker>,
+    /// Whether the `Driver` has been dropped or failed, so no further I/O will take place
+    driver_lost: bool,
+    /// Whether a thread panicked while holding the lock, leaving the state unusable
+    poisoned: bool,
+}
+
+struct EndpointSocket {
+    socket: Box<dyn AsyncUdpSocket>,
+    /// `Transmit`s not yet accepted by the socket
+    outgoing: Vec<quinn::Transmit>,
+    ipv6: bool,
+}
+
+impl EndpointSocket {
+    fn new(socket: Box<dyn AsyncUdpSocket>) -> io::Result<Self> {
+        let ipv6 = socket.local_addr()?.is_ipv6();
+        Ok(Self {
+            socket,
+            outgoing: Vec::with_capacity(BATCH_SIZE),
+            ipv6,
+        })
+    }
+}
+
+impl<S: CryptoSession> EndpointInner<S> {
+    /// Fail every connection, as no further I/O will take place
+    fn lose_driver(&mut self, reason: &'static str) {
+        if self.driver_lost {
+            return;
+        }
+        self.driver_lost = true;
+        if let Some(x) = self.incoming_reader.take() {
+            x.wake();
+        }
+        for ch in self.pending.values_mut() {
+            ch.fail(driver_lost_error(reason));
+            // Nothing will drain the connection now
+            if let Some(x) = ch.closing.take() {
+                x.wake();
+            }
+        }
+    }
+
+    /// Wake up a blocked `Driver` task to process I/O
+    fn notify(&self) {
+        if let Some(x) = self.driver.as_ref() {
+            x.wake_by_ref();
+        }
+    }
+
+    /// Microseconds elapsed since `epoch`, according to the runtime's clock
+    fn now(&self) -> u64 {
+        micros_from(self.runtime.now() - self.epoch)
+    }
+
+    /// Perform all pending I/O, returning once the socket and timers are blocked
+    fn drive(&mut self, cx: &mut Context<'_>) -> Result<(), io::Error> {
+        let now = self.now();
+        let mut metas = [RecvMeta::default(); BATCH_SIZE];
         loop {
-            loop {
-                match endpoint.socket.poll_recv(&mut buf) {
-                    Ok(Async::Ready((n, addr, ecn))) => {
-                        endpoint.inner.handle(now, addr, ecn, (&buf[0..n]).into());
-                    }
-                    Ok(Async::NotReady) => {
-                        break;
-                    }
-                    // Ignore ECONNRESET as it's undefined in QUIC and may be injected by an
-                    // attacker
-                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => {
-                        continue;
-                    }
-                    Err(e) => {
-                        return Err(e);
+            let mut iovs = self
+                .recv_buf
+                .chunks_mut(RECV_SLOT_SIZE)
+                .map(IoSliceMut::new)
+                .collect::<Vec<_>>();
+            for (index, socket) in self.sockets.iter().enumerate() {
+                loop {
+                    match socket.socket.poll_recv(cx, &mut iovs, &mut metas) {
+                        Poll::Ready(Ok(n)) => {
+                            for (meta, buf) in metas.iter().zip(iovs.iter()).take(n) {
+                                for datagram in meta.datagrams(buf) {
+                                    self.inner.handle(
+                                        now,
+                                        meta.addr,
+                                        meta.dst_ip.map(|ip| quinn::LocalAddress {
+                                            ip,
+                                            interface: meta.interface,
+                                        }),
+                                        index,
+                                        meta.ecn,
+                                        datagram.into(),
+                                    );
+                                }
+                            }
+                        }
+                        Poll::Pending => {
+                            break;
+                        }
+                        // Ignore ECONNRESET as it's undefined in QUIC and may be injected by an
+                        // attacker
+                        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => {
+                            continue;
+                        }
+                        Poll::Ready(Err(e)) => {
+                            return Err(e);
+                        }
                     }
                 }
             }
-            while let Some((ch, event)) = endpoint.inner.poll() {
+            while let Some((ch, event)) = self.inner.poll() {
                 use crate::quinn::Event::*;
                 match event {
                     Connected { .. } => {
-                        let _ = endpoint
-                            .pending
-                            .get_mut(&ch)
-                            .unwrap()
-                            .connecting
-                            .take()
-                            .map(|chan| chan.send(None));
+                        let pending = self.pending.get_mut(&ch).unwrap();
+                        pending.connected = true;
+                        if let Some(x) = pending.connecting.take() {
+                            x.wake();
+                        }
                     }
                     ConnectionLost { reason } => {
-                        if let Some(x) = endpoint.pending.get_mut(&ch) {
+                        if let Some(x) = self.pending.get_mut(&ch) {
                             x.fail(reason);
                         }
                     }
                     StreamWritable { stream } => {
-                        if let Some(writer) = endpoint
+                        if let Some(writer) = self
                             .pending
                             .get_mut(&ch)
                             .unwrap()
                             .blocked_writers
                             .remove(&stream)
                         {
-                            writer.notify();
+                            writer.wake();
                         }
                     }
                     StreamOpened => {
-                        let pending = endpoint.pending.get_mut(&ch).unwrap();
+                        let pending = self.pending.get_mut(&ch).unwrap();
                         if let Some(x) = pending.incoming_streams_reader.take() {
-                            x.notify();
+                            x.wake();
                         }
                     }
                     StreamReadable { stream } => {
-                        let pending = endpoint.pending.get_mut(&ch).unwrap();
+                        let pending = self.pending.get_mut(&ch).unwrap();
                         if let Some(reader) = pending.blocked_readers.remove(&stream) {
-                            reader.notify();
+                            reader.wake();
                         }
                     }
                     StreamAvailable { directionality } => {
-                        let pending = endpoint.pending.get_mut(&ch).unwrap();
+                        let pending = self.pending.get_mut(&ch).unwrap();
                         let queue = match directionality {
                             Directionality::Uni => &mut pending.uni_opening,
                             Directionality::Bi => &mut pending.bi_opening,
                         };
-                        while let Some(connection) = queue.pop_front() {
-                            if let Some(id) = endpoint.inner.open(ch, directionality) {
-                                let _ = connection.send(Ok(id));
-                            } else {
-                                queue.push_front(connection);
-                                break;
-                            }
+                        for x in queue.drain(..) {
+                            x.wake();
                         }
                     }
                     StreamFinished { stream } => {
-                        let _ = endpoint
-                            .pending
-                            .get_mut(&ch)
-                            .unwrap()
-                            .finishing
-                            .remove(&stream)
-                            .unwrap()
-                            .send(None);
+                        let pending = self.pending.get_mut(&ch).unwrap();
+                        pending.finished.insert(stream);
+                        if let Some(Some(x)) = pending.finishing.remove(&stream) {
+                            x.wake();
+                        }
                     }
                     Handshaking => {
-                        endpoint.pending.insert(ch, Pending::new(None));
-                        match endpoint.incoming.poll_ready() {
-                            Ok(Async::Ready(())) => {
-                                endpoint
-                                    .incoming
-                                    .start_send(NewConnection::new(self.0.clone(), ch))
-                                    .unwrap();
-                                endpoint.inner.accept();
-                            }
-                            _ => {
-                                endpoint.buffered_incoming.push_back(ch);
-                            }
+                        self.pending.insert(ch, Pending::default());
+                        self.incoming.push_back(ch);
+                        if let Some(x) = self.incoming_reader.take() {
+                            x.wake();
                         }
                     }
                 }
             }
-            while let Ok(Async::Ready(())) = endpoint.incoming.poll_ready() {
-                if let Some(ch) = endpoint.buffered_incoming.pop_front() {
-                    endpoint
-                        .incoming
-                        .start_send(NewConnection::new(self.0.clone(), ch))
-                        .unwrap();
-                    endpoint.inner.accept();
-                } else {
-                    break;
-                }
-            }
-            let _ = endpoint.incoming.poll_complete();
-            let mut blocked = false;
-            if let Some(ref x) = endpoint.outgoing {
-                match endpoint.socket.poll_send(&x.destination, x.ecn, &x.packet) {
-                    Ok(Async::Ready(_)) => {
-                        endpoint.outgoing = None;
-                    }
-                    Ok(Async::NotReady) => {
-                        blocked = true;
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                        blocked = true;
-                    }
-                    Err(e) => {
-                        return Err(e);
+            let max_datagrams = self
+                .sockets
+                .iter()
+                .map(|x| x.socket.max_transmit_segments())
+                .min()
+                .unwrap();
+            loop {
+                // Skip connections routed to a socket with a full batch, so that a blocked socket
+                // neither accumulates an unbounded backlog nor holds up the others
+                loop {
+                    let sockets = &self.sockets;
+                    let transmit = self.inner.poll_transmit_where(
+                        now,
+                        max_datagrams,
+                        |socket, destination| {
+                            sockets[route(sockets, socket, destination)].outgoing.len() < BATCH_SIZE
+                        },
+                    );
+                    match transmit {
+                        Some(x) => {
+                            let index = route(&self.sockets, x.socket, &x.destination);
+                            self.sockets[index].outgoing.push(x);
+                        }
+                        None => break,
                     }
                 }
-            }
-            if !blocked {
-                while let Some(x) = endpoint.inner.poll_transmit(now) {
-                    match endpoint.socket.poll_send(&x.destination, x.ecn, &x.packet) {
-                        Ok(Async::Ready(_)) => {}
-                        Ok(Async::NotReady) => {
-                            endpoint.outgoing = Some(x);
-                            break;
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                            endpoint.outgoing = Some(x);
-                            break;
+                let mut sent = false;
+                for socket in &mut self.sockets {
+                    if socket.outgoing.is_empty() {
+                        continue;
+                    }
+                    match socket.socket.poll_send(cx, &socket.outgoing) {
+                        Poll::Ready(Ok(n)) => {
+                            socket.outgoing.drain(..n);
+                            sent = true;
                         }
-                        Err(e) => {
+                        Poll::Pending => {}
+                        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::PermissionDenied => {}
+                        Poll::Ready(Err(e)) => {
                             return Err(e);
                         }
                     }
                 }
+                if !sent {
+                    break;
+                }
             }
-            let mut timer_fired = false;
-            loop {
-                match endpoint.timers.poll() {
-                    Ok(Async::Ready(Some(Some((ch, timer))))) => {
-                        trace!(endpoint.log, "timeout"; "timer" => ?timer);
-                        endpoint.inner.timeout(now, ch, timer);
-                        if timer == quinn::Timer::Close {
-                            // Connection drained
-                            if let hash_map::Entry::Occupied(mut p) = endpoint.pending.entry(ch) {
-                                if let Some(x) = p.get_mut().closing.take() {
-                                    let _ = x.send(());
-                                }
-                                if p.get().dropped {
-                                    p.remove();
-                                } else {
-                                    p.get_mut().drained = true;
-                                }
+            // Apply updates before polling, so that new timers register for wakeups
+            while let Some((ch, TimerUpdate { timer, update })) = self.inner.poll_timers() {
+                match update {
+                    quinn::TimerSetting::Start(time) => {
+                        let instant = self.epoch + duration_micros(time);
+                        trace!(self.log, "timer start"; "timer" => ?timer, "time" => ?duration_micros(time));
+                        match self.timers.entry((ch, timer)) {
+                            hash_map::Entry::Occupied(mut x) => {
+                                x.get_mut().as_mut().reset(instant);
+                            }
+                            hash_map::Entry::Vacant(x) => {
+                                x.insert(self.runtime.new_timer(instant));
                             }
                         }
-                        timer_fired = true;
                     }
-                    Ok(Async::Ready(Some(None))) => {}
-                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => {
-                        break;
+                    quinn::TimerSetting::Stop => {
+                        trace!(self.log, "timer stop"; "timer" => ?timer);
+                        self.timers.remove(&(ch, timer));
                     }
-                    Err(()) => unreachable!(),
                 }
             }
-            while let Some((ch, x)) = endpoint.inner.poll_timers() {
-                match x {
-                    TimerUpdate {
-                        timer: timer @ quinn::Timer::Close,
-                        update: quinn::TimerSetting::Start(time),
-                    } => {
-                        let instant = endpoint.epoch + duration_micros(time);
-                        endpoint.timers.push(Timer {
-                            ch,
-                            ty: timer,
-                            delay: Delay::new(instant),
-                            cancel: None,
-                        });
-                    }
-                    TimerUpdate {
-                        timer,
-                        update: quinn::TimerSetting::Start(time),
-                    } => {
-                        let pending = endpoint.pending.get_mut(&ch).unwrap();
-                        let cancel = &mut pending.cancel_timers[timer as usize];
-                        let instant = endpoint.epoch + duration_micros(time);
-                        if let Some(cancel) = cancel.take() {
-                            let _ = cancel.send(());
+            let expired = self
+                .timers
+                .iter_mut()
+                .filter_map(|(&key, timer)| match timer.as_mut().poll(cx) {
+                    Poll::Ready(()) => Some(key),
+                    Poll::Pending => None,
+                })
+                .collect::<Vec<_>>();
+            let timer_fired = !expired.is_empty();
+            for (ch, timer) in expired {
+                self.timers.remove(&(ch, timer));
+                trace!(self.log, "timeout"; "timer" => ?timer);
+                self.inner.timeout(now, ch, timer);
+                if timer == quinn::Timer::Close {
+                    // Connection drained
+                    if let hash_map::Entry::Occupied(mut p) = self.pending.entry(ch) {
+                        if let Some(x) = p.get_mut().closing.take() {
+                            x.wake();
                         }
-                        let (send, recv) = oneshot::channel();
-                        *cancel = Some(send);
-                        trace!(endpoint.log, "timer start"; "timer" => ?timer, "time" => ?duration_micros(time));
-                        endpoint.timers.push(Timer {
-                            ch,
-                            ty: timer,
-                            delay: Delay::new(instant),
-                            cancel: Some(recv),
-                        });
-                    }
-                    TimerUpdate {
-                        timer,
-                        update: quinn::TimerSetting::Stop,
-                    } => {
-                        trace!(endpoint.log, "timer stop"; "timer" => ?timer);
-                        // If a connection was lost, we already canceled its loss/idle timers.
-                        if let Some(pending) = endpoint.pending.get_mut(&ch) {
-                            if let Some(x) = pending.cancel_timers[timer as usize].take() {
-                                let _ = x.send(());
-                            }
+                        if p.get().dropped {
+                            p.remove();
+                        } else {
+                            p.get_mut().drained = true;
                         }
                     }
                 }
@@ -462,144 +653,120 @@ impl Future for Driver {
                 break;
             }
         }
-        Ok(Async::NotReady)
-    }
-}
-
-impl Drop for Driver {
-    fn drop(&mut self) {
-        let mut endpoint = self.0.borrow_mut();
-        for ch in endpoint.pending.values_mut() {
-            ch.fail(ConnectionError::TransportError(quinn::TransportError {
-                code: quinn::TransportErrorCode::INTERNAL_ERROR,
-                frame: None,
-                reason: "driver future was dropped",
-            }));
-        }
-    }
-}
-
-struct EndpointInner {
-    log: Logger,
-    socket: UdpSocket,
-    inner: quinn::Endpoint,
-    outgoing: Option<quinn::Transmit>,
-    epoch: Instant,
-    pending: FnvHashMap<ConnectionHandle, Pending>,
-    // TODO: Replace this with something custom that avoids using oneshots to cancel
-    timers: FuturesUnordered<Timer>,
-    buffered_incoming: VecDeque<ConnectionHandle>,
-    incoming: futures::sync::mpsc::Sender<NewConnection>,
-    driver: Option<Task>,
-    ipv6: bool,
-}
-
-impl EndpointInner {
-    /// Wake up a blocked `Driver` task to process I/O
-    fn notify(&self) {
-        if let Some(x) = self.driver.as_ref() {
-            x.notify();
-        }
+        Ok(())
     }
 }
 
+#[derive(Default)]
 struct Pending {
-    blocked_writers: FnvHashMap<StreamId, Task>,
-    blocked_readers: FnvHashMap<StreamId, Task>,
-    connecting: Option<oneshot::Sender<Option<ConnectionError>>>,
-    uni_opening: VecDeque<oneshot::Sender<Result<StreamId, ConnectionError>>>,
-    bi_opening: VecDeque<oneshot::Sender<Result<StreamId, ConnectionError>>>,
-    cancel_timers: [Option<oneshot::Sender<()>>; 5],
-    incoming_streams_reader: Option<Task>,
-    finishing: FnvHashMap<StreamId, oneshot::Sender<Option<ConnectionError>>>,
+    blocked_writers: FnvHashMap<StreamId, Waker>,
+    blocked_readers: FnvHashMap<StreamId, Waker>,
+    connected: bool,
+    connecting: Option<Waker>,
+    uni_opening: Vec<Waker>,
+    bi_opening: Vec<Waker>,
+    incoming_streams_reader: Option<Waker>,
+    /// Streams being finished, with the task awaiting each, if any
+    finishing: FnvHashMap<StreamId, Option<Waker>>,
+    /// Streams whose data has been fully acknowledged by the peer
+    finished: FnvHashSet<StreamId>,
     error: Option<ConnectionError>,
-    closing: Option<oneshot::Sender<()>>,
+    closing: Option<Waker>,
+    closed: bool,
     dropped: bool,
     drained: bool,
 }
 
-impl Pending {
-    fn new(connecting: Option<oneshot::Sender<Option<ConnectionError>>>) -> Self {
-        Self {
-            blocked_writers: FnvHashMap::default(),
-            blocked_readers: FnvHashMap::default(),
-            connecting,
-            uni_opening: VecDeque::new(),
-            bi_opening: VecDeque::new(),
-            cancel_timers: [None, None, None, None, None],
-            incoming_streams_reader: None,
-            finishing: FnvHashMap::default(),
-            error: None,
-            closing: None,
-            dropped: false,
-            drained: false,
-        }
-    }
+fn driver_lost_error(reason: &'static str) -> ConnectionError {
+    ConnectionError::TransportError(quinn::TransportError {
+        code: quinn::TransportErrorCode::INTERNAL_ERROR,
+        frame: None,
+        reason,
+    })
+}
 
+impl Pending {
     fn fail(&mut self, reason: ConnectionError) {
-        self.error = Some(reason.clone());
+        self.error = Some(reason);
         for (_, writer) in self.blocked_writers.drain() {
-            writer.notify()
+            writer.wake()
         }
         for (_, reader) in self.blocked_readers.drain() {
-            reader.notify()
-        }
-        if let Some(c) = self.connecting.take() {
-            let _ = c.send(Some(reason.clone()));
+            reader.wake()
         }
-        for x in self.uni_opening.drain(..) {
-            let _ = x.send(Err(reason.clone()));
+        if let Some(x) = self.connecting.take() {
+            x.wake();
         }
-        for x in self.bi_opening.drain(..) {
-            let _ = x.send(Err(reason.clone()));
+        for x in self.uni_opening.drain(..).chain(self.bi_opening.drain(..)) {
+            x.wake();
         }
         if let Some(x) = self.incoming_streams_reader.take() {
-            x.notify();
+            x.wake();
         }
-        for (_, x) in self.finishing.drain() {
-            let _ = x.send(Some(reason.clone()));
+        for x in self.finishing.values_mut() {
+            if let Some(x) = x.take() {
+                x.wake();
+            }
         }
     }
 }
 
 /// Stream of incoming connections.
-pub type Incoming = futures::sync::mpsc::Receiver<NewConnection>;
+///
+/// Ends once the endpoint's `Driver` has been dropped.
+pub struct Incoming<S: CryptoSession = TlsSession>(EndpointRef<S>);
+
+impl<S: CryptoSession> Stream for Incoming<S> {
+    type Item = NewConnection<S>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let endpoint = &mut *self.0.lock();
+        if let Some(ch) = endpoint.incoming.pop_front() {
+            endpoint.inner.accept();
+            return Poll::Ready(Some(NewConnection::new(self.0.clone(), ch)));
+        }
+        if endpoint.driver_lost {
+            return Poll::Ready(None);
+        }
+        endpoint.incoming_reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
 
 /// A connection initiated by a remote client.
-pub struct NewConnection {
+pub struct NewConnection<S: CryptoSession = TlsSession> {
     /// The connection itself.
-    pub connection: Connection,
+    pub connection: Connection<S>,
     /// The stream of QUIC streams initiated by the client.
-    pub incoming: IncomingStreams,
+    pub incoming: IncomingStreams<S>,
 }
 
-impl NewConnection {
-    fn new(endpoint: Rc<RefCell<EndpointInner>>, handle: quinn::ConnectionHandle) -> Self {
-        let conn = Rc::new(ConnectionInner {
+impl<S: CryptoSession> NewConnection<S> {
+    fn new(endpoint: EndpointRef<S>, handle: quinn::ConnectionHandle) -> Self {
+        let conn = Arc::new(ConnectionInner {
             endpoint,
             handle,
             side: Side::Server,
         });
         NewConnection {
             connection: Connection(conn.clone()),
-            incoming: IncomingStreams(conn),
+            incoming: IncomingStreams::new(conn),
         }
     }
 }
 
 /// A connection initiated locally.
-pub struct NewClientConnection {
+pub struct NewClientConnection<S: CryptoSession = TlsSession> {
     /// The connection itself.
-    pub connection: Connection,
+    pub connection: Connection<S>,
     /// The stream of QUIC streams initiated by the client.
-    pub incoming: IncomingStreams,
+    pub incoming: IncomingStreams<S>,
 }
 
-impl NewClientConnection {
-    fn new(conn: Rc<ConnectionInner>) -> Self {
+impl<S: CryptoSession> NewClientConnection<S> {
+    fn new(conn: Arc<ConnectionInner<S>>) -> Self {
         Self {
             connection: Connection(conn.clone()),
-            incoming: IncomingStreams(conn.clone()),
+            incoming: IncomingStreams::new(conn.clone()),
         }
     }
 }
@@ -610,48 +777,36 @@ impl NewClientConnection {
 /// with an `error_code` of 0 and an empty `reason`.
 ///
 /// May be cloned to obtain another handle to the same connection.
-#[derive(Clone)]
-pub struct Connection(Rc<ConnectionInner>);
+pub struct Connection<S: CryptoSession = TlsSession>(Arc<ConnectionInner<S>>);
 
-impl Connection {
+impl<S: CryptoSession> Clone for Connection<S> {
+    fn clone(&self) -> Self {
+        Connection(self.0.clone())
+    }
+}
+
+impl<S: CryptoSession> Connection<S> {
     /// Initite a new outgoing unidirectional stream.
-    pub fn open_uni(&self) -> impl Future<Item = SendStream, Error = ConnectionError> {
-        let (send, recv) = oneshot::channel();
-        {
-            let mut endpoint = self.0.endpoint.borrow_mut();
-            if let Some(x) = endpoint.inner.open(self.0.handle, Directionality::Uni) {
-                let _ = send.send(Ok(x));
-            } else {
-                let pending = endpoint.pending.get_mut(&self.0.handle).unwrap();
-                pending.uni_opening.push_back(send);
-                // We don't notify the driver here because there's no way to ask the peer for more
-                // streams
-            }
+    ///
+    /// Completes once the peer's stream limit allows.
+    pub async fn open_uni(&self) -> Result<SendStream<S>, ConnectionError> {
+        let stream = OpenStream {
+            conn: self.0.clone(),
+            directionality: Directionality::Uni,
         }
-        let conn = self.0.clone();
-        recv.map_err(|_| unreachable!())
-            .and_then(|result| result)
-            .map(move |stream| SendStream(BiStream::new(conn, stream)))
+        .await?;
+        Ok(SendStream(stream))
     }
 
     /// Initiate a new outgoing bidirectional stream.
-    pub fn open_bi(&self) -> impl Future<Item = BiStream, Error = ConnectionError> {
-        let (send, recv) = oneshot::channel();
-        {
-            let mut endpoint = self.0.endpoint.borrow_mut();
-            if let Some(x) = endpoint.inner.open(self.0.handle, Directionality::Bi) {
-                let _ = send.send(Ok(x));
-            } else {
-                let pending = endpoint.pending.get_mut(&self.0.handle).unwrap();
-                pending.bi_opening.push_back(send);
-                // We don't notify the driver here because there's no way to ask the peer for more
-                // streams
-            }
+    ///
+    /// Completes once the peer's stream limit allows.
+    pub async fn open_bi(&self) -> Result<BiStream<S>, ConnectionError> {
+        OpenStream {
+            conn: self.0.clone(),
+            directionality: Directionality::Bi,
         }
-        let conn = self.0.clone();
-        recv.map_err(|_| unreachable!())
-            .and_then(|result| result)
-            .map(move |stream| BiStream::new(conn.clone(), stream))
+        .await
     }
 
     /// Close the connection immediately.
@@ -664,41 +819,33 @@ impl Connection {
     /// `reason` will be truncated to fit in a single packet with overhead; to be certain it is
     /// preserved in full, it should be kept under 1KiB.
     ///
+    /// The returned future completes when the connection has drained, and need not be awaited
+    /// for the connection to close.
+    ///
     /// # Panics
     /// - If called more than once on handles to the same connection
-    // FIXME: Infallible
-    pub fn close(&self, error_code: u16, reason: &[u8]) -> impl Future<Item = (), Error = ()> {
-        let (send, recv) = oneshot::channel();
+    pub fn close(&self, error_code: u64, reason: &[u8]) -> impl Future<Output = ()> {
         {
-            let endpoint = &mut *self.0.endpoint.borrow_mut();
+            let endpoint = &mut *self.0.endpoint.lock();
 
             let pending = endpoint.pending.get_mut(&self.0.handle).unwrap();
-            assert!(
-                pending.closing.is_none(),
-                "a connection can only be closed once"
-            );
-            pending.closing = Some(send);
-
-            endpoint.inner.close(
-                micros_from(endpoint.epoch.elapsed()),
-                self.0.handle,
-                error_code,
-                reason.into(),
-            );
+            assert!(!pending.closed, "a connection can only be closed once");
+            pending.closed = true;
+
+            endpoint
+                .inner
+                .close(endpoint.now(), self.0.handle, error_code, reason.into());
+            endpoint.notify();
         }
-        let handle = self.clone();
-        recv.then(move |_| {
-            // Ensure the connection isn't dropped until it's fully drained.
-            let _ = handle;
-            Ok(())
-        })
+        // Ensure the connection isn't dropped until it's fully drained.
+        Closing(self.clone())
     }
 
     /// The peer's UDP address.
     pub fn remote_address(&self) -> SocketAddr {
         self.0
             .endpoint
-            .borrow()
+            .lock()
             .inner
             .connection(self.0.handle)
             .remote()
@@ -708,7 +855,7 @@ impl Connection {
     pub fn local_ids(&self) -> impl Iterator<Item = ConnectionId> {
         self.0
             .endpoint
-            .borrow()
+            .lock()
             .inner
             .connection(self.0.handle)
             .loc_cids()
@@ -720,7 +867,7 @@ impl Connection {
     pub fn remote_id(&self) -> ConnectionId {
         self.0
             .endpoint
-            .borrow()
+            .lock()
             .inner
             .connection(self.0.handle)
             .rem_cid()
@@ -730,99 +877,250 @@ impl Connection {
     pub fn protocol(&self) -> Option<Box<[u8]>> {
         self.0
             .endpoint
-            .borrow()
+            .lock()
             .inner
             .connection(self.0.handle)
             .protocol()
             .map(|x| x.into())
     }
 
+    /// The server name requested by the client, if any (servers only)
+    pub fn server_name(&self) -> Option<String> {
+        self.0
+            .endpoint
+            .lock()
+            .inner
+            .connection(self.0.handle)
+            .server_name()
+            .map(|x| x.into())
+    }
+
+    /// Time taken to establish the connection, once it has been
+    pub fn handshake_duration(&self) -> Option<Duration> {
+        self.0
+            .endpoint
+            .lock()
+            .inner
+            .connection(self.0.handle)
+            .handshake_duration()
+            .map(Duration::from_micros)
+    }
+
+    /// Derive `len` bytes of keying material bound to this connection
+    ///
+    /// Both endpoints obtain the same material for the same `label` and `context`, as in
+    /// RFC 5705. Fails until the handshake is complete.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, ExportKeyingMaterialError> {
+        self.0
+            .endpoint
+            .lock()
+            .inner
+            .connection(self.0.handle)
+            .export_keying_material(label, context, len)
+    }
+
     // Update traffic keys spontaneously for testing purposes.
     #[doc(hidden)]
     pub fn force_key_update(&self) {
+        self.0.endpoint.lock().inner.force_key_update(self.0.handle)
+    }
+}
+
+impl Connection<TlsSession> {
+    /// The certificate chain presented by the peer, once the handshake has verified it
+    ///
+    /// For servers, `None` unless client authentication was configured with
+    /// `ServerConfigBuilder::require_client_certificates` or `request_client_certificates` and
+    /// the client presented a certificate.
+    pub fn peer_certificates(&self) -> Option<CertificateChain> {
+        self.0
+            .endpoint
+            .lock()
+            .inner
+            .connection(self.0.handle)
+            .crypto_session()
+            .get_peer_certificates()
+            .map(|certs| CertificateChain { certs })
+    }
+
+    /// The negotiated TLS cipher suite, once known
+    pub fn cipher_suite(&self) -> Option<rustls::CipherSuite> {
+        self.0
+            .endpoint
+            .lock()
+            .inner
+            .connection(self.0.handle)
+            .crypto_session()
+            .cipher_suite()
+    }
+
+    /// Whether the handshake resumed a previous TLS session, once it has completed
+    pub fn is_resumed(&self) -> Option<bool> {
+        self.0
+            .endpoint
+            .lock()
+            .inner
+            .connection(self.0.handle)
+            .crypto_session()
+            .is_resumed()
+    }
+}
+
+#[cfg(feature = "noise")]
+impl Connection<crypto::noise::NoiseSession> {
+    /// The peer's static public key, once the handshake has authenticated it
+    pub fn remote_public_key(&self) -> Option<Vec<u8>> {
         self.0
             .endpoint
-            .borrow_mut()
+            .lock()
             .inner
-            .force_key_update(self.0.handle)
+            .connection(self.0.handle)
+            .crypto_session()
+            .remote_public_key()
+            .map(|x| x.to_vec())
     }
 }
 
-struct ConnectionInner {
-    endpoint: Rc<RefCell<EndpointInner>>,
+struct ConnectionInner<S: CryptoSession> {
+    endpoint: EndpointRef<S>,
     handle: ConnectionHandle,
     side: Side,
 }
 
-impl Drop for ConnectionInner {
+impl<S: CryptoSession> Drop for ConnectionInner<S> {
     fn drop(&mut self) {
-        let endpoint = &mut *self.endpoint.borrow_mut();
+        let endpoint = &mut *self.endpoint.lock();
         if let hash_map::Entry::Occupied(mut pending) = endpoint.pending.entry(self.handle) {
             if pending.get().drained {
                 pending.remove();
                 return;
             }
             pending.get_mut().dropped = true;
-            if pending.get().closing.is_none() {
-                endpoint.inner.close(
-                    micros_from(endpoint.epoch.elapsed()),
-                    self.handle,
-                    0,
-                    (&[][..]).into(),
-                );
-                if let Some(x) = endpoint.driver.as_ref() {
-                    x.notify();
-                }
+            if !pending.get().closed {
+                endpoint
+                    .inner
+                    .close(endpoint.now(), self.handle, 0, (&[][..]).into());
+                endpoint.notify();
             }
         }
     }
 }
 
+/// Future produced by `Connection::open_uni` and `Connection::open_bi`
+struct OpenStream<S: CryptoSession> {
+    conn: Arc<ConnectionInner<S>>,
+    directionality: Directionality,
+}
+
+impl<S: CryptoSession> Future for OpenStream<S> {
+    type Output = Result<BiStream<S>, ConnectionError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let endpoint = &mut *self.conn.endpoint.lock();
+        if let Some(ref x) = endpoint.pending[&self.conn.handle].error {
+            return Poll::Ready(Err(x.clone()));
+        }
+        if let Some(id) = endpoint.inner.open(self.conn.handle, self.directionality) {
+            return Poll::Ready(Ok(BiStream::new(self.conn.clone(), id)));
+        }
+        let pending = endpoint.pending.get_mut(&self.conn.handle).unwrap();
+        let queue = match self.directionality {
+            Directionality::Uni => &mut pending.uni_opening,
+            Directionality::Bi => &mut pending.bi_opening,
+        };
+        if !queue.iter().any(|x| x.will_wake(cx.waker())) {
+            queue.push(cx.waker().clone());
+        }
+        // We don't notify the driver here because there's no way to ask the peer for more streams
+        Poll::Pending
+    }
+}
+
+/// Future produced by `Connection::close`
+struct Closing<S: CryptoSession>(Connection<S>);
+
+impl<S: CryptoSession> Future for Closing<S> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let conn = &(self.0).0;
+        let endpoint = &mut *conn.endpoint.lock();
+        let pending = endpoint.pending.get_mut(&conn.handle).unwrap();
+        if pending.drained || endpoint.driver_lost {
+            return Poll::Ready(());
+        }
+        pending.closing = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
 /// A stream of QUIC streams initiated by a remote peer.
-pub struct IncomingStreams(Rc<ConnectionInner>);
-
-impl FuturesStream for IncomingStreams {
-    type Item = NewStream;
-    type Error = ConnectionError;
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut endpoint = self.0.endpoint.borrow_mut();
-        if let Some(x) = endpoint.inner.accept_stream(self.0.handle) {
-            let stream = BiStream::new(self.0.clone(), x);
+///
+/// Yields the error that ended the connection, then ends.
+pub struct IncomingStreams<S: CryptoSession = TlsSession> {
+    conn: Arc<ConnectionInner<S>>,
+    done: bool,
+}
+
+impl<S: CryptoSession> IncomingStreams<S> {
+    fn new(conn: Arc<ConnectionInner<S>>) -> Self {
+        Self { conn, done: false }
+    }
+}
+
+impl<S: CryptoSession> Stream for IncomingStreams<S> {
+    type Item = Result<NewStream<S>, ConnectionError>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let this = &mut *self;
+        let mut endpoint = this.conn.endpoint.lock();
+        if let Some(x) = endpoint.inner.accept_stream(this.conn.handle) {
+            let stream = BiStream::new(this.conn.clone(), x);
             let stream = if x.directionality() == Directionality::Uni {
                 NewStream::Uni(RecvStream(stream))
             } else {
                 NewStream::Bi(stream)
             };
-            return Ok(Async::Ready(Some(stream)));
+            return Poll::Ready(Some(Ok(stream)));
         }
-        let pending = endpoint.pending.get_mut(&self.0.handle).unwrap();
+        let pending = endpoint.pending.get_mut(&this.conn.handle).unwrap();
         if let Some(ref x) = pending.error {
-            Err(x.clone())
+            this.done = true;
+            Poll::Ready(Some(Err(x.clone())))
         } else {
-            pending.incoming_streams_reader = Some(task::current());
-            Ok(Async::NotReady)
+            pending.incoming_streams_reader = Some(cx.waker().clone());
+            Poll::Pending
         }
     }
 }
 
 /// A stream initiated by a remote peer.
-pub enum NewStream {
+pub enum NewStream<S: CryptoSession = TlsSession> {
     /// A unidirectional stream.
-    Uni(RecvStream),
+    Uni(RecvStream<S>),
     /// A bidirectional stream.
-    Bi(BiStream),
+    Bi(BiStream<S>),
 }
 
 /// A bidirectional stream, supporting both sending and receiving data.
 ///
 /// Similar to a TCP connection. Each direction of data flow can be reset or finished by the
 /// sending endpoint without interfering with activity in the other direction.
-pub struct BiStream {
-    conn: Rc<ConnectionInner>,
+///
+/// With the `runtime-tokio` feature, the `std::io` and `tokio_io` traits are implemented for use
+/// from futures 0.1 tasks, such as those of `tokio_io::io`; elsewhere, use the futures provided by
+/// `Read` and `Write`.
+pub struct BiStream<S: CryptoSession = TlsSession> {
+    conn: Arc<ConnectionInner<S>>,
     stream: StreamId,
 
     // Send only
-    finishing: Option<oneshot::Receiver<Option<ConnectionError>>>,
+    finishing: bool,
     finished: bool,
 
     // Recv only
@@ -830,67 +1128,99 @@ pub struct BiStream {
     recvd: bool,
 }
 
-impl BiStream {
-    fn new(conn: Rc<ConnectionInner>, stream: StreamId) -> Self {
+impl<S: CryptoSession> BiStream<S> {
+    fn new(conn: Arc<ConnectionInner<S>>, stream: StreamId) -> Self {
         Self {
             conn,
             stream,
-            finishing: None,
+            finishing: false,
             finished: false,
             recvd: false,
         }
     }
+
+    /// Write bytes to the stream, see `Write::poll_write`
+    pub fn write<'a>(&'a mut self, buf: &'a [u8]) -> WriteFuture<'a, Self> {
+        WriteFuture { stream: self, buf }
+    }
+
+    /// Write all of `buf` to the stream
+    pub fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> WriteAllFuture<'a, Self> {
+        WriteAllFuture { stream: self, buf }
+    }
+
+    /// Shut down the stream gracefully, see `Write::poll_finish`
+    pub fn finish(&mut self) -> FinishFuture<'_, Self> {
+        FinishFuture { stream: self }
+    }
+
+    /// Read data contiguously from the stream, see `Read::poll_read`
+    pub fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadFuture<'a, Self> {
+        ReadFuture { stream: self, buf }
+    }
+
+    /// Read a segment of data from any offset in the stream, see `Read::poll_read_unordered`
+    pub fn read_unordered(&mut self) -> ReadUnorderedFuture<'_, Self> {
+        ReadUnorderedFuture { stream: self }
+    }
 }
 
-impl Write for BiStream {
-    fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError> {
-        let mut endpoint = self.conn.endpoint.borrow_mut();
+impl<S: CryptoSession> Write for BiStream<S> {
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, WriteError>> {
+        let mut endpoint = self.conn.endpoint.lock();
         use crate::quinn::WriteError::*;
         let n = match endpoint.inner.write(self.conn.handle, self.stream, buf) {
             Ok(n) => n,
             Err(Blocked) => {
                 let pending = endpoint.pending.get_mut(&self.conn.handle).unwrap();
                 if let Some(ref x) = pending.error {
-                    return Err(WriteError::ConnectionClosed(x.clone()));
+                    return Poll::Ready(Err(WriteError::ConnectionClosed(x.clone())));
                 }
-                pending.blocked_writers.insert(self.stream, task::current());
-                return Ok(Async::NotReady);
+                pending
+                    .blocked_writers
+                    .insert(self.stream, cx.waker().clone());
+                return Poll::Pending;
             }
             Err(Stopped { error_code }) => {
-                return Err(WriteError::Stopped { error_code });
+                return Poll::Ready(Err(WriteError::Stopped { error_code }));
             }
         };
         endpoint.notify();
-        Ok(Async::Ready(n))
+        Poll::Ready(Ok(n))
     }
 
-    fn poll_finish(&mut self) -> Poll<(), ConnectionError> {
-        let mut endpoint = self.conn.endpoint.borrow_mut();
-        if self.finishing.is_none() {
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectionError>> {
+        if self.finished {
+            return Poll::Ready(Ok(()));
+        }
+        let endpoint = &mut *self.conn.endpoint.lock();
+        if !self.finishing {
             endpoint.inner.finish(self.conn.handle, self.stream);
-            let (send, recv) = oneshot::channel();
-            self.finishing = Some(recv);
             endpoint
                 .pending
                 .get_mut(&self.conn.handle)
                 .unwrap()
                 .finishing
-                .insert(self.stream, send);
+                .insert(self.stream, None);
             endpoint.notify();
+            self.finishing = true;
         }
-        let r = self.finishing.as_mut().unwrap().poll().unwrap();
-        match r {
-            Async::Ready(None) => {
-                self.finished = true;
-                Ok(Async::Ready(()))
-            }
-            Async::Ready(Some(e)) => Err(e),
-            Async::NotReady => Ok(Async::NotReady),
+        let pending = endpoint.pending.get_mut(&self.conn.handle).unwrap();
+        if pending.finished.remove(&self.stream) {
+            self.finished = true;
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(ref x) = pending.error {
+            return Poll::Ready(Err(x.clone()));
         }
+        pending
+            .finishing
+            .insert(self.stream, Some(cx.waker().clone()));
+        Poll::Pending
     }
 
-    fn reset(&mut self, error_code: u16) {
-        let endpoint = &mut *self.conn.endpoint.borrow_mut();
+    fn reset(&mut self, error_code: u64) {
+        let endpoint = &mut *self.conn.endpoint.lock();
         endpoint
             .inner
             .reset(self.conn.handle, self.stream, error_code);
@@ -898,57 +1228,68 @@ impl Write for BiStream {
     }
 }
 
-impl Read for BiStream {
-    fn poll_read_unordered(&mut self) -> Poll<(Bytes, u64), ReadError> {
-        let endpoint = &mut *self.conn.endpoint.borrow_mut();
+impl<S: CryptoSession> Read for BiStream<S> {
+    fn poll_read_unordered(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(Bytes, u64), ReadError>> {
+        let endpoint = &mut *self.conn.endpoint.lock();
         use crate::quinn::ReadError::*;
         let pending = endpoint.pending.get_mut(&self.conn.handle).unwrap();
         match endpoint.inner.read_unordered(self.conn.handle, self.stream) {
-            Ok((bytes, offset)) => Ok(Async::Ready((bytes, offset))),
+            Ok((bytes, offset)) => Poll::Ready(Ok((bytes, offset))),
             Err(Blocked) => {
                 if let Some(ref x) = pending.error {
-                    return Err(ReadError::ConnectionClosed(x.clone()));
+                    return Poll::Ready(Err(ReadError::ConnectionClosed(x.clone())));
                 }
-                pending.blocked_readers.insert(self.stream, task::current());
-                Ok(Async::NotReady)
+                pending
+                    .blocked_readers
+                    .insert(self.stream, cx.waker().clone());
+                Poll::Pending
             }
             Err(Reset { error_code }) => {
                 self.recvd = true;
-                Err(ReadError::Reset { error_code })
+                Poll::Ready(Err(ReadError::Reset { error_code }))
             }
             Err(Finished) => {
                 self.recvd = true;
-                Err(ReadError::Finished)
+                Poll::Ready(Err(ReadError::Finished))
             }
         }
     }
 
-    fn poll_read(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError> {
-        let endpoint = &mut *self.conn.endpoint.borrow_mut();
+    fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, ReadError>> {
+        let endpoint = &mut *self.conn.endpoint.lock();
         use crate::quinn::ReadError::*;
         let pending = endpoint.pending.get_mut(&self.conn.handle).unwrap();
         match endpoint.inner.read(self.conn.handle, self.stream, buf) {
-            Ok(n) => Ok(Async::Ready(n)),
+            Ok(n) => Poll::Ready(Ok(n)),
             Err(Blocked) => {
                 if let Some(ref x) = pending.error {
-                    return Err(ReadError::ConnectionClosed(x.clone()));
+                    return Poll::Ready(Err(ReadError::ConnectionClosed(x.clone())));
                 }
-                pending.blocked_readers.insert(self.stream, task::current());
-                Ok(Async::NotReady)
+                pending
+                    .blocked_readers
+                    .insert(self.stream, cx.waker().clone());
+                Poll::Pending
             }
             Err(Reset { error_code }) => {
                 self.recvd = true;
-                Err(ReadError::Reset { error_code })
+                Poll::Ready(Err(ReadError::Reset { error_code }))
             }
             Err(Finished) => {
                 self.recvd = true;
-                Err(ReadError::Finished)
+                Poll::Ready(Err(ReadError::Finished))
             }
         }
     }
 
-    fn stop(&mut self, error_code: u16) {
-        let endpoint = &mut *self.conn.endpoint.borrow_mut();
+    fn stop(&mut self, error_code: u64) {
+        let endpoint = &mut *self.conn.endpoint.lock();
         endpoint
             .inner
             .stop_sending(self.conn.handle, self.stream, error_code);
@@ -957,19 +1298,12 @@ impl Read for BiStream {
     }
 }
 
-impl io::Write for BiStream {
+#[cfg(feature = "runtime-tokio")]
+impl<S: CryptoSession> io::Write for BiStream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match Write::poll_write(self, buf) {
-            Ok(Async::Ready(n)) => Ok(n),
-            Ok(Async::NotReady) => Err(io::Error::new(io::ErrorKind::WouldBlock, "stream blocked")),
-            Err(WriteError::Stopped { error_code }) => Err(io::Error::new(
-                io::ErrorKind::ConnectionReset,
-                format!("stream stopped by peer: error {}", error_code),
-            )),
-            Err(WriteError::ConnectionClosed(e)) => Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                format!("connection closed: {}", e),
-            )),
+        match compat::with_context(|cx| Write::poll_write(self, cx, buf)) {
+            Poll::Ready(x) => x.map_err(write_io_error),
+            Poll::Pending => Err(io::Error::new(io::ErrorKind::WouldBlock, "stream blocked")),
         }
     }
 
@@ -978,20 +1312,38 @@ impl io::Write for BiStream {
     }
 }
 
-impl AsyncWrite for BiStream {
-    fn shutdown(&mut self) -> Poll<(), io::Error> {
-        self.poll_finish().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                format!("connection closed: {}", e),
-            )
-        })
+#[cfg(feature = "runtime-tokio")]
+impl<S: CryptoSession> AsyncWrite for BiStream<S> {
+    fn shutdown(&mut self) -> futures::Poll<(), io::Error> {
+        match compat::with_context(|cx| self.poll_finish(cx)) {
+            Poll::Ready(Ok(())) => Ok(Async::Ready(())),
+            Poll::Pending => Ok(Async::NotReady),
+            Poll::Ready(Err(e)) => Err(closed_io_error(e)),
+        }
+    }
+}
+
+impl<S: CryptoSession> futures_io::AsyncWrite for BiStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Write::poll_write(self.get_mut(), cx, buf).map_err(write_io_error)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_finish(cx).map_err(closed_io_error)
     }
 }
 
-impl Drop for BiStream {
+impl<S: CryptoSession> Drop for BiStream<S> {
     fn drop(&mut self) {
-        let endpoint = &mut *self.conn.endpoint.borrow_mut();
+        let endpoint = &mut *self.conn.endpoint.lock();
         let ours = self.stream.initiator() == self.conn.side;
         let (send, recv) = match self.stream.directionality() {
             Directionality::Bi => (true, true),
@@ -1005,92 +1357,197 @@ impl Drop for BiStream {
                 .inner
                 .stop_sending(self.conn.handle, self.stream, 0);
         }
+        if let Some(pending) = endpoint.pending.get_mut(&self.conn.handle) {
+            pending.finishing.remove(&self.stream);
+            pending.finished.remove(&self.stream);
+        }
         endpoint.notify();
     }
 }
 
-impl io::Read for BiStream {
+#[cfg(feature = "runtime-tokio")]
+impl<S: CryptoSession> io::Read for BiStream<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        use crate::ReadError::*;
-        match Read::poll_read(self, buf) {
-            Ok(Async::Ready(n)) => Ok(n),
-            Err(Finished) => Ok(0),
-            Ok(Async::NotReady) => Err(io::Error::new(io::ErrorKind::WouldBlock, "stream blocked")),
-            Err(Reset { error_code }) => Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                format!("stream reset by peer: error {}", error_code),
-            )),
-            Err(ConnectionClosed(e)) => Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                format!("connection closed: {}", e),
-            )),
+        match compat::with_context(|cx| Read::poll_read(self, cx, buf)) {
+            Poll::Ready(x) => read_io_result(x),
+            Poll::Pending => Err(io::Error::new(io::ErrorKind::WouldBlock, "stream blocked")),
         }
     }
 }
 
-impl AsyncRead for BiStream {
+impl<S: CryptoSession> futures_io::AsyncRead for BiStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Read::poll_read(self.get_mut(), cx, buf).map(read_io_result)
+    }
+}
+
+/// Translate the outcome of a read for `std::io` users, to whom a finished stream is empty
+fn read_io_result(x: Result<usize, ReadError>) -> io::Result<usize> {
+    use crate::ReadError::*;
+    match x {
+        Ok(n) => Ok(n),
+        Err(Finished) => Ok(0),
+        Err(Reset { error_code }) => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            format!("stream reset by peer: error {}", error_code),
+        )),
+        Err(ConnectionClosed(e)) => Err(closed_io_error(e)),
+    }
+}
+
+fn write_io_error(e: WriteError) -> io::Error {
+    match e {
+        WriteError::Stopped { error_code } => io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            format!("stream stopped by peer: error {}", error_code),
+        ),
+        WriteError::ConnectionClosed(e) => closed_io_error(e),
+    }
+}
+
+fn closed_io_error(e: ConnectionError) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        format!("connection closed: {}", e),
+    )
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl<S: CryptoSession> AsyncRead for BiStream<S> {
     unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
         false
     }
 }
 
 /// A stream that can only be used to send data
-pub struct SendStream(BiStream);
+pub struct SendStream<S: CryptoSession = TlsSession>(BiStream<S>);
 
-impl Write for SendStream {
-    fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError> {
-        Write::poll_write(&mut self.0, buf)
+impl<S: CryptoSession> SendStream<S> {
+    /// Write bytes to the stream, see `Write::poll_write`
+    pub fn write<'a>(&'a mut self, buf: &'a [u8]) -> WriteFuture<'a, Self> {
+        WriteFuture { stream: self, buf }
     }
-    fn poll_finish(&mut self) -> Poll<(), ConnectionError> {
-        self.0.poll_finish()
+
+    /// Write all of `buf` to the stream
+    pub fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> WriteAllFuture<'a, Self> {
+        WriteAllFuture { stream: self, buf }
+    }
+
+    /// Shut down the stream gracefully, see `Write::poll_finish`
+    pub fn finish(&mut self) -> FinishFuture<'_, Self> {
+        FinishFuture { stream: self }
     }
-    fn reset(&mut self, error_code: u16) {
+}
+
+impl<S: CryptoSession> Write for SendStream<S> {
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, WriteError>> {
+        Write::poll_write(&mut self.0, cx, buf)
+    }
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectionError>> {
+        self.0.poll_finish(cx)
+    }
+    fn reset(&mut self, error_code: u64) {
         self.0.reset(error_code);
     }
 }
 
-impl io::Write for SendStream {
+#[cfg(feature = "runtime-tokio")]
+impl<S: CryptoSession> io::Write for SendStream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        io::Write::write(&mut self.0, buf)
     }
     fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
 }
 
-impl AsyncWrite for SendStream {
-    fn shutdown(&mut self) -> Poll<(), io::Error> {
+#[cfg(feature = "runtime-tokio")]
+impl<S: CryptoSession> AsyncWrite for SendStream<S> {
+    fn shutdown(&mut self) -> futures::Poll<(), io::Error> {
         self.0.shutdown()
     }
 }
 
+impl<S: CryptoSession> futures_io::AsyncWrite for SendStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
 /// A stream that can only be used to receive data
-pub struct RecvStream(BiStream);
+pub struct RecvStream<S: CryptoSession = TlsSession>(BiStream<S>);
+
+impl<S: CryptoSession> RecvStream<S> {
+    /// Read data contiguously from the stream, see `Read::poll_read`
+    pub fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadFuture<'a, Self> {
+        ReadFuture { stream: self, buf }
+    }
+
+    /// Read a segment of data from any offset in the stream, see `Read::poll_read_unordered`
+    pub fn read_unordered(&mut self) -> ReadUnorderedFuture<'_, Self> {
+        ReadUnorderedFuture { stream: self }
+    }
+}
 
-impl Read for RecvStream {
-    fn poll_read_unordered(&mut self) -> Poll<(Bytes, u64), ReadError> {
-        self.0.poll_read_unordered()
+impl<S: CryptoSession> Read for RecvStream<S> {
+    fn poll_read_unordered(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(Bytes, u64), ReadError>> {
+        self.0.poll_read_unordered(cx)
     }
-    fn poll_read(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError> {
-        Read::poll_read(&mut self.0, buf)
+    fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, ReadError>> {
+        Read::poll_read(&mut self.0, cx, buf)
     }
-    fn stop(&mut self, error_code: u16) {
+    fn stop(&mut self, error_code: u64) {
         self.0.stop(error_code)
     }
 }
 
-impl io::Read for RecvStream {
+#[cfg(feature = "runtime-tokio")]
+impl<S: CryptoSession> io::Read for RecvStream<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        io::Read::read(&mut self.0, buf)
     }
 }
 
-impl AsyncRead for RecvStream {
+#[cfg(feature = "runtime-tokio")]
+impl<S: CryptoSession> AsyncRead for RecvStream<S> {
     unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
         false
     }
 }
 
+impl<S: CryptoSession> futures_io::AsyncRead for RecvStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
 /// Uses unordered reads to be more efficient than using `AsyncRead` would allow
 pub fn read_to_end<T: Read>(stream: T, size_limit: usize) -> ReadToEnd<T> {
     ReadToEnd {
@@ -1100,31 +1557,6 @@ pub fn read_to_end<T: Read>(stream: T, size_limit: usize) -> ReadToEnd<T> {
     }
 }
 
-struct Timer {
-    ch: ConnectionHandle,
-    ty: quinn::Timer,
-    delay: Delay,
-    cancel: Option<oneshot::Receiver<()>>,
-}
-
-impl Future for Timer {
-    type Item = Option<(ConnectionHandle, quinn::Timer)>;
-    type Error = (); // FIXME
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Some(ref mut cancel) = self.cancel {
-            if let Ok(Async::NotReady) = cancel.poll() {
-            } else {
-                return Ok(Async::Ready(None));
-            }
-        }
-        match self.delay.poll() {
-            Err(e) => panic!("unexpected timer error: {}", e),
-            Ok(Async::NotReady) => Ok(Async::NotReady),
-            Ok(Async::Ready(())) => Ok(Async::Ready(Some((self.ch, self.ty)))),
-        }
-    }
-}
-
 /// Future produced by `read_to_end`
 pub struct ReadToEnd<T> {
     stream: Option<T>,
@@ -1132,32 +1564,32 @@ pub struct ReadToEnd<T> {
     size_limit: usize,
 }
 
-impl<T: Read> Future for ReadToEnd<T> {
-    type Item = (T, Box<[u8]>);
-    type Error = ReadError;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+impl<T: Read + Unpin> Future for ReadToEnd<T> {
+    type Output = Result<(T, Box<[u8]>), ReadError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
         loop {
-            match self.stream.as_mut().unwrap().poll_read_unordered() {
-                Ok(Async::Ready((data, offset))) => {
-                    let len = self.buffer.len().max(offset as usize + data.len());
-                    if len > self.size_limit {
-                        return Err(ReadError::Finished);
+            match this.stream.as_mut().unwrap().poll_read_unordered(cx) {
+                Poll::Ready(Ok((data, offset))) => {
+                    let len = this.buffer.len().max(offset as usize + data.len());
+                    if len > this.size_limit {
+                        return Poll::Ready(Err(ReadError::Finished));
                     }
-                    self.buffer.resize(len, 0);
-                    self.buffer[offset as usize..offset as usize + data.len()]
+                    this.buffer.resize(len, 0);
+                    this.buffer[offset as usize..offset as usize + data.len()]
                         .copy_from_slice(&data);
                 }
-                Ok(Async::NotReady) => {
-                    return Ok(Async::NotReady);
+                Poll::Pending => {
+                    return Poll::Pending;
                 }
-                Err(ReadError::Finished) => {
-                    return Ok(Async::Ready((
-                        self.stream.take().unwrap(),
-                        mem::replace(&mut self.buffer, Vec::new()).into(),
+                Poll::Ready(Err(ReadError::Finished)) => {
+                    return Poll::Ready(Ok((
+                        this.stream.take().unwrap(),
+                        mem::replace(&mut this.buffer, Vec::new()).into(),
                     )));
                 }
-                Err(e) => {
-                    return Err(e);
+                Poll::Ready(Err(e)) => {
+                    return Poll::Ready(Err(e));
                 }
             }
         }
@@ -1178,7 +1610,8 @@ pub trait Read {
     ///   This is forbidden because an unordered read could consume a segment of data from a
     ///   location other than the start of the receive buffer, making it impossible for future
     ///   ordered reads to proceed.
-    fn poll_read(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError>;
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8])
+        -> Poll<Result<usize, ReadError>>;
 
     /// Read a segment of data from any offset in the stream.
     ///
@@ -1187,7 +1620,10 @@ pub trait Read {
     ///
     /// Unordered reads have reduced overhead and higher throughput, and should therefore be
     /// preferred when applicable.
-    fn poll_read_unordered(&mut self) -> Poll<(Bytes, u64), ReadError>;
+    fn poll_read_unordered(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(Bytes, u64), ReadError>>;
 
     /// Close the receive stream immediately.
     ///
@@ -1198,7 +1634,33 @@ pub trait Read {
     /// ignore the request entirely and continue sending until halted by flow control.
     ///
     /// Has no effect if the incoming stream already finished.
-    fn stop(&mut self, error_code: u16);
+    fn stop(&mut self, error_code: u64);
+}
+
+/// Future produced by `read` on a stream
+pub struct ReadFuture<'a, T> {
+    stream: &'a mut T,
+    buf: &'a mut [u8],
+}
+
+impl<'a, T: Read> Future for ReadFuture<'a, T> {
+    type Output = Result<usize, ReadError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.stream.poll_read(cx, this.buf)
+    }
+}
+
+/// Future produced by `read_unordered` on a stream
+pub struct ReadUnorderedFuture<'a, T> {
+    stream: &'a mut T,
+}
+
+impl<'a, T: Read> Future for ReadUnorderedFuture<'a, T> {
+    type Output = Result<(Bytes, u64), ReadError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.stream.poll_read_unordered(cx)
+    }
 }
 
 /// Errors that arise from reading from a stream.
@@ -1208,7 +1670,7 @@ pub enum ReadError {
     #[error(display = "stream reset by peer: error {}", error_code)]
     Reset {
         /// The error code supplied by the peer.
-        error_code: u16,
+        error_code: u64,
     },
     /// The data on this stream has been fully delivered and no more will be transmitted.
     #[error(display = "the stream has been completely received")]
@@ -1224,13 +1686,13 @@ pub trait Write {
     ///
     /// Returns the number of bytes written on success. Congestion and flow control may cause this
     /// to be shorter than `buf.len()`, indicating that only a prefix of `buf` was written.
-    fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError>;
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, WriteError>>;
 
     /// Shut down the send stream gracefully.
     ///
     /// No new data may be written after calling this method. Completes when the peer has
     /// acknowledged all sent data, retransmitting data as needed.
-    fn poll_finish(&mut self) -> Poll<(), ConnectionError>;
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectionError>>;
 
     /// Close the send stream immediately.
     ///
@@ -1238,7 +1700,60 @@ pub trait Write {
     /// and previously transmitted data will no longer be retransmitted if lost. If `poll_finish`
     /// was called previously and all data has already been transmitted at least once, the peer
     /// may still receive all written data.
-    fn reset(&mut self, error_code: u16);
+    fn reset(&mut self, error_code: u64);
+}
+
+/// Future produced by `write` on a stream
+pub struct WriteFuture<'a, T> {
+    stream: &'a mut T,
+    buf: &'a [u8],
+}
+
+impl<'a, T: Write> Future for WriteFuture<'a, T> {
+    type Output = Result<usize, WriteError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.stream.poll_write(cx, this.buf)
+    }
+}
+
+/// Future produced by `write_all` on a stream
+pub struct WriteAllFuture<'a, T> {
+    stream: &'a mut T,
+    buf: &'a [u8],
+}
+
+impl<'a, T: Write> Future for WriteAllFuture<'a, T> {
+    type Output = Result<(), WriteError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        while !this.buf.is_empty() {
+            match this.stream.poll_write(cx, this.buf) {
+                Poll::Ready(Ok(n)) => {
+                    this.buf = &this.buf[n..];
+                }
+                Poll::Ready(Err(e)) => {
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => {
+                    return Poll::Pending;
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future produced by `finish` on a stream
+pub struct FinishFuture<'a, T> {
+    stream: &'a mut T,
+}
+
+impl<'a, T: Write> Future for FinishFuture<'a, T> {
+    type Output = Result<(), ConnectionError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.stream.poll_finish(cx)
+    }
 }
 
 /// Errors that arise from writing to a stream
@@ -1248,13 +1763,26 @@ pub enum WriteError {
     #[error(display = "sending stopped by peer: error {}", error_code)]
     Stopped {
         /// The error code supplied by the peer.
-        error_code: u16,
+        error_code: u64,
     },
     /// The connection was closed.
     #[error(display = "connection closed: {}", _0)]
     ConnectionClosed(ConnectionError),
 }
 
+/// Choose the socket to send a packet for `destination` from, given its `Transmit::socket`
+///
+/// Connections use the socket their peer last reached them on. Those yet to hear from their peer
+/// use the first socket of the destination's address family.
+fn route(sockets: &[EndpointSocket], socket: Option<usize>, destination: &SocketAddr) -> usize {
+    socket.unwrap_or_else(|| {
+        sockets
+            .iter()
+            .position(|x| x.ipv6 == destination.is_ipv6())
+            .expect("connections are only made to destinations a socket can reach")
+    })
+}
+
 fn ensure_ipv6(x: SocketAddr) -> SocketAddrV6 {
     match x {
         SocketAddr::V6(x) => x,
@@ -0,0 +1,466 @@
+//! An in-memory datagram network with a simulated clock, for testing without OS sockets
+//!
+//! A `Network` supplies the sockets and timers of the endpoints attached to it, and runs the tasks
+//! driving them. Whenever every task is blocked, its clock skips ahead to the next timer or
+//! datagram arrival, so simulated delays cost no real time, and since random impairments are drawn
+//! from a fixed seed, a given exchange always plays out the same way.
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use quinn::loopback::{Conditions, Network};
+//! let network = Network::new(Conditions::default());
+//! let start = network.now();
+//! network.block_on(async {
+//!     network.sleep(Duration::from_secs(60)).await;
+//! });
+//! assert_eq!(network.now() - start, Duration::from_secs(60));
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+use quinn_proto::{EcnCodepoint, Transmit};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::runtime::{AsyncTimer, AsyncUdpSocket, RecvMeta, Runtime};
+
+/// Impairments applied to every datagram crossing a `Network`
+#[derive(Debug, Clone)]
+pub struct Conditions {
+    /// Time taken for a datagram to reach its destination
+    pub delay: Duration,
+    /// Upper bound on extra delay, drawn at random for each datagram, reordering those sent
+    /// closer together than this
+    pub jitter: Duration,
+    /// Probability of a datagram being dropped
+    pub loss: f64,
+    /// Bytes per second that may be delivered to each socket, or `None` for no limit
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            bandwidth: None,
+        }
+    }
+}
+
+/// A simulated network connecting any number of sockets, and an executor for the tasks using it
+///
+/// Endpoints must be built with the `Runtime` returned by `runtime`, so that their timers follow
+/// the network's clock, and driven by tasks passed to `spawn`. Spawned tasks run on the current
+/// thread whenever `block_on` does, and live as long as the network.
+#[derive(Clone)]
+pub struct Network {
+    state: Arc<Mutex<State>>,
+    /// IDs of tasks to be polled, in the order they were woken
+    ready: Arc<Mutex<VecDeque<u64>>>,
+    tasks: Rc<RefCell<Tasks>>,
+}
+
+impl Network {
+    /// Construct a network on which datagrams are impaired by `conditions`
+    pub fn new(conditions: Conditions) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                now: Instant::now(),
+                conditions,
+                rng: StdRng::seed_from_u64(0),
+                next_port: 1,
+                next_seq: 0,
+                inboxes: FnvHashMap::default(),
+                wakeups: Wakeups::default(),
+            })),
+            ready: Arc::new(Mutex::new(VecDeque::new())),
+            tasks: Rc::new(RefCell::new(Tasks {
+                next_id: MAIN + 1,
+                idle: FnvHashMap::default(),
+            })),
+        }
+    }
+
+    /// Attach a new socket to the network at an unused address
+    pub fn socket(&self) -> Box<dyn AsyncUdpSocket> {
+        let state = &mut *lock(&self.state);
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), state.next_port);
+        state.next_port += 1;
+        state.inboxes.insert(
+            addr,
+            Inbox {
+                queue: BTreeMap::new(),
+                link_free: state.now,
+                reader: None,
+                wakeup: None,
+            },
+        );
+        Box::new(Socket {
+            state: self.state.clone(),
+            addr,
+        })
+    }
+
+    /// A `Runtime` supplying timers that follow the network's clock
+    ///
+    /// The runtime can't wrap OS sockets; attach endpoints to the network with
+    /// `EndpointBuilder::from_transport` and `socket` instead.
+    pub fn runtime(&self) -> Arc<dyn Runtime> {
+        Arc::new(Clock(self.state.clone()))
+    }
+
+    /// The current time on the network's clock
+    pub fn now(&self) -> Instant {
+        lock(&self.state).now
+    }
+
+    /// Move the network's clock forward by `duration`, expiring any timers passed over
+    pub fn advance(&self, duration: Duration) {
+        let deadline = lock(&self.state).now + duration;
+        self.advance_to(deadline);
+    }
+
+    /// Wait for `duration` to pass on the network's clock
+    pub fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        let deadline = self.now() + duration;
+        Timer {
+            state: self.state.clone(),
+            deadline,
+            wakeup: None,
+        }
+    }
+
+    /// Run `future` in the background of calls to `block_on`
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) {
+        let tasks = &mut *self.tasks.borrow_mut();
+        let id = tasks.next_id;
+        tasks.next_id += 1;
+        tasks.idle.insert(id, Box::pin(future));
+        wake(&self.ready, id);
+    }
+
+    /// Run `future` and any spawned tasks to completion of the former
+    ///
+    /// # Panics
+    /// - If every task is blocked, and no timer or datagram could wake any of them
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        wake(&self.ready, MAIN);
+        loop {
+            let next = lock(&self.ready).pop_front();
+            let id = match next {
+                Some(x) => x,
+                None => {
+                    let next = lock(&self.state).wakeups.queue.keys().next().map(|x| x.0);
+                    match next {
+                        Some(x) => self.advance_to(x),
+                        None => panic!("every task is blocked forever"),
+                    }
+                    continue;
+                }
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                id,
+                ready: self.ready.clone(),
+            }));
+            let cx = &mut Context::from_waker(&waker);
+            if id == MAIN {
+                if let Poll::Ready(x) = future.as_mut().poll(cx) {
+                    return x;
+                }
+                continue;
+            }
+            // Tasks already being polled or complete have nothing to do
+            let task = self.tasks.borrow_mut().idle.remove(&id);
+            if let Some(mut task) = task {
+                if task.as_mut().poll(cx).is_pending() {
+                    self.tasks.borrow_mut().idle.insert(id, task);
+                }
+            }
+        }
+    }
+
+    fn advance_to(&self, deadline: Instant) {
+        let mut woken = Vec::new();
+        {
+            let state = &mut *lock(&self.state);
+            state.now = state.now.max(deadline);
+            while let Some(&key) = state.wakeups.queue.keys().next() {
+                if key.0 > state.now {
+                    break;
+                }
+                woken.push(state.wakeups.queue.remove(&key).unwrap());
+            }
+        }
+        for waker in woken {
+            waker.wake();
+        }
+    }
+}
+
+/// Task ID of the future passed to `Network::block_on`
+const MAIN: u64 = 0;
+
+struct Tasks {
+    next_id: u64,
+    /// Spawned tasks not currently being polled
+    idle: FnvHashMap<u64, Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+struct TaskWaker {
+    id: u64,
+    ready: Arc<Mutex<VecDeque<u64>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        wake(&self.ready, self.id);
+    }
+}
+
+/// Queue task `id` to be polled, unless it already is
+fn wake(ready: &Mutex<VecDeque<u64>>, id: u64) {
+    let ready = &mut *lock(ready);
+    if !ready.contains(&id) {
+        ready.push_back(id);
+    }
+}
+
+struct State {
+    now: Instant,
+    conditions: Conditions,
+    rng: StdRng,
+    next_port: u16,
+    /// Tiebreaker for datagrams due at the same time, preserving send order
+    next_seq: u64,
+    inboxes: FnvHashMap<SocketAddr, Inbox>,
+    wakeups: Wakeups,
+}
+
+/// Tasks to wake once the clock reaches a certain time
+#[derive(Default)]
+struct Wakeups {
+    /// Tiebreaker for wakeups due at the same time, preserving the order they were scheduled in
+    next_seq: u64,
+    queue: BTreeMap<(Instant, u64), Waker>,
+}
+
+impl Wakeups {
+    /// Arrange for `waker` to be woken at `time`, replacing the wakeup previously stored in `slot`
+    fn set(&mut self, slot: &mut Option<(Instant, u64)>, time: Instant, waker: &Waker) {
+        self.clear(slot);
+        let key = (time, self.next_seq);
+        self.next_seq += 1;
+        self.queue.insert(key, waker.clone());
+        *slot = Some(key);
+    }
+
+    fn clear(&mut self, slot: &mut Option<(Instant, u64)>) {
+        if let Some(key) = slot.take() {
+            self.queue.remove(&key);
+        }
+    }
+}
+
+struct Inbox {
+    /// Datagrams in flight, by arrival time
+    queue: BTreeMap<(Instant, u64), Datagram>,
+    /// When the link to this socket finishes delivering the data already sent over it
+    link_free: Instant,
+    reader: Option<Waker>,
+    /// Wakes the reader when the next datagram arrives
+    wakeup: Option<(Instant, u64)>,
+}
+
+struct Datagram {
+    source: SocketAddr,
+    ecn: Option<EcnCodepoint>,
+    data: Box<[u8]>,
+}
+
+struct Clock(Arc<Mutex<State>>);
+
+impl Runtime for Clock {
+    fn now(&self) -> Instant {
+        lock(&self.0).now
+    }
+
+    fn new_timer(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(Timer {
+            state: self.0.clone(),
+            deadline,
+            wakeup: None,
+        })
+    }
+
+    fn wrap_udp_socket(&self, _: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "OS sockets can't be attached to a loopback network",
+        ))
+    }
+}
+
+struct Timer {
+    state: Arc<Mutex<State>>,
+    deadline: Instant,
+    wakeup: Option<(Instant, u64)>,
+}
+
+impl AsyncTimer for Timer {
+    fn reset(self: Pin<&mut Self>, deadline: Instant) {
+        self.get_mut().deadline = deadline;
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let state = &mut *lock(&this.state);
+        if state.now >= this.deadline {
+            return Poll::Ready(());
+        }
+        state
+            .wakeups
+            .set(&mut this.wakeup, this.deadline, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        lock(&self.state).wakeups.clear(&mut self.wakeup);
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        AsyncTimer::poll(self, cx)
+    }
+}
+
+struct Socket {
+    state: Arc<Mutex<State>>,
+    addr: SocketAddr,
+}
+
+impl Socket {
+    fn send(&self, destination: &SocketAddr, ecn: Option<EcnCodepoint>, datagram: &[u8]) {
+        let state = &mut *lock(&self.state);
+        let now = state.now;
+        let lost = state.rng.gen::<f64>() < state.conditions.loss;
+        let jitter = state.conditions.jitter.as_nanos() as f64 * state.rng.gen::<f64>();
+        // As with UDP, datagrams which are lost or sent to a missing peer go unreported
+        let inbox = match state.inboxes.get_mut(destination) {
+            Some(x) if !lost => x,
+            _ => return,
+        };
+        let mut arrival = now;
+        if let Some(bandwidth) = state.conditions.bandwidth {
+            let transmit = datagram.len() as u64 * 1_000_000_000 / bandwidth;
+            inbox.link_free = inbox.link_free.max(now) + Duration::from_nanos(transmit);
+            arrival = inbox.link_free;
+        }
+        arrival += state.conditions.delay + Duration::from_nanos(jitter as u64);
+        inbox.queue.insert(
+            (arrival, state.next_seq),
+            Datagram {
+                source: self.addr,
+                ecn,
+                data: datagram.into(),
+            },
+        );
+        state.next_seq += 1;
+        if let Some(x) = inbox.reader.take() {
+            x.wake();
+        }
+    }
+}
+
+impl AsyncUdpSocket for Socket {
+    fn poll_send(&self, _: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        for transmit in transmits {
+            let segment_size = transmit
+                .segment_size
+                .unwrap_or_else(|| transmit.contents.len());
+            for datagram in transmit.contents.chunks(segment_size) {
+                self.send(&transmit.destination, transmit.ecn, datagram);
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let state = &mut *lock(&self.state);
+        let now = state.now;
+        let wakeups = &mut state.wakeups;
+        let inbox = state.inboxes.get_mut(&self.addr).unwrap();
+        let mut filled = 0;
+        while filled < bufs.len() {
+            let next = match inbox.queue.keys().next() {
+                Some(&x) if x.0 <= now => x,
+                _ => break,
+            };
+            let datagram = inbox.queue.remove(&next).unwrap();
+            let buf = &mut bufs[filled];
+            let len = datagram.data.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram.data[..len]);
+            meta[filled] = RecvMeta {
+                addr: datagram.source,
+                len,
+                stride: len,
+                ecn: datagram.ecn,
+                dst_ip: Some(self.addr.ip()),
+                interface: None,
+            };
+            filled += 1;
+        }
+        if filled > 0 {
+            return Poll::Ready(Ok(filled));
+        }
+        // Wake up when the next datagram arrives, or earlier if another overtakes it
+        inbox.reader = Some(cx.waker().clone());
+        if let Some(&(arrival, _)) = inbox.queue.keys().next() {
+            wakeups.set(&mut inbox.wakeup, arrival, cx.waker());
+        }
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        // Like a socket with segmentation offload
+        16
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let state = &mut *lock(&self.state);
+        if let Some(mut inbox) = state.inboxes.remove(&self.addr) {
+            state.wakeups.clear(&mut inbox.wakeup);
+        }
+    }
+}
+
+fn lock<T>(x: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    x.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -1,26 +1,63 @@
 use std::{mem, ptr};
 
-pub fn encode<T: Copy + ?Sized>(
-    hdr: &mut libc::msghdr,
-    buf: &mut [u8],
-    level: libc::c_int,
-    ty: libc::c_int,
-    value: T,
-) {
-    assert!(mem::align_of::<T>() <= mem::align_of::<libc::cmsghdr>());
-    let space = unsafe { libc::CMSG_SPACE(mem::size_of_val(&value) as _) as usize };
-    assert!(buf.len() >= space);
-    hdr.msg_control = buf.as_mut_ptr() as _;
-    hdr.msg_controllen = buf.len() as _;
-
-    let mut cmsg = unsafe { &mut *libc::CMSG_FIRSTHDR(hdr) };
-    cmsg.cmsg_level = level;
-    cmsg.cmsg_type = ty;
-    cmsg.cmsg_len = unsafe { libc::CMSG_LEN(mem::size_of_val(&value) as _) } as _;
-    unsafe {
-        ptr::write(libc::CMSG_DATA(cmsg) as *const T as *mut T, value);
+/// Control message buffer, aligned for the `cmsghdr`s it will hold
+#[derive(Copy, Clone)]
+#[repr(align(8))]
+pub struct Aligned<T>(pub T);
+
+/// Helper to encode a series of control messages for `sendmsg`
+///
+/// `hdr.msg_controllen` is only updated once the `Encoder` is finished or dropped.
+pub struct Encoder<'a> {
+    hdr: &'a mut libc::msghdr,
+    cmsg: Option<&'a mut libc::cmsghdr>,
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    /// # Safety
+    ///
+    /// `hdr.msg_control` must point to zeroed memory containing at least `hdr.msg_controllen`
+    /// bytes, aligned for `libc::cmsghdr`, which lives at least as long as `'a`.
+    pub unsafe fn new(hdr: &'a mut libc::msghdr) -> Self {
+        Self {
+            cmsg: libc::CMSG_FIRSTHDR(hdr).as_mut(),
+            hdr,
+            len: 0,
+        }
+    }
+
+    /// Append a control message
+    ///
+    /// Panics if the buffer is too small.
+    pub fn push<T: Copy>(&mut self, level: libc::c_int, ty: libc::c_int, value: T) {
+        assert!(mem::align_of::<T>() <= mem::align_of::<libc::cmsghdr>());
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of_val(&value) as _) as usize };
+        assert!(
+            self.hdr.msg_controllen as usize >= self.len + space,
+            "control message buffer too small"
+        );
+        let cmsg = self.cmsg.take().expect("control message buffer too small");
+        cmsg.cmsg_level = level;
+        cmsg.cmsg_type = ty;
+        cmsg.cmsg_len = unsafe { libc::CMSG_LEN(mem::size_of_val(&value) as _) } as _;
+        unsafe {
+            ptr::write(libc::CMSG_DATA(cmsg) as *const T as *mut T, value);
+        }
+        self.len += space;
+        self.cmsg = unsafe { libc::CMSG_NXTHDR(self.hdr, cmsg).as_mut() };
+    }
+
+    /// Set `hdr.msg_controllen` to cover the messages pushed
+    pub fn finish(self) {
+        // Handled by `Drop`
+    }
+}
+
+impl<'a> Drop for Encoder<'a> {
+    fn drop(&mut self) {
+        self.hdr.msg_controllen = self.len as _;
     }
-    hdr.msg_controllen = space as _;
 }
 
 pub unsafe fn decode<T: Copy>(cmsg: &libc::cmsghdr) -> T {
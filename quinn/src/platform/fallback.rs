@@ -1,24 +1,46 @@
-use std::{io, net::SocketAddr};
+use std::io;
+use std::net::SocketAddr;
 
 use mio::net::UdpSocket;
 
-use quinn_proto::EcnCodepoint;
+use quinn_proto::Transmit;
+
+use crate::runtime::RecvMeta;
 
 impl super::UdpExt for UdpSocket {
     fn init_ext(&self) -> io::Result<()> {
         Ok(())
     }
 
-    fn send_ext(
-        &self,
-        remote: &SocketAddr,
-        _: Option<EcnCodepoint>,
-        msg: &[u8],
-    ) -> io::Result<usize> {
-        self.send_to(msg, remote)
+    fn max_gso_segments(&self) -> usize {
+        1
+    }
+
+    fn send_ext(&self, transmit: &Transmit) -> io::Result<usize> {
+        debug_assert!(
+            transmit.segment_size.is_none(),
+            "segmentation offload unsupported"
+        );
+        self.send_to(&transmit.contents, &transmit.destination)
     }
 
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)> {
-        self.recv_from(buf).map(|(x, y)| (x, y, None))
+    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<RecvMeta> {
+        let (len, addr) = self.recv_from(buf)?;
+        Ok(RecvMeta {
+            addr,
+            len,
+            stride: len,
+            ecn: None,
+            dst_ip: None,
+            interface: None,
+        })
     }
 }
+
+/// `SO_REUSEPORT` only balances datagrams across sockets on Linux
+pub fn bind_reuseport(_: &SocketAddr) -> io::Result<std::net::UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "load-balanced socket sharing is unsupported on this platform",
+    ))
+}
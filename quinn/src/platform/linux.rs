@@ -1,105 +1,71 @@
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::{
-    io, mem,
-    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    io::{self, IoSliceMut},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     ptr,
 };
 
 use mio::net::UdpSocket;
 
-use quinn_proto::EcnCodepoint;
+use quinn_proto::{EcnCodepoint, Transmit};
 
 use super::cmsg;
+use crate::runtime::RecvMeta;
+use crate::BATCH_SIZE;
 
 impl super::UdpExt for UdpSocket {
     fn init_ext(&self) -> io::Result<()> {
-        // Safety
-        assert_eq!(
-            mem::size_of::<SocketAddrV4>(),
-            mem::size_of::<libc::sockaddr_in>()
+        assert!(
+            CMSG_LEN
+                >= 2 * unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as _) as usize }
+                    + unsafe {
+                        libc::CMSG_SPACE(mem::size_of::<libc::in6_pktinfo>() as _) as usize
+                    }
         );
-        assert_eq!(
-            mem::size_of::<SocketAddrV6>(),
-            mem::size_of::<libc::sockaddr_in6>()
-        );
-        assert_eq!(CMSG_LEN, unsafe {
-            libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as _) as usize
-        });
 
         let addr = self.local_addr()?;
 
         if addr.is_ipv4() || !self.only_v6()? {
-            let rc = unsafe {
-                libc::setsockopt(
-                    self.as_raw_fd(),
-                    libc::IPPROTO_IP,
-                    libc::IP_RECVTOS,
-                    &true as *const _ as _,
-                    1,
-                )
-            };
-            if rc == -1 {
-                return Err(io::Error::last_os_error());
-            }
+            set_socket_option(self, libc::IPPROTO_IP, libc::IP_RECVTOS)?;
+            set_socket_option(self, libc::IPPROTO_IP, libc::IP_PKTINFO)?;
         }
         if addr.is_ipv6() {
-            let on: libc::c_int = 1;
-            let rc = unsafe {
-                libc::setsockopt(
-                    self.as_raw_fd(),
-                    libc::IPPROTO_IPV6,
-                    libc::IPV6_RECVTCLASS,
-                    &on as *const _ as _,
-                    mem::size_of::<libc::c_int>() as _,
-                )
-            };
-            if rc == -1 {
-                return Err(io::Error::last_os_error());
-            }
+            set_socket_option(self, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS)?;
+            set_socket_option(self, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)?;
         }
+        // Have the kernel coalesce incoming datagrams where it can. Older kernels will deliver them
+        // one at a time regardless.
+        let _ = set_socket_option(self, libc::SOL_UDP, UDP_GRO);
         Ok(())
     }
 
-    fn send_ext(
-        &self,
-        remote: &SocketAddr,
-        ecn: Option<EcnCodepoint>,
-        msg: &[u8],
-    ) -> io::Result<usize> {
-        let (name, namelen) = match *remote {
-            SocketAddr::V4(ref addr) => {
-                (addr as *const _ as _, mem::size_of::<libc::sockaddr_in>())
-            }
-            SocketAddr::V6(ref addr) => {
-                (addr as *const _ as _, mem::size_of::<libc::sockaddr_in6>())
-            }
-        };
-        let ecn = ecn.map_or(0, |x| x as libc::c_int);
-        let mut iov = libc::iovec {
-            iov_base: msg.as_ptr() as *const _ as *mut _,
-            iov_len: msg.len(),
-        };
-        let mut hdr = libc::msghdr {
-            msg_name: name,
-            msg_namelen: namelen as _,
-            msg_iov: &mut iov,
-            msg_iovlen: 1,
-            msg_control: ptr::null_mut(),
-            msg_controllen: 0,
-            msg_flags: 0,
+    fn max_gso_segments(&self) -> usize {
+        // Kernels without segmentation offload (pre-4.18) don't recognize the option
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                self.as_raw_fd(),
+                libc::SOL_UDP,
+                UDP_SEGMENT,
+                &mut value as *mut _ as _,
+                &mut len,
+            )
         };
-        let mut ctrl: [u8; CMSG_LEN] = unsafe { mem::uninitialized() };
-        if remote.is_ipv4() {
-            cmsg::encode(&mut hdr, &mut ctrl, libc::IPPROTO_IP, libc::IP_TOS, ecn);
+        if rc == -1 {
+            1
         } else {
-            cmsg::encode(
-                &mut hdr,
-                &mut ctrl,
-                libc::IPPROTO_IPV6,
-                libc::IPV6_TCLASS,
-                ecn,
-            );
+            MAX_GSO_SEGMENTS
         }
+    }
+
+    fn send_ext(&self, transmit: &Transmit) -> io::Result<usize> {
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        let mut iov: libc::iovec = unsafe { mem::zeroed() };
+        let mut ctrl = cmsg::Aligned([0u8; CMSG_LEN]);
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        prepare_msg(transmit, &mut hdr, &mut iov, &mut ctrl, &mut name);
         loop {
             let n = unsafe { libc::sendmsg(self.as_raw_fd(), &hdr, 0) };
             if n == -1 {
@@ -113,19 +79,19 @@ impl super::UdpExt for UdpSocket {
         }
     }
 
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)> {
-        let mut name: libc::sockaddr_storage = unsafe { mem::uninitialized() };
+    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<RecvMeta> {
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
         let mut iov = libc::iovec {
             iov_base: buf.as_ptr() as *mut _,
             iov_len: buf.len(),
         };
-        let mut ctrl: [u8; CMSG_LEN] = unsafe { mem::uninitialized() };
+        let mut ctrl = cmsg::Aligned([0u8; CMSG_LEN]);
         let mut hdr = libc::msghdr {
             msg_name: &mut name as *mut _ as _,
             msg_namelen: mem::size_of::<libc::sockaddr_storage>() as _,
             msg_iov: &mut iov,
             msg_iovlen: 1,
-            msg_control: ctrl.as_mut_ptr() as _,
+            msg_control: ctrl.0.as_mut_ptr() as _,
             msg_controllen: CMSG_LEN as _,
             msg_flags: 0,
         };
@@ -140,24 +106,280 @@ impl super::UdpExt for UdpSocket {
             }
             break n;
         };
-        let ecn_bits = if let Some(cmsg) = unsafe { cmsg::Iter::new(&hdr).next() } {
-            match (cmsg.cmsg_level, cmsg.cmsg_type) {
-                (libc::IPPROTO_IP, libc::IP_TOS) => unsafe { cmsg::decode::<u8>(cmsg) },
-                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => unsafe {
-                    cmsg::decode::<libc::c_int>(cmsg) as u8
-                },
-                _ => 0,
+        Ok(decode_recv(&name, &hdr, n as usize))
+    }
+
+    fn send_batch_ext(&self, transmits: &[Transmit]) -> io::Result<usize> {
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut ctrls = [cmsg::Aligned([0u8; CMSG_LEN]); BATCH_SIZE];
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let n = transmits.len().min(BATCH_SIZE);
+        for (i, transmit) in transmits.iter().take(n).enumerate() {
+            prepare_msg(
+                transmit,
+                &mut msgs[i].msg_hdr,
+                &mut iovs[i],
+                &mut ctrls[i],
+                &mut names[i],
+            );
+        }
+        loop {
+            let n = unsafe { libc::sendmmsg(self.as_raw_fd(), msgs.as_mut_ptr(), n as _, 0) };
+            if n == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
             }
-        } else {
-            0
-        };
-        let addr = match name.ss_family as libc::c_int {
-            libc::AF_INET => unsafe { SocketAddr::V4(ptr::read(&name as *const _ as _)) },
-            libc::AF_INET6 => unsafe { SocketAddr::V6(ptr::read(&name as *const _ as _)) },
-            _ => unreachable!(),
+            return Ok(n as usize);
+        }
+    }
+
+    fn recv_batch_ext(
+        &self,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> io::Result<usize> {
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut ctrls = [cmsg::Aligned([0u8; CMSG_LEN]); BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let max_msg_count = bufs.len().min(meta.len()).min(BATCH_SIZE);
+        for i in 0..max_msg_count {
+            let hdr = &mut hdrs[i].msg_hdr;
+            hdr.msg_name = &mut names[i] as *mut _ as _;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            // `IoSliceMut` is guaranteed to be ABI compatible with `iovec`
+            hdr.msg_iov = &mut bufs[i] as *mut IoSliceMut<'_> as *mut libc::iovec;
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = ctrls[i].0.as_mut_ptr() as _;
+            hdr.msg_controllen = CMSG_LEN as _;
+        }
+        let msg_count = loop {
+            let n = unsafe {
+                libc::recvmmsg(
+                    self.as_raw_fd(),
+                    hdrs.as_mut_ptr(),
+                    max_msg_count as _,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+            if n == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            break n as usize;
         };
-        Ok((n as usize, addr, EcnCodepoint::from_bits(ecn_bits)))
+        for i in 0..msg_count {
+            meta[i] = decode_recv(&names[i], &hdrs[i].msg_hdr, hdrs[i].msg_len as usize);
+        }
+        Ok(msg_count)
+    }
+}
+
+/// Fill in `hdr` to send `transmit`, using `iov`, `ctrl` and `name` as storage
+///
+/// `hdr` refers to `transmit`, `iov`, `ctrl` and `name` by pointer, so must not outlive them.
+fn prepare_msg(
+    transmit: &Transmit,
+    hdr: &mut libc::msghdr,
+    iov: &mut libc::iovec,
+    ctrl: &mut cmsg::Aligned<[u8; CMSG_LEN]>,
+    name: &mut libc::sockaddr_storage,
+) {
+    iov.iov_base = transmit.contents.as_ptr() as *const _ as *mut _;
+    iov.iov_len = transmit.contents.len();
+    hdr.msg_namelen = encode_sockaddr(&transmit.destination, name);
+    hdr.msg_name = name as *mut _ as _;
+    hdr.msg_iov = iov;
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = ctrl.0.as_mut_ptr() as _;
+    hdr.msg_controllen = CMSG_LEN as _;
+    let mut encoder = unsafe { cmsg::Encoder::new(hdr) };
+    let ecn = transmit.ecn.map_or(0, |x| x as libc::c_int);
+    if transmit.destination.is_ipv4() {
+        encoder.push(libc::IPPROTO_IP, libc::IP_TOS, ecn);
+    } else {
+        encoder.push(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, ecn);
+    }
+    if let Some(segment_size) = transmit.segment_size {
+        encoder.push(libc::SOL_UDP, UDP_SEGMENT, segment_size as u16);
     }
+    // IPv4 sources are valid for IPv4-mapped destinations on dual-stack sockets too
+    let interface = transmit.src.and_then(|x| x.interface).unwrap_or(0);
+    match transmit.src.map(|x| x.ip) {
+        Some(IpAddr::V4(ip)) => {
+            let pktinfo = libc::in_pktinfo {
+                ipi_ifindex: interface as _,
+                ipi_spec_dst: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(ip.octets()),
+                },
+                ipi_addr: libc::in_addr { s_addr: 0 },
+            };
+            encoder.push(libc::IPPROTO_IP, libc::IP_PKTINFO, pktinfo);
+        }
+        Some(IpAddr::V6(ip)) => {
+            let pktinfo = libc::in6_pktinfo {
+                ipi6_ifindex: interface as _,
+                ipi6_addr: libc::in6_addr {
+                    s6_addr: ip.octets(),
+                },
+            };
+            encoder.push(libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, pktinfo);
+        }
+        None => {}
+    }
+    encoder.finish();
 }
 
-const CMSG_LEN: usize = 24;
+/// Interpret the source address and control messages of a received message of `len` bytes
+fn decode_recv(name: &libc::sockaddr_storage, hdr: &libc::msghdr, len: usize) -> RecvMeta {
+    let mut ecn_bits = 0;
+    let mut stride = len;
+    let mut dst_ip = None;
+    let mut interface = None;
+    for cmsg in unsafe { cmsg::Iter::new(hdr) } {
+        match (cmsg.cmsg_level, cmsg.cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_TOS) => unsafe {
+                ecn_bits = cmsg::decode::<u8>(cmsg);
+            },
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => unsafe {
+                ecn_bits = cmsg::decode::<libc::c_int>(cmsg) as u8;
+            },
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let pktinfo = unsafe { cmsg::decode::<libc::in_pktinfo>(cmsg) };
+                dst_ip = Some(Ipv4Addr::from(pktinfo.ipi_addr.s_addr.to_ne_bytes()).into());
+                interface = Some(pktinfo.ipi_ifindex as u32);
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let pktinfo = unsafe { cmsg::decode::<libc::in6_pktinfo>(cmsg) };
+                dst_ip = Some(Ipv6Addr::from(pktinfo.ipi6_addr.s6_addr).into());
+                interface = Some(pktinfo.ipi6_ifindex);
+            }
+            (libc::SOL_UDP, UDP_GRO) => unsafe {
+                stride = cmsg::decode::<libc::c_int>(cmsg) as usize;
+            },
+            _ => {}
+        }
+    }
+    RecvMeta {
+        addr: decode_sockaddr(name),
+        len,
+        stride,
+        ecn: EcnCodepoint::from_bits(ecn_bits),
+        dst_ip,
+        interface,
+    }
+}
+
+/// Write `addr` into `storage` in the form the kernel expects, returning its length
+fn encode_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            let sin = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as _;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(addr.ip().octets()),
+            };
+            mem::size_of::<libc::sockaddr_in>() as _
+        }
+        SocketAddr::V6(ref addr) => {
+            let sin6 = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as _;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as _
+        }
+    }
+}
+
+fn decode_sockaddr(storage: &libc::sockaddr_storage) -> SocketAddr {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )
+            .into()
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )
+            .into()
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Bind a socket to `addr` which other sockets bound the same way share, by way of `SO_REUSEPORT`
+///
+/// The kernel spreads incoming datagrams across such sockets by hashing their addresses.
+pub fn bind_reuseport(addr: &SocketAddr) -> io::Result<std::net::UdpSocket> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Closes the descriptor on failure
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    set_socket_option(&socket, libc::SOL_SOCKET, libc::SO_REUSEPORT)?;
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = encode_sockaddr(addr, &mut name);
+    if unsafe { libc::bind(fd, &name as *const _ as _, len) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Enable a boolean socket option
+fn set_socket_option(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const _ as _,
+            mem::size_of::<libc::c_int>() as _,
+        )
+    };
+    if rc == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Enough space for every control message we might send or receive, including both the IPv4 and
+/// IPv6 forms of those reported for IPv4 traffic on dual-stack sockets
+const CMSG_LEN: usize = 256;
+
+// Not exposed by libc
+const UDP_SEGMENT: libc::c_int = 103;
+const UDP_GRO: libc::c_int = 104;
+
+/// Largest number of datagrams to send at once, keeping batches of full-sized packets well within
+/// the 64KiB limit on a single UDP send
+const MAX_GSO_SEGMENTS: usize = 32;
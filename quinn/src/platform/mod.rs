@@ -1,6 +1,8 @@
 //! Uniform interface to send/recv UDP packets with ECN information.
-use quinn_proto::EcnCodepoint;
-use std::{io, net::SocketAddr};
+use quinn_proto::Transmit;
+use std::io::{self, IoSliceMut};
+
+use crate::runtime::RecvMeta;
 
 // The Linux code should work for most unixes, but as of this writing nobody's ported the
 // CMSG_... macros to the libc crate for any of the BSDs.
@@ -8,18 +10,49 @@ use std::{io, net::SocketAddr};
 mod cmsg;
 #[cfg(target_os = "linux")]
 mod linux;
+#[cfg(target_os = "linux")]
+pub use self::linux::bind_reuseport;
 
-// No ECN support
+// No ECN or source address support
 #[cfg(not(target_os = "linux"))]
 mod fallback;
+#[cfg(not(target_os = "linux"))]
+pub use self::fallback::bind_reuseport;
 
 pub trait UdpExt {
     fn init_ext(&self) -> io::Result<()>;
-    fn send_ext(
+    /// Number of datagrams `send_ext` can send at once
+    fn max_gso_segments(&self) -> usize;
+    /// Send `transmit`, split into datagrams of `transmit.segment_size` bytes if specified
+    fn send_ext(&self, transmit: &Transmit) -> io::Result<usize>;
+    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<RecvMeta>;
+
+    /// Send a prefix of `transmits`, returning the number sent
+    ///
+    /// Fails only if the first `Transmit` could not be sent.
+    fn send_batch_ext(&self, transmits: &[Transmit]) -> io::Result<usize> {
+        let mut sent = 0;
+        for transmit in transmits {
+            match self.send_ext(transmit) {
+                Ok(_) => sent += 1,
+                Err(e) => {
+                    if sent == 0 {
+                        return Err(e);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Receive into a prefix of `bufs`, describing each in `meta`, returning the number filled
+    fn recv_batch_ext(
         &self,
-        remote: &SocketAddr,
-        ecn: Option<EcnCodepoint>,
-        msg: &[u8],
-    ) -> io::Result<usize>;
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)>;
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> io::Result<usize> {
+        meta[0] = self.recv_ext(&mut bufs[0])?;
+        Ok(1)
+    }
 }
@@ -0,0 +1,131 @@
+//! Abstractions over the timers and sockets used to drive an endpoint
+//!
+//! An endpoint's `Driver` performs no I/O of its own, but polls the timers and UDP socket
+//! supplied by a `Runtime`, so that it can be run on any executor for which one is available. An
+//! implementation for tokio is provided by `TokioRuntime`, behind the `runtime-tokio` feature.
+
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::slice::Chunks;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use quinn_proto::{EcnCodepoint, Transmit};
+
+#[cfg(feature = "runtime-tokio")]
+mod tokio;
+#[cfg(feature = "runtime-tokio")]
+pub use self::tokio::TokioRuntime;
+
+/// Source of the timers and UDP sockets needed to drive an endpoint
+pub trait Runtime: Send + Sync + 'static {
+    /// The current time, as measured by the runtime's timers
+    ///
+    /// Runtimes simulating the passage of time must override this to match their timers.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Construct a timer that expires at `deadline`
+    fn new_timer(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>>;
+
+    /// Prepare `socket` to be polled by an endpoint's `Driver`
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>>;
+}
+
+/// A timer that can be polled for expiry and rescheduled
+pub trait AsyncTimer: Send + 'static {
+    /// Change the deadline, rearming the timer if it has already expired
+    fn reset(self: Pin<&mut Self>, deadline: Instant);
+
+    /// Check whether the deadline has passed, arranging for the task to be woken when it does if
+    /// not
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+/// A non-blocking UDP socket that can be polled by an endpoint's `Driver`
+///
+/// Need not be backed by an OS socket; see `EndpointBuilder::from_transport`. When an operation
+/// returns `Poll::Pending`, the task in `cx` must be woken once it may succeed. Sockets which
+/// can't read or write ECN codepoints, or choose the source address of each datagram, should
+/// ignore `ecn` and `src` when sending, and report `None` for them when receiving.
+pub trait AsyncUdpSocket: Send + 'static {
+    /// Send a prefix of `transmits`, returning the number of `Transmit`s sent
+    ///
+    /// `transmits` is never empty, and each will contain no more than `max_transmit_segments`
+    /// datagrams. A `Transmit` that is only partly sent, e.g. due to a full send buffer, may be
+    /// counted as sent, leaving the remainder to loss recovery.
+    fn poll_send(&self, cx: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>>;
+
+    /// Receive datagrams into a prefix of `bufs`, returning the number of buffers filled
+    ///
+    /// Each buffer may hold several datagrams from the same source, as described by the
+    /// corresponding element of `meta`, which is at least as long as `bufs`.
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>>;
+
+    /// The address the socket is bound to
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// The number of datagrams that can be sent by a single call to `poll_send`
+    ///
+    /// Sockets supporting segmentation offload, such as Linux's `UDP_SEGMENT`, may accept several
+    /// datagrams at once.
+    fn max_transmit_segments(&self) -> usize {
+        1
+    }
+}
+
+/// Metadata for the datagrams read by `AsyncUdpSocket::poll_recv`
+#[derive(Debug, Copy, Clone)]
+pub struct RecvMeta {
+    /// The source of the datagrams
+    pub addr: SocketAddr,
+    /// Total number of bytes read
+    pub len: usize,
+    /// The size of each datagram but the last, which may be shorter
+    ///
+    /// Equal to `len` when a single datagram was read.
+    pub stride: usize,
+    /// ECN codepoint the datagrams were marked with
+    pub ecn: Option<EcnCodepoint>,
+    /// The local address the datagrams were sent to, if known
+    ///
+    /// Replies are sent from this address, which matters for sockets bound to a wildcard address
+    /// on hosts with more than one.
+    pub dst_ip: Option<IpAddr>,
+    /// Index of the network interface the datagrams arrived on, if known
+    pub interface: Option<u32>,
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+            len: 0,
+            stride: 0,
+            ecn: None,
+            dst_ip: None,
+            interface: None,
+        }
+    }
+}
+
+impl RecvMeta {
+    /// The datagrams among the `len` bytes read into `buf`
+    ///
+    /// Empty datagrams carry nothing and are skipped, so a `stride` of zero is harmless.
+    pub(crate) fn datagrams<'a>(&self, buf: &'a [u8]) -> Chunks<'a, u8> {
+        let stride = if self.stride == 0 {
+            self.len
+        } else {
+            self.stride
+        };
+        buf[..self.len].chunks(stride.max(1))
+    }
+}
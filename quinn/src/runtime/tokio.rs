@@ -0,0 +1,90 @@
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::{Async, Future};
+use quinn_proto::Transmit;
+use tokio_reactor::Handle;
+use tokio_timer::Delay;
+
+use super::{AsyncTimer, AsyncUdpSocket, RecvMeta, Runtime};
+use crate::compat;
+use crate::udp::UdpSocket;
+
+/// Drives endpoints using the tokio 0.1 reactor and timer
+///
+/// The `Driver` must be run on a tokio runtime, e.g. by way of `futures_util::compat`.
+#[derive(Debug, Clone, Default)]
+pub struct TokioRuntime {
+    reactor: Handle,
+}
+
+impl TokioRuntime {
+    /// Register sockets with `reactor`, rather than that of the runtime the `Driver` is run on
+    pub fn with_reactor(reactor: Handle) -> Self {
+        Self { reactor }
+    }
+}
+
+impl Runtime for TokioRuntime {
+    fn new_timer(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(Timer(Delay::new(deadline)))
+    }
+
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Ok(Box::new(UdpSocket::from_std(socket, &self.reactor)?))
+    }
+}
+
+struct Timer(Delay);
+
+impl AsyncTimer for Timer {
+    fn reset(mut self: Pin<&mut Self>, deadline: Instant) {
+        self.0.reset(deadline)
+    }
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match compat::with_task(cx, || self.0.poll()) {
+            Ok(Async::Ready(())) => Poll::Ready(()),
+            Ok(Async::NotReady) => Poll::Pending,
+            Err(e) => panic!("unexpected timer error: {}", e),
+        }
+    }
+}
+
+impl AsyncUdpSocket for UdpSocket {
+    fn poll_send(&self, cx: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        poll_from(compat::with_task(cx, || {
+            UdpSocket::poll_send(self, transmits)
+        }))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        poll_from(compat::with_task(cx, || {
+            UdpSocket::poll_recv(self, bufs, meta)
+        }))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        UdpSocket::max_transmit_segments(self)
+    }
+}
+
+fn poll_from<T>(x: futures::Poll<T, io::Error>) -> Poll<io::Result<T>> {
+    match x {
+        Ok(Async::Ready(x)) => Poll::Ready(Ok(x)),
+        Ok(Async::NotReady) => Poll::Pending,
+        Err(e) => Poll::Ready(Err(e)),
+    }
+}
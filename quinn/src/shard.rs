@@ -0,0 +1,191 @@
+//! Routing of datagrams between the shards of an endpoint sharing a single address
+
+use std::collections::VecDeque;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use quinn_proto::{Config, Transmit};
+
+use crate::runtime::{AsyncUdpSocket, RecvMeta};
+
+/// Maximum number of datagrams waiting to be read by a shard, beyond which more are dropped as if
+/// by a full socket buffer
+const MAX_FORWARDED: usize = 1024;
+
+/// Wrap each shard's socket so that datagrams received by one shard for a connection owned by
+/// another are passed on to the owner
+///
+/// `sockets[i]` is read by shard `i`, and `config` supplies the shard count and connection ID
+/// length used to attribute datagrams to shards. Every shard must receive into slots of the same
+/// size, as datagrams passed on to a shard that can't hold them are dropped.
+pub(crate) fn connect(
+    sockets: Vec<Box<dyn AsyncUdpSocket>>,
+    config: Config,
+) -> Vec<Box<dyn AsyncUdpSocket>> {
+    let shared = Arc::new(Shared {
+        config,
+        inboxes: (0..sockets.len())
+            .map(|_| Mutex::new(Inbox::default()))
+            .collect(),
+    });
+    sockets
+        .into_iter()
+        .enumerate()
+        .map(|(shard, inner)| {
+            Box::new(ShardSocket {
+                inner,
+                shard: shard as u8,
+                shared: shared.clone(),
+                error: Mutex::new(None),
+            }) as Box<dyn AsyncUdpSocket>
+        })
+        .collect()
+}
+
+struct Shared {
+    config: Config,
+    /// Datagrams passed on to each shard
+    inboxes: Vec<Mutex<Inbox>>,
+}
+
+impl Shared {
+    fn push(&self, shard: u8, data: &[u8], meta: &RecvMeta) -> io::Result<()> {
+        let inbox = &mut *lock(&self.inboxes[shard as usize])?;
+        if inbox.queue.len() >= MAX_FORWARDED {
+            return Ok(());
+        }
+        inbox.queue.push_back(Forwarded {
+            meta: *meta,
+            data: data.into(),
+        });
+        if let Some(x) = inbox.reader.take() {
+            x.wake();
+        }
+        Ok(())
+    }
+}
+
+/// Lock `x`, failing if a thread panicked while holding it, as the state may be half-updated
+fn lock<T>(x: &Mutex<T>) -> io::Result<MutexGuard<'_, T>> {
+    x.lock()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "shard state poisoned by a panic"))
+}
+
+#[derive(Default)]
+struct Inbox {
+    queue: VecDeque<Forwarded>,
+    reader: Option<Waker>,
+}
+
+struct Forwarded {
+    meta: RecvMeta,
+    data: Box<[u8]>,
+}
+
+struct ShardSocket {
+    inner: Box<dyn AsyncUdpSocket>,
+    shard: u8,
+    shared: Arc<Shared>,
+    /// Failure to receive from `inner`, reported after the datagrams received before it
+    error: Mutex<Option<io::Error>>,
+}
+
+impl ShardSocket {
+    /// Pass the datagrams in `buf` on to the shards owning them, unless they're all ours
+    ///
+    /// Returns whether the datagrams were passed on.
+    fn forward(&self, buf: &[u8], meta: &RecvMeta) -> io::Result<bool> {
+        let owner = |x: &[u8]| self.shared.config.shard_of(x).unwrap_or(self.shard);
+        // Datagrams coalesced by receive offload share a peer address, but not necessarily a
+        // connection
+        if meta.datagrams(buf).all(|x| owner(x) == self.shard) {
+            return Ok(false);
+        }
+        for datagram in meta.datagrams(buf) {
+            self.shared.push(owner(datagram), datagram, meta)?;
+        }
+        Ok(true)
+    }
+}
+
+impl AsyncUdpSocket for ShardSocket {
+    fn poll_send(&self, cx: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        self.inner.poll_send(cx, transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(e) = lock(&self.error)?.take() {
+            return Poll::Ready(Err(e));
+        }
+        let mut filled = 0;
+        {
+            let inbox = &mut *lock(&self.shared.inboxes[self.shard as usize])?;
+            while filled < bufs.len() {
+                let datagram = match inbox.queue.pop_front() {
+                    Some(x) => x,
+                    None => break,
+                };
+                let buf = &mut bufs[filled];
+                let len = datagram.data.len();
+                if len > buf.len() {
+                    // Delivering part of a datagram would pass it off as complete
+                    continue;
+                }
+                buf[..len].copy_from_slice(&datagram.data);
+                meta[filled] = RecvMeta {
+                    len,
+                    stride: len,
+                    ..datagram.meta
+                };
+                filled += 1;
+            }
+            inbox.reader = Some(cx.waker().clone());
+        }
+        while filled < bufs.len() {
+            let n = match self
+                .inner
+                .poll_recv(cx, &mut bufs[filled..], &mut meta[filled..])
+            {
+                // The transport has nothing more to offer right now, but didn't register a wakeup
+                Poll::Ready(Ok(0)) if filled == 0 => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(0)) => break,
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) if filled == 0 => return Poll::Ready(Err(e)),
+                Poll::Ready(Err(e)) => {
+                    *lock(&self.error)? = Some(e);
+                    break;
+                }
+                Poll::Pending => break,
+            };
+            let mut kept = filled;
+            for i in filled..filled + n {
+                if self.forward(&bufs[i], &meta[i])? {
+                    continue;
+                }
+                bufs.swap(kept, i);
+                meta.swap(kept, i);
+                kept += 1;
+            }
+            filled = kept;
+        }
+        if filled == 0 {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(filled))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+}
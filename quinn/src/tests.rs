@@ -1,15 +1,38 @@
 use super::{
-    read_to_end, ClientConfigBuilder, Config, Endpoint, EndpointBuilder, NewStream,
-    ServerConfigBuilder,
+    read_to_end, BiStream, Certificate, CertificateChain, CertificateResolver, ClientConfigBuilder,
+    Config, ConnectError, Connecting, Connection, ConnectionError, Driver, Endpoint,
+    EndpointBuilder, EndpointError, Incoming, IncomingStreams, NewConnection, NewStream,
+    PrivateKey, RecvStream, SendStream, ServerConfigBuilder,
 };
-use futures::{Future, Stream};
+use crate::loopback::{Conditions, Network};
+use crate::runtime::{AsyncTimer, AsyncUdpSocket, RecvMeta, Runtime, TokioRuntime};
+use crate::shard;
+use futures::Future;
+use futures_util::{FutureExt, StreamExt, TryFutureExt};
+use quinn_proto::{crypto::CryptoSession, Transmit, TransportErrorCode};
+use rustls::ResolvesServerCert;
 use slog::{Drain, Logger, KV};
+use std::future::Future as _;
 use std::{
-    fmt, io,
+    cell::RefCell,
+    collections::VecDeque,
+    fmt,
+    io::{self, IoSliceMut},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    panic,
+    pin::Pin,
+    rc::Rc,
     str,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
 };
 use tokio;
+use tokio::runtime::current_thread;
+
+mod threads;
 
 #[test]
 fn echo_v6() {
@@ -36,7 +59,458 @@ fn echo_dualstack() {
     );
 }
 
+#[test]
+fn custom_runtime() {
+    let runtime = Arc::new(CountingRuntime::default());
+    run_echo_with(
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+        runtime.clone(),
+        b"foo",
+    );
+    assert_eq!(runtime.sockets.load(Ordering::Relaxed), 2);
+    assert!(runtime.timers.load(Ordering::Relaxed) > 0);
+}
+
+/// Drive endpoints without tokio, to check that they rely on nothing but their `Runtime`
+#[test]
+fn thread_runtime() {
+    use self::threads::{block_on, spawn, ThreadRuntime};
+
+    let runtime = Arc::new(ThreadRuntime);
+    let (cert, key) = self_signed();
+    let mut server = tls_server(&cert, &key, Config::default());
+    server.runtime(runtime.clone());
+    let server_sock = UdpSocket::bind("[::1]:0").unwrap();
+    let server_addr = server_sock.local_addr().unwrap();
+    let (_, server_driver, mut incoming) = server.from_socket(server_sock).unwrap();
+    let mut client = tls_client(&cert);
+    client.runtime(runtime);
+    let (client, client_driver, _) = client
+        .from_socket(UdpSocket::bind("[::1]:0").unwrap())
+        .unwrap();
+
+    spawn(async move { server_driver.await.expect("server driver failed") });
+    spawn(async move { client_driver.await.expect("client driver failed") });
+    spawn(async move {
+        while let Some(conn) = incoming.next().await {
+            echo(conn.incoming).await;
+        }
+    });
+    block_on(async move {
+        let conn = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connection failed")
+            .connection;
+        echo_roundtrip(&conn, b"foo").await;
+        conn.close(0, b"done").await;
+    });
+}
+
+/// Delegates to `TokioRuntime`, counting the resources requested of it
+#[derive(Default)]
+struct CountingRuntime {
+    inner: TokioRuntime,
+    timers: AtomicUsize,
+    sockets: AtomicUsize,
+}
+
+impl Runtime for CountingRuntime {
+    fn new_timer(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        self.timers.fetch_add(1, Ordering::Relaxed);
+        self.inner.new_timer(deadline)
+    }
+
+    fn wrap_udp_socket(&self, socket: UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        self.sockets.fetch_add(1, Ordering::Relaxed);
+        self.inner.wrap_udp_socket(socket)
+    }
+}
+
+#[test]
+fn custom_transport() {
+    let log = logger();
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    let (server_addrs, cert) = spawn_echo_server(&mut runtime, &log, &["[::1]:0".parse().unwrap()]);
+    let sent = Arc::new(AtomicUsize::new(0));
+    let received = Arc::new(AtomicUsize::new(0));
+    let socket = TokioRuntime::default()
+        .wrap_udp_socket(UdpSocket::bind("[::1]:0").unwrap())
+        .unwrap();
+    let mut client = tls_client(&cert);
+    client.logger(log.clone());
+    let (client, client_driver, _) = client
+        .from_transport(Box::new(CountingSocket {
+            inner: socket,
+            sent: sent.clone(),
+            received: received.clone(),
+        }))
+        .unwrap();
+    runtime.spawn(
+        client_driver
+            .compat()
+            .map_err(|e| panic!("client driver failed: {}", e)),
+    );
+
+    runtime
+        .block_on(compat(async move {
+            connect_and_echo(&client, server_addrs[0], b"foo").await;
+        }))
+        .unwrap();
+    assert!(sent.load(Ordering::Relaxed) > 0);
+    assert!(received.load(Ordering::Relaxed) > 0);
+}
+
+/// Delegates to another transport, counting the datagrams passing through it
+struct CountingSocket {
+    inner: Box<dyn AsyncUdpSocket>,
+    sent: Arc<AtomicUsize>,
+    received: Arc<AtomicUsize>,
+}
+
+impl AsyncUdpSocket for CountingSocket {
+    fn poll_send(&self, cx: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        let result = self.inner.poll_send(cx, transmits);
+        if let Poll::Ready(Ok(n)) = result {
+            self.sent.fetch_add(n, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let result = self.inner.poll_recv(cx, bufs, meta);
+        if let Poll::Ready(Ok(n)) = result {
+            self.received.fetch_add(n, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+}
+
+/// Connect two endpoints whose only transport is a pair of in-memory queues
+#[test]
+fn channel_transport() {
+    let (cert, key) = self_signed();
+    let (server_sock, client_sock) = ChannelSocket::pair();
+    let server_addr = server_sock.local_addr().unwrap();
+    let (_, server_driver, incoming) = tls_server(&cert, &key, Config::default())
+        .from_transport(Box::new(server_sock))
+        .unwrap();
+    let (client, client_driver, _) = tls_client(&cert)
+        .from_transport(Box::new(client_sock))
+        .unwrap();
+
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    runtime.spawn(
+        server_driver
+            .compat()
+            .map_err(|e| panic!("server driver failed: {}", e)),
+    );
+    runtime.spawn(
+        client_driver
+            .compat()
+            .map_err(|e| panic!("client driver failed: {}", e)),
+    );
+    runtime.spawn(compat(serve_echo(incoming)));
+    runtime
+        .block_on(compat(async move {
+            connect_and_echo(&client, server_addr, b"foo").await;
+        }))
+        .unwrap();
+}
+
+/// One end of a lossless in-memory link, delivering everything it sends to the other end
+struct ChannelSocket {
+    addr: SocketAddr,
+    peer: SocketAddr,
+    inbox: Arc<Mutex<ChannelInbox>>,
+    peer_inbox: Arc<Mutex<ChannelInbox>>,
+}
+
+#[derive(Default)]
+struct ChannelInbox {
+    queue: VecDeque<Box<[u8]>>,
+    reader: Option<Waker>,
+}
+
+impl ChannelSocket {
+    fn pair() -> (Self, Self) {
+        let a = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 1);
+        let b = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 2);
+        let a_inbox = Arc::new(Mutex::new(ChannelInbox::default()));
+        let b_inbox = Arc::new(Mutex::new(ChannelInbox::default()));
+        (
+            ChannelSocket {
+                addr: a,
+                peer: b,
+                inbox: a_inbox.clone(),
+                peer_inbox: b_inbox.clone(),
+            },
+            ChannelSocket {
+                addr: b,
+                peer: a,
+                inbox: b_inbox,
+                peer_inbox: a_inbox,
+            },
+        )
+    }
+}
+
+impl AsyncUdpSocket for ChannelSocket {
+    fn poll_send(&self, _: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        let inbox = &mut *self.peer_inbox.lock().unwrap();
+        for transmit in transmits {
+            // Anything not addressed to the peer is lost, as it would be on a real network
+            if transmit.destination == self.peer {
+                inbox.queue.push_back(transmit.contents.clone());
+            }
+        }
+        if let Some(x) = inbox.reader.take() {
+            x.wake();
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let inbox = &mut *self.inbox.lock().unwrap();
+        let mut filled = 0;
+        while filled < bufs.len() {
+            let data = match inbox.queue.pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+            let len = data.len().min(bufs[filled].len());
+            bufs[filled][..len].copy_from_slice(&data[..len]);
+            meta[filled] = RecvMeta {
+                addr: self.peer,
+                len,
+                stride: len,
+                ..RecvMeta::default()
+            };
+            filled += 1;
+        }
+        if filled > 0 {
+            return Poll::Ready(Ok(filled));
+        }
+        inbox.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+/// Echo enough data that it's sent and received in batches, where the platform supports it
+#[test]
+fn echo_batched() {
+    run_echo_with(
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+        Arc::new(TokioRuntime::default()),
+        &[0xAB; 256 * 1024],
+    );
+}
+
+/// Serve several clients at once, so that a single batch of sends covers multiple destinations
+#[test]
+fn echo_many_clients() {
+    let log = logger();
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    let (server_addrs, cert) = spawn_echo_server(&mut runtime, &log, &["[::1]:0".parse().unwrap()]);
+    let server_addr = server_addrs[0];
+    let clients = (0..4)
+        .map(|_| spawn_client(&mut runtime, &log, &cert, "[::1]:0".parse().unwrap()))
+        .collect::<Vec<_>>();
+
+    runtime
+        .block_on(compat(async move {
+            let echoes = clients
+                .iter()
+                .map(|client| connect_and_echo(client, server_addr, &[0xAB; 64 * 1024]));
+            futures_util::future::join_all(echoes).await;
+        }))
+        .unwrap();
+}
+
+/// Reach a server bound to a wildcard address by one of several local addresses, which it must
+/// reply from for the client to recognize it
+#[test]
+#[cfg(target_os = "linux")] // Relies on all of 127.0.0.0/8 being routed to the loopback interface
+fn echo_multihomed() {
+    let log = logger();
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    let (server_addrs, cert) =
+        spawn_echo_server(&mut runtime, &log, &["0.0.0.0:0".parse().unwrap()]);
+    let client = spawn_client(&mut runtime, &log, &cert, "0.0.0.0:0".parse().unwrap());
+    let server_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), server_addrs[0].port());
+
+    runtime
+        .block_on(compat(async move {
+            connect_and_echo(&client, server_addr, b"foo").await;
+        }))
+        .unwrap();
+}
+
+/// Serve IPv4 and IPv6 clients from separate sockets belonging to the same endpoint
+#[test]
+fn echo_multiple_sockets() {
+    let log = logger();
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    let (server_addrs, cert) = spawn_echo_server(
+        &mut runtime,
+        &log,
+        &["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()],
+    );
+    let client_v4 = spawn_client(&mut runtime, &log, &cert, "127.0.0.1:0".parse().unwrap());
+    let client_v6 = spawn_client(&mut runtime, &log, &cert, "[::1]:0".parse().unwrap());
+
+    runtime
+        .block_on(compat(async move {
+            futures_util::future::join(
+                connect_and_echo(&client_v4, server_addrs[0], b"foo"),
+                connect_and_echo(&client_v6, server_addrs[1], b"bar"),
+            )
+            .await;
+        }))
+        .unwrap();
+}
+
+/// Ignore a zero-length datagram, which carries nothing to pass on to the protocol
+#[test]
+fn empty_datagram() {
+    let log = logger();
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    let (server_addrs, cert) = spawn_echo_server(&mut runtime, &log, &["[::1]:0".parse().unwrap()]);
+    let client = spawn_client(&mut runtime, &log, &cert, "[::1]:0".parse().unwrap());
+    let socket = UdpSocket::bind("[::1]:0").unwrap();
+    socket.send_to(&[], server_addrs[0]).unwrap();
+
+    runtime
+        .block_on(compat(async move {
+            connect_and_echo(&client, server_addrs[0], b"foo").await;
+        }))
+        .unwrap();
+}
+
+/// Refuse to connect to an IPv6 address from an endpoint with only IPv4 sockets
+#[test]
+fn unreachable_family() {
+    let log = logger();
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    let (cert, _) = self_signed();
+    let client = spawn_client(&mut runtime, &log, &cert, "127.0.0.1:0".parse().unwrap());
+    let addr = "[::1]:4433".parse().unwrap();
+    match client.connect(&addr, "localhost") {
+        Err(ConnectError::UnreachableRemote(x)) => assert_eq!(x, addr),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected to an unreachable address"),
+    }
+}
+
+/// Run an echo server with a socket bound to each of `addrs` on `runtime`, returning the sockets'
+/// addresses and the server's certificate
+fn spawn_echo_server(
+    runtime: &mut current_thread::Runtime,
+    log: &Logger,
+    addrs: &[SocketAddr],
+) -> (Vec<SocketAddr>, Certificate) {
+    let mut server_config = ServerConfigBuilder::default();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]);
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der()).unwrap();
+    let cert_chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+    server_config.set_certificate(cert_chain, key).unwrap();
+
+    let mut server = Endpoint::new();
+    server.logger(log.clone());
+    server.listen(server_config.build());
+    let sockets = addrs
+        .iter()
+        .map(|&addr| UdpSocket::bind(addr).unwrap())
+        .collect::<Vec<_>>();
+    let server_addrs = sockets
+        .iter()
+        .map(|x| x.local_addr().unwrap())
+        .collect::<Vec<_>>();
+    let (_, server_driver, server_incoming) = server.from_sockets(sockets).unwrap();
+    runtime.spawn(
+        server_driver
+            .compat()
+            .map_err(|e| panic!("server driver failed: {}", e)),
+    );
+    runtime.spawn(compat(serve_echo(server_incoming)));
+    (server_addrs, cert)
+}
+
+/// Run a client endpoint bound to `addr` on `runtime`, trusting `cert`
+fn spawn_client(
+    runtime: &mut current_thread::Runtime,
+    log: &Logger,
+    cert: &Certificate,
+    addr: SocketAddr,
+) -> Endpoint {
+    let mut client_config = ClientConfigBuilder::default();
+    client_config
+        .add_certificate_authority(cert.clone())
+        .unwrap();
+    let mut client = Endpoint::new();
+    client.logger(log.clone());
+    client.default_client_config(client_config.build());
+    let (client, client_driver, _) = client.bind(addr).unwrap();
+    runtime.spawn(
+        client_driver
+            .compat()
+            .map_err(|e| panic!("client driver failed: {}", e)),
+    );
+    client
+}
+
+async fn connect_and_echo(client: &Endpoint, server_addr: SocketAddr, msg: &[u8]) {
+    let conn = client
+        .connect(&server_addr, "localhost")
+        .unwrap()
+        .await
+        .expect("connection failed")
+        .connection;
+    echo_roundtrip(&conn, msg).await;
+    conn.close(0, b"done").await;
+}
+
 fn run_echo(client_addr: SocketAddr, server_addr: SocketAddr) {
+    run_echo_with(
+        client_addr,
+        server_addr,
+        Arc::new(TokioRuntime::default()),
+        b"foo",
+    );
+}
+
+fn run_echo_with(
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+    runtime: Arc<dyn Runtime>,
+    msg: &[u8],
+) {
     let log = logger();
     let mut server_config = ServerConfigBuilder::default();
     let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]);
@@ -50,6 +524,7 @@ fn run_echo(client_addr: SocketAddr, server_addr: SocketAddr) {
         ..Config::default()
     });
     server.logger(log.clone());
+    server.runtime(runtime.clone());
     server.listen(server_config.build());
     let server_sock = UdpSocket::bind(server_addr).unwrap();
     let server_addr = server_sock.local_addr().unwrap();
@@ -59,59 +534,1162 @@ fn run_echo(client_addr: SocketAddr, server_addr: SocketAddr) {
     client_config.add_certificate_authority(cert).unwrap();
     let mut client = Endpoint::new();
     client.logger(log.clone());
+    client.runtime(runtime.clone());
     client.default_client_config(client_config.build());
     let (client, client_driver, _) = client.bind(client_addr).unwrap();
 
-    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
-    runtime.spawn(server_driver.map_err(|e| panic!("server driver failed: {}", e)));
-    runtime.spawn(client_driver.map_err(|e| panic!("client driver failed: {}", e)));
-    runtime.spawn(server_incoming.for_each(move |conn| {
-        tokio_current_thread::spawn(conn.incoming.map_err(|_| ()).for_each(echo));
-        Ok(())
-    }));
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    runtime.spawn(
+        server_driver
+            .compat()
+            .map_err(|e| panic!("server driver failed: {}", e)),
+    );
+    runtime.spawn(
+        client_driver
+            .compat()
+            .map_err(|e| panic!("client driver failed: {}", e)),
+    );
+    runtime.spawn(compat(serve_echo(server_incoming)));
 
     info!(log, "connecting from {} to {}", client_addr, server_addr);
+    let msg = msg.to_vec();
     runtime
-        .block_on(
-            client
+        .block_on(compat(async move {
+            let conn = client
                 .connect(&server_addr, "localhost")
                 .unwrap()
-                .map_err(|e| panic!("connection failed: {}", e))
-                .and_then(move |conn| {
-                    let conn = conn.connection;
-                    let stream = conn.open_bi();
-                    stream
-                        .map_err(|_| ())
-                        .and_then(move |stream| {
-                            tokio::io::write_all(stream, b"foo".to_vec())
-                                .map_err(|e| panic!("write: {}", e))
-                        })
-                        .and_then(|(stream, _)| {
-                            tokio::io::shutdown(stream).map_err(|e| panic!("finish: {}", e))
-                        })
-                        .and_then(move |stream| {
-                            read_to_end(stream, usize::max_value())
-                                .map_err(|e| panic!("read: {}", e))
-                        })
-                        .and_then(move |(_, data)| {
-                            assert_eq!(&data[..], b"foo");
-                            conn.close(0, b"done").map_err(|_| unreachable!())
-                        })
-                }),
+                .await
+                .expect("connection failed")
+                .connection;
+            echo_roundtrip(&conn, &msg).await;
+            conn.close(0, b"done").await;
+        }))
+        .unwrap();
+}
+
+#[test]
+#[cfg(feature = "noise")]
+fn echo_noise() {
+    use crate::crypto::noise::{self, Keypair, NoiseSession};
+    use crate::{ClientConfig, ServerConfig};
+
+    let log = logger();
+    let server_key = Keypair::generate();
+    let client_key = Keypair::generate();
+    let client_config = ClientConfig::<NoiseSession> {
+        tls_config: noise::ClientConfig {
+            remote_public_key: Some(server_key.public_key().to_vec()),
+            ..noise::ClientConfig::new(client_key.clone())
+        },
+    };
+
+    let mut server = EndpointBuilder::with_client_config(Config::default(), client_config.clone());
+    server.logger(log.clone());
+    server.listen(ServerConfig {
+        tls_config: noise::ServerConfig::new(server_key.clone()),
+        ..ServerConfig::default()
+    });
+    let server_sock = UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    let server_addr = server_sock.local_addr().unwrap();
+    let (_, server_driver, server_incoming) = server.from_socket(server_sock).unwrap();
+
+    let mut client = EndpointBuilder::with_client_config(Config::default(), client_config);
+    client.logger(log.clone());
+    let (client, client_driver, _) = client
+        .bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0))
+        .unwrap();
+
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    runtime.spawn(
+        server_driver
+            .compat()
+            .map_err(|e| panic!("server driver failed: {}", e)),
+    );
+    runtime.spawn(
+        client_driver
+            .compat()
+            .map_err(|e| panic!("client driver failed: {}", e)),
+    );
+    let client_public = client_key.public_key().to_vec();
+    runtime.spawn(compat(async move {
+        let mut incoming = server_incoming;
+        while let Some(conn) = incoming.next().await {
+            assert_eq!(
+                conn.connection.remote_public_key(),
+                Some(client_public.clone())
+            );
+            tokio_current_thread::spawn(compat(echo(conn.incoming)));
+        }
+    }));
+
+    runtime
+        .block_on(compat(async move {
+            let conn = client
+                .connect(&server_addr, "")
+                .unwrap()
+                .await
+                .expect("connection failed")
+                .connection;
+            assert_eq!(
+                conn.remote_public_key(),
+                Some(server_key.public_key().to_vec())
+            );
+            echo_roundtrip(&conn, b"foo").await;
+            conn.close(0, b"done").await;
+        }))
+        .unwrap();
+}
+
+#[test]
+fn echo_loopback() {
+    run_echo_loopback(&Network::new(Conditions::default()), b"foo");
+}
+
+#[test]
+fn echo_impaired() {
+    let network = Network::new(Conditions {
+        delay: Duration::from_millis(5),
+        jitter: Duration::from_millis(10),
+        loss: 0.1,
+        ..Conditions::default()
+    });
+    run_echo_loopback(&network, b"foo");
+}
+
+#[test]
+fn bandwidth_limit() {
+    const SIZE: usize = 32 * 1024;
+    const BANDWIDTH: u64 = 512 * 1024;
+    let network = Network::new(Conditions {
+        bandwidth: Some(BANDWIDTH),
+        ..Conditions::default()
+    });
+    let start = network.now();
+    run_echo_loopback(&network, &[0xAB; SIZE]);
+    // The message must cross the network in each direction
+    let min = Duration::from_nanos(2 * SIZE as u64 * 1_000_000_000 / BANDWIDTH);
+    assert!(network.now() - start >= min);
+}
+
+/// Echo `msg` between a client and server connected through `network`
+fn run_echo_loopback(network: &Network, msg: &[u8]) {
+    let (client, server_addr, incoming) = loopback_pair(network);
+    network.spawn(serve_echo(incoming));
+    network.block_on(connect_and_echo(&client, server_addr, msg));
+}
+
+/// Close a connection, which is only drained once a timer started during the driver's final pass
+/// wakes it
+#[test]
+fn close_loopback() {
+    let network = Network::new(Conditions::default());
+    let (client, server_addr, _incoming) = loopback_pair(&network);
+    network.block_on(async move {
+        let conn = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connection failed")
+            .connection;
+        conn.close(0, b"done").await;
+    });
+}
+
+#[test]
+fn echo_futures_io() {
+    use futures_util::io::{AsyncReadExt, AsyncWriteExt};
+
+    let network = Network::new(Conditions::default());
+    let (client, server_addr, incoming) = loopback_pair(&network);
+    network.spawn(serve_echo(incoming));
+    let (conn, mut stream) = network.block_on(async move {
+        let conn = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connection failed")
+            .connection;
+        let mut stream = conn.open_bi().await.expect("open");
+        stream.write_all(b"hello").await.expect("write");
+        stream.close().await.expect("close");
+        let mut first = [0; 1];
+        stream.read_exact(&mut first).await.expect("read");
+        assert_eq!(&first, b"h");
+        (conn, stream)
+    });
+
+    // Reads that complete immediately don't need a task to be woken
+    let mut rest = [0; 8];
+    let n = io::Read::read(&mut stream, &mut rest).unwrap();
+    assert_eq!(&rest[..n], b"ello");
+
+    network.block_on(async move {
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.expect("read");
+        assert!(data.is_empty());
+        conn.close(0, b"done").await;
+    });
+}
+
+/// Start a TLS server and a client trusting it, connected through `network`
+///
+/// Returns the client, the server's address, and its incoming connections.
+fn loopback_pair(network: &Network) -> (Endpoint, SocketAddr, Incoming) {
+    let (server, client) = tls_builders();
+    loopback_endpoints(network, server, client)
+}
+
+/// Builders for a TLS server and a client trusting it
+fn tls_builders() -> (EndpointBuilder, EndpointBuilder) {
+    let (cert, key) = self_signed();
+    (
+        tls_server(&cert, &key, Config::default()),
+        tls_client(&cert),
+    )
+}
+
+fn self_signed() -> (Certificate, PrivateKey) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]);
+    let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = Certificate::from_der(&cert.serialize_der()).unwrap();
+    (cert, key)
+}
+
+/// Builder for a server identified by `cert`
+fn tls_server(cert: &Certificate, key: &PrivateKey, config: Config) -> EndpointBuilder {
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .set_certificate(
+            CertificateChain::from_certs(vec![cert.clone()]),
+            key.clone(),
         )
         .unwrap();
+    let mut server = EndpointBuilder::new(config);
+    server.logger(logger());
+    server.listen(server_config.build());
+    server
+}
+
+/// Builder for a client trusting `cert`
+fn tls_client(cert: &Certificate) -> EndpointBuilder {
+    let mut client_config = ClientConfigBuilder::default();
+    client_config
+        .add_certificate_authority(cert.clone())
+        .unwrap();
+    let mut client = Endpoint::new();
+    client.logger(logger());
+    client.default_client_config(client_config.build());
+    client
+}
+
+/// Build `server` and `client` on sockets attached to `network`, spawning their drivers
+fn loopback_endpoints<S: CryptoSession + 'static>(
+    network: &Network,
+    mut server: EndpointBuilder<S>,
+    mut client: EndpointBuilder<S>,
+) -> (Endpoint<S>, SocketAddr, Incoming<S>) {
+    let socket = network.socket();
+    let server_addr = socket.local_addr().unwrap();
+    server.runtime(network.runtime());
+    let (_, server_driver, incoming) = server.from_transport(socket).unwrap();
+    client.runtime(network.runtime());
+    let (client, client_driver, _) = client.from_transport(network.socket()).unwrap();
+    network.spawn(async move { server_driver.await.expect("server driver failed") });
+    network.spawn(async move { client_driver.await.expect("client driver failed") });
+    (client, server_addr, incoming)
+}
+
+/// Reach an endpoint on each of several sockets sharing an address, so connections are only
+/// recognized if replies leave from the socket they arrived on
+#[test]
+fn multiple_sockets_loopback() {
+    let network = Network::new(Conditions::default());
+    let (mut server, mut client) = tls_builders();
+    let sockets = vec![network.socket(), network.socket(), network.socket()];
+    let server_addrs = sockets
+        .iter()
+        .map(|x| x.local_addr().unwrap())
+        .collect::<Vec<_>>();
+    server.runtime(network.runtime());
+    let (_, server_driver, incoming) = server.from_transports(sockets).unwrap();
+    client.runtime(network.runtime());
+    let (client, client_driver, _) = client.from_transport(network.socket()).unwrap();
+    network.spawn(async move { server_driver.await.expect("server driver failed") });
+    network.spawn(async move { client_driver.await.expect("client driver failed") });
+    network.spawn(serve_echo(incoming));
+
+    network.block_on(async move {
+        let echoes = server_addrs
+            .iter()
+            .map(|&addr| connect_and_echo(&client, addr, b"foo"));
+        futures_util::future::join_all(echoes).await;
+    });
+}
+
+/// Serve a connection on one socket while another can't send at all
+#[test]
+fn stalled_socket_loopback() {
+    let network = Network::new(Conditions::default());
+    let (cert, key) = self_signed();
+    // Enough to fill the stalled socket's queue in one go
+    let mut server = tls_server(
+        &cert,
+        &key,
+        Config {
+            initial_window: 1024 * 1024,
+            ..Config::default()
+        },
+    );
+    let mut client = tls_client(&cert);
+    let stalled = Arc::new(AtomicBool::new(false));
+    let sockets: Vec<Box<dyn AsyncUdpSocket>> = vec![
+        Box::new(StallingSocket {
+            inner: network.socket(),
+            stalled: stalled.clone(),
+        }),
+        network.socket(),
+    ];
+    let server_addrs = sockets
+        .iter()
+        .map(|x| x.local_addr().unwrap())
+        .collect::<Vec<_>>();
+    server.runtime(network.runtime());
+    let (_, server_driver, mut incoming) = server.from_transports(sockets).unwrap();
+    client.runtime(network.runtime());
+    let (client, client_driver, _) = client.from_transport(network.socket()).unwrap();
+    network.spawn(async move { server_driver.await.expect("server driver failed") });
+    network.spawn(async move { client_driver.await.expect("client driver failed") });
+
+    let (_stalled_conn, server_conn) = network.block_on(futures_util::future::join(
+        client.connect(&server_addrs[0], "localhost").unwrap(),
+        incoming.next(),
+    ));
+    stalled.store(true, Ordering::Relaxed);
+    let server_conn = server_conn.unwrap().connection;
+    network.spawn(async move {
+        let mut stream = server_conn.open_uni().await.expect("open");
+        stream.write_all(&[0xAB; 256 * 1024]).await.expect("write");
+        // Never acknowledged, so the connection isn't dropped while its data is waiting to be sent
+        let _ = stream.finish().await;
+    });
+    network.spawn(serve_echo(incoming));
+    network.block_on(connect_and_echo(&client, server_addrs[1], b"foo"));
+}
+
+/// Accepts nothing for sending once `stalled` is set, as if its send buffer never drained
+struct StallingSocket {
+    inner: Box<dyn AsyncUdpSocket>,
+    stalled: Arc<AtomicBool>,
+}
+
+impl AsyncUdpSocket for StallingSocket {
+    fn poll_send(&self, cx: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        if self.stalled.load(Ordering::Relaxed) {
+            return Poll::Pending;
+        }
+        self.inner.poll_send(cx, transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_recv(cx, bufs, meta)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Reach one shard of a sharded endpoint, then, as if after NAT rebinding, another, which must
+/// pass the connection's datagrams on to the shard that owns it
+#[test]
+fn sharded_loopback() {
+    sharded_rebinding(false);
+}
+
+/// As `sharded_loopback`, but with the peer's address changing before the handshake completes,
+/// so that Handshake packets reach the wrong shard too
+#[test]
+fn sharded_handshake() {
+    sharded_rebinding(true);
+}
+
+fn sharded_rebinding(handshake: bool) {
+    let network = Network::new(Conditions::default());
+    let routing = Config {
+        shards: 2,
+        ..Config::default()
+    };
+    let sockets = shard::connect(vec![network.socket(), network.socket()], routing);
+    let server_addrs = sockets
+        .iter()
+        .map(|x| x.local_addr().unwrap())
+        .collect::<Vec<_>>();
+    let (cert, key) = self_signed();
+    for (shard, socket) in sockets.into_iter().enumerate() {
+        let config = Config {
+            shards: 2,
+            shard: shard as u8,
+            ..Config::default()
+        };
+        let mut server = tls_server(&cert, &key, config);
+        server.runtime(network.runtime());
+        let (_, driver, incoming) = server.from_transport(socket).unwrap();
+        network.spawn(async move { driver.await.expect("server driver failed") });
+        network.spawn(serve_echo(incoming));
+    }
+    let redirected = Arc::new(AtomicUsize::new(0));
+    let socket = Box::new(Rebinding {
+        inner: network.socket(),
+        to: server_addrs[1],
+        handshake,
+        redirected: redirected.clone(),
+    });
+    let mut client = tls_client(&cert);
+    client.runtime(network.runtime());
+    let (client, client_driver, _) = client.from_transport(socket).unwrap();
+    network.spawn(async move { client_driver.await.expect("client driver failed") });
+
+    network.block_on(connect_and_echo(&client, server_addrs[0], b"foo"));
+    assert_eq!(redirected.load(Ordering::Relaxed) > 0, handshake);
+}
+
+/// Sends datagrams with short headers to `to`, as if the sender's address changed once the
+/// handshake completed and the kernel picked a different shard's socket for it
+struct Rebinding {
+    inner: Box<dyn AsyncUdpSocket>,
+    to: SocketAddr,
+    /// Whether datagrams starting with a Handshake packet are sent to `to` as well
+    handshake: bool,
+    /// Number of datagrams starting with a Handshake packet sent to `to`
+    redirected: Arc<AtomicUsize>,
+}
+
+impl AsyncUdpSocket for Rebinding {
+    fn poll_send(&self, cx: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        let transmits = transmits
+            .iter()
+            .map(|x| Transmit {
+                destination: if x.contents[0] & 0x80 == 0 {
+                    self.to
+                } else if self.handshake && x.contents[0] & 0x30 == 0x20 {
+                    self.redirected.fetch_add(1, Ordering::Relaxed);
+                    self.to
+                } else {
+                    x.destination
+                },
+                ecn: x.ecn,
+                contents: x.contents.clone(),
+                segment_size: x.segment_size,
+                src: x.src,
+                socket: x.socket,
+            })
+            .collect::<Vec<_>>();
+        self.inner.poll_send(cx, &transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_recv(cx, bufs, meta)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+}
+
+/// Serve several clients from shards sharing an address, each client landing on whichever shard
+/// the kernel picks for it
+#[test]
+#[cfg(target_os = "linux")]
+fn echo_sharded() {
+    let log = logger();
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    // Find a free port, as the shards must all be bound to it
+    let server_addr = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (cert, key) = self_signed();
+    let shards = EndpointBuilder::bind_sharded(server_addr, 4, |_| {
+        tls_server(&cert, &key, Config::default())
+    })
+    .unwrap();
+    assert_eq!(shards.len(), 4);
+    for (_, driver, incoming) in shards {
+        runtime.spawn(
+            driver
+                .compat()
+                .map_err(|e| panic!("server driver failed: {}", e)),
+        );
+        runtime.spawn(compat(serve_echo(incoming)));
+    }
+    let clients = (0..8)
+        .map(|_| spawn_client(&mut runtime, &log, &cert, "127.0.0.1:0".parse().unwrap()))
+        .collect::<Vec<_>>();
+
+    runtime
+        .block_on(compat(async move {
+            let echoes = clients
+                .iter()
+                .map(|client| connect_and_echo(client, server_addr, b"foo"));
+            futures_util::future::join_all(echoes).await;
+        }))
+        .unwrap();
+}
+
+/// Spread datagrams from many peers across real `SO_REUSEPORT` shards, each of which must end up
+/// with exactly those addressed to it
+#[test]
+#[cfg(target_os = "linux")]
+fn reuseport_forwarding() {
+    use self::threads::ThreadRuntime;
+    use crate::platform::bind_reuseport;
+
+    const SHARDS: u8 = 2;
+    const PEERS: usize = 16;
+    let first = bind_reuseport(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = first.local_addr().unwrap();
+    let mut sockets = vec![ThreadRuntime.wrap_udp_socket(first).unwrap()];
+    for _ in 1..SHARDS {
+        let socket = bind_reuseport(&addr).unwrap();
+        sockets.push(ThreadRuntime.wrap_udp_socket(socket).unwrap());
+    }
+    let routing = || Config {
+        shards: SHARDS,
+        ..Config::default()
+    };
+    let shards = shard::connect(sockets, routing());
+    let routing = routing();
+
+    // The kernel picks a shard for each peer by hashing its address, so with enough peers some
+    // datagrams are bound to reach the wrong one
+    let peers = (0..PEERS)
+        .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+        .collect::<Vec<_>>();
+    // Carries no connection ID, and so stays wherever it lands
+    peers[0].send_to(&[], addr).unwrap();
+    for (i, peer) in peers.iter().enumerate() {
+        for shard in 0..SHARDS {
+            let mut datagram = vec![0; 1 + routing.local_cid_len + 1];
+            datagram[0] = 0x40;
+            datagram[1] = shard;
+            *datagram.last_mut().unwrap() = i as u8;
+            peer.send_to(&datagram, addr).unwrap();
+        }
+    }
+
+    // Shards only pass on what they've received themselves when polled, so poll them all
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut received = vec![Vec::new(); shards.len()];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while received.iter().any(|x| x.len() < PEERS) {
+        assert!(Instant::now() < deadline, "datagrams were lost");
+        for (shard, socket) in shards.iter().enumerate() {
+            let mut storage = [[0; 64]; 4];
+            let mut bufs = storage
+                .iter_mut()
+                .map(|x| IoSliceMut::new(x))
+                .collect::<Vec<_>>();
+            let mut meta = [RecvMeta::default(); 4];
+            let n = match socket.poll_recv(&mut cx, &mut bufs, &mut meta) {
+                Poll::Ready(x) => x.expect("receive failed"),
+                Poll::Pending => continue,
+            };
+            for (meta, buf) in meta.iter().zip(bufs.iter()).take(n) {
+                for datagram in meta.datagrams(buf) {
+                    assert_eq!(routing.shard_of(datagram), Some(shard as u8));
+                    received[shard].push(datagram[datagram.len() - 1]);
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    for mut x in received {
+        x.sort();
+        assert_eq!(x, (0..PEERS as u8).collect::<Vec<_>>());
+    }
+}
+
+/// Report a receive error only after the datagrams received before it
+#[test]
+fn shard_receive_error() {
+    let routing = Config {
+        shards: 2,
+        ..Config::default()
+    };
+    // Not attributable to a shard, so kept by the one receiving it
+    let datagram = vec![0x40; 16];
+    let error = Err(io::ErrorKind::Other.into());
+    let shards = shard::connect(
+        vec![
+            Box::new(ScriptedSocket(Mutex::new(vec![Ok(datagram), error].into()))),
+            Box::new(ScriptedSocket(Mutex::new(VecDeque::new()))),
+        ],
+        routing,
+    );
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut storage = [[0; 64]; 4];
+    let mut bufs = storage
+        .iter_mut()
+        .map(|x| IoSliceMut::new(x))
+        .collect::<Vec<_>>();
+    let mut meta = [RecvMeta::default(); 4];
+    match shards[0].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Ready(Ok(1)) => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+    match shards[0].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::Other => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+}
+
+/// Stop receiving when the transport reports an empty receive, rather than polling it again
+#[test]
+fn shard_empty_receive() {
+    let routing = Config {
+        shards: 2,
+        ..Config::default()
+    };
+    // Bound for the second shard, but received by the first
+    let mut datagram = vec![0; 1 + routing.local_cid_len + 1];
+    datagram[0] = 0x40;
+    datagram[1] = 1;
+    let shards = shard::connect(
+        vec![
+            Box::new(ScriptedSocket(Mutex::new(vec![Ok(datagram)].into()))),
+            Box::new(EmptySocket),
+        ],
+        routing,
+    );
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut storage = [[0; 64]; 4];
+    let mut bufs = storage
+        .iter_mut()
+        .map(|x| IoSliceMut::new(x))
+        .collect::<Vec<_>>();
+    let mut meta = [RecvMeta::default(); 4];
+    match shards[1].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Ready(Ok(0)) => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+    match shards[0].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Pending => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+    match shards[1].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Ready(Ok(1)) => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+}
+
+/// Drop forwarded datagrams too large for the receiving shard's slots, rather than truncating them
+#[test]
+fn shard_oversized_forward() {
+    let routing = Config {
+        shards: 2,
+        ..Config::default()
+    };
+    // Bound for the second shard, but received by the first
+    let datagram = |len: usize| {
+        let mut x = vec![0xab; len];
+        x[0] = 0x40;
+        x[1] = 1;
+        x
+    };
+    let shards = shard::connect(
+        vec![
+            Box::new(ScriptedSocket(Mutex::new(
+                vec![Ok(datagram(128)), Ok(datagram(32))].into(),
+            ))),
+            Box::new(ScriptedSocket(Mutex::new(VecDeque::new()))),
+        ],
+        routing,
+    );
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut storage = [[0; 128]; 4];
+    let mut bufs = storage
+        .iter_mut()
+        .map(|x| IoSliceMut::new(x))
+        .collect::<Vec<_>>();
+    let mut meta = [RecvMeta::default(); 4];
+    match shards[0].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Pending => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+
+    let mut storage = [[0; 64]; 4];
+    let mut bufs = storage
+        .iter_mut()
+        .map(|x| IoSliceMut::new(x))
+        .collect::<Vec<_>>();
+    match shards[1].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Ready(Ok(1)) => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+    assert_eq!(meta[0].len, 32);
+    assert_eq!(meta[0].stride, 32);
+    assert!(meta[0].len <= bufs[0].len());
+    assert_eq!(&bufs[0][..32], &datagram(32)[..]);
+}
+
+/// Fail to receive once a panic may have left a shard's state half-updated
+#[test]
+fn shard_poisoned() {
+    let routing = Config {
+        shards: 2,
+        ..Config::default()
+    };
+    // Bound for the second shard, but received by the first
+    let mut datagram = vec![0; 1 + routing.local_cid_len + 1];
+    datagram[0] = 0x40;
+    datagram[1] = 1;
+    let shards = shard::connect(
+        vec![
+            Box::new(ScriptedSocket(Mutex::new(vec![Ok(datagram)].into()))),
+            Box::new(ScriptedSocket(Mutex::new(VecDeque::new()))),
+        ],
+        routing,
+    );
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut storage = [[0; 64]; 4];
+    let mut bufs = storage
+        .iter_mut()
+        .map(|x| IoSliceMut::new(x))
+        .collect::<Vec<_>>();
+    let mut meta = [RecvMeta::default(); 4];
+    match shards[0].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Pending => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+    // Without room to describe the forwarded datagram, the second shard panics while holding
+    // its inbox
+    panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let _ = shards[1].poll_recv(&mut cx, &mut bufs, &mut []);
+    }))
+    .unwrap_err();
+    match shards[1].poll_recv(&mut cx, &mut bufs, &mut meta) {
+        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::Other => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+}
+
+/// Completes every receive immediately, without any datagrams
+struct EmptySocket;
+
+impl AsyncUdpSocket for EmptySocket {
+    fn poll_send(&self, _: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        _: &mut Context<'_>,
+        _: &mut [IoSliceMut<'_>],
+        _: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok("[::1]:4433".parse().unwrap())
+    }
+}
+
+/// Yields the results in its script, one per receive, then nothing
+struct ScriptedSocket(Mutex<VecDeque<io::Result<Vec<u8>>>>);
+
+impl AsyncUdpSocket for ScriptedSocket {
+    fn poll_send(&self, _: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        _: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let data = match self.0.lock().unwrap().pop_front() {
+            Some(x) => x?,
+            None => return Poll::Pending,
+        };
+        bufs[0][..data.len()].copy_from_slice(&data);
+        meta[0] = RecvMeta {
+            len: data.len(),
+            stride: data.len(),
+            ..RecvMeta::default()
+        };
+        Poll::Ready(Ok(1))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok("[::1]:4433".parse().unwrap())
+    }
+}
+
+#[test]
+fn no_sockets() {
+    assert!(match Endpoint::new().from_transports(Vec::new()) {
+        Err(EndpointError::NoSockets) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn certificate_resolver() {
+    fn cert(names: &[&str]) -> (CertificateChain, PrivateKey, Vec<u8>) {
+        let cert = rcgen::generate_simple_self_signed(
+            names.iter().map(|&x| x.to_string()).collect::<Vec<_>>(),
+        );
+        let der = cert.serialize_der();
+        let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let chain = CertificateChain::from_certs(Certificate::from_der(&der));
+        (chain, key, der)
+    }
+    let resolve = |resolver: &CertificateResolver, name: Option<&str>| {
+        let name = name.map(|x| webpki::DNSNameRef::try_from_ascii_str(x).unwrap());
+        resolver.resolve(name, &[]).map(|x| x.cert[0].0.clone())
+    };
+
+    let resolver = CertificateResolver::new();
+    let (chain, key, exact) = cert(&["a.example.com"]);
+    resolver.add("a.example.com", chain, key).unwrap();
+    let (chain, key, wildcard) = cert(&["*.example.com"]);
+    resolver.add("*.example.com", chain, key).unwrap();
+    let (chain, key, _) = cert(&["b.example.com"]);
+    assert!(resolver.add("c.example.com", chain, key).is_err());
+
+    assert_eq!(resolve(&resolver, Some("A.example.com")), Some(exact));
+    assert_eq!(
+        resolve(&resolver, Some("b.example.com")),
+        Some(wildcard.clone())
+    );
+    assert_eq!(resolve(&resolver, Some("x.b.example.com")), None);
+    assert_eq!(resolve(&resolver, Some("example.com")), None);
+    assert_eq!(resolve(&resolver, None), None);
+
+    // Changes are visible through clones, e.g. one installed in a `ServerConfig`
+    let (chain, key, default) = cert(&["localhost"]);
+    resolver.clone().set_default(chain, key).unwrap();
+    assert!(resolver.remove("a.example.com"));
+    assert_eq!(resolve(&resolver, Some("a.example.com")), Some(wildcard));
+    assert_eq!(
+        resolve(&resolver, Some("example.com")),
+        Some(default.clone())
+    );
+    assert_eq!(resolve(&resolver, None), Some(default));
+}
+
+#[test]
+fn certificate_pinning() {
+    use crate::tls::{CustomVerifier, PinningVerifier};
+    use rustls::{RootCertStore, ServerCertVerifier};
+
+    let cert = |name: &str| {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]);
+        let hash = p256_public_key_sha256(&cert.serialize_private_key_der());
+        (Certificate::from_der(&cert.serialize_der()).unwrap(), hash)
+    };
+    let verify = |verifier: &dyn ServerCertVerifier, cert: &Certificate| {
+        let name = webpki::DNSNameRef::try_from_ascii_str("example.com").unwrap();
+        verifier
+            .verify_server_cert(&RootCertStore::empty(), &[cert.inner.clone()], name, &[])
+            .is_ok()
+    };
+    let ((pinned, pinned_hash), (trusted, _), (other, other_hash)) =
+        (cert("a.test"), cert("b.test"), cert("c.test"));
+    assert_eq!(pinned.public_key_sha256().unwrap(), pinned_hash);
+    assert_eq!(other.public_key_sha256().unwrap(), other_hash);
+
+    let verifier = PinningVerifier {
+        public_keys: vec![pinned_hash],
+        certificates: vec![trusted.inner.clone()],
+    };
+    assert!(verify(&verifier, &pinned));
+    assert!(verify(&verifier, &trusted));
+    assert!(!verify(&verifier, &other));
+    assert!(Certificate::from_der(b"garbage")
+        .unwrap()
+        .public_key_sha256()
+        .is_err());
+
+    let expected = other.as_der().to_vec();
+    let verifier = CustomVerifier(Box::new(move |chain: &CertificateChain, name: &str| {
+        name == "example.com" && chain.iter().next() == Some(&expected[..])
+    }));
+    assert!(verify(&verifier, &other));
+    assert!(!verify(&verifier, &pinned));
+}
+
+#[test]
+fn pinned_handshake() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]);
+    let hash = p256_public_key_sha256(&cert.serialize_private_key_der());
+    let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = Certificate::from_der(&cert.serialize_der()).unwrap();
+    let other = rcgen::generate_simple_self_signed(vec!["localhost".into()]);
+    let other_hash = p256_public_key_sha256(&other.serialize_private_key_der());
+
+    let connects = |pin: [u8; 32]| {
+        let mut client_config = ClientConfigBuilder::default();
+        client_config.pin_public_key(pin);
+        let mut client = Endpoint::new();
+        client.logger(logger());
+        client.default_client_config(client_config.build());
+
+        let network = Network::new(Conditions::default());
+        let server = tls_server(&cert, &key, Config::default());
+        let (client, server_addr, incoming) = loopback_endpoints(&network, server, client);
+        network.spawn(serve_echo(incoming));
+        let connecting = client.connect(&server_addr, "localhost").unwrap();
+        network.block_on(connecting).is_ok()
+    };
+    assert!(connects(hash));
+    assert!(!connects(other_hash));
+}
+
+/// Compute the SHA-256 digest of the SubjectPublicKeyInfo of a PKCS#8 encoded P-256 key, as
+/// generated by rcgen, independently of the certificate it's embedded in
+fn p256_public_key_sha256(pkcs8: &[u8]) -> [u8; 32] {
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    let key = EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_ASN1_SIGNING,
+        untrusted::Input::from(pkcs8),
+    )
+    .unwrap();
+    // SEQUENCE { SEQUENCE { id-ecPublicKey, prime256v1 }, BIT STRING { uncompressed point } }
+    let mut spki = vec![
+        0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08,
+        0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+    ];
+    spki.extend_from_slice(key.public_key().as_ref());
+    let mut hash = [0; 32];
+    hash.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, &spki).as_ref());
+    hash
+}
+
+#[test]
+fn client_authentication() {
+    let (client_cert, client_key) = self_signed();
+    let authorities = [client_cert.clone()];
+    let require = || ServerConfigBuilder::require_client_certificates(&authorities).unwrap();
+    let request = || ServerConfigBuilder::request_client_certificates(&authorities).unwrap();
+    let identity = Some((client_cert.clone(), client_key));
+    let presented = Ok(Some(client_cert.as_der().to_vec()));
+
+    assert_eq!(authenticate(require(), identity.clone()), presented);
+    assert_eq!(authenticate(require(), None), Err(()));
+    assert_eq!(authenticate(request(), identity), presented);
+    assert_eq!(authenticate(request(), None), Ok(None));
+    // A certificate from an unknown authority is refused even when certificates are optional
+    let (other_cert, other_key) = self_signed();
+    assert_eq!(
+        authenticate(request(), Some((other_cert, other_key))),
+        Err(())
+    );
+}
+
+/// Connect a client presenting `identity` to a server configured by `server_config`
+///
+/// Returns the end-entity certificate the server verified, or `Err` if the handshake failed.
+fn authenticate(
+    mut server_config: ServerConfigBuilder,
+    identity: Option<(Certificate, PrivateKey)>,
+) -> Result<Option<Vec<u8>>, ()> {
+    let (cert, key) = self_signed();
+    server_config
+        .set_certificate(CertificateChain::from_certs(vec![cert.clone()]), key)
+        .unwrap();
+    let mut server = EndpointBuilder::new(Config::default());
+    server.logger(logger());
+    server.listen(server_config.build());
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    if let Some((cert, key)) = identity {
+        client_config
+            .set_certificate(CertificateChain::from_certs(vec![cert]), key)
+            .unwrap();
+    }
+    let mut client = Endpoint::new();
+    client.logger(logger());
+    client.default_client_config(client_config.build());
+
+    let network = Network::new(Conditions::default());
+    let (client, server_addr, mut incoming) = loopback_endpoints(&network, server, client);
+    let verified = Rc::new(RefCell::new(None));
+    network.spawn({
+        let verified = verified.clone();
+        async move {
+            while let Some(conn) = incoming.next().await {
+                let mut streams = conn.incoming;
+                let stream = match streams.next().await {
+                    Some(Ok(NewStream::Bi(stream))) => stream,
+                    _ => continue,
+                };
+                // Streams are only accepted once the client's certificate has been verified
+                *verified.borrow_mut() = Some(conn.connection.peer_certificates());
+                if let Ok((mut stream, data)) = read_to_end(stream, usize::max_value()).await {
+                    let _ = stream.write_all(&data).await;
+                    let _ = stream.finish().await;
+                }
+            }
+        }
+    });
+    network.block_on(async move {
+        // The client may consider itself connected before the server has checked its
+        // certificate, so only a completed exchange proves the server accepted it
+        let conn = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .await
+            .map_err(drop)?
+            .connection;
+        let mut stream = conn.open_bi().await.map_err(drop)?;
+        stream.write_all(b"hello").await.map_err(drop)?;
+        stream.finish().await.map_err(drop)?;
+        read_to_end(stream, usize::max_value())
+            .await
+            .map_err(drop)?;
+        conn.close(0, b"done").await;
+        Ok(())
+    })?;
+    let chain = verified
+        .borrow_mut()
+        .take()
+        .expect("server saw no connection");
+    Ok(chain.and_then(|x| x.iter().next().map(|x| x.to_vec())))
+}
+
+#[test]
+fn handles_used_across_threads() {
+    let log = logger();
+    let mut runtime = current_thread::Runtime::new().unwrap();
+    let (server_addrs, cert) =
+        spawn_echo_server(&mut runtime, &log, &["127.0.0.1:0".parse().unwrap()]);
+    let client = spawn_client(&mut runtime, &log, &cert, "127.0.0.1:0".parse().unwrap());
+
+    // The drivers run here while another thread uses the client
+    let (send, recv) = futures::sync::oneshot::channel();
+    let user = thread::spawn(move || {
+        let server_addr = server_addrs[0];
+        compat(async move { connect_and_echo(&client, server_addr, b"foo").await })
+            .wait()
+            .unwrap();
+        send.send(()).unwrap();
+    });
+    runtime.block_on(recv).unwrap();
+    user.join().unwrap();
+}
+
+/// A thread panicking while it holds the lock may leave the endpoint's state half-updated, so the
+/// driver and every connection must fail
+#[test]
+fn poisoned_endpoint() {
+    let mut client = Endpoint::new();
+    client.logger(logger());
+    client.default_client_config(ClientConfigBuilder::default().build());
+    let (client, mut driver, _) = client
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .unwrap();
+    let server_addr = "127.0.0.1:1".parse().unwrap();
+    let mut connecting = client.connect(&server_addr, "localhost").unwrap();
+
+    let poisoned = client.clone();
+    thread::spawn(move || {
+        let _guard = poisoned.inner.lock();
+        panic!("poisoning the endpoint");
+    })
+    .join()
+    .unwrap_err();
+
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let failed = |x: Poll<Result<_, ConnectionError>>| match x {
+        Poll::Ready(Err(ConnectionError::TransportError(e))) => {
+            e.code == TransportErrorCode::INTERNAL_ERROR
+        }
+        _ => false,
+    };
+    assert!(failed(Pin::new(&mut connecting).poll(&mut cx)));
+    match Pin::new(&mut driver).poll(&mut cx) {
+        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::Other => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+    // Connections made afterwards fail too, rather than waiting for a driver that's gone
+    let mut connecting = client.connect(&server_addr, "localhost").unwrap();
+    assert!(failed(Pin::new(&mut connecting).poll(&mut cx)));
+}
+
+#[test]
+fn handles_are_send_sync() {
+    fn check<T: Send + Sync>() {}
+    check::<Endpoint>();
+    check::<Driver>();
+    check::<Connecting>();
+    check::<Incoming>();
+    check::<NewConnection>();
+    check::<Connection>();
+    check::<IncomingStreams>();
+    check::<BiStream>();
+    check::<SendStream>();
+    check::<RecvStream>();
+    #[cfg(feature = "noise")]
+    check::<Connection<crate::crypto::noise::NoiseSession>>();
+}
+
+/// Run `future` on a tokio 0.1 runtime
+fn compat(
+    future: impl std::future::Future<Output = ()> + 'static,
+) -> impl Future<Item = (), Error = ()> {
+    Box::pin(future.unit_error()).compat()
+}
+
+/// Send `msg` on a new stream and check it's echoed back
+async fn echo_roundtrip<S: CryptoSession>(conn: &Connection<S>, msg: &[u8]) {
+    let mut stream = conn.open_bi().await.expect("open");
+    stream.write_all(msg).await.expect("write");
+    stream.finish().await.expect("finish");
+    let (_, data) = read_to_end(stream, usize::max_value()).await.expect("read");
+    assert_eq!(&data[..], msg);
+}
+
+/// Echo the streams of every incoming connection
+async fn serve_echo<S: CryptoSession + 'static>(incoming: Incoming<S>) {
+    incoming
+        .for_each_concurrent(None, |conn| echo(conn.incoming))
+        .await;
 }
 
-fn echo(stream: NewStream) -> Box<dyn Future<Item = (), Error = ()>> {
-    match stream {
-        NewStream::Bi(stream) => Box::new(
-            tokio::io::read_to_end(stream, Vec::new())
-                .and_then(|(stream, data)| tokio::io::write_all(stream, data))
-                .and_then(|(stream, _)| tokio::io::shutdown(stream))
-                .map_err(|_| ())
-                .map(|_| ()),
-        ),
-        _ => panic!("only bidi streams allowed"),
+async fn echo<S: CryptoSession>(mut streams: IncomingStreams<S>) {
+    while let Some(Ok(stream)) = streams.next().await {
+        let stream = match stream {
+            NewStream::Bi(stream) => stream,
+            _ => panic!("only bidi streams allowed"),
+        };
+        if let Ok((mut stream, data)) = read_to_end(stream, usize::max_value()).await {
+            let _ = stream.write_all(&data).await;
+            let _ = stream.finish().await;
+        }
     }
 }
 
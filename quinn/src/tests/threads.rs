@@ -0,0 +1,210 @@
+//! A runtime built from nothing but std threads, so endpoints can be tested without tokio
+//!
+//! Every timer and socket is watched by a thread of its own, and each future runs on a dedicated
+//! thread that parks while it's pending. Hopelessly inefficient, but independent of any reactor.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, IoSliceMut};
+use std::net::{SocketAddr, UdpSocket};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use quinn_proto::Transmit;
+
+use crate::runtime::{AsyncTimer, AsyncUdpSocket, RecvMeta, Runtime};
+
+/// Supplies timers and sockets that are each driven by a thread
+pub struct ThreadRuntime;
+
+impl Runtime for ThreadRuntime {
+    fn new_timer(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(Timer {
+            deadline,
+            scheduled: None,
+            waker: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    fn wrap_udp_socket(&self, socket: UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Ok(Box::new(Socket::new(socket)?))
+    }
+}
+
+/// Run `future` to completion on the current thread
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(x) = future.as_mut().poll(&mut cx) {
+            return x;
+        }
+        thread::park();
+    }
+}
+
+/// Run `future` to completion on a new thread
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    thread::spawn(move || block_on(future));
+}
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+struct Timer {
+    deadline: Instant,
+    /// Deadline for which a thread has been started to wake the task
+    scheduled: Option<Instant>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl AsyncTimer for Timer {
+    fn reset(self: Pin<&mut Self>, deadline: Instant) {
+        self.get_mut().deadline = deadline;
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let now = Instant::now();
+        if now >= this.deadline {
+            return Poll::Ready(());
+        }
+        *this.waker.lock().unwrap() = Some(cx.waker().clone());
+        if this.scheduled != Some(this.deadline) {
+            this.scheduled = Some(this.deadline);
+            let (delay, waker) = (this.deadline - now, this.waker.clone());
+            // Threads sleeping until a superseded deadline just wake the task early
+            thread::spawn(move || {
+                thread::sleep(delay);
+                if let Some(ref x) = *waker.lock().unwrap() {
+                    x.wake_by_ref();
+                }
+            });
+        }
+        Poll::Pending
+    }
+}
+
+/// A blocking UDP socket whose datagrams are received by a thread of their own
+struct Socket {
+    socket: UdpSocket,
+    inbox: Arc<Mutex<Inbox>>,
+    closed: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct Inbox {
+    queue: VecDeque<(SocketAddr, Box<[u8]>)>,
+    error: Option<io::Error>,
+    reader: Option<Waker>,
+}
+
+impl Socket {
+    fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(false)?;
+        // Bounds how long the receiving thread outlives the socket
+        socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+        let receiver = socket.try_clone()?;
+        let inbox = Arc::new(Mutex::new(Inbox::default()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (shared, done) = (inbox.clone(), closed.clone());
+        thread::spawn(move || {
+            let mut buf = [0; 64 * 1024];
+            while !done.load(Ordering::Relaxed) {
+                let result = receiver.recv_from(&mut buf);
+                let inbox = &mut *shared.lock().unwrap();
+                match result {
+                    Ok((len, addr)) => inbox.queue.push_back((addr, buf[..len].into())),
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        continue;
+                    }
+                    Err(e) => {
+                        inbox.error = Some(e);
+                        done.store(true, Ordering::Relaxed);
+                    }
+                }
+                if let Some(x) = inbox.reader.take() {
+                    x.wake();
+                }
+            }
+        });
+        Ok(Self {
+            socket,
+            inbox,
+            closed,
+        })
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl AsyncUdpSocket for Socket {
+    fn poll_send(&self, _: &mut Context<'_>, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        for (i, transmit) in transmits.iter().enumerate() {
+            if let Err(e) = self
+                .socket
+                .send_to(&transmit.contents, transmit.destination)
+            {
+                if i == 0 {
+                    return Poll::Ready(Err(e));
+                }
+                return Poll::Ready(Ok(i));
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let inbox = &mut *self.inbox.lock().unwrap();
+        let mut filled = 0;
+        while filled < bufs.len() {
+            let (addr, data) = match inbox.queue.pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+            let len = data.len().min(bufs[filled].len());
+            bufs[filled][..len].copy_from_slice(&data[..len]);
+            meta[filled] = RecvMeta {
+                addr,
+                len,
+                stride: len,
+                ..RecvMeta::default()
+            };
+            filled += 1;
+        }
+        if filled > 0 {
+            return Poll::Ready(Ok(filled));
+        }
+        if let Some(e) = inbox.error.take() {
+            return Poll::Ready(Err(e));
+        }
+        inbox.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
@@ -1,8 +1,14 @@
 //! TLS-related helpers
 
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::{Arc, RwLock};
 
-use rustls::{self, internal::pemfile};
+use ring::digest;
+use rustls::{
+    self, internal::pemfile, sign, ResolvesServerCert, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, SignatureScheme, TLSError,
+};
 
 /// A single TLS certificate
 #[derive(Debug, Clone)]
@@ -17,6 +23,22 @@ impl Certificate {
             inner: rustls::Certificate(der.to_vec()),
         })
     }
+
+    /// The DER encoding of the certificate
+    pub fn as_der(&self) -> &[u8] {
+        &self.inner.0
+    }
+
+    /// The SHA-256 digest of the certificate's DER-encoded SubjectPublicKeyInfo
+    ///
+    /// Suitable for use with `ClientConfigBuilder::pin_public_key`.
+    pub fn public_key_sha256(&self) -> Result<[u8; 32], ParseError> {
+        let spki =
+            subject_public_key_info(&self.inner.0).ok_or(ParseError("malformed certificate"))?;
+        let mut hash = [0; 32];
+        hash.copy_from_slice(digest::digest(&digest::SHA256, spki).as_ref());
+        Ok(hash)
+    }
 }
 
 /// A chain of signed TLS certificates ending the one to be used by a server
@@ -43,6 +65,11 @@ impl CertificateChain {
     pub fn from_certs(certs: impl IntoIterator<Item = Certificate>) -> Self {
         certs.into_iter().collect()
     }
+
+    /// The DER encoding of each certificate, starting with the end-entity certificate
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.certs.iter().map(|x| &x.0[..])
+    }
 }
 
 impl std::iter::FromIterator<Certificate> for CertificateChain {
@@ -91,6 +118,256 @@ impl PrivateKey {
     }
 }
 
+/// Selects the certificate presented to each client based on the server name it requests
+///
+/// Names may be exact hostnames or wildcards such as `*.example.com`, which match exactly one
+/// label. Clients which request no known name, or none at all, are presented the default
+/// certificate if one is set, and otherwise fail the handshake.
+///
+/// Clones share the same set of certificates, so a clone retained after passing the resolver to
+/// `ServerConfigBuilder::set_certificate_resolver` can be used to update certificates without
+/// rebinding the endpoint. Updates take effect for subsequent handshakes.
+#[derive(Clone, Default)]
+pub struct CertificateResolver {
+    inner: Arc<RwLock<ResolverState>>,
+}
+
+#[derive(Default)]
+struct ResolverState {
+    exact: HashMap<String, sign::CertifiedKey>,
+    /// Wildcard certificates, keyed by the name with the leading `*.` removed
+    wildcard: HashMap<String, sign::CertifiedKey>,
+    default: Option<sign::CertifiedKey>,
+}
+
+impl CertificateResolver {
+    /// Construct a resolver with no certificates
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Present `cert_chain` to clients requesting `name`, replacing any existing entry
+    ///
+    /// Fails if `name` is not a valid DNS name or wildcard, or if the certificate is not valid for
+    /// it.
+    pub fn add(
+        &self,
+        name: &str,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<(), TLSError> {
+        let name = name.to_ascii_lowercase();
+        let (suffix, check_name) = match name.get(..2) {
+            Some("*.") => (Some(&name[2..]), format!("wildcard-check.{}", &name[2..])),
+            _ => (None, name.clone()),
+        };
+        let check_name = webpki::DNSNameRef::try_from_ascii_str(&check_name)
+            .map_err(|_| TLSError::General("invalid DNS name".into()))?;
+        let certified = certified_key(cert_chain, key)?;
+        certified.cross_check_end_entity_cert(Some(check_name))?;
+        let mut state = self.inner.write().unwrap();
+        match suffix {
+            Some(suffix) => state.wildcard.insert(suffix.into(), certified),
+            None => state.exact.insert(name, certified),
+        };
+        Ok(())
+    }
+
+    /// Stop presenting the certificate registered for `name`, returning whether there was one
+    pub fn remove(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        let mut state = self.inner.write().unwrap();
+        match name.get(..2) {
+            Some("*.") => state.wildcard.remove(&name[2..]).is_some(),
+            _ => state.exact.remove(&name).is_some(),
+        }
+    }
+
+    /// Present `cert_chain` to clients requesting no known name
+    pub fn set_default(
+        &self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<(), TLSError> {
+        let certified = certified_key(cert_chain, key)?;
+        certified.cross_check_end_entity_cert(None)?;
+        self.inner.write().unwrap().default = Some(certified);
+        Ok(())
+    }
+
+    /// Stop presenting a certificate to clients requesting no known name
+    pub fn clear_default(&self) {
+        self.inner.write().unwrap().default = None;
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef<'_>>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<sign::CertifiedKey> {
+        let state = self.inner.read().unwrap();
+        if let Some(name) = server_name {
+            let name: &str = name.into();
+            let name = name.to_ascii_lowercase();
+            if let Some(x) = state.exact.get(&name) {
+                return Some(x.clone());
+            }
+            if let Some(x) = name
+                .find('.')
+                .and_then(|i| state.wildcard.get(&name[i + 1..]))
+            {
+                return Some(x.clone());
+            }
+        }
+        state.default.clone()
+    }
+}
+
+impl fmt::Debug for CertificateResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.inner.read().unwrap();
+        f.debug_struct("CertificateResolver")
+            .field("exact", &state.exact.keys().collect::<Vec<_>>())
+            .field(
+                "wildcard",
+                &state
+                    .wildcard
+                    .keys()
+                    .map(|x| format!("*.{}", x))
+                    .collect::<Vec<_>>(),
+            )
+            .field("default", &state.default.is_some())
+            .finish()
+    }
+}
+
+fn certified_key(
+    cert_chain: CertificateChain,
+    key: PrivateKey,
+) -> Result<sign::CertifiedKey, TLSError> {
+    let key = sign::any_supported_type(&key.inner)
+        .map_err(|()| TLSError::General("invalid private key".into()))?;
+    Ok(sign::CertifiedKey::new(cert_chain.certs, Arc::new(key)))
+}
+
+/// Application-defined verification of the certificates presented by servers
+///
+/// Implemented for any suitable closure. The server's possession of the end-entity certificate's
+/// private key is verified by the TLS handshake regardless of this decision.
+pub trait CertificateVerifier: Send + Sync {
+    /// Whether to accept `presented`, starting with the end-entity certificate, from a server
+    /// that was connected to as `server_name`
+    fn verify(&self, presented: &CertificateChain, server_name: &str) -> bool;
+}
+
+impl<F> CertificateVerifier for F
+where
+    F: Fn(&CertificateChain, &str) -> bool + Send + Sync,
+{
+    fn verify(&self, presented: &CertificateChain, server_name: &str) -> bool {
+        self(presented, server_name)
+    }
+}
+
+/// Accepts servers presenting one of a set of known end-entity certificates or public keys
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PinningVerifier {
+    pub(crate) public_keys: Vec<[u8; 32]>,
+    pub(crate) certificates: Vec<rustls::Certificate>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let end_entity = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        if self.certificates.contains(end_entity) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let cert = Certificate {
+            inner: end_entity.clone(),
+        };
+        match cert.public_key_sha256() {
+            Ok(hash) if self.public_keys.contains(&hash) => Ok(ServerCertVerified::assertion()),
+            _ => Err(TLSError::WebPKIError(webpki::Error::UnknownIssuer)),
+        }
+    }
+}
+
+/// Adapts a `CertificateVerifier` to rustls
+pub(crate) struct CustomVerifier(pub(crate) Box<dyn CertificateVerifier>);
+
+impl ServerCertVerifier for CustomVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let chain = CertificateChain {
+            certs: presented_certs.to_vec(),
+        };
+        if self.0.verify(&chain, dns_name.into()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TLSError::WebPKIError(webpki::Error::UnknownIssuer))
+        }
+    }
+}
+
+/// Locate the DER-encoded SubjectPublicKeyInfo within a DER-encoded X.509 certificate
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let mut cert = cert;
+    let (mut cert, _) = der_element(&mut cert, DER_SEQUENCE)?;
+    let (mut tbs, _) = der_element(&mut cert, DER_SEQUENCE)?;
+    if tbs.first() == Some(&DER_EXPLICIT_VERSION) {
+        der_element(&mut tbs, DER_EXPLICIT_VERSION)?;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    der_element(&mut tbs, DER_INTEGER)?;
+    for _ in 0..4 {
+        der_element(&mut tbs, DER_SEQUENCE)?;
+    }
+    let (_, spki) = der_element(&mut tbs, DER_SEQUENCE)?;
+    Some(spki)
+}
+
+/// Split an element with tag `tag` off the front of `buf`, returning its contents and complete
+/// encoding
+fn der_element<'a>(buf: &mut &'a [u8], tag: u8) -> Option<(&'a [u8], &'a [u8])> {
+    let whole = *buf;
+    if *buf.first()? != tag || buf.len() < 2 {
+        return None;
+    }
+    let (len, header_len) = match buf[1] {
+        x if x < 0x80 => (usize::from(x), 2),
+        x @ 0x81..=0x84 => {
+            let n = usize::from(x & 0x7f);
+            let bytes = buf.get(2..2 + n)?;
+            let len = bytes.iter().fold(0, |acc, &b| (acc << 8) | usize::from(b));
+            (len, 2 + n)
+        }
+        _ => return None,
+    };
+    let end = header_len.checked_add(len)?;
+    let contents = buf.get(header_len..end)?;
+    *buf = &buf[end..];
+    Some((contents, &whole[..end]))
+}
+
+const DER_INTEGER: u8 = 0x02;
+const DER_SEQUENCE: u8 = 0x30;
+const DER_EXPLICIT_VERSION: u8 = 0xa0;
+
 /// Errors encountered while parsing a TLS certificate or private key
 #[derive(Debug, Clone)]
 pub struct ParseError(&'static str);
@@ -1,39 +1,47 @@
-use std::io;
+use std::io::{self, IoSliceMut};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use futures::{try_ready, Async, Poll};
 use mio;
 
 use tokio_reactor::{Handle, PollEvented};
 
-use quinn_proto::EcnCodepoint;
+use quinn_proto::Transmit;
 
 use crate::platform::UdpExt;
+use crate::runtime::RecvMeta;
 
 /// Tokio-compatible UDP socket with some useful specializations.
 ///
-/// Unlike a standard tokio UDP socket, this allows ECN bits to be read and written on some
-/// platforms.
+/// Unlike a standard tokio UDP socket, this allows ECN bits to be read and written, and batches of
+/// datagrams to be sent and received at once, on some platforms.
 pub struct UdpSocket {
     io: PollEvented<mio::net::UdpSocket>,
+    /// Datagrams that may be sent at once; reduced to 1 if segmentation offload fails
+    max_segments: AtomicUsize,
 }
 
 impl UdpSocket {
     pub fn from_std(socket: std::net::UdpSocket, handle: &Handle) -> io::Result<UdpSocket> {
         let io = mio::net::UdpSocket::from_socket(socket)?;
         io.init_ext()?;
+        let max_segments = AtomicUsize::new(io.max_gso_segments());
         let io = PollEvented::new_with_handle(io, handle)?;
-        Ok(UdpSocket { io })
+        Ok(UdpSocket { io, max_segments })
     }
 
-    pub fn poll_send(
-        &self,
-        remote: &SocketAddr,
-        ecn: Option<EcnCodepoint>,
-        msg: &[u8],
-    ) -> Poll<usize, io::Error> {
+    pub fn max_transmit_segments(&self) -> usize {
+        self.max_segments.load(Ordering::Relaxed)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+
+    pub fn poll_send(&self, transmits: &[Transmit]) -> Poll<usize, io::Error> {
         try_ready!(self.io.poll_write_ready());
-        match self.io.get_ref().send_ext(remote, ecn, msg) {
+        match send(self.io.get_ref(), &self.max_segments, transmits) {
             Ok(n) => Ok(Async::Ready(n)),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 self.io.clear_write_ready()?;
@@ -45,10 +53,11 @@ impl UdpSocket {
 
     pub fn poll_recv(
         &self,
-        buf: &mut [u8],
-    ) -> Poll<(usize, SocketAddr, Option<EcnCodepoint>), io::Error> {
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<usize, io::Error> {
         try_ready!(self.io.poll_read_ready(mio::Ready::readable()));
-        match self.io.get_ref().recv_ext(buf) {
+        match self.io.get_ref().recv_batch_ext(bufs, meta) {
             Ok(n) => Ok(n.into()),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 self.io.clear_read_ready(mio::Ready::readable())?;
@@ -58,3 +67,231 @@ impl UdpSocket {
         }
     }
 }
+
+/// Send a prefix of `transmits` on `io`, returning the number sent
+///
+/// Segmentation offload can fail at send time, e.g. if the outgoing interface lacks checksum
+/// offload. If so, `max_segments` is reduced to 1 and the batch is sent one datagram at a time,
+/// leaving any that won't fit in the send buffer to loss recovery.
+fn send(io: &impl UdpExt, max_segments: &AtomicUsize, transmits: &[Transmit]) -> io::Result<usize> {
+    match io.send_batch_ext(transmits) {
+        Err(ref e)
+            if transmits[0].segment_size.is_some()
+                && (e.raw_os_error() == Some(libc::EIO)
+                    || e.raw_os_error() == Some(libc::EINVAL)) =>
+        {
+            max_segments.store(1, Ordering::Relaxed);
+            let transmit = &transmits[0];
+            let segment_size = transmit.segment_size.unwrap();
+            for datagram in transmit.contents.chunks(segment_size) {
+                let datagram = Transmit {
+                    destination: transmit.destination,
+                    ecn: transmit.ecn,
+                    contents: datagram.into(),
+                    segment_size: None,
+                    src: transmit.src,
+                    socket: transmit.socket,
+                };
+                match io.send_ext(&datagram) {
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(1)
+        }
+        x => x,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::net::Ipv4Addr;
+
+    /// Rejects batches with `error`, and records the datagrams it's asked to send one at a time
+    struct NoOffload {
+        error: i32,
+        sent: RefCell<Vec<Box<[u8]>>>,
+    }
+
+    impl NoOffload {
+        fn new(error: i32) -> Self {
+            Self {
+                error,
+                sent: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl UdpExt for NoOffload {
+        fn init_ext(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn max_gso_segments(&self) -> usize {
+            32
+        }
+
+        fn send_ext(&self, transmit: &Transmit) -> io::Result<usize> {
+            if transmit.segment_size.is_some() {
+                return Err(io::Error::from_raw_os_error(self.error));
+            }
+            self.sent.borrow_mut().push(transmit.contents.clone());
+            Ok(transmit.contents.len())
+        }
+
+        fn recv_ext(&self, _: &mut [u8]) -> io::Result<RecvMeta> {
+            unimplemented!()
+        }
+    }
+
+    fn transmit(destination: SocketAddr, segment_size: Option<usize>, contents: &[u8]) -> Transmit {
+        Transmit {
+            destination,
+            ecn: None,
+            contents: contents.into(),
+            segment_size,
+            src: None,
+            socket: None,
+        }
+    }
+
+    /// 2.5 datagrams' worth of distinguishable bytes
+    fn payload() -> Vec<u8> {
+        (0..2500).map(|x| x as u8).collect()
+    }
+
+    #[test]
+    fn offload_fallback() {
+        let destination = (Ipv4Addr::LOCALHOST, 4433).into();
+        for &error in &[libc::EIO, libc::EINVAL] {
+            let io = NoOffload::new(error);
+            let max_segments = AtomicUsize::new(io.max_gso_segments());
+            let transmits = [
+                transmit(destination, Some(1000), &payload()),
+                transmit(destination, Some(1000), &payload()),
+            ];
+            assert_eq!(send(&io, &max_segments, &transmits).unwrap(), 1);
+            assert_eq!(max_segments.load(Ordering::Relaxed), 1);
+            let sent = io.sent.into_inner();
+            let expected = payload().chunks(1000).map(Box::from).collect::<Vec<_>>();
+            assert_eq!(sent, expected);
+        }
+    }
+
+    #[test]
+    fn other_errors_propagate() {
+        let io = NoOffload::new(libc::EPERM);
+        let max_segments = AtomicUsize::new(io.max_gso_segments());
+        let transmits = [transmit(
+            (Ipv4Addr::LOCALHOST, 4433).into(),
+            Some(1000),
+            &payload(),
+        )];
+        let e = send(&io, &max_segments, &transmits).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+        assert_eq!(max_segments.load(Ordering::Relaxed), 32);
+        assert!(io.sent.into_inner().is_empty());
+    }
+
+    /// Datagrams coalesced by receive offload must be split back up along the reported stride
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn receive_offload() {
+        use std::time::{Duration, Instant};
+
+        let bind = || {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let socket = mio::net::UdpSocket::from_socket(socket).unwrap();
+            socket.init_ext().unwrap();
+            socket
+        };
+        let (sender, receiver) = (bind(), bind());
+        let destination = receiver.local_addr().unwrap();
+        if sender.max_gso_segments() > 1 {
+            sender
+                .send_ext(&transmit(destination, Some(1000), &payload()))
+                .unwrap();
+        } else {
+            // Without segmentation offload there's nothing for the receiver to coalesce, but the
+            // datagrams must still come out intact
+            for datagram in payload().chunks(1000) {
+                sender
+                    .send_ext(&transmit(destination, None, datagram))
+                    .unwrap();
+            }
+        }
+
+        let mut storage = vec![0; 3 * 64 * 1024];
+        let mut received = Vec::<Box<[u8]>>::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < 3 {
+            assert!(Instant::now() < deadline, "datagrams were lost");
+            let mut bufs = storage
+                .chunks_mut(64 * 1024)
+                .map(IoSliceMut::new)
+                .collect::<Vec<_>>();
+            let mut meta = [RecvMeta::default(); 3];
+            let n = match receiver.recv_batch_ext(&mut bufs, &mut meta) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                Err(e) => panic!("receive failed: {}", e),
+            };
+            for (meta, buf) in meta.iter().zip(bufs.iter()).take(n) {
+                received.extend(meta.datagrams(buf).map(Box::from));
+            }
+        }
+        let expected = payload().chunks(1000).map(Box::from).collect::<Vec<_>>();
+        assert_eq!(received, expected);
+    }
+
+    /// Receive no more datagrams than there's room to describe, however many buffers are given
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn receive_short_meta() {
+        use std::time::{Duration, Instant};
+
+        let bind = || {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let socket = mio::net::UdpSocket::from_socket(socket).unwrap();
+            socket.init_ext().unwrap();
+            socket
+        };
+        let (sender, receiver) = (bind(), bind());
+        let destination = receiver.local_addr().unwrap();
+        for datagram in payload().chunks(1000) {
+            sender
+                .send_ext(&transmit(destination, None, datagram))
+                .unwrap();
+        }
+
+        let mut storage = vec![0; 3 * 64 * 1024];
+        let mut received = Vec::<Box<[u8]>>::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < 3 {
+            assert!(Instant::now() < deadline, "datagrams were lost");
+            let mut bufs = storage
+                .chunks_mut(64 * 1024)
+                .map(IoSliceMut::new)
+                .collect::<Vec<_>>();
+            let mut meta = [RecvMeta::default(); 1];
+            let n = match receiver.recv_batch_ext(&mut bufs, &mut meta) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                Err(e) => panic!("receive failed: {}", e),
+            };
+            assert_eq!(n, 1);
+            received.extend(meta[0].datagrams(&bufs[0]).map(Box::from));
+        }
+        let expected = payload().chunks(1000).map(Box::from).collect::<Vec<_>>();
+        assert_eq!(received, expected);
+    }
+}